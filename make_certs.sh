@@ -1,4 +1,5 @@
 #!/bin/bash
+#prefer `rrocker-cli certs`, which produces the same layout plus a CRL of the client CA
 set -ex
 
 mkdir -p certs/CA
@@ -60,7 +61,7 @@ openssl x509 -req -days 365 -in $CLIENT_CA_CRS_PATH -CA $ROOT_CA_CRT_PATH -CAkey
 #generate admin1 private key and cert sign request (CSR) 
 openssl req -new -newkey ec:<(openssl ecparam -name prime256v1) -keyout $ADMIN1_KEY_PATH -out $ADMIN1_CRS_PATH -extensions usr_cert -addext "keyUsage = keyEncipherment" -addext "extendedKeyUsage = clientAuth" -nodes -subj '/CN=admin1/O=admin'
 #sign admin1 CRS with server CA
-openssl x509 -req -days 1 -in $ADMIN1_CRS_PATH -CA $CLIENT_CA_CRT_PATH -CAkey $CLIENT_CA_KEY_PATH -set_serial 01 -out $CLIENT1_CRT_PATH -extfile <(printf "keyUsage = keyEncipherment\nextendedKeyUsage = clientAuth\n")
+openssl x509 -req -days 1 -in $ADMIN1_CRS_PATH -CA $CLIENT_CA_CRT_PATH -CAkey $CLIENT_CA_KEY_PATH -set_serial 01 -out $ADMIN1_CRT_PATH -extfile <(printf "keyUsage = keyEncipherment\nextendedKeyUsage = clientAuth\n")
 
 #generate client1 private key and cert sign request (CSR) 
 openssl req -new -newkey ec:<(openssl ecparam -name prime256v1) -keyout $CLIENT1_KEY_PATH -out $CLIENT1_CRS_PATH -extensions usr_cert -addext "keyUsage = keyEncipherment" -addext "extendedKeyUsage = clientAuth" -nodes -subj '/CN=client1/O=client'
@@ -6,4 +6,33 @@ mkdir -p certs/test
 openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/good_key.pem -out certs/test/good_crt.pem -days 3650 -nodes -subj '/CN=client1/O=client'
 openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/invalid_org_name_key.pem -out certs/test/invalid_org_name_crt.pem -days 3650 -nodes -subj '/CN=client1/O=invalid'
 openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/missing_org_name_key.pem -out certs/test/missing_org_name_crt.pem -days 3650 -nodes -subj '/CN=client1'
-openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/missing_cn_key.pem -out certs/test/missing_cn_crt.pem -days 3650 -nodes -subj '/'
\ No newline at end of file
+openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/missing_cn_key.pem -out certs/test/missing_cn_crt.pem -days 3650 -nodes -subj '/'
+openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/spiffe_key.pem -out certs/test/spiffe_crt.pem -days 3650 -nodes -subj '/CN=client1' -addext 'subjectAltName=URI:spiffe://corp/team/x/user/y'
+openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/invalid_spiffe_key.pem -out certs/test/invalid_spiffe_crt.pem -days 3650 -nodes -subj '/CN=client1/O=client' -addext 'subjectAltName=URI:spiffe://corp/../y'
+openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/spiffe_cn_key.pem -out certs/test/spiffe_cn_crt.pem -days 3650 -nodes -subj '/CN=spiffe:\/\/corp\/team\/x\/user\/y/O=client'
+openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/unix_cn_key.pem -out certs/test/unix_cn_crt.pem -days 3650 -nodes -subj '/CN=unix:1003/O=client'
+openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/multi_group_key.pem -out certs/test/multi_group_crt.pem -days 3650 -nodes -subj '/CN=client2/O=client/O=ml/OU=sre/OU=oncall'
+
+# A CA with a revoked and a valid client cert plus its CRL for the revocation tests
+CA_DIR=$(mktemp -d)
+trap 'rm -rf "$CA_DIR"' EXIT
+openssl req -x509 -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/crl_ca_key.pem -out certs/test/crl_ca_crt.pem -days 3650 -nodes -subj '/CN=test-ca/O=rrocker'
+SERIAL=1
+for NAME in revoked not_revoked; do
+    openssl req -newkey ec:<(openssl ecparam -name prime256v1) -keyout certs/test/${NAME}_key.pem -out "$CA_DIR/$NAME.csr" -nodes -subj "/CN=$NAME/O=client"
+    openssl x509 -req -in "$CA_DIR/$NAME.csr" -CA certs/test/crl_ca_crt.pem -CAkey certs/test/crl_ca_key.pem -set_serial $SERIAL -days 3650 -out certs/test/${NAME}_crt.pem
+    SERIAL=$((SERIAL + 1))
+done
+touch "$CA_DIR/index.txt"
+echo 01 > "$CA_DIR/crlnumber"
+cat > "$CA_DIR/ca.cnf" <<CNF
+[ca]
+default_ca = test_ca
+[test_ca]
+database = $CA_DIR/index.txt
+crlnumber = $CA_DIR/crlnumber
+default_md = sha256
+default_crl_days = 3650
+CNF
+openssl ca -config "$CA_DIR/ca.cnf" -cert certs/test/crl_ca_crt.pem -keyfile certs/test/crl_ca_key.pem -revoke certs/test/revoked_crt.pem
+openssl ca -config "$CA_DIR/ca.cnf" -cert certs/test/crl_ca_crt.pem -keyfile certs/test/crl_ca_key.pem -gencrl -out certs/test/crl.pem
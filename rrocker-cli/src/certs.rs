@@ -0,0 +1,671 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Clap;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, CertificateRevocationList,
+    CertificateRevocationListParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyIdMethod, KeyPair, KeyUsagePurpose, RevocationReason, RevokedCertParams, SanType,
+    SerialNumber, PKCS_ECDSA_P256_SHA256,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+
+#[derive(Clap, Debug)]
+pub struct CertsOpts {
+    /// Directory holding the CAs and certificates, rrockerd and rrocker-cli default to `certs`
+    #[clap(long, default_value = "certs")]
+    dir: PathBuf,
+    #[clap(subcommand)]
+    cmd: CertsCommand,
+}
+
+#[derive(Clap, Debug)]
+enum CertsCommand {
+    /// Create the root CA, the server and client CAs it signs, their chains and an empty CRL
+    Init(InitOpts),
+    /// Issue a certificate for a rrockerd daemon
+    Server(ServerOpts),
+    /// Issue a certificate for a client, e.g. to onboard a new teammate
+    Client(ClientOpts),
+    /// List the issued certificates
+    List,
+    /// Revoke a client certificate by name or serial and reissue the client CA's CRL
+    Revoke(RevokeOpts),
+}
+
+#[derive(Clap, Debug)]
+struct InitOpts {
+    /// Days the CAs are valid for
+    #[clap(long, default_value = "3650")]
+    days: u32,
+    /// Replace existing CAs, certificates issued by them stop being trusted
+    #[clap(long)]
+    force: bool,
+}
+
+#[derive(Clap, Debug)]
+struct ServerOpts {
+    /// Name of the certificate, it's written to `<name>_crt.pem` and `<name>_key.pem`
+    #[clap(long, default_value = "server1")]
+    name: String,
+    /// DNS names and IP addresses the daemon is reachable at, defaults to localhost and 127.0.0.1
+    #[clap(long = "san")]
+    sans: Vec<String>,
+    /// Days the certificate is valid for
+    #[clap(long, default_value = "365")]
+    days: u32,
+    /// Replace an existing certificate of the same name
+    #[clap(long)]
+    force: bool,
+}
+
+#[derive(Clap, Debug)]
+struct ClientOpts {
+    /// Common name (CN) of the client, its principal unless it has a SPIFFE ID
+    common_name: String,
+    /// Organization (O) of the client, the group its quotas and role bindings apply to
+    #[clap(long)]
+    org: Option<String>,
+    /// Organizational unit (OU) of the client
+    #[clap(long)]
+    ou: Option<String>,
+    /// `spiffe://` URI SAN identifying the client instead of its common name
+    #[clap(long)]
+    spiffe_id: Option<String>,
+    /// Days the certificate is valid for
+    #[clap(long, default_value = "365")]
+    days: u32,
+    /// Name of the certificate's files, defaults to the common name
+    #[clap(long)]
+    name: Option<String>,
+    /// Replace an existing certificate of the same name, the old one stays valid until revoked
+    #[clap(long)]
+    force: bool,
+}
+
+#[derive(Clap, Debug)]
+struct RevokeOpts {
+    /// Name or serial of the certificate
+    cert: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Kind {
+    RootCa,
+    ServerCa,
+    ClientCa,
+    Server,
+    Client,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Kind::RootCa => "root-ca",
+            Kind::ServerCa => "server-ca",
+            Kind::ClientCa => "client-ca",
+            Kind::Server => "server",
+            Kind::Client => "client",
+        })
+    }
+}
+
+/// A certificate issued by one of the CAs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Issued {
+    serial: u64,
+    name: String,
+    kind: Kind,
+    subject: String,
+    /// RFC 3339
+    not_after: String,
+    /// RFC 3339 time of the revocation
+    revoked: Option<String>,
+}
+
+/// Everything issued so far, the CAs' serials are allocated from here
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    next_serial: u64,
+    crl_number: u64,
+    certs: Vec<Issued>,
+}
+
+/// The files in the directory, the same layout make_certs.sh produces
+struct Layout {
+    dir: PathBuf,
+}
+
+impl Layout {
+    fn ca_key(&self, ca: Kind) -> PathBuf {
+        self.dir
+            .join("CA")
+            .join(format!("{}_key.pem", Self::ca_name(ca)))
+    }
+
+    fn ca_crt(&self, ca: Kind) -> PathBuf {
+        self.dir
+            .join("CA")
+            .join(format!("{}_crt.pem", Self::ca_name(ca)))
+    }
+
+    fn ca_name(ca: Kind) -> &'static str {
+        match ca {
+            Kind::RootCa => "root_ca",
+            Kind::ServerCa => "server_ca",
+            Kind::ClientCa => "client_ca",
+            _ => unreachable!("{} isn't a CA", ca),
+        }
+    }
+
+    fn chain(&self, ca: Kind) -> PathBuf {
+        self.dir.join(format!("{}_chain.pem", Self::ca_name(ca)))
+    }
+
+    fn crl(&self) -> PathBuf {
+        self.dir.join("client_ca_crl.pem")
+    }
+
+    fn index(&self) -> PathBuf {
+        self.dir.join("CA").join("index.json")
+    }
+
+    fn key(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}_key.pem", name))
+    }
+
+    fn crt(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}_crt.pem", name))
+    }
+
+    fn read(path: &Path) -> Result<String> {
+        std::fs::read_to_string(path).context(format!("Failed to read '{:?}'", path))
+    }
+
+    /// Write a file, keys are only readable by their owner
+    fn write(path: &Path, content: &str, mode: u32) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(path)
+            .context(format!("Failed to create '{:?}'", path))?;
+        file.write_all(content.as_bytes())
+            .context(format!("Failed to write '{:?}'", path))
+    }
+
+    fn load_index(&self) -> Result<Index> {
+        let path = self.index();
+        if !path.exists() {
+            return Err(anyhow!(
+                "'{:?}' doesn't exist, run `rrocker-cli certs init` first",
+                path
+            ));
+        }
+        serde_json::from_str(&Self::read(&path)?).context(format!("Invalid index '{:?}'", path))
+    }
+
+    fn save_index(&self, index: &Index) -> Result<()> {
+        Self::write(&self.index(), &serde_json::to_string_pretty(index)?, 0o644)
+    }
+
+    /// Load a CA so it can sign, only its subject, key and algorithm matter
+    fn load_ca(&self, ca: Kind) -> Result<Certificate> {
+        let key = KeyPair::from_pem(&Self::read(&self.ca_key(ca))?)
+            .context(format!("Invalid key of the {}", ca))?;
+        let params = CertificateParams::from_ca_cert_pem(&Self::read(&self.ca_crt(ca))?, key)
+            .context(format!("Invalid certificate of the {}", ca))?;
+        Ok(Certificate::from_params(params)?)
+    }
+}
+
+/// Certificates only store whole seconds
+fn now() -> OffsetDateTime {
+    let now = OffsetDateTime::now_utc();
+    now - Duration::nanoseconds(now.nanosecond().into())
+}
+
+fn rfc3339(time: OffsetDateTime) -> String {
+    //formatting a UTC time as RFC 3339 can't fail
+    time.format(&Rfc3339).unwrap()
+}
+
+fn subject(dn: &DistinguishedName) -> String {
+    [
+        (DnType::CommonName, "CN"),
+        (DnType::OrganizationName, "O"),
+        (DnType::OrganizationalUnitName, "OU"),
+    ]
+    .iter()
+    .filter_map(|(ty, label)| match dn.get(ty)? {
+        rcgen::DnValue::Utf8String(s) | rcgen::DnValue::PrintableString(s) => {
+            Some(format!("{}={}", label, s))
+        }
+        _ => None,
+    })
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// Params shared by every certificate, the serial is the next one of the index
+fn new_params(index: &Index, common_name: &str, days: u32) -> CertificateParams {
+    let now = now();
+    let mut params = CertificateParams::default();
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.key_identifier_method = KeyIdMethod::Sha256;
+    params.not_before = now;
+    params.not_after = now + Duration::days(days.into());
+    params.serial_number = Some(SerialNumber::from(index.next_serial));
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params.use_authority_key_identifier_extension = true;
+    params
+}
+
+/// Sign the certificate and record it in the index, returns the certificate's PEM
+fn issue(
+    index: &mut Index,
+    name: &str,
+    kind: Kind,
+    cert: &Certificate,
+    issuer: Option<&Certificate>,
+) -> Result<String> {
+    let pem = match issuer {
+        Some(issuer) => cert.serialize_pem_with_signer(issuer)?,
+        None => cert.serialize_pem()?,
+    };
+    let params = cert.get_params();
+    index.certs.push(Issued {
+        serial: index.next_serial,
+        name: name.to_owned(),
+        kind,
+        subject: subject(&params.distinguished_name),
+        not_after: rfc3339(params.not_after),
+        revoked: None,
+    });
+    index.next_serial += 1;
+    Ok(pem)
+}
+
+fn init(layout: &Layout, opts: &InitOpts) -> Result<()> {
+    if layout.ca_crt(Kind::RootCa).exists() && !opts.force {
+        return Err(anyhow!(
+            "The CAs in '{:?}' already exist, pass --force to replace them",
+            layout.dir
+        ));
+    }
+    std::fs::create_dir_all(layout.dir.join("CA"))
+        .context(format!("Failed to create '{:?}'", layout.dir))?;
+
+    let mut index = Index {
+        next_serial: 1,
+        ..Default::default()
+    };
+
+    let mut params = new_params(&index, "Root Cert Authority", opts.days);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params.use_authority_key_identifier_extension = false;
+    let root = Certificate::from_params(params)?;
+    let root_pem = issue(&mut index, "root_ca", Kind::RootCa, &root, None)?;
+    Layout::write(
+        &layout.ca_key(Kind::RootCa),
+        &root.serialize_private_key_pem(),
+        0o600,
+    )?;
+    Layout::write(&layout.ca_crt(Kind::RootCa), &root_pem, 0o644)?;
+
+    for (ca, common_name) in [
+        (Kind::ServerCa, "Server Cert Authority"),
+        (Kind::ClientCa, "Client Cert Authority"),
+    ] {
+        let mut params = new_params(&index, common_name, opts.days);
+        //intermediates may only sign leaf certificates
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let cert = Certificate::from_params(params)?;
+        let pem = issue(&mut index, Layout::ca_name(ca), ca, &cert, Some(&root))?;
+        Layout::write(&layout.ca_key(ca), &cert.serialize_private_key_pem(), 0o600)?;
+        Layout::write(&layout.ca_crt(ca), &pem, 0o644)?;
+        //the chains are what rrockerd and rrocker-cli trust
+        Layout::write(&layout.chain(ca), &(pem + &root_pem), 0o644)?;
+    }
+
+    write_crl(layout, &mut index)?;
+    layout.save_index(&index)?;
+    println!("Created the CAs in '{}'", layout.dir.display());
+    Ok(())
+}
+
+fn check_name(layout: &Layout, name: &str, force: bool) -> Result<()> {
+    if name.is_empty() || name.contains('/') {
+        return Err(anyhow!("Invalid certificate name '{}'", name));
+    }
+    if layout.crt(name).exists() && !force {
+        return Err(anyhow!(
+            "'{:?}' already exists, pass --force to replace it",
+            layout.crt(name)
+        ));
+    }
+    Ok(())
+}
+
+fn issue_leaf(
+    layout: &Layout,
+    index: &mut Index,
+    name: &str,
+    kind: Kind,
+    params: CertificateParams,
+) -> Result<()> {
+    let ca = layout.load_ca(match kind {
+        Kind::Server => Kind::ServerCa,
+        _ => Kind::ClientCa,
+    })?;
+    let cert = Certificate::from_params(params)?;
+    let pem = issue(index, name, kind, &cert, Some(&ca))?;
+    Layout::write(&layout.key(name), &cert.serialize_private_key_pem(), 0o600)?;
+    Layout::write(&layout.crt(name), &pem, 0o644)?;
+    layout.save_index(index)?;
+    println!(
+        "Issued {} and {}",
+        layout.crt(name).display(),
+        layout.key(name).display()
+    );
+    Ok(())
+}
+
+fn server(layout: &Layout, opts: &ServerOpts) -> Result<()> {
+    let mut index = layout.load_index()?;
+    check_name(layout, &opts.name, opts.force)?;
+
+    let mut params = new_params(&index, &opts.name, opts.days);
+    let sans = if opts.sans.is_empty() {
+        vec!["localhost".to_owned(), "127.0.0.1".to_owned()]
+    } else {
+        opts.sans.clone()
+    };
+    params.subject_alt_names = sans
+        .into_iter()
+        .map(|san| match san.parse() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(san),
+        })
+        .collect();
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    issue_leaf(layout, &mut index, &opts.name, Kind::Server, params)
+}
+
+fn client(layout: &Layout, opts: &ClientOpts) -> Result<()> {
+    let mut index = layout.load_index()?;
+    let name = opts.name.as_ref().unwrap_or(&opts.common_name);
+    check_name(layout, name, opts.force)?;
+
+    let mut params = new_params(&index, &opts.common_name, opts.days);
+    if let Some(org) = &opts.org {
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, org.as_str());
+    }
+    if let Some(ou) = &opts.ou {
+        params
+            .distinguished_name
+            .push(DnType::OrganizationalUnitName, ou.as_str());
+    }
+    if let Some(id) = &opts.spiffe_id {
+        if !id.starts_with("spiffe://") {
+            return Err(anyhow!("'{}' isn't a spiffe:// URI", id));
+        }
+        params.subject_alt_names = vec![SanType::URI(id.clone())];
+    }
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    issue_leaf(layout, &mut index, name, Kind::Client, params)
+}
+
+fn list(layout: &Layout) -> Result<()> {
+    let index = layout.load_index()?;
+    let now = now();
+    println!(
+        "{:>6} {:<10} {:<20} {:<40} {:<21} STATUS",
+        "SERIAL", "KIND", "NAME", "SUBJECT", "NOT AFTER"
+    );
+    for cert in &index.certs {
+        let expired = OffsetDateTime::parse(&cert.not_after, &Rfc3339).is_ok_and(|t| t < now);
+        let status = match &cert.revoked {
+            Some(at) => format!("revoked {}", at),
+            None if expired => "expired".to_owned(),
+            None => "valid".to_owned(),
+        };
+        println!(
+            "{:>6} {:<10} {:<20} {:<40} {:<21} {}",
+            cert.serial, cert.kind, cert.name, cert.subject, cert.not_after, status
+        );
+    }
+    Ok(())
+}
+
+/// Sign a new CRL of the client CA listing every revoked client certificate
+fn write_crl(layout: &Layout, index: &mut Index) -> Result<()> {
+    let ca = layout.load_ca(Kind::ClientCa)?;
+    let revoked_certs = index
+        .certs
+        .iter()
+        .filter(|cert| cert.kind == Kind::Client)
+        .filter_map(|cert| Some((cert.serial, cert.revoked.as_ref()?)))
+        .map(|(serial, at)| {
+            Ok(RevokedCertParams {
+                serial_number: SerialNumber::from(serial),
+                revocation_time: OffsetDateTime::parse(at, &Rfc3339)?,
+                reason_code: Some(RevocationReason::Unspecified),
+                invalidity_date: None,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    index.crl_number += 1;
+    //rrockerd doesn't enforce nextUpdate and the CRL is reissued on every revocation,
+    //so it's simply valid as long as the CA
+    let crl = CertificateRevocationList::from_params(CertificateRevocationListParams {
+        this_update: now(),
+        next_update: ca.get_params().not_after,
+        crl_number: SerialNumber::from(index.crl_number),
+        issuing_distribution_point: None,
+        revoked_certs,
+        alg: &PKCS_ECDSA_P256_SHA256,
+        key_identifier_method: KeyIdMethod::Sha256,
+    })?;
+    Layout::write(&layout.crl(), &crl.serialize_pem_with_signer(&ca)?, 0o644)
+}
+
+fn revoke(layout: &Layout, opts: &RevokeOpts) -> Result<()> {
+    let mut index = layout.load_index()?;
+    let serial = opts.cert.parse::<u64>().ok();
+    let matches = |cert: &Issued| Some(cert.serial) == serial || cert.name == opts.cert;
+
+    let now = rfc3339(now());
+    let mut revoked = Vec::new();
+    for cert in index.certs.iter_mut().filter(|c| matches(c)) {
+        if cert.kind != Kind::Client {
+            return Err(anyhow!(
+                "'{}' is a {} certificate, rrockerd only checks the client CA's CRL",
+                opts.cert,
+                cert.kind
+            ));
+        }
+        if cert.revoked.is_none() {
+            cert.revoked = Some(now.clone());
+            revoked.push(cert.serial);
+        }
+    }
+    if revoked.is_empty() {
+        return Err(anyhow!(
+            "No unrevoked client certificate named '{}' or with that serial",
+            opts.cert
+        ));
+    }
+
+    write_crl(layout, &mut index)?;
+    layout.save_index(&index)?;
+    println!(
+        "Revoked serial {:?}, rrockerd picks up {} on its next CRL reload",
+        revoked,
+        layout.crl().display()
+    );
+    Ok(())
+}
+
+pub fn run(opts: &CertsOpts) -> Result<()> {
+    let layout = Layout {
+        dir: opts.dir.clone(),
+    };
+    match &opts.cmd {
+        CertsCommand::Init(init_opts) => init(&layout, init_opts),
+        CertsCommand::Server(server_opts) => server(&layout, server_opts),
+        CertsCommand::Client(client_opts) => client(&layout, client_opts),
+        CertsCommand::List => list(&layout),
+        CertsCommand::Revoke(revoke_opts) => revoke(&layout, revoke_opts),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use x509_parser::pem::parse_x509_pem;
+
+    fn layout(test: &str) -> Layout {
+        let dir =
+            std::env::temp_dir().join(format!("rrocker-certs-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let layout = Layout { dir };
+        init(
+            &layout,
+            &InitOpts {
+                days: 30,
+                force: false,
+            },
+        )
+        .unwrap();
+        layout
+    }
+
+    fn client_opts(common_name: &str) -> ClientOpts {
+        ClientOpts {
+            common_name: common_name.into(),
+            org: Some("client".into()),
+            ou: None,
+            spiffe_id: None,
+            days: 1,
+            name: None,
+            force: false,
+        }
+    }
+
+    fn der(path: &Path) -> Vec<u8> {
+        let (_, pem) = parse_x509_pem(&std::fs::read(path).unwrap()).unwrap();
+        pem.contents
+    }
+
+    #[test]
+    fn test_issue() {
+        let layout = layout("issue");
+        server(
+            &layout,
+            &ServerOpts {
+                name: "server1".into(),
+                sans: Vec::new(),
+                days: 1,
+                force: false,
+            },
+        )
+        .unwrap();
+        client(&layout, &client_opts("client1")).unwrap();
+        assert!(client(&layout, &client_opts("client1")).is_err());
+        assert!(init(
+            &layout,
+            &InitOpts {
+                days: 1,
+                force: false
+            }
+        )
+        .is_err());
+
+        let ca = der(&layout.ca_crt(Kind::ClientCa));
+        let (_, ca) = x509_parser::parse_x509_certificate(&ca).unwrap();
+        let crt = der(&layout.crt("client1"));
+        let (_, crt) = x509_parser::parse_x509_certificate(&crt).unwrap();
+        assert_eq!(crt.issuer().as_raw(), ca.subject().as_raw());
+        assert!(crt.verify_signature(Some(ca.public_key())).is_ok());
+        assert_eq!(crt.subject().to_string(), "CN=client1, O=client");
+        assert_eq!(crt.raw_serial(), [5]);
+
+        let crt = der(&layout.crt("server1"));
+        let (_, crt) = x509_parser::parse_x509_certificate(&crt).unwrap();
+        let sans = crt.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(sans.value.general_names.len(), 2);
+
+        let mode = std::fs::metadata(layout.key("client1"))
+            .unwrap()
+            .permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+            0o600
+        );
+
+        let index = layout.load_index().unwrap();
+        let kinds = index.certs.iter().map(|c| c.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                Kind::RootCa,
+                Kind::ServerCa,
+                Kind::ClientCa,
+                Kind::Server,
+                Kind::Client
+            ]
+        );
+        std::fs::remove_dir_all(&layout.dir).unwrap();
+    }
+
+    #[test]
+    fn test_revoke() {
+        let layout = layout("revoke");
+        client(&layout, &client_opts("client1")).unwrap();
+        client(&layout, &client_opts("client2")).unwrap();
+        revoke(
+            &layout,
+            &RevokeOpts {
+                cert: "client2".into(),
+            },
+        )
+        .unwrap();
+        //already revoked and not a client cert
+        for cert in ["client2", "1"] {
+            let opts = RevokeOpts { cert: cert.into() };
+            assert!(revoke(&layout, &opts).is_err());
+        }
+
+        //rrockerd matches revocations by the issuer's name and the raw serial
+        let crl = der(&layout.crl());
+        let (_, crl) = x509_parser::parse_x509_crl(&crl).unwrap();
+        let crt = der(&layout.crt("client2"));
+        let (_, crt) = x509_parser::parse_x509_certificate(&crt).unwrap();
+        assert_eq!(crl.issuer().as_raw(), crt.issuer().as_raw());
+        let revoked = crl
+            .iter_revoked_certificates()
+            .map(|r| r.raw_serial().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(revoked, vec![crt.raw_serial().to_vec()]);
+        assert_eq!(crl.crl_number(), Some(&2u32.into()));
+        std::fs::remove_dir_all(&layout.dir).unwrap();
+    }
+}
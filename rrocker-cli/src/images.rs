@@ -0,0 +1,145 @@
+use anyhow::{Context, Result};
+use clap::Clap;
+use flate2::read::GzDecoder;
+use futures::stream;
+use rrocker_lib::api::{scheduler_client::SchedulerClient, ImportImageRequest};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+use tokio::sync::mpsc;
+use tonic::transport::Channel;
+
+/// Size of the chunks archives are streamed in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clap, Debug)]
+pub struct ImagesOpts {
+    #[clap(subcommand)]
+    cmd: ImagesCommand,
+}
+
+#[derive(Clap, Debug)]
+enum ImagesCommand {
+    /// List the images you may start tasks from
+    List,
+    /// Import an image, replacing the image imported under the same name before
+    Import(ImportOpts),
+}
+
+#[derive(Clap, Debug)]
+struct ImportOpts {
+    /// Name tasks select the image by
+    name: String,
+    /// An OCI image layout dir, or a tar of one or from `docker save`, optionally gzipped
+    path: PathBuf,
+}
+
+/// Sends what's written to it as the chunks of an import
+struct ChunkWriter {
+    tx: mpsc::Sender<ImportImageRequest>,
+    /// Only sent with the first chunk
+    name: String,
+    buf: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let request = ImportImageRequest {
+            name: std::mem::take(&mut self.name),
+            chunk: std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE)),
+        };
+        self.tx
+            .blocking_send(request)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The import was aborted"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the archive at `path` to `writer`, an image layout dir is archived on the fly
+fn write_archive(path: &Path, mut writer: ChunkWriter) -> Result<()> {
+    if path.is_dir() {
+        let mut builder = tar::Builder::new(&mut writer);
+        builder.follow_symlinks(false);
+        builder
+            .append_dir_all(".", path)
+            .context(format!("Failed to archive '{:?}'", path))?;
+        builder.finish()?;
+    } else {
+        let mut file =
+            BufReader::new(File::open(path).context(format!("Failed to open '{:?}'", path))?);
+        let copied = if file.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+            io::copy(&mut GzDecoder::new(file), &mut writer)
+        } else {
+            io::copy(&mut file, &mut writer)
+        };
+        copied.context(format!("Failed to read '{:?}'", path))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+async fn import(mut client: SchedulerClient<Channel>, opts: &ImportOpts) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel(4);
+    let writer = ChunkWriter {
+        tx,
+        name: opts.name.clone(),
+        buf: Vec::with_capacity(CHUNK_SIZE),
+    };
+    let path = opts.path.clone();
+    let mut writing = tokio::task::spawn_blocking(move || write_archive(&path, writer));
+
+    let chunks = stream::poll_fn(move |cx| rx.poll_recv(cx));
+    let import = client.import_image(chunks);
+    tokio::pin!(import);
+    let reply = tokio::select! {
+        written = &mut writing => {
+            //dropping the import on a failed read cancels it instead of sending a truncated archive
+            written.context("Failed to read the image")??;
+            import.await
+        }
+        reply = &mut import => reply,
+    };
+
+    let reply = reply.context("Failed to import the image")?.into_inner();
+    println!("Imported '{}' as {}", reply.name, reply.digest);
+    Ok(())
+}
+
+pub async fn run(mut client: SchedulerClient<Channel>, opts: &ImagesOpts) -> Result<()> {
+    match &opts.cmd {
+        ImagesCommand::List => {
+            let reply = client
+                .list_images(())
+                .await
+                .context("Failed to list images")?
+                .into_inner();
+            for image in reply.images {
+                if image.name == reply.default_image {
+                    println!("{} (default)", image.name);
+                } else {
+                    println!("{}", image.name);
+                }
+            }
+            Ok(())
+        }
+        ImagesCommand::Import(opts) => import(client, opts).await,
+    }
+}
@@ -1,3 +1,91 @@
-fn main() {
-    println!("Hello, world!");
+use anyhow::{Context, Result};
+use clap::Clap;
+use rrocker_lib::api::scheduler_client::SchedulerClient;
+use tokio::net::UnixStream;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Uri};
+use tower::service_fn;
+
+mod certs;
+mod images;
+mod tasks;
+mod top;
+
+#[derive(Clap, Debug)]
+#[clap(
+    name = "rrocker-cli",
+    about = "Schedule and inspect tasks on a rrockerd daemon"
+)]
+struct Opts {
+    /// Address of the rrockerd daemon, `unix://<path>` connects to its local socket
+    /// which authenticates by user instead of by certificate
+    #[clap(long, default_value = "https://localhost:50051")]
+    addr: String,
+    /// Client certificate used to authenticate with the daemon
+    #[clap(long, default_value = "certs/client1_crt.pem")]
+    cert: String,
+    /// Private key of the client certificate
+    #[clap(long, default_value = "certs/client1_key.pem")]
+    key: String,
+    /// CA chain used to verify the daemon's certificate
+    #[clap(long, default_value = "certs/server_ca_chain.pem")]
+    ca: String,
+    /// Domain name the daemon's certificate must be valid for
+    #[clap(long, default_value = "localhost")]
+    domain: String,
+    #[clap(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Clap, Debug)]
+enum Command {
+    /// Continuously display the resource usage of one or more tasks
+    Top(top::TopOpts),
+    /// Manage the CAs and certificates rrockerd and its clients authenticate with
+    Certs(certs::CertsOpts),
+    /// List the images tasks are started from or import one
+    Images(images::ImagesOpts),
+    /// List the tasks you own or that are shared with you, or all tasks with `list-all`
+    Tasks,
+}
+
+async fn connect(opts: &Opts) -> Result<SchedulerClient<Channel>> {
+    if let Some(path) = opts.addr.strip_prefix("unix://") {
+        let path = path.to_owned();
+        //the endpoint's URI is ignored as the connector always dials the socket
+        let channel = Endpoint::from_static("http://localhost")
+            .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+            .await
+            .context(format!("Failed to connect to '{}'", opts.addr))?;
+        return Ok(SchedulerClient::new(channel));
+    }
+
+    let read = |path: &str| std::fs::read(path).context(format!("Failed to read '{}'", path));
+
+    let tls = ClientTlsConfig::new()
+        .domain_name(opts.domain.clone())
+        .ca_certificate(Certificate::from_pem(read(&opts.ca)?))
+        .identity(Identity::from_pem(read(&opts.cert)?, read(&opts.key)?));
+
+    let channel = Channel::from_shared(opts.addr.clone())
+        .context("Invalid daemon address")?
+        .tls_config(tls)
+        .context("Invalid TLS config")?
+        .connect()
+        .await
+        .context(format!("Failed to connect to '{}'", opts.addr))?;
+
+    Ok(SchedulerClient::new(channel))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let opts = Opts::parse();
+
+    match &opts.cmd {
+        Command::Top(top_opts) => top::run(connect(&opts).await?, top_opts).await,
+        Command::Certs(certs_opts) => certs::run(certs_opts),
+        Command::Images(images_opts) => images::run(connect(&opts).await?, images_opts).await,
+        Command::Tasks => tasks::list(connect(&opts).await?).await,
+    }
 }
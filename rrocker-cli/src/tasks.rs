@@ -0,0 +1,24 @@
+use anyhow::{Context, Result};
+use rrocker_lib::api::scheduler_client::SchedulerClient;
+use tonic::transport::Channel;
+
+/// Print the tasks visible to the client, one per line
+pub async fn list(mut client: SchedulerClient<Channel>) -> Result<()> {
+    let reply = client
+        .list_tasks(())
+        .await
+        .context("Failed to list tasks")?
+        .into_inner();
+    for task in reply.tasks {
+        let uuid = task.handle.map(|h| h.uuid).unwrap_or_default();
+        let state = task.state.unwrap_or_default();
+        println!(
+            "{} {:?} {} {}",
+            uuid,
+            state.status(),
+            state.code,
+            task.owner
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use clap::Clap;
+use futures::{stream, StreamExt};
+use rrocker_lib::api::{
+    scheduler_client::SchedulerClient, TaskHandle, TaskStatsReply, TaskStatsStreamRequest,
+};
+use std::collections::BTreeMap;
+use tonic::transport::Channel;
+
+#[derive(Clap, Debug)]
+pub struct TopOpts {
+    /// Refresh interval in milliseconds
+    #[clap(long, default_value = "1000")]
+    interval_ms: u32,
+    /// UUIDs of the tasks to watch
+    #[clap(required = true)]
+    tasks: Vec<String>,
+}
+
+/// The latest sample of a task plus the CPU % computed from the previous sample
+struct Row {
+    last: TaskStatsReply,
+    cpu_percent: Option<f64>,
+}
+
+fn timestamp_usec(stats: &TaskStatsReply) -> Option<i128> {
+    stats
+        .timestamp
+        .as_ref()
+        .map(|ts| ts.seconds as i128 * 1_000_000 + ts.nanos as i128 / 1_000)
+}
+
+/// CPU usage between two samples in % of a single core
+fn cpu_percent(prev: &TaskStatsReply, cur: &TaskStatsReply) -> Option<f64> {
+    let wall = timestamp_usec(cur)? - timestamp_usec(prev)?;
+    let used = cur.cpu.as_ref()?.usage_usec as i128 - prev.cpu.as_ref()?.usage_usec as i128;
+    if wall <= 0 || used < 0 {
+        return None;
+    }
+    Some(used as f64 * 100.0 / wall as f64)
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+fn render(rows: &BTreeMap<String, Row>) {
+    //clear the screen and move the cursor to the top left
+    print!("\x1b[2J\x1b[H");
+    println!(
+        "{:<36} {:>7} {:>9} {:>9} {:>6} {:>9} {:>9} {:>5}",
+        "TASK", "CPU%", "MEM", "PEAK", "PIDS", "READ", "WRITE", "OOMK"
+    );
+    for (uuid, row) in rows {
+        let mem = row.last.memory.clone().unwrap_or_default();
+        let pids = row
+            .last
+            .pids
+            .as_ref()
+            .map(|p| p.current.to_string())
+            .unwrap_or_else(|| "-".to_owned());
+        let (read, write) = row
+            .last
+            .io
+            .iter()
+            .fold((0, 0), |(r, w), io| (r + io.read_bytes, w + io.write_bytes));
+        let cpu = row
+            .cpu_percent
+            .map(|c| format!("{:.1}", c))
+            .unwrap_or_else(|| "-".to_owned());
+
+        println!(
+            "{:<36} {:>7} {:>9} {:>9} {:>6} {:>9} {:>9} {:>5}",
+            uuid,
+            cpu,
+            human_bytes(mem.current_bytes),
+            human_bytes(mem.peak_bytes),
+            pids,
+            human_bytes(read),
+            human_bytes(write),
+            mem.oom_kill_events
+        );
+    }
+}
+
+pub async fn run(client: SchedulerClient<Channel>, opts: &TopOpts) -> Result<()> {
+    let mut streams = Vec::with_capacity(opts.tasks.len());
+    for uuid in &opts.tasks {
+        let stream = client
+            .clone()
+            .task_stats_stream(TaskStatsStreamRequest {
+                handle: Some(TaskHandle { uuid: uuid.clone() }),
+                interval_ms: opts.interval_ms,
+            })
+            .await
+            .context(format!("Failed to stream stats of task '{}'", uuid))?
+            .into_inner();
+        streams.push(stream);
+    }
+
+    let mut rows = BTreeMap::<String, Row>::new();
+    let mut merged = stream::select_all(streams);
+    while let Some(stats) = merged.next().await {
+        let stats = stats.context("Stats stream failed")?;
+        let uuid = stats.handle.clone().unwrap_or_default().uuid;
+        let cpu_percent = rows
+            .get(&uuid)
+            .and_then(|prev| cpu_percent(&prev.last, &stats));
+        rows.insert(
+            uuid,
+            Row {
+                last: stats,
+                cpu_percent,
+            },
+        );
+        render(&rows);
+    }
+
+    Ok(())
+}
@@ -1,4 +1,14 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("protos/api.proto")?;
+    //the daemon's audit log records the requested constraints as JSON
+    let mut config = tonic_build::configure();
+    for message in &[
+        "rrocker.api.ResourceConstraints",
+        "rrocker.api.ResourceConstraints.cpu",
+        "rrocker.api.IoDeviceLimit",
+        "rrocker.api.CpuQuota",
+    ] {
+        config = config.type_attribute(message, "#[derive(serde::Serialize)]");
+    }
+    config.compile(&["protos/api.proto"], &["protos"])?;
     Ok(())
 }
@@ -1,3 +1,4 @@
 pub mod api {
+    #![allow(clippy::four_forward_slashes)]
     tonic::include_proto!("rrocker.api");
 }
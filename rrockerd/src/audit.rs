@@ -0,0 +1,413 @@
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use futures::future::BoxFuture;
+use hyper::{Request, Response};
+use percent_encoding::percent_decode_str;
+use rrocker_lib::api::ResourceConstraints;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+};
+use tonic::Code;
+use tower::{Layer, Service};
+
+/// Where syslog messages are sent to
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// The authpriv facility at the info severity, see RFC 3164
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// File the audit trail is appended to as JSON lines, unset disables the file
+    pub path: Option<PathBuf>,
+    /// Size after which the file is rotated to `<path>.1`, 0 disables rotation
+    pub max_bytes: u64,
+    /// How many rotated files are kept, older ones are deleted
+    pub max_files: usize,
+    /// Also send every event to the local syslog daemon
+    pub syslog: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_bytes: 100 * 1024 * 1024,
+            max_files: 5,
+            syslog: false,
+        }
+    }
+}
+
+/// A single RPC call, filled in as the call passes through the interceptor and the scheduler
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: String,
+    /// The RPC's method name, e.g. `StopTask`
+    pub rpc: String,
+    /// The client's principal, unset if the client couldn't be identified
+    pub principal: Option<String>,
+    /// Subject of the client's cert, recorded even if it's rejected
+    pub subject: Option<String>,
+    /// The task handle as sent by the client or as returned by StartTask
+    pub task: Option<String>,
+    pub cmd: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    pub constraints: Option<ResourceConstraints>,
+    /// `ok`, `denied` if the client was unauthenticated or isn't permitted the call, else `error`
+    pub outcome: &'static str,
+    /// The gRPC status code. Streaming calls are recorded once the stream starts, so errors
+    /// ending the stream later aren't recorded.
+    pub code: i32,
+    pub message: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn finish(&mut self, code: Code, message: Option<String>) {
+        self.timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        self.outcome = match code {
+            Code::Ok => "ok",
+            Code::Unauthenticated | Code::PermissionDenied => "denied",
+            _ => "error",
+        };
+        self.code = code as i32;
+        self.message = message;
+    }
+}
+
+/// The event of the ongoing call, inserted into the request's extensions by [`AuditLayer`]
+#[derive(Debug, Clone, Default)]
+pub struct AuditContext(Arc<Mutex<AuditEvent>>);
+
+impl AuditContext {
+    pub fn update(&self, f: impl FnOnce(&mut AuditEvent)) {
+        f(&mut self.0.lock().unwrap());
+    }
+
+    /// Update the event of the call if it's audited
+    pub fn annotate<T>(request: &tonic::Request<T>, f: impl FnOnce(&mut AuditEvent)) {
+        if let Some(audit) = request.extensions().get::<AuditContext>() {
+            audit.update(f);
+        }
+    }
+}
+
+/// The currently open audit file and how much has been written to it
+#[derive(Debug)]
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+/// Append-only audit trail of every RPC call
+#[derive(Debug)]
+pub struct AuditLog {
+    config: AuditConfig,
+    file: Mutex<Option<AuditFile>>,
+    syslog: Option<UnixDatagram>,
+}
+
+fn open(path: &Path) -> Result<AuditFile> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("Failed to open audit log '{:?}'", path))?;
+    let size = file.metadata()?.len();
+    Ok(AuditFile { file, size })
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+impl AuditLog {
+    pub fn new(config: AuditConfig) -> Result<Self> {
+        let file = config.path.as_deref().map(open).transpose()?;
+        let syslog = if config.syslog {
+            Some(UnixDatagram::unbound().context("Failed to create syslog socket")?)
+        } else {
+            None
+        };
+        Ok(Self {
+            config,
+            file: Mutex::new(file),
+            syslog,
+        })
+    }
+
+    /// Shift `<path>.N` to `<path>.N+1`, dropping the oldest, and start a new file
+    fn rotate(&self, path: &Path) -> Result<AuditFile> {
+        if self.config.max_files == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            for n in (1..self.config.max_files).rev() {
+                let from = rotated(path, n);
+                if from.exists() {
+                    std::fs::rename(from, rotated(path, n + 1))?;
+                }
+            }
+            std::fs::rename(path, rotated(path, 1))?;
+        }
+        open(path)
+    }
+
+    fn write(&self, line: &str) -> Result<()> {
+        let path = match &self.config.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut file = self.file.lock().unwrap();
+        let len = line.len() as u64 + 1;
+        let full = file.as_ref().is_none_or(|f| {
+            self.config.max_bytes > 0 && f.size > 0 && f.size + len > self.config.max_bytes
+        });
+        if full {
+            //drop the current file first so a failed rotation is retried on the next event
+            let current = file.take();
+            *file = Some(match current {
+                Some(_) => self.rotate(path)?,
+                None => open(path)?,
+            });
+        }
+
+        let current = file.as_mut().unwrap();
+        writeln!(current.file, "{}", line)?;
+        current.size += len;
+        Ok(())
+    }
+
+    /// Write the event to the file and syslog, failures are logged but don't fail the call
+    pub fn record(&self, event: &AuditEvent) {
+        let line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => return tracing::error!("Failed to serialize audit event: {:?}", e),
+        };
+        if let Err(e) = self.write(&line) {
+            tracing::error!("Failed to write audit event {}: {:?}", line, e);
+        }
+        if let Some(socket) = &self.syslog {
+            let message = format!(
+                "<{}>rrockerd[{}]: {}",
+                SYSLOG_PRIORITY,
+                std::process::id(),
+                line
+            );
+            if let Err(e) = socket.send_to(message.as_bytes(), SYSLOG_SOCKET) {
+                tracing::error!("Failed to send audit event to syslog: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Audits every call, including the ones rejected by the authorization interceptor.
+/// It has to wrap the intercepted service as the interceptor doesn't see the RPC's path.
+#[derive(Clone)]
+pub struct AuditLayer {
+    log: Arc<AuditLog>,
+}
+
+impl AuditLayer {
+    pub fn new(log: Arc<AuditLog>) -> Self {
+        Self { log }
+    }
+}
+
+impl<S> Layer<S> for AuditLayer {
+    type Service = Audit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Audit {
+            inner,
+            log: self.log.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Audit<S> {
+    inner: S,
+    log: Arc<AuditLog>,
+}
+
+/// The call's status, errors returned by the service are sent as trailers only responses
+/// so the status is in the headers, a response without it has started successfully
+fn status<B>(response: &Response<B>) -> (Code, Option<String>) {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned)
+    };
+    let code = header("grpc-status")
+        .and_then(|c| c.parse::<i32>().ok())
+        .map_or(Code::Ok, Code::from_i32);
+    //the message is percent encoded on the wire
+    let message =
+        header("grpc-message").map(|m| percent_decode_str(&m).decode_utf8_lossy().into_owned());
+    (code, message)
+}
+
+/// Calls rejected by the interceptor fail with the status as the error, tonic only turns
+/// it into a response further out. Anything else is reported to the client as UNKNOWN.
+fn error_status(err: &(dyn Error + 'static)) -> (Code, Option<String>) {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(status) = err.downcast_ref::<tonic::Status>() {
+            return (status.code(), Some(status.message().to_owned()));
+        }
+        source = err.source();
+    }
+    (Code::Unknown, Some(err.to_string()))
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Audit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: AsRef<dyn Error + Send + Sync>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let audit = AuditContext::default();
+        audit.update(|e| e.rpc = req.uri().path().rsplit('/').next().unwrap_or("").to_owned());
+        req.extensions_mut().insert(audit.clone());
+
+        let log = self.log.clone();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            let (code, message) = match &res {
+                Ok(response) => status(response),
+                Err(e) => error_status(e.as_ref()),
+            };
+            audit.update(|e| {
+                e.finish(code, message);
+                log.record(e);
+            });
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(rpc: &str) -> AuditEvent {
+        let mut event = AuditEvent {
+            rpc: rpc.into(),
+            principal: Some("client1".into()),
+            task: Some("b0d1d5d2-6b0e-4c43-a1ba-9dc2a9c1f4e2".into()),
+            ..Default::default()
+        };
+        event.finish(
+            Code::PermissionDenied,
+            Some("Starting tasks isn't permitted".into()),
+        );
+        event
+    }
+
+    #[test]
+    fn test_event() {
+        let mut event = event("StartTask");
+        event.cmd = Some("/bin/ls".into());
+        event.args = vec!["-l".into()];
+        event.constraints = Some(ResourceConstraints {
+            max_pids: Some(10),
+            ..Default::default()
+        });
+
+        let json: serde_json::Value = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["rpc"], "StartTask");
+        assert_eq!(json["principal"], "client1");
+        assert_eq!(json["outcome"], "denied");
+        assert_eq!(json["code"], Code::PermissionDenied as i32);
+        assert_eq!(json["args"][0], "-l");
+        assert_eq!(json["constraints"]["max_pids"], 10);
+        assert!(json["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn test_status() {
+        let ok = Response::new(());
+        assert_eq!(status(&ok), (Code::Ok, None));
+
+        let denied = tonic::Status::permission_denied("Listing quotas requires an admin")
+            .to_http()
+            .map(|_| ());
+        assert_eq!(
+            status(&denied),
+            (
+                Code::PermissionDenied,
+                Some("Listing quotas requires an admin".to_owned())
+            )
+        );
+
+        let rejected: Box<dyn Error + Send + Sync> =
+            Box::new(tonic::Status::unauthenticated("Missing certs"));
+        assert_eq!(
+            error_status(rejected.as_ref()),
+            (Code::Unauthenticated, Some("Missing certs".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("rrocker-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let line_len = serde_json::to_string(&event("StopTask")).unwrap().len() as u64 + 1;
+
+        let log = AuditLog::new(AuditConfig {
+            path: Some(path.clone()),
+            max_bytes: line_len * 2,
+            max_files: 2,
+            syslog: false,
+        })
+        .unwrap();
+        for _ in 0..7 {
+            log.record(&event("StopTask"));
+        }
+
+        let lines = |p: &Path| std::fs::read_to_string(p).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&rotated(&path, 1)), 2);
+        assert_eq!(lines(&rotated(&path, 2)), 2);
+        assert!(!rotated(&path, 3).exists());
+
+        //reopening appends instead of truncating
+        drop(log);
+        let log = AuditLog::new(AuditConfig {
+            path: Some(path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        log.record(&event("QueryTask"));
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        let last: serde_json::Value =
+            serde_json::from_str(content.lines().last().unwrap()).unwrap();
+        assert_eq!(last["rpc"], "QueryTask");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
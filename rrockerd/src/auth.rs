@@ -1,67 +1,368 @@
+use crate::{audit::AuditContext, crl::Revocations, policy::Policy};
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt, sync::Arc};
 use tonic::{Request, Status};
-use x509_parser::prelude::X509Certificate;
+use x509_parser::{
+    extensions::GeneralName,
+    prelude::{AttributeTypeAndValue, X509Certificate},
+};
 
-#[derive(Debug)]
+const SPIFFE_SCHEME: &str = "spiffe://";
+/// Reserved for the principals and groups of local users, certs can't claim it
+pub const LOCAL_PREFIX: &str = "unix:";
+
+/// How the client's principal and groups are taken from its certificate
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// Take the principal from a `spiffe://` URI SAN when the cert has one, otherwise the CN is used
+    pub use_spiffe_id: bool,
+    /// Reject certs without a SPIFFE ID instead of falling back to the CN
+    pub require_spiffe_id: bool,
+    /// Trust domains SPIFFE IDs are accepted from, empty means any
+    pub trust_domains: Vec<String>,
+    /// Count the organizational units (OU) as groups in addition to the organizations (O)
+    pub org_units_as_groups: bool,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self {
+            use_spiffe_id: true,
+            require_spiffe_id: false,
+            trust_domains: Vec::new(),
+            org_units_as_groups: false,
+        }
+    }
+}
+
+/// A SPIFFE ID such as `spiffe://corp/team/x/user/y`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpiffeId {
+    pub trust_domain: String,
+    /// The path including its leading `/`
+    pub path: String,
+}
+
+impl SpiffeId {
+    pub fn parse(uri: &str) -> Option<Self> {
+        let rest = uri.strip_prefix(SPIFFE_SCHEME)?;
+        let (trust_domain, path) = rest.split_at(rest.find('/')?);
+
+        let valid_trust_domain = !trust_domain.is_empty()
+            && trust_domain
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"-._".contains(&b));
+        let valid_path = path[1..].split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-._".contains(&b))
+        });
+
+        if valid_trust_domain && valid_path {
+            Some(Self {
+                trust_domain: trust_domain.to_owned(),
+                path: path.to_owned(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for SpiffeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", SPIFFE_SCHEME, self.trust_domain, self.path)
+    }
+}
+
+/// Who the client is, as taken from its certificate.
+/// Clients are told apart by the variant too, so a CN never passes for a local user.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Principal {
+    Spiffe(SpiffeId),
+    CommonName(String),
+    /// A local user connected over the Unix socket, as mapped by the `[local]` config.
+    /// Always starts with `unix:`.
+    Local(String),
+}
+
+impl Principal {
+    pub fn is_local(&self) -> bool {
+        matches!(self, Principal::Local(_))
+    }
+}
+
+/// Parses a principal ID as shown to clients, the reserved prefixes tell the variants apart
+impl TryFrom<String> for Principal {
+    type Error = String;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        if id.starts_with(SPIFFE_SCHEME) {
+            SpiffeId::parse(&id)
+                .map(Principal::Spiffe)
+                .ok_or_else(|| format!("'{}' isn't a valid SPIFFE ID", id))
+        } else if id.starts_with(LOCAL_PREFIX) {
+            Ok(Principal::Local(id))
+        } else if id.is_empty() || id.to_ascii_lowercase().starts_with("spiffe:") {
+            Err(format!("'{}' isn't a valid principal", id))
+        } else {
+            Ok(Principal::CommonName(id))
+        }
+    }
+}
+
+impl Default for Principal {
+    fn default() -> Self {
+        Principal::CommonName(String::new())
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::Spiffe(id) => id.fmt(f),
+            Principal::CommonName(cn) | Principal::Local(cn) => f.write_str(cn),
+        }
+    }
+}
+
+/// A group qualified by where it comes from, a local group never matches a cert's O or OU
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(from = "String")]
+pub enum Group {
+    /// An organization (O) or, if configured, an organizational unit (OU)
+    Cert(String),
+    /// A local group by its configured name, written as `unix:<name>`
+    Local(String),
+}
+
+impl From<String> for Group {
+    fn from(name: String) -> Self {
+        match name.strip_prefix(LOCAL_PREFIX) {
+            Some(local) => Group::Local(local.to_owned()),
+            None => Group::Cert(name),
+        }
+    }
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Group::Cert(name) => f.write_str(name),
+            Group::Local(name) => write!(f, "{}{}", LOCAL_PREFIX, name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 /// The request's authorization
 pub struct ClientAuth {
     //in a production system you'd convert both the id and group to integer based ids asap
     //for perf reasons but in simplicity's name I'm cutting that corner
+    /// The principal as a string, as matched by policy bindings and shown in logs
     pub id: String,
+    /// Identifies the client as a task owner, in sharing and in quotas
+    pub principal: Principal,
+    /// The cert subject's common name (CN), if any
+    pub common_name: Option<String>,
+    /// The primary group, the first of `groups` or empty if there are none
     pub group: String,
+    /// All organizations (O) of the cert's subject plus, if configured, its organizational units
+    pub groups: Vec<String>,
+    /// All organizational units (OU) of the cert's subject
+    pub org_units: Vec<String>,
 }
 
-/// Interceptor used to check the certificate of a request has a valid organization name
-#[tracing::instrument]
-pub(crate) fn authorization_interceptor(req: Request<()>) -> Result<Request<()>, Status> {
-    let peer_certs = req
-        .peer_certs()
-        .ok_or_else(|| Status::unauthenticated("Missing certs"))?;
-
-    let (_, cert) = peer_certs
-        .iter()
-        .map(|c| x509_parser::parse_x509_certificate(c.get_ref()))
-        .next()
-        .ok_or_else(|| Status::unauthenticated("Empty cert list"))?
-        .map_err(|_| Status::unauthenticated("One or more certs are invalid"))?;
+impl ClientAuth {
+    pub fn new(principal: Principal) -> Self {
+        Self {
+            id: principal.to_string(),
+            principal,
+            ..Default::default()
+        }
+    }
 
-    validate_cert(cert, req)
+    /// The deduplicated `groups`, qualified by whether the client is a local user
+    pub fn qualified_groups(&self) -> Vec<Group> {
+        let qualify = |group: &String| match self.principal {
+            Principal::Local(_) => Group::Local(group.clone()),
+            _ => Group::Cert(group.clone()),
+        };
+        let mut groups = self.groups.iter().map(qualify).collect::<Vec<_>>();
+        groups.sort();
+        groups.dedup();
+        groups
+    }
 }
 
-#[tracing::instrument]
-/// Split out from authorization_interceptor to make it testable
-fn validate_cert(cert: X509Certificate, mut req: Request<()>) -> Result<Request<()>, Status> {
-    let common_name = cert
-        .subject()
-        .iter_common_name()
-        .next()
-        .ok_or_else(|| Status::unauthenticated("Cert doesn't contain common name"))?;
-    let common_name = common_name
-        .as_str()
-        .map_err(|_| Status::unauthenticated("Invalid common name"))?;
-    let org_name = cert
-        .subject()
-        .iter_organization()
-        .next()
-        .ok_or_else(|| Status::unauthenticated("Cert doesn't contain organization"))?;
-    let org_name = org_name
-        .as_str()
-        .map_err(|_| Status::unauthenticated("Invalid organization"))?;
-
-    const VALID_ORG_NAMES: &[&str] = &["client", "admin"];
-
-    if VALID_ORG_NAMES.contains(&org_name) {
-        req.extensions_mut().insert(ClientAuth {
-            id: common_name.to_owned(),
-            group: org_name.to_owned(),
+/// Interceptor used to check the certificate of a request hasn't been revoked
+/// and is bound to at least one role of the policy
+pub fn authorization_interceptor(
+    identity: Arc<IdentityConfig>,
+    policy: Arc<Policy>,
+    revocations: Arc<Revocations>,
+) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |req: Request<()>| {
+        let peer_certs = req
+            .peer_certs()
+            .ok_or_else(|| Status::unauthenticated("Missing certs"))?;
+
+        let mut certs = peer_certs
+            .iter()
+            .map(|c| x509_parser::parse_x509_certificate(c.get_ref()).map(|(_, cert)| cert))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Status::unauthenticated("One or more certs are invalid"))?;
+        AuditContext::annotate(&req, |e| {
+            e.subject = certs.first().map(|c| c.subject().to_string())
         });
+        revocations.check(&certs)?;
 
+        if certs.is_empty() {
+            return Err(Status::unauthenticated("Empty cert list"));
+        }
+        let req = validate_cert(certs.swap_remove(0), req, &identity, &policy)?;
+        if let Some(auth) = req.extensions().get::<ClientAuth>() {
+            AuditContext::annotate(&req, |e| e.principal = Some(auth.id.clone()));
+        }
         Ok(req)
+    }
+}
+
+/// The cert's SPIFFE ID if it has one, more than one is invalid
+fn spiffe_id(
+    cert: &X509Certificate,
+    identity: &IdentityConfig,
+) -> Result<Option<SpiffeId>, Status> {
+    let uris = cert
+        .tbs_certificate
+        .subject_alternative_name()
+        .map(|(_, san)| {
+            san.general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::URI(uri) if uri.starts_with(SPIFFE_SCHEME) => Some(*uri),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let uri = match uris.as_slice() {
+        [] => return Ok(None),
+        [uri] => uri,
+        _ => {
+            return Err(Status::unauthenticated(
+                "Cert contains more than one SPIFFE ID",
+            ))
+        }
+    };
+    let id = SpiffeId::parse(uri).ok_or_else(|| Status::unauthenticated("Invalid SPIFFE ID"))?;
+
+    if !identity.trust_domains.is_empty() && !identity.trust_domains.contains(&id.trust_domain) {
+        tracing::warn!(
+            "Received request with SPIFFE ID of untrusted domain: {}",
+            id
+        );
+        return Err(Status::unauthenticated(
+            "The SPIFFE ID's trust domain isn't trusted",
+        ));
+    }
+    Ok(Some(id))
+}
+
+fn strings<'a, 'b: 'a>(
+    attrs: impl Iterator<Item = &'a AttributeTypeAndValue<'b>>,
+    what: &str,
+) -> Result<Vec<String>, Status> {
+    attrs
+        .map(|attr| attr.as_str().map(str::to_owned))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Status::unauthenticated(format!("Invalid {}", what)))
+}
+
+/// Extract the client's principal and groups from its cert
+fn client_auth(cert: &X509Certificate, identity: &IdentityConfig) -> Result<ClientAuth, Status> {
+    let subject = cert.subject();
+    let common_name = strings(subject.iter_common_name(), "common name")?
+        .into_iter()
+        .next();
+    let mut groups = strings(subject.iter_organization(), "organization")?;
+    let org_units = strings(subject.iter_organizational_unit(), "organizational unit")?;
+    //they'd pass for the principal or a group of a local user
+    let reserved = |name: &String| name.to_ascii_lowercase().starts_with(LOCAL_PREFIX);
+    if common_name
+        .iter()
+        .chain(&groups)
+        .chain(&org_units)
+        .any(reserved)
+    {
+        return Err(Status::unauthenticated(format!(
+            "The cert's subject can't use the '{}' prefix of local users",
+            LOCAL_PREFIX
+        )));
+    }
+    if identity.org_units_as_groups {
+        groups.extend(org_units.iter().cloned());
+    }
+
+    let spiffe_id = if identity.use_spiffe_id || identity.require_spiffe_id {
+        spiffe_id(cert, identity)?
     } else {
-        tracing::warn!("Received request with invalid org/group: {}", org_name);
-        Err(Status::unauthenticated(
-            "The organization of the provided cert is not valid",
-        ))
+        None
+    };
+    let principal = match (spiffe_id, &common_name) {
+        (Some(id), _) => Principal::Spiffe(id),
+        (None, _) if identity.require_spiffe_id => {
+            return Err(Status::unauthenticated("Cert doesn't contain a SPIFFE ID"))
+        }
+        //a CN must never pass for a SPIFFE ID, they share the namespace of task owners
+        (None, Some(cn)) if cn.to_ascii_lowercase().starts_with("spiffe:") => {
+            return Err(Status::unauthenticated(
+                "The cert's common name can't be a SPIFFE ID",
+            ))
+        }
+        (None, Some(cn)) => Principal::CommonName(cn.clone()),
+        (None, None) => return Err(Status::unauthenticated("Cert doesn't contain common name")),
+    };
+
+    Ok(ClientAuth {
+        common_name,
+        group: groups.first().cloned().unwrap_or_default(),
+        groups,
+        org_units,
+        ..ClientAuth::new(principal)
+    })
+}
+
+#[tracing::instrument(skip(identity, policy))]
+/// Split out from authorization_interceptor to make it testable
+fn validate_cert(
+    cert: X509Certificate,
+    mut req: Request<()>,
+    identity: &IdentityConfig,
+    policy: &Policy,
+) -> Result<Request<()>, Status> {
+    let auth = client_auth(&cert, identity)?;
+
+    //a cert that isn't bound to any role can't do anything so reject it right away
+    if policy.grants(&auth).roles.is_empty() {
+        tracing::warn!(
+            "Received request from {} with groups {:?} not bound to any role",
+            auth.id,
+            auth.groups
+        );
+        return Err(Status::unauthenticated(
+            "The provided cert isn't bound to any role",
+        ));
     }
+
+    req.extensions_mut().insert(auth);
+    Ok(req)
 }
 
 #[cfg(test)]
@@ -70,20 +371,39 @@ mod test {
     use tonic::Code;
     use x509_parser::pem::Pem;
 
+    const GOOD_CERT: &[u8] = include_bytes!("../../certs/test/good_crt.pem");
+    const SPIFFE_CERT: &[u8] = include_bytes!("../../certs/test/spiffe_crt.pem");
+
+    fn pem(cert: &[u8]) -> Pem {
+        Pem::iter_from_buffer(cert).next().unwrap().unwrap()
+    }
+
     #[test]
     fn test_missing_cert() {
         //this would've been nicer if Request and Status implemented Eq :(
-        let status = authorization_interceptor(Request::new(())).unwrap_err();
+        let mut interceptor =
+            authorization_interceptor(Arc::default(), Arc::new(Policy::default()), Arc::default());
+        let status = interceptor(Request::new(())).unwrap_err();
         assert_eq!(status.code(), Code::Unauthenticated);
     }
 
     #[test]
     fn test_good_name() {
-        const GOOD_CERT: &[u8] = include_bytes!("../../certs/test/good_crt.pem");
-        let pem = Pem::iter_from_buffer(GOOD_CERT).next().unwrap().unwrap();
+        let pem = pem(GOOD_CERT);
         let invalid_cert = pem.parse_x509().unwrap();
-        let _ = validate_cert(invalid_cert, Request::new(())).unwrap();
+        let req = validate_cert(
+            invalid_cert,
+            Request::new(()),
+            &IdentityConfig::default(),
+            &Policy::default(),
+        )
+        .unwrap();
+        let auth = req.extensions().get::<ClientAuth>().unwrap();
+        assert_eq!(auth.id, "client1");
+        assert_eq!(auth.principal, Principal::CommonName("client1".into()));
+        assert_eq!(auth.group, "client");
     }
+
     #[test]
     fn test_invalid_certs() {
         const MISSING_CN_CERT: &[u8] = include_bytes!("../../certs/test/missing_cn_crt.pem");
@@ -91,16 +411,150 @@ mod test {
             include_bytes!("../../certs/test/invalid_org_name_crt.pem");
         const MISSING_ORG_NAME_CERT: &[u8] =
             include_bytes!("../../certs/test/missing_org_name_crt.pem");
+        const INVALID_SPIFFE_CERT: &[u8] =
+            include_bytes!("../../certs/test/invalid_spiffe_crt.pem");
 
         for cert in [
             MISSING_CN_CERT,
             INVALID_ORG_NAME_CERT,
             MISSING_ORG_NAME_CERT,
+            INVALID_SPIFFE_CERT,
         ] {
-            let pem = Pem::iter_from_buffer(cert).next().unwrap().unwrap();
+            let pem = pem(cert);
             let invalid_cert = pem.parse_x509().unwrap();
-            let status = validate_cert(invalid_cert, Request::new(())).unwrap_err();
+            let status = validate_cert(
+                invalid_cert,
+                Request::new(()),
+                &IdentityConfig::default(),
+                &Policy::default(),
+            )
+            .unwrap_err();
+            assert_eq!(status.code(), Code::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn test_spiffe_id() {
+        let pem = pem(SPIFFE_CERT);
+        let cert = pem.parse_x509().unwrap();
+
+        let auth = client_auth(&cert, &IdentityConfig::default()).unwrap();
+        assert_eq!(auth.id, "spiffe://corp/team/x/user/y");
+        assert_eq!(
+            auth.principal,
+            Principal::Spiffe(SpiffeId {
+                trust_domain: "corp".into(),
+                path: "/team/x/user/y".into(),
+            })
+        );
+        assert_eq!(auth.common_name.as_deref(), Some("client1"));
+        assert!(auth.groups.is_empty());
+
+        let cn_only = IdentityConfig {
+            use_spiffe_id: false,
+            ..Default::default()
+        };
+        assert_eq!(client_auth(&cert, &cn_only).unwrap().id, "client1");
+
+        let other_domain = IdentityConfig {
+            trust_domains: vec!["other".into()],
+            ..Default::default()
+        };
+        let status = client_auth(&cert, &other_domain).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        //without a SPIFFE ID the CN is used unless one is required
+        let pem = self::pem(GOOD_CERT);
+        let cert = pem.parse_x509().unwrap();
+        assert_eq!(
+            client_auth(&cert, &IdentityConfig::default()).unwrap().id,
+            "client1"
+        );
+        let required = IdentityConfig {
+            require_spiffe_id: true,
+            ..Default::default()
+        };
+        assert!(client_auth(&cert, &required).is_err());
+    }
+
+    #[test]
+    fn test_spiffe_id_as_cn() {
+        const SPIFFE_CN_CERT: &[u8] = include_bytes!("../../certs/test/spiffe_cn_crt.pem");
+        let pem = pem(SPIFFE_CN_CERT);
+        let cert = pem.parse_x509().unwrap();
+
+        //it would otherwise own the tasks of the real SPIFFE ID and match its bindings
+        for identity in [
+            IdentityConfig::default(),
+            IdentityConfig {
+                use_spiffe_id: false,
+                ..Default::default()
+            },
+        ] {
+            let status = client_auth(&cert, &identity).unwrap_err();
             assert_eq!(status.code(), Code::Unauthenticated);
         }
     }
+
+    #[test]
+    fn test_local_prefix() {
+        const UNIX_CN_CERT: &[u8] = include_bytes!("../../certs/test/unix_cn_crt.pem");
+        let pem = pem(UNIX_CN_CERT);
+        let cert = pem.parse_x509().unwrap();
+
+        //it would otherwise own the tasks of the local user unix:1003
+        let status = client_auth(&cert, &IdentityConfig::default()).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        assert_eq!(
+            Principal::try_from("unix:1003".to_owned()),
+            Ok(Principal::Local("unix:1003".into()))
+        );
+        assert_eq!(
+            Principal::try_from("client1".to_owned()),
+            Ok(Principal::CommonName("client1".into()))
+        );
+        assert!(Principal::try_from("spiffe:x".to_owned()).is_err());
+        assert_eq!(
+            Group::from("unix:ops".to_owned()),
+            Group::Local("ops".into())
+        );
+        assert_eq!(Group::Local("ops".into()).to_string(), "unix:ops");
+    }
+
+    #[test]
+    fn test_parse_spiffe_id() {
+        assert!(SpiffeId::parse("spiffe://corp/a/b").is_some());
+        for invalid in &[
+            "https://corp/a",
+            "spiffe://corp",
+            "spiffe:///a",
+            "spiffe://Corp/a",
+            "spiffe://corp/",
+            "spiffe://corp/a//b",
+            "spiffe://corp/a/../b",
+            "spiffe://corp/a?b",
+        ] {
+            assert_eq!(SpiffeId::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_groups() {
+        const MULTI_GROUP_CERT: &[u8] = include_bytes!("../../certs/test/multi_group_crt.pem");
+        let pem = pem(MULTI_GROUP_CERT);
+        let cert = pem.parse_x509().unwrap();
+
+        let auth = client_auth(&cert, &IdentityConfig::default()).unwrap();
+        assert_eq!(auth.group, "client");
+        assert_eq!(auth.groups, vec!["client", "ml"]);
+        assert_eq!(auth.org_units, vec!["sre", "oncall"]);
+
+        let with_ous = IdentityConfig {
+            org_units_as_groups: true,
+            ..Default::default()
+        };
+        let auth = client_auth(&cert, &with_ous).unwrap();
+        assert_eq!(auth.groups, vec!["client", "ml", "sre", "oncall"]);
+    }
 }
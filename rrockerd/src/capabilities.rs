@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Context, Result};
+use caps::{CapSet, Capability, CapsHashSet};
+use nix::{errno::Errno, libc};
+
+/// Parse capability names, with or without the `CAP_` prefix and in any case
+pub fn parse<S: AsRef<str>>(names: &[S]) -> Result<CapsHashSet> {
+    names
+        .iter()
+        .map(|name| {
+            let name = name.as_ref().to_uppercase();
+            let name = if name.starts_with("CAP_") {
+                name
+            } else {
+                format!("CAP_{}", name)
+            };
+            name.parse::<Capability>()
+                .map_err(|_| anyhow!("Unknown capability '{}'", name))
+        })
+        .collect()
+}
+
+/// Reduce every capability set of the calling thread to `keep`.
+/// The bounding set goes first as dropping from it needs CAP_SETPCAP.
+pub fn restrict(keep: &CapsHashSet) -> Result<()> {
+    for cap in caps::all().difference(keep) {
+        caps::drop(None, CapSet::Bounding, *cap)
+            .context(format!("Failed to drop {} from the bounding set", cap))?;
+    }
+    //effective before permitted as it must stay a subset of it
+    for set in [CapSet::Effective, CapSet::Inheritable, CapSet::Permitted] {
+        caps::set(None, set, keep).context(format!("Failed to set the {:?} capabilities", set))?;
+    }
+    //ambient capabilities survive execve as non-root
+    caps::set(None, CapSet::Ambient, keep).context("Failed to set the ambient capabilities")
+}
+
+/// Ensure neither the task nor its children gain privileges on execve, e.g. by setuid binaries
+pub fn set_no_new_privs() -> Result<()> {
+    let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    Errno::result(res)
+        .map(drop)
+        .context("Failed to set no_new_privs")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clone_context::CloneContext;
+
+    #[test]
+    fn test_parse() {
+        let keep = parse(&["net_bind_service", "CAP_KILL"]).unwrap();
+        assert!(keep.contains(&Capability::CAP_NET_BIND_SERVICE));
+        assert!(keep.contains(&Capability::CAP_KILL));
+        assert_eq!(keep.len(), 2);
+        assert!(parse::<&str>(&[]).unwrap().is_empty());
+        assert!(parse(&["CAP_FLY"]).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_restrict() {
+        //the child of the clone is root in its own user namespace with a full capability set
+        let cc = CloneContext::new(|| -> Result<Vec<String>> {
+            restrict(&parse(&["CAP_NET_BIND_SERVICE"])?)?;
+            set_no_new_privs()?;
+            Ok(std::fs::read_to_string("/proc/self/status")?
+                .lines()
+                .filter(|l| l.starts_with("Cap") || l.starts_with("NoNewPrivs"))
+                .map(str::to_owned)
+                .collect())
+        })
+        .unwrap();
+        let (pid, mut rr) = cc.execute().unwrap();
+        let status = rr.get_result().unwrap();
+        nix::sys::wait::waitpid(pid, None).unwrap();
+
+        let field = |name: &str| {
+            status
+                .iter()
+                .find_map(|l| l.strip_prefix(name))
+                .map(|v| v.trim_start_matches(':').trim().to_owned())
+        };
+        for set in ["CapInh", "CapPrm", "CapEff", "CapBnd", "CapAmb"] {
+            assert_eq!(field(set).as_deref(), Some("0000000000000400"), "{}", set);
+        }
+        assert_eq!(field("NoNewPrivs").as_deref(), Some("1"));
+    }
+}
@@ -0,0 +1,271 @@
+use crate::cgroup::Limits;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, MutexGuard},
+};
+use tonic::Status;
+use uuid::Uuid;
+
+/// An amount of CPU and memory, either requested by a task or offered by the host
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Resources {
+    pub cpu_millicores: u64,
+    pub memory_bytes: u64,
+}
+
+impl Resources {
+    /// A budget that never runs out
+    pub const UNLIMITED: Resources = Resources {
+        cpu_millicores: u64::MAX,
+        memory_bytes: u64::MAX,
+    };
+
+    /// The resources a task with the given limits can at most use.
+    /// Unconstrained resources are charged as `default`.
+    pub fn from_limits(limits: &Limits, default: Resources) -> Self {
+        Self {
+            cpu_millicores: limits
+                .cpu_max
+                .map_or(default.cpu_millicores, |(quota, period)| {
+                    //round up so we never commit less than the task may use
+                    (quota * 1000).div_ceil(period)
+                }),
+            memory_bytes: limits.memory_max.unwrap_or(default.memory_bytes),
+        }
+    }
+
+    fn fits_within(&self, other: &Resources) -> bool {
+        self.cpu_millicores <= other.cpu_millicores && self.memory_bytes <= other.memory_bytes
+    }
+
+    fn saturating_add(&self, other: &Resources) -> Resources {
+        Resources {
+            cpu_millicores: self.cpu_millicores.saturating_add(other.cpu_millicores),
+            memory_bytes: self.memory_bytes.saturating_add(other.memory_bytes),
+        }
+    }
+
+    fn saturating_sub(&self, other: &Resources) -> Resources {
+        Resources {
+            cpu_millicores: self.cpu_millicores.saturating_sub(other.cpu_millicores),
+            memory_bytes: self.memory_bytes.saturating_sub(other.memory_bytes),
+        }
+    }
+}
+
+/// Whether a task may run right away or has to wait for capacity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    Queued,
+}
+
+/// Keeps track of the resources committed to running tasks against the host's budget
+/// plus a FIFO queue of tasks waiting for capacity.
+#[derive(Debug)]
+pub struct Capacity {
+    state: Mutex<CapacityState>,
+}
+
+#[derive(Debug)]
+pub struct CapacityState {
+    budget: Resources,
+    committed: Resources,
+    running: HashMap<Uuid, Resources>,
+    pending: VecDeque<(Uuid, Resources)>,
+}
+
+impl Default for Capacity {
+    fn default() -> Self {
+        Self::new(Resources::UNLIMITED)
+    }
+}
+
+impl Capacity {
+    pub fn new(budget: Resources) -> Self {
+        Self {
+            state: Mutex::new(CapacityState {
+                budget,
+                committed: Resources::default(),
+                running: HashMap::new(),
+                pending: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Lock the capacity state. Task state changes that depend on admission
+    /// must happen while holding the lock so they can't race with `release`.
+    pub fn lock(&self) -> MutexGuard<'_, CapacityState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl CapacityState {
+    pub fn committed(&self) -> Resources {
+        self.committed
+    }
+
+    pub fn is_pending(&self, uuid: &Uuid) -> bool {
+        self.pending.iter().any(|(u, _)| u == uuid)
+    }
+
+    /// Admit a task if there's room, otherwise queue it if `queue` is set.
+    /// Tasks are admitted in FIFO order so a task can't skip ahead of queued ones.
+    pub fn admit(&mut self, uuid: Uuid, req: Resources, queue: bool) -> Result<Admission, Status> {
+        if !req.fits_within(&self.budget) {
+            return Err(Status::resource_exhausted(format!(
+                "Task requests {} millicores and {} bytes which exceeds the daemon's budget of {} millicores and {} bytes",
+                req.cpu_millicores,
+                req.memory_bytes,
+                self.budget.cpu_millicores,
+                self.budget.memory_bytes
+            )));
+        }
+
+        if self.pending.is_empty()
+            && self
+                .committed
+                .saturating_add(&req)
+                .fits_within(&self.budget)
+        {
+            self.committed = self.committed.saturating_add(&req);
+            self.running.insert(uuid, req);
+            Ok(Admission::Admitted)
+        } else if queue {
+            self.pending.push_back((uuid, req));
+            Ok(Admission::Queued)
+        } else {
+            Err(Status::resource_exhausted(
+                "The daemon is at capacity, retry later or set wait_for_capacity to queue the task",
+            ))
+        }
+    }
+
+    /// Release the resources of a task that has stopped or drop it from the queue
+    /// if it never started. Returns the queued tasks that got admitted as a result.
+    pub fn release(&mut self, uuid: &Uuid) -> Vec<Uuid> {
+        if let Some(res) = self.running.remove(uuid) {
+            self.committed = self.committed.saturating_sub(&res);
+        } else {
+            self.pending.retain(|(u, _)| u != uuid);
+        }
+
+        let mut admitted = Vec::new();
+        while let Some((uuid, req)) = self.pending.front().copied() {
+            let committed = self.committed.saturating_add(&req);
+            if !committed.fits_within(&self.budget) {
+                break;
+            }
+            self.pending.pop_front();
+            self.committed = committed;
+            self.running.insert(uuid, req);
+            admitted.push(uuid);
+        }
+        admitted
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tonic::Code;
+
+    const fn res(cpu_millicores: u64, memory_bytes: u64) -> Resources {
+        Resources {
+            cpu_millicores,
+            memory_bytes,
+        }
+    }
+
+    #[test]
+    fn test_from_limits() {
+        let default = res(100, 1000);
+        assert_eq!(Resources::from_limits(&Limits::default(), default), default);
+
+        let limits = Limits {
+            cpu_max: Some((150_000, 100_000)),
+            memory_max: Some(32 << 30),
+            ..Default::default()
+        };
+        assert_eq!(
+            Resources::from_limits(&limits, default),
+            res(1500, 32 << 30)
+        );
+
+        //partial millicores are rounded up
+        let limits = Limits {
+            cpu_max: Some((1_001, 1_000_000)),
+            ..Default::default()
+        };
+        assert_eq!(Resources::from_limits(&limits, default).cpu_millicores, 2);
+    }
+
+    #[test]
+    fn test_admit_and_reject() {
+        let capacity = Capacity::new(res(2000, 1000));
+        let mut state = capacity.lock();
+        let (t1, t2, t3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(
+            state.admit(t1, res(1000, 500), false).unwrap(),
+            Admission::Admitted
+        );
+        assert_eq!(
+            state.admit(t2, res(1000, 500), false).unwrap(),
+            Admission::Admitted
+        );
+        assert_eq!(state.committed(), res(2000, 1000));
+
+        let status = state.admit(t3, res(1, 1), false).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+
+        //more than the whole budget can never be admitted, not even by waiting
+        let status = state.admit(t3, res(1, 1001), true).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+
+        assert!(state.release(&t1).is_empty());
+        assert_eq!(state.committed(), res(1000, 500));
+    }
+
+    #[test]
+    fn test_queue() {
+        let capacity = Capacity::new(res(2000, 1000));
+        let mut state = capacity.lock();
+        let (t1, t2, t3, t4) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+
+        assert_eq!(
+            state.admit(t1, res(2000, 500), true).unwrap(),
+            Admission::Admitted
+        );
+        assert_eq!(
+            state.admit(t2, res(1000, 500), true).unwrap(),
+            Admission::Queued
+        );
+        assert_eq!(
+            state.admit(t3, res(1000, 500), true).unwrap(),
+            Admission::Queued
+        );
+        //t4 would fit memory wise but mustn't skip the queue
+        let status = state.admit(t4, res(0, 0), false).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(state.is_pending(&t2));
+
+        assert_eq!(state.release(&t1), vec![t2, t3]);
+        assert!(!state.is_pending(&t2));
+        assert_eq!(state.committed(), res(2000, 1000));
+
+        //stopping a queued task drops it from the queue
+        assert_eq!(
+            state.admit(t4, res(500, 0), true).unwrap(),
+            Admission::Queued
+        );
+        assert!(state.release(&t4).is_empty());
+        assert!(!state.is_pending(&t4));
+        assert_eq!(state.committed(), res(2000, 1000));
+    }
+}
@@ -0,0 +1,620 @@
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::Pid;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Where the cgroup v2 unified hierarchy is mounted on the daemon host
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+/// The parent cgroup all task cgroups are created under
+const CGROUP_PARENT: &str = "rrocker";
+
+/// A handle to a task's cgroup (v2) directory.
+/// Creating the handle doesn't touch the filesystem.
+#[derive(Debug, Clone)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CpuStat {
+    pub usage_usec: u64,
+    pub user_usec: u64,
+    pub system_usec: u64,
+    pub nr_periods: u64,
+    pub nr_throttled: u64,
+    pub throttled_usec: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryEvents {
+    pub low: u64,
+    pub high: u64,
+    pub max: u64,
+    pub oom: u64,
+    pub oom_kill: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IoStat {
+    pub major: u32,
+    pub minor: u32,
+    pub rbytes: u64,
+    pub wbytes: u64,
+    pub rios: u64,
+    pub wios: u64,
+    pub dbytes: u64,
+    pub dios: u64,
+}
+
+/// The cgroup v2 controllers our limits can make use of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Controller {
+    Cpu,
+    Cpuset,
+    Memory,
+    Io,
+    Pids,
+}
+
+impl Controller {
+    pub const ALL: &'static [Controller] = &[
+        Controller::Cpu,
+        Controller::Cpuset,
+        Controller::Memory,
+        Controller::Io,
+        Controller::Pids,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Controller::Cpu => "cpu",
+            Controller::Cpuset => "cpuset",
+            Controller::Memory => "memory",
+            Controller::Io => "io",
+            Controller::Pids => "pids",
+        }
+    }
+}
+
+impl fmt::Display for Controller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Controller {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Controller::ALL
+            .iter()
+            .find(|c| c.name() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown cgroup controller '{}'", s))
+    }
+}
+
+/// Limits of a single block device written to io.max, `None` means no limit
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IoLimit {
+    pub major: u32,
+    pub minor: u32,
+    pub rbps: Option<u64>,
+    pub wbps: Option<u64>,
+    pub riops: Option<u64>,
+    pub wiops: Option<u64>,
+}
+
+/// Limits that can be applied to a cgroup, `None` means the kernel default is kept
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// (quota, period) in microseconds written to cpu.max
+    pub cpu_max: Option<(u64, u64)>,
+    pub cpu_weight: Option<u64>,
+    pub cpuset_cpus: Option<String>,
+    pub cpuset_mems: Option<String>,
+    pub memory_max: Option<u64>,
+    pub memory_swap_max: Option<u64>,
+    pub pids_max: Option<u64>,
+    pub io_max: Vec<IoLimit>,
+}
+
+fn max_or(value: Option<u64>) -> String {
+    value.map_or_else(|| "max".to_owned(), |v| v.to_string())
+}
+
+impl Limits {
+    /// The controllers which must be enabled for all of the limits to take effect
+    pub fn required_controllers(&self) -> BTreeSet<Controller> {
+        let mut set = BTreeSet::new();
+        if self.cpu_max.is_some() || self.cpu_weight.is_some() {
+            set.insert(Controller::Cpu);
+        }
+        if self.cpuset_cpus.is_some() || self.cpuset_mems.is_some() {
+            set.insert(Controller::Cpuset);
+        }
+        if self.memory_max.is_some() || self.memory_swap_max.is_some() {
+            set.insert(Controller::Memory);
+        }
+        if self.pids_max.is_some() {
+            set.insert(Controller::Pids);
+        }
+        if !self.io_max.is_empty() {
+            set.insert(Controller::Io);
+        }
+        set
+    }
+
+    /// The cgroup interface files and their content needed to apply the limits.
+    /// Note io.max takes a single device per write so it may appear multiple times.
+    fn files(&self) -> Vec<(&'static str, String)> {
+        let mut files = Vec::new();
+        if let Some((quota, period)) = self.cpu_max {
+            files.push(("cpu.max", format!("{} {}", quota, period)));
+        }
+        if let Some(weight) = self.cpu_weight {
+            files.push(("cpu.weight", weight.to_string()));
+        }
+        //cpuset.mems must be set before cpus can be used on NUMA hosts so write it first
+        if let Some(mems) = &self.cpuset_mems {
+            files.push(("cpuset.mems", mems.clone()));
+        }
+        if let Some(cpus) = &self.cpuset_cpus {
+            files.push(("cpuset.cpus", cpus.clone()));
+        }
+        if let Some(max) = self.memory_max {
+            files.push(("memory.max", max.to_string()));
+        }
+        if let Some(max) = self.memory_swap_max {
+            files.push(("memory.swap.max", max.to_string()));
+        }
+        if let Some(max) = self.pids_max {
+            files.push(("pids.max", max.to_string()));
+        }
+        for io in &self.io_max {
+            files.push((
+                "io.max",
+                format!(
+                    "{}:{} rbps={} wbps={} riops={} wiops={}",
+                    io.major,
+                    io.minor,
+                    max_or(io.rbps),
+                    max_or(io.wbps),
+                    max_or(io.riops),
+                    max_or(io.wiops)
+                ),
+            ));
+        }
+        files
+    }
+}
+
+/// A snapshot of all the accounting files of a cgroup we care about
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CgroupStats {
+    pub cpu: CpuStat,
+    pub memory_current: u64,
+    /// `None` on kernels older than 5.19 which don't have memory.peak
+    pub memory_peak: Option<u64>,
+    pub memory_events: MemoryEvents,
+    /// `None` when the pids controller isn't enabled for the cgroup
+    pub pids_current: Option<u64>,
+    /// Empty when the io controller isn't enabled for the cgroup
+    pub io: Vec<IoStat>,
+}
+
+impl Cgroup {
+    /// The cgroup of a single task, named after the task
+    pub fn for_task(name: &str) -> Self {
+        Self::parent().child(name)
+    }
+
+    pub fn child(&self, name: &str) -> Self {
+        Self::from_path(self.path.join(name))
+    }
+
+    pub fn from_path<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The cgroup all task cgroups are created under
+    pub fn parent() -> Self {
+        Self::from_path(Path::new(CGROUP_MOUNT).join(CGROUP_PARENT))
+    }
+
+    /// Create the parent cgroup if needed and enable every controller it has access to
+    /// for its children. Returns the controllers that are available to task cgroups.
+    pub fn setup_parent() -> Result<BTreeSet<Controller>> {
+        let parent = Self::parent();
+        std::fs::create_dir_all(&parent.path)
+            .context(format!("Failed to create '{:?}'", parent.path))?;
+
+        for controller in parent.controllers("cgroup.controllers")? {
+            //enabling can fail e.g. for cpuset inside some containers, that's not fatal
+            //as the controller then simply won't be reported as available
+            if let Err(e) = parent.write("cgroup.subtree_control", &format!("+{}", controller)) {
+                tracing::warn!("Failed to enable cgroup controller {}: {:?}", controller, e);
+            }
+        }
+
+        parent.controllers("cgroup.subtree_control")
+    }
+
+    /// Parses a space separated controller list such as cgroup.controllers,
+    /// ignoring controllers we don't make use of
+    fn controllers(&self, file: &str) -> Result<BTreeSet<Controller>> {
+        Ok(self
+            .read(file)?
+            .split_whitespace()
+            .flat_map(Controller::from_str)
+            .collect())
+    }
+
+    pub fn create(&self) -> Result<()> {
+        std::fs::create_dir(&self.path).context(format!("Failed to create '{:?}'", self.path))
+    }
+
+    /// Remove the cgroup, which fails while it still has processes
+    pub fn remove(&self) -> Result<()> {
+        std::fs::remove_dir(&self.path).context(format!("Failed to remove '{:?}'", self.path))
+    }
+
+    /// Move the process into the cgroup, its children forked afterwards are in it too
+    pub fn add(&self, pid: Pid) -> Result<()> {
+        self.write("cgroup.procs", &pid.to_string())
+    }
+
+    /// SIGKILL every process in the cgroup, needs Linux 5.14
+    pub fn kill(&self) -> Result<()> {
+        self.write("cgroup.kill", "1")
+    }
+
+    /// Write the limits into the cgroup's interface files
+    pub fn apply(&self, limits: &Limits) -> Result<()> {
+        for (file, content) in limits.files() {
+            self.write(file, &content)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, file: &str, content: &str) -> Result<()> {
+        let p = self.path.join(file);
+        std::fs::write(&p, content).context(format!("Failed to write '{}' to '{:?}'", content, p))
+    }
+
+    fn read(&self, file: &str) -> Result<String> {
+        let p = self.path.join(file);
+        std::fs::read_to_string(&p).context(format!("Failed to read '{:?}'", p))
+    }
+
+    fn read_u64(&self, file: &str) -> Result<u64> {
+        let s = self.read(file)?;
+        s.trim()
+            .parse()
+            .context(format!("Failed to parse '{}' as an integer", file))
+    }
+
+    /// Read a file which only exists with some kernels or controllers
+    fn read_optional<T>(&self, file: &str, read: impl Fn(&Self) -> Result<T>) -> Result<Option<T>> {
+        if self.path.join(file).exists() {
+            read(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read all accounting files of the cgroup.
+    /// Note the files are read one by one so the values aren't an atomic snapshot.
+    pub fn stats(&self) -> Result<CgroupStats> {
+        let cpu = parse_cpu_stat(&self.read("cpu.stat")?)?;
+        let memory_current = self.read_u64("memory.current")?;
+        let memory_peak = self.read_optional("memory.peak", |cg| cg.read_u64("memory.peak"))?;
+        let memory_events = parse_memory_events(&self.read("memory.events")?)?;
+        let pids_current = self.read_optional("pids.current", |cg| cg.read_u64("pids.current"))?;
+        let io = self
+            .read_optional("io.stat", |cg| parse_io_stat(&cg.read("io.stat")?))?
+            .unwrap_or_default();
+
+        Ok(CgroupStats {
+            cpu,
+            memory_current,
+            memory_peak,
+            memory_events,
+            pids_current,
+            io,
+        })
+    }
+}
+
+/// Parses the "flat keyed" cgroup file format, i.e. lines of `<key> <u64>`
+fn parse_flat_keyed(content: &str) -> Result<HashMap<&str, u64>> {
+    content
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let mut it = l.split_whitespace();
+            match (it.next(), it.next(), it.next()) {
+                (Some(k), Some(v), None) => v
+                    .parse::<u64>()
+                    .map(|v| (k, v))
+                    .context(format!("Invalid value in line '{}'", l)),
+                _ => Err(anyhow!("Malformed flat keyed line '{}'", l)),
+            }
+        })
+        .collect()
+}
+
+fn parse_cpu_stat(content: &str) -> Result<CpuStat> {
+    let map = parse_flat_keyed(content).context("Failed to parse cpu.stat")?;
+    let get = |k: &str| map.get(k).copied().unwrap_or_default();
+
+    Ok(CpuStat {
+        usage_usec: get("usage_usec"),
+        user_usec: get("user_usec"),
+        system_usec: get("system_usec"),
+        nr_periods: get("nr_periods"),
+        nr_throttled: get("nr_throttled"),
+        throttled_usec: get("throttled_usec"),
+    })
+}
+
+fn parse_memory_events(content: &str) -> Result<MemoryEvents> {
+    let map = parse_flat_keyed(content).context("Failed to parse memory.events")?;
+    let get = |k: &str| map.get(k).copied().unwrap_or_default();
+
+    Ok(MemoryEvents {
+        low: get("low"),
+        high: get("high"),
+        max: get("max"),
+        oom: get("oom"),
+        oom_kill: get("oom_kill"),
+    })
+}
+
+/// Parses io.stat which is "nested keyed", i.e. lines of `<major>:<minor> <key>=<u64> ...`
+fn parse_io_stat(content: &str) -> Result<Vec<IoStat>> {
+    content
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let mut it = l.split_whitespace();
+            let dev = it.next().ok_or_else(|| anyhow!("Empty io.stat line"))?;
+            let (major, minor) = dev
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Malformed device '{}' in io.stat", dev))?;
+            let mut stat = IoStat {
+                major: major.parse().context("Invalid major device number")?,
+                minor: minor.parse().context("Invalid minor device number")?,
+                ..Default::default()
+            };
+
+            for kv in it {
+                let (k, v) = kv
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Malformed key/value '{}' in io.stat", kv))?;
+                let v = v
+                    .parse::<u64>()
+                    .context(format!("Invalid value of '{}' in io.stat", k))?;
+                match k {
+                    "rbytes" => stat.rbytes = v,
+                    "wbytes" => stat.wbytes = v,
+                    "rios" => stat.rios = v,
+                    "wios" => stat.wios = v,
+                    "dbytes" => stat.dbytes = v,
+                    "dios" => stat.dios = v,
+                    //newer kernels may add keys we don't know about
+                    _ => {}
+                }
+            }
+
+            Ok(stat)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_stat() {
+        const CPU_STAT: &str = "usage_usec 1234\nuser_usec 1000\nsystem_usec 234\nnr_periods 10\nnr_throttled 2\nthrottled_usec 5678\n";
+        assert_eq!(
+            parse_cpu_stat(CPU_STAT).unwrap(),
+            CpuStat {
+                usage_usec: 1234,
+                user_usec: 1000,
+                system_usec: 234,
+                nr_periods: 10,
+                nr_throttled: 2,
+                throttled_usec: 5678,
+            }
+        );
+
+        //without the cpu controller enabled only the usage keys are present
+        let partial = parse_cpu_stat("usage_usec 1\nuser_usec 1\nsystem_usec 0\n").unwrap();
+        assert_eq!(partial.nr_periods, 0);
+
+        assert!(parse_cpu_stat("usage_usec abc\n").is_err());
+        assert!(parse_cpu_stat("usage_usec 1 2\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_memory_events() {
+        const EVENTS: &str = "low 0\nhigh 3\nmax 4\noom 1\noom_kill 1\noom_group_kill 0\n";
+        assert_eq!(
+            parse_memory_events(EVENTS).unwrap(),
+            MemoryEvents {
+                low: 0,
+                high: 3,
+                max: 4,
+                oom: 1,
+                oom_kill: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_io_stat() {
+        const IO_STAT: &str = "8:16 rbytes=1459200 wbytes=314773504 rios=192 wios=353 dbytes=0 dios=0\n\
+                               8:0 rbytes=90430464 wbytes=299008000 rios=8950 wios=1252 dbytes=50331648 dios=3021\n";
+        let stats = parse_io_stat(IO_STAT).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats[1],
+            IoStat {
+                major: 8,
+                minor: 0,
+                rbytes: 90430464,
+                wbytes: 299008000,
+                rios: 8950,
+                wios: 1252,
+                dbytes: 50331648,
+                dios: 3021,
+            }
+        );
+
+        assert!(parse_io_stat("").unwrap().is_empty());
+        assert!(parse_io_stat("8-16 rbytes=1").is_err());
+        assert!(parse_io_stat("8:16 rbytes").is_err());
+    }
+
+    #[test]
+    fn test_controller_names() {
+        for c in Controller::ALL {
+            assert_eq!(c.name().parse::<Controller>().unwrap(), *c);
+        }
+        assert!("hugetlb".parse::<Controller>().is_err());
+    }
+
+    #[test]
+    fn test_limit_files() {
+        assert!(Limits::default().files().is_empty());
+        assert!(Limits::default().required_controllers().is_empty());
+
+        let limits = Limits {
+            cpu_max: Some((50000, 100000)),
+            cpuset_cpus: Some("0-3".into()),
+            cpuset_mems: Some("0".into()),
+            memory_swap_max: Some(0),
+            pids_max: Some(64),
+            io_max: vec![IoLimit {
+                major: 8,
+                minor: 0,
+                wbps: Some(1048576),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            limits.files(),
+            vec![
+                ("cpu.max", "50000 100000".to_owned()),
+                ("cpuset.mems", "0".to_owned()),
+                ("cpuset.cpus", "0-3".to_owned()),
+                ("memory.swap.max", "0".to_owned()),
+                ("pids.max", "64".to_owned()),
+                (
+                    "io.max",
+                    "8:0 rbps=max wbps=1048576 riops=max wiops=max".to_owned()
+                ),
+            ]
+        );
+        assert_eq!(
+            limits
+                .required_controllers()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                Controller::Cpu,
+                Controller::Cpuset,
+                Controller::Memory,
+                Controller::Io,
+                Controller::Pids
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_limits() {
+        let dir = std::env::temp_dir().join(format!("rrocker-cg-{}", uuid::Uuid::new_v4()));
+        let cg = Cgroup::from_path(&dir);
+        cg.create().unwrap();
+        std::fs::write(
+            dir.join("cgroup.controllers"),
+            "cpuset cpu io memory hugetlb\n",
+        )
+        .unwrap();
+
+        cg.apply(&Limits {
+            pids_max: Some(10),
+            cpu_weight: Some(200),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("pids.max")).unwrap(), "10");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("cpu.weight")).unwrap(),
+            "200"
+        );
+        assert_eq!(
+            cg.controllers("cgroup.controllers")
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                Controller::Cpu,
+                Controller::Cpuset,
+                Controller::Memory,
+                Controller::Io
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stats_from_dir() {
+        let dir = std::env::temp_dir().join(format!("rrocker-cg-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, content) in [
+            ("cpu.stat", "usage_usec 10\nuser_usec 5\nsystem_usec 5\n"),
+            ("memory.current", "4096\n"),
+            ("memory.events", "low 0\nhigh 0\nmax 0\noom 0\noom_kill 0\n"),
+            ("pids.current", "3\n"),
+            ("io.stat", ""),
+        ] {
+            std::fs::write(dir.join(file), content).unwrap();
+        }
+
+        let cg = Cgroup::from_path(&dir);
+        let stats = cg.stats().unwrap();
+        assert_eq!(stats.cpu.usage_usec, 10);
+        assert_eq!(stats.memory_current, 4096);
+        assert_eq!(stats.memory_peak, None);
+        assert_eq!(stats.pids_current, Some(3));
+
+        std::fs::write(dir.join("memory.peak"), "8192\n").unwrap();
+        assert_eq!(cg.stats().unwrap().memory_peak, Some(8192));
+
+        //cpu and memory are still reported without the pids and io controllers
+        std::fs::remove_file(dir.join("pids.current")).unwrap();
+        std::fs::remove_file(dir.join("io.stat")).unwrap();
+        let stats = cg.stats().unwrap();
+        assert_eq!(stats.cpu.usage_usec, 10);
+        assert_eq!(stats.pids_current, None);
+        assert!(stats.io.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(cg.stats().is_err());
+    }
+}
@@ -1,34 +1,150 @@
-use crate::pipe::Pipe;
+use crate::{
+    namespaces::{self, Join, Namespace, Namespaces},
+    pipe::Pipe,
+};
 use anyhow::{Context, Result};
 use nix::{
-    sched::{self, CloneCb, CloneFlags},
-    sys::signal::Signal::SIGCHLD,
-    unistd::Pid,
+    libc,
+    sched::{self, CloneFlags},
+    sys::{
+        signal::Signal::{SIGCHLD, SIGKILL},
+        wait::{self, WaitStatus},
+    },
+    unistd::{self, ForkResult, Pid},
 };
 use serde::{de::DeserializeOwned, Serialize};
-use std::{fs::File, io::BufReader, marker::PhantomData, ops::DerefMut};
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    marker::PhantomData,
+    ops::DerefMut,
+    os::unix::io::AsRawFd,
+};
 
 const STACK_SIZE: usize = 1024 * 1024; //1MiB
 ///Used to hold our
 pub struct CloneContext<'a, T: Serialize + DeserializeOwned + Send> {
     stack: Box<[u8; STACK_SIZE]>,
-    func: CloneCb<'a>,
+    func: Box<dyn FnMut() -> Result<T> + 'a>,
     res_reader: File,
+    res_writer: File,
+    namespaces: BTreeSet<Namespace>,
+    join: Option<Join>,
+    /// The clone blocks on it until the daemon is done setting it up
+    ready: Option<File>,
     phantom: PhantomData<T>,
 }
 
+/// Where the clone ends up after entering its namespaces
+enum Entered {
+    /// Runs the task's code
+    Task,
+    /// Waits for the child in the new pid namespace
+    Intermediate(Pid),
+}
+
+/// Enter the namespaces in the clone. Joined namespaces must be entered before the new ones
+/// are created, which then have to be unshared and a pid namespace only applies to children.
+fn enter(new: &BTreeSet<Namespace>, join: Option<&Join>) -> Result<Entered> {
+    if let Some(join) = join {
+        join.enter()?;
+        sched::unshare(namespaces::clone_flags(new)).context("Failed to call unshare()")?;
+        if new.contains(&Namespace::Pid) {
+            match unsafe { unistd::fork() }.context("Failed to call fork()")? {
+                ForkResult::Parent { child } => return Ok(Entered::Intermediate(child)),
+                ForkResult::Child => {
+                    //don't outlive the process the daemon tracks
+                    unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, SIGKILL as libc::c_int) };
+                }
+            }
+        }
+    }
+    if new.contains(&Namespace::Time) {
+        namespaces::enter_new_time()?;
+    }
+    Ok(Entered::Task)
+}
+
+/// Pass on how the child exited
+fn wait_exit(child: Pid) -> isize {
+    match wait::waitpid(child, None) {
+        Ok(WaitStatus::Exited(_, code)) => code as isize,
+        Ok(WaitStatus::Signaled(_, signal, _)) => 128 + signal as isize,
+        _ => 1,
+    }
+}
+
 impl<'a, T: Serialize + DeserializeOwned + Send> CloneContext<'a, T> {
-    pub fn new<F: 'a + FnMut() -> Result<T>>(mut func: F) -> Result<Self> {
+    pub fn new<F: 'a + FnMut() -> Result<T>>(func: F) -> Result<Self> {
         let (res_reader, res_writer) = Pipe::new()?.split();
         Ok(Self {
             res_reader,
+            res_writer,
             stack: Box::new([0u8; STACK_SIZE]),
-            func: Box::new(move || {
+            func: Box::new(func),
+            namespaces: Namespaces::default().new,
+            join: None,
+            ready: None,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Create `new` namespaces for the child after entering the ones of `join`,
+    /// every other namespace is shared with the daemon
+    pub fn namespaces(mut self, new: BTreeSet<Namespace>, join: Option<Join>) -> Self {
+        self.namespaces = new;
+        self.join = join;
+        self
+    }
+
+    /// Make the clone wait for a byte on `ready` before it enters any namespace
+    /// or forks, so e.g. its cgroup can be set up first
+    pub fn wait_ready(mut self, ready: File) -> Self {
+        self.ready = Some(ready);
+        self
+    }
+
+    pub fn execute(self) -> Result<(Pid, ResultReader<T>)> {
+        let Self {
+            mut stack,
+            mut func,
+            res_reader,
+            res_writer,
+            namespaces,
+            join,
+            ready,
+            ..
+        } = self;
+        //joined namespaces can't be entered by clone() so the clone unshares the new ones
+        let flags = match join {
+            Some(_) => CloneFlags::empty(),
+            None => namespaces::clone_flags(&namespaces),
+        };
+
+        let pid = sched::clone(
+            Box::new(move || {
+                let entered = match ready.as_ref() {
+                    Some(mut ready) => ready
+                        .read_exact(&mut [0u8])
+                        .context("Failed to wait for the daemon")
+                        .and_then(|_| enter(&namespaces, join.as_ref())),
+                    None => enter(&namespaces, join.as_ref()),
+                };
+                let res = match entered {
+                    Ok(Entered::Intermediate(child)) => {
+                        //only the child reports, a successful exec of it closes the pipe
+                        let _ = unistd::close(res_writer.as_raw_fd());
+                        return wait_exit(child);
+                    }
+                    Ok(Entered::Task) => func(),
+                    Err(e) => Err(e),
+                };
                 //this is quite an abomination because anyhow errors don't impl Serialize
                 //so we use the serde_error crate to magically wrap it.
                 //It's ugly but allows us much easier insight into what went wrong on the clone side.
                 //In a production system this wouldn't be an issue since you'd make proper Error enums
-                let res = func().map_err(|e| serde_error::Error::new(&*e));
+                let res = res.map_err(|e| serde_error::Error::new(&*e));
 
                 if let Err(_e) = bincode::serialize_into(&res_writer, &res) {
                     1
@@ -36,27 +152,15 @@ impl<'a, T: Serialize + DeserializeOwned + Send> CloneContext<'a, T> {
                     0
                 }
             }),
-            phantom: PhantomData::default(),
-        })
-    }
-
-    pub fn execute(mut self) -> Result<(Pid, ResultReader<T>)> {
-        let pid = sched::clone(
-            self.func,
-            self.stack.deref_mut(),
+            stack.deref_mut(),
             //it's of UTMOST importance CLONE_VM is __NOT__ specified here
             //as that gives the child process write access to the daemon
-            CloneFlags::CLONE_NEWPID
-                | CloneFlags::CLONE_NEWNS
-                | CloneFlags::CLONE_NEWNET
-                | CloneFlags::CLONE_NEWUSER
-                | CloneFlags::CLONE_NEWUTS
-                | CloneFlags::CLONE_NEWCGROUP,
+            flags,
             Some(SIGCHLD as i32),
         )
         .context("Failed to call clone()")?;
 
-        Ok((pid, ResultReader::new(self.res_reader)))
+        Ok((pid, ResultReader::new(res_reader)))
     }
 }
 
@@ -79,6 +183,14 @@ impl<T: DeserializeOwned> ResultReader<T> {
             .context("Failed to deserialize inner Result")?
             .map_err(anyhow::Error::from)
     }
+
+    /// Block until the child either execs, which closes the pipe without a result, or fails
+    pub fn wait_exec(&mut self) -> Result<()> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(());
+        }
+        self.get_result().map(drop)
+    }
 }
 
 #[cfg(test)]
@@ -120,11 +232,60 @@ mod test {
 
         match res {
             Ok(s) => assert_eq!(s, data),
-            Err(e) => assert!(false, "{:?}", e),
+            Err(e) => panic!("{:?}", e),
         }
 
         let wait_res = nix::sys::wait::waitpid(pid, None);
 
         assert_eq!(wait_res, Ok(WaitStatus::Exited(pid, 0)));
     }
+
+    #[test]
+    #[ignore]
+    fn joins_namespaces() {
+        let ns = |pid: &str, name: &str| std::fs::read_link(format!("/proc/{}/ns/{}", pid, name));
+
+        //a peer that waits until it's been joined
+        let (reader, mut writer) = Pipe::new().unwrap().split();
+        let peer = CloneContext::new(|| -> Result<()> {
+            use std::io::Read;
+            (&reader).read_exact(&mut [0u8])?;
+            Ok(())
+        })
+        .unwrap();
+        let (peer_pid, mut peer_rr) = peer.execute().unwrap();
+        let peer_proc = peer_pid.to_string();
+
+        let new = [Namespace::Mnt, Namespace::Pid, Namespace::Time]
+            .iter()
+            .copied()
+            .collect::<BTreeSet<_>>();
+        let join = Join::open(peer_pid, &[Namespace::Net].iter().copied().collect()).unwrap();
+        let cc = CloneContext::new(|| -> Result<(i32, Vec<String>)> {
+            let links = ["user", "net", "ipc", "time"]
+                .iter()
+                .map(|name| Ok(ns("self", name)?.to_string_lossy().into_owned()))
+                .collect::<Result<_>>()?;
+            Ok((unistd::getpid().as_raw(), links))
+        })
+        .unwrap()
+        .namespaces(new, Some(join));
+        let (pid, mut rr) = cc.execute().unwrap();
+        let (inner_pid, links) = rr.get_result().unwrap();
+        assert_eq!(wait::waitpid(pid, None), Ok(WaitStatus::Exited(pid, 0)));
+
+        //the child of the intermediate is the init of the new pid namespace
+        assert_eq!(inner_pid, 1);
+        assert_eq!(links[0], ns(&peer_proc, "user").unwrap().to_string_lossy());
+        assert_eq!(links[1], ns(&peer_proc, "net").unwrap().to_string_lossy());
+        //the ipc namespace was neither joined nor created
+        assert_eq!(links[2], ns("self", "ipc").unwrap().to_string_lossy());
+        assert_ne!(links[3], ns("self", "time").unwrap().to_string_lossy());
+
+        //the peer got its own ipc namespace by default
+        assert_ne!(ns(&peer_proc, "ipc").unwrap(), ns("self", "ipc").unwrap());
+        std::io::Write::write_all(&mut writer, &[0]).unwrap();
+        peer_rr.get_result().unwrap();
+        wait::waitpid(peer_pid, None).unwrap();
+    }
 }
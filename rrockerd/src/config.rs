@@ -0,0 +1,420 @@
+use crate::{
+    audit::AuditConfig, auth::IdentityConfig, capacity::Resources, images::ImageConfig,
+    isolation::IsolationConfig, local::LocalConfig, mounts::MountConfig, policy::PolicyConfig,
+    quota::QuotaConfig, rate_limit::RateLimitConfig, seccomp::SeccompConfig, subid::SubIdConfig,
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+/// The daemon's configuration, loaded from a TOML file.
+/// Every section is optional and falls back to the defaults below.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the gRPC server listens on
+    pub listen: SocketAddr,
+    pub tls: TlsConfig,
+    pub identity: IdentityConfig,
+    pub capacity: CapacityConfig,
+    pub quotas: QuotaConfig,
+    pub policy: PolicyConfig,
+    pub audit: AuditConfig,
+    pub rate_limits: RateLimitConfig,
+    pub local: LocalConfig,
+    pub seccomp: SeccompConfig,
+    pub isolation: IsolationConfig,
+    pub subids: SubIdConfig,
+    pub mounts: MountConfig,
+    pub images: ImageConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA chain client certificates must be signed by
+    pub client_ca: PathBuf,
+    /// CRLs (PEM or DER) of the client CAs, certs revoked by any of them are rejected.
+    /// Each must be issued and signed by a cert of `client_ca`.
+    pub crls: Vec<PathBuf>,
+    /// How often the CRLs are reloaded, they're also reloaded on SIGHUP
+    pub crl_reload_interval_secs: u64,
+    /// How often the cert, key and client CA are checked for changes, 0 disables watching.
+    /// Either way they're reloaded on SIGHUP.
+    pub watch_interval_secs: u64,
+}
+
+/// The host budget that admission control commits running tasks against.
+/// An unset budget means that resource isn't limited.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapacityConfig {
+    pub cpu_millicores: Option<u64>,
+    pub memory_bytes: Option<u64>,
+    /// Charged for tasks that don't constrain their CPU, defaults to 0
+    pub default_task_cpu_millicores: u64,
+    /// Charged for tasks that don't constrain their memory, defaults to 0
+    pub default_task_memory_bytes: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 50051),
+            tls: Default::default(),
+            identity: Default::default(),
+            capacity: Default::default(),
+            quotas: Default::default(),
+            policy: Default::default(),
+            audit: Default::default(),
+            rate_limits: Default::default(),
+            local: Default::default(),
+            seccomp: Default::default(),
+            isolation: Default::default(),
+            subids: Default::default(),
+            mounts: Default::default(),
+            images: Default::default(),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: "certs/server1_crt.pem".into(),
+            key: "certs/server1_key.pem".into(),
+            client_ca: "certs/client_ca_chain.pem".into(),
+            crls: Vec::new(),
+            crl_reload_interval_secs: 300,
+            watch_interval_secs: 10,
+        }
+    }
+}
+
+impl CapacityConfig {
+    pub fn budget(&self) -> Resources {
+        Resources {
+            cpu_millicores: self.cpu_millicores.unwrap_or(u64::MAX),
+            memory_bytes: self.memory_bytes.unwrap_or(u64::MAX),
+        }
+    }
+
+    pub fn default_task(&self) -> Resources {
+        Resources {
+            cpu_millicores: self.default_task_cpu_millicores,
+            memory_bytes: self.default_task_memory_bytes,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).context(format!("Failed to read '{:?}'", path))?;
+        toml::from_str(&content).context(format!("Failed to parse '{:?}'", path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.listen.port(), 50051);
+        assert_eq!(config.capacity.budget(), Resources::UNLIMITED);
+        assert_eq!(config.capacity.default_task(), Resources::default());
+        assert!(config.tls.crls.is_empty());
+    }
+
+    #[test]
+    fn test_tls() {
+        let config: Config = toml::from_str(
+            r#"
+            [tls]
+            crls = ["certs/client_ca_crl.pem"]
+            crl_reload_interval_secs = 60
+            watch_interval_secs = 0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.tls.cert, PathBuf::from("certs/server1_crt.pem"));
+        assert_eq!(
+            config.tls.crls,
+            vec![PathBuf::from("certs/client_ca_crl.pem")]
+        );
+        assert_eq!(config.tls.crl_reload_interval_secs, 60);
+        assert_eq!(config.tls.watch_interval_secs, 0);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let config: Config = toml::from_str(
+            r#"
+            listen = "0.0.0.0:1234"
+
+            [capacity]
+            cpu_millicores = 8000
+            default_task_memory_bytes = 1073741824
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen.port(), 1234);
+        assert_eq!(
+            config.capacity.budget(),
+            Resources {
+                cpu_millicores: 8000,
+                memory_bytes: u64::MAX,
+            }
+        );
+        assert_eq!(config.capacity.default_task().memory_bytes, 1 << 30);
+    }
+
+    #[test]
+    fn test_quotas() {
+        let config: Config = toml::from_str(
+            r#"
+            [quotas.default]
+            max_tasks = 10
+
+            [quotas.clients.client1]
+            max_tasks = 2
+            log_bytes = 1024
+
+            [quotas.groups.client]
+            cpu_millicores = 16000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.quotas.default.max_tasks, Some(10));
+        let client1 = crate::auth::Principal::CommonName("client1".into());
+        assert_eq!(config.quotas.clients[&client1].log_bytes, Some(1024));
+        let client = crate::auth::Group::Cert("client".into());
+        assert_eq!(config.quotas.groups[&client].cpu_millicores, Some(16000));
+    }
+
+    #[test]
+    fn test_policy() {
+        let config: Config = toml::from_str(
+            r#"
+            [policy.roles.oncall]
+            permissions = ["read-output", "list-all"]
+
+            [[policy.bindings]]
+            role = "oncall"
+            organizational_units = ["sre"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.policy.bindings.len(), 1);
+        assert!(config.policy.roles["oncall"]
+            .permissions
+            .contains(&crate::policy::Permission::ListAll));
+        assert!(toml::from_str::<Config>(
+            "[policy.roles.x]
+permissions = [\"reboot\"]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_identity() {
+        let config: Config = toml::from_str(
+            r#"
+            [identity]
+            require_spiffe_id = true
+            trust_domains = ["corp"]
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.identity.use_spiffe_id);
+        assert!(config.identity.require_spiffe_id);
+        assert_eq!(config.identity.trust_domains, vec!["corp"]);
+        assert!(!config.identity.org_units_as_groups);
+    }
+
+    #[test]
+    fn test_audit() {
+        assert_eq!(Config::default().audit.path, None);
+
+        let config: Config = toml::from_str(
+            r#"
+            [audit]
+            path = "/var/log/rrockerd/audit.log"
+            syslog = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.audit.path,
+            Some(PathBuf::from("/var/log/rrockerd/audit.log"))
+        );
+        assert!(config.audit.syslog);
+        assert_eq!(config.audit.max_files, 5);
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let config: Config = toml::from_str(
+            r#"
+            [rate_limits]
+            default = { per_second = 10, burst = 20 }
+            max_streams = 16
+
+            [rate_limits.rpcs.StartTask]
+            per_second = 0.5
+            burst = 5
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.rate_limits.default.unwrap().burst, 20);
+        assert_eq!(config.rate_limits.rpcs["StartTask"].per_second, 0.5);
+        assert_eq!(config.rate_limits.max_streams, Some(16));
+    }
+
+    #[test]
+    fn test_local() {
+        assert_eq!(Config::default().local.socket, None);
+
+        let config: Config = toml::from_str(
+            r#"
+            [local]
+            socket = "/run/rrockerd.sock"
+            socket_mode = 0o600
+
+            [[local.users]]
+            uid = 1001
+            roles = ["client"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.local.socket,
+            Some(PathBuf::from("/run/rrockerd.sock"))
+        );
+        assert_eq!(config.local.socket_mode, 0o600);
+        assert_eq!(config.local.users[0].principal, None);
+    }
+
+    #[test]
+    fn test_seccomp() {
+        assert_eq!(Config::default().seccomp.default_profile, "default");
+
+        let config: Config = toml::from_str(
+            r#"
+            [seccomp]
+            default_profile = "strict"
+            profiles = { build = "/etc/rrockerd/seccomp/build.json" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.seccomp.default_profile, "strict");
+        assert_eq!(
+            config.seccomp.profiles["build"],
+            PathBuf::from("/etc/rrockerd/seccomp/build.json")
+        );
+    }
+
+    #[test]
+    fn test_isolation() {
+        assert!(Config::default().isolation.capabilities.is_empty());
+        assert!(Config::default().isolation.no_new_privs);
+
+        let config: Config = toml::from_str(
+            r#"
+            [isolation]
+            capabilities = ["CAP_NET_BIND_SERVICE"]
+            uid = 1000
+            gid = 1000
+            masked_paths = ["/proc/kcore"]
+            scratch_paths = [{ path = "/tmp", size_bytes = 1048576 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.isolation.capabilities, vec!["CAP_NET_BIND_SERVICE"]);
+        assert_eq!(config.isolation.uid, 1000);
+        assert!(config.isolation.no_new_privs);
+        assert_eq!(
+            config.isolation.masked_paths,
+            vec![PathBuf::from("/proc/kcore")]
+        );
+        assert!(config
+            .isolation
+            .readonly_paths
+            .contains(&PathBuf::from("/proc/sys")));
+        assert_eq!(
+            config.isolation.scratch_paths[0].path,
+            PathBuf::from("/tmp")
+        );
+        assert_eq!(config.isolation.scratch_paths.len(), 1);
+    }
+
+    #[test]
+    fn test_subids() {
+        assert!(!Config::default().subids.enabled);
+
+        let config: Config = toml::from_str(
+            r#"
+            [subids]
+            enabled = true
+            user = "rrocker"
+            "#,
+        )
+        .unwrap();
+        assert!(config.subids.enabled);
+        assert_eq!(config.subids.range_size, 65536);
+        assert_eq!(config.subids.subgid_file, PathBuf::from("/etc/subgid"));
+    }
+
+    #[test]
+    fn test_mounts() {
+        assert!(Config::default().mounts.allowed_bind_prefixes.is_empty());
+
+        let config: Config = toml::from_str(
+            r#"
+            [mounts]
+            allowed_bind_prefixes = ["/srv/data"]
+            max_tmpfs_size_bytes = 1073741824
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.mounts.allowed_bind_prefixes,
+            vec![PathBuf::from("/srv/data")]
+        );
+        assert_eq!(config.mounts.max_tmpfs_size_bytes, Some(1 << 30));
+    }
+
+    #[test]
+    fn test_images() {
+        assert_eq!(Config::default().images.max_import_bytes, 16 << 30);
+
+        let config: Config = toml::from_str(
+            r#"
+            [images]
+            default_image = "alpine"
+            max_import_bytes = 1073741824
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.images.default_image.as_deref(), Some("alpine"));
+        assert_eq!(config.images.max_import_bytes, 1 << 30);
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(toml::from_str::<Config>("[capacity]\ncpu = 1").is_err());
+    }
+}
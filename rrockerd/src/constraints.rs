@@ -0,0 +1,412 @@
+use crate::cgroup::{IoLimit, Limits};
+use rrocker_lib::api::{resource_constraints::Cpu, CpuQuota, IoDeviceLimit, ResourceConstraints};
+use tonic::Status;
+
+/// The cpu.max period we use, quotas are computed relative to it
+const CPU_PERIOD_USEC: u64 = 100_000;
+/// The kernel's bounds on the cpu.max period (and minimum quota)
+const MIN_CPU_PERIOD_USEC: u64 = 1_000;
+const MAX_CPU_PERIOD_USEC: u64 = 1_000_000;
+/// The smallest millicore limit whose quota the kernel accepts
+const MIN_CPU_MILLICORES: u32 = (MIN_CPU_PERIOD_USEC * 1000 / CPU_PERIOD_USEC) as u32;
+/// (quota, period) in microseconds as written to cpu.max, `None` means no limit
+type CpuMax = Option<(u64, u64)>;
+
+const MIN_CPU_WEIGHT: u32 = 1;
+const MAX_CPU_WEIGHT: u32 = 10_000;
+
+fn non_zero(value: u64) -> Option<u64> {
+    if value == 0 {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Validates a cpuset list such as "0-3,6"
+fn validate_cpuset(field: &str, list: &str) -> Result<(), Status> {
+    let valid_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let valid = list.split(',').all(|part| match part.split_once('-') {
+        Some((from, to)) => {
+            valid_number(from)
+                && valid_number(to)
+                && from.parse::<u32>().ok() <= to.parse::<u32>().ok()
+        }
+        None => valid_number(part),
+    });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Status::invalid_argument(format!(
+            "{} '{}' is not a valid cpu/node list",
+            field, list
+        )))
+    }
+}
+
+fn io_limit(limit: &IoDeviceLimit) -> Result<IoLimit, Status> {
+    let io = IoLimit {
+        major: limit.major,
+        minor: limit.minor,
+        rbps: non_zero(limit.read_bps),
+        wbps: non_zero(limit.write_bps),
+        riops: non_zero(limit.read_iops),
+        wiops: non_zero(limit.write_iops),
+    };
+
+    if io.rbps.is_none() && io.wbps.is_none() && io.riops.is_none() && io.wiops.is_none() {
+        Err(Status::invalid_argument(format!(
+            "IO limit of device {}:{} doesn't limit anything",
+            limit.major, limit.minor
+        )))
+    } else {
+        Ok(io)
+    }
+}
+
+fn cpu_max(cpu: &Cpu, ncpus: u64) -> Result<CpuMax, Status> {
+    let (quota, period) = match cpu {
+        Cpu::CpuMillicores(millis) if *millis < MIN_CPU_MILLICORES => {
+            return Err(Status::invalid_argument(format!(
+                "cpu_millicores must be at least {}",
+                MIN_CPU_MILLICORES
+            )))
+        }
+        Cpu::CpuMillicores(millis) => (*millis as u64 * CPU_PERIOD_USEC / 1000, CPU_PERIOD_USEC),
+        Cpu::CpuQuota(CpuQuota {
+            quota_usec,
+            period_usec,
+        }) => {
+            if !(MIN_CPU_PERIOD_USEC..=MAX_CPU_PERIOD_USEC).contains(period_usec) {
+                return Err(Status::invalid_argument(format!(
+                    "cpu_quota.period_usec must be between {} and {}",
+                    MIN_CPU_PERIOD_USEC, MAX_CPU_PERIOD_USEC
+                )));
+            }
+            if *quota_usec < MIN_CPU_PERIOD_USEC {
+                return Err(Status::invalid_argument(format!(
+                    "cpu_quota.quota_usec must be at least {}",
+                    MIN_CPU_PERIOD_USEC
+                )));
+            }
+            (*quota_usec, *period_usec)
+        }
+    };
+
+    //best effort, anything above what the host can offer means no constraint
+    if quota >= period * ncpus {
+        Ok(None)
+    } else {
+        Ok(Some((quota, period)))
+    }
+}
+
+/// The deprecated `max_cpu` and `max_mem_bytes` fields are only honored when their
+/// replacements are unset, so old clients keep their semantics of 0 meaning no limit
+#[allow(deprecated)]
+fn legacy_limits(
+    constraints: &ResourceConstraints,
+    ncpus: u64,
+) -> Result<(CpuMax, Option<u64>), Status> {
+    if constraints.max_cpu < 0 || constraints.max_mem_bytes < 0 {
+        return Err(Status::invalid_argument(
+            "Resource constraints must not be negative",
+        ));
+    }
+    if constraints.max_cpu != 0 && constraints.cpu.is_some() {
+        return Err(Status::invalid_argument(
+            "The deprecated max_cpu can't be combined with cpu_millicores/cpu_quota",
+        ));
+    }
+    if constraints.max_mem_bytes != 0 && constraints.memory_max_bytes.is_some() {
+        return Err(Status::invalid_argument(
+            "The deprecated max_mem_bytes can't be combined with memory_max_bytes",
+        ));
+    }
+
+    //best effort, anything above 100% of the host means no constraint
+    let cpu_max = match constraints.max_cpu {
+        0 | 100..=i32::MAX => None,
+        pct => Some((pct as u64 * ncpus * CPU_PERIOD_USEC / 100, CPU_PERIOD_USEC)),
+    };
+
+    Ok((cpu_max, non_zero(constraints.max_mem_bytes as u64)))
+}
+
+/// Translate the requested constraints into cgroup limits.
+/// `ncpus` is the number of cores on the daemon host which CPU limits are relative to.
+pub fn to_limits(constraints: &ResourceConstraints, ncpus: u64) -> Result<Limits, Status> {
+    let (legacy_cpu_max, legacy_memory_max) = legacy_limits(constraints, ncpus)?;
+
+    let cpu_max = match &constraints.cpu {
+        Some(cpu) => cpu_max(cpu, ncpus)?,
+        None => legacy_cpu_max,
+    };
+
+    let memory_max = match constraints.memory_max_bytes {
+        Some(0) => {
+            return Err(Status::invalid_argument(
+                "memory_max_bytes must be positive when set",
+            ))
+        }
+        Some(bytes) => Some(bytes),
+        None => legacy_memory_max,
+    };
+
+    let cpu_weight = match constraints.cpu_weight {
+        None => None,
+        Some(w @ MIN_CPU_WEIGHT..=MAX_CPU_WEIGHT) => Some(w.into()),
+        Some(_) => {
+            return Err(Status::invalid_argument(format!(
+                "cpu_weight must be between {} and {}",
+                MIN_CPU_WEIGHT, MAX_CPU_WEIGHT
+            )))
+        }
+    };
+
+    let pids_max = match constraints.max_pids {
+        Some(0) => {
+            return Err(Status::invalid_argument(
+                "max_pids must be positive when set",
+            ))
+        }
+        pids => pids,
+    };
+
+    let cpuset = |field: &str, list: &str| -> Result<Option<String>, Status> {
+        if list.is_empty() {
+            Ok(None)
+        } else {
+            validate_cpuset(field, list)?;
+            Ok(Some(list.to_owned()))
+        }
+    };
+
+    Ok(Limits {
+        cpu_max,
+        cpu_weight,
+        cpuset_cpus: cpuset("cpuset_cpus", &constraints.cpuset_cpus)?,
+        cpuset_mems: cpuset("cpuset_mems", &constraints.cpuset_mems)?,
+        memory_max,
+        memory_swap_max: constraints.max_swap_bytes,
+        pids_max,
+        io_max: constraints
+            .io_limits
+            .iter()
+            .map(io_limit)
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tonic::Code;
+
+    #[test]
+    fn test_empty_constraints() {
+        let limits = to_limits(&ResourceConstraints::default(), 4).unwrap();
+        assert_eq!(limits, Limits::default());
+    }
+
+    #[test]
+    fn test_constraints() {
+        let limits = to_limits(
+            &ResourceConstraints {
+                cpu: Some(Cpu::CpuMillicores(2000)),
+                memory_max_bytes: Some(32 << 30),
+                max_pids: Some(32),
+                io_limits: vec![IoDeviceLimit {
+                    major: 8,
+                    minor: 0,
+                    read_iops: 100,
+                    ..Default::default()
+                }],
+                cpuset_cpus: "0-1,3".into(),
+                cpuset_mems: "".into(),
+                max_swap_bytes: Some(0),
+                cpu_weight: Some(50),
+                ..Default::default()
+            },
+            4,
+        )
+        .unwrap();
+
+        assert_eq!(
+            limits,
+            Limits {
+                cpu_max: Some((200_000, CPU_PERIOD_USEC)),
+                cpu_weight: Some(50),
+                cpuset_cpus: Some("0-1,3".into()),
+                cpuset_mems: None,
+                memory_max: Some(32 << 30),
+                memory_swap_max: Some(0),
+                pids_max: Some(32),
+                io_max: vec![IoLimit {
+                    major: 8,
+                    minor: 0,
+                    riops: Some(100),
+                    ..Default::default()
+                }],
+            }
+        );
+
+        let limits = to_limits(
+            &ResourceConstraints {
+                cpu: Some(Cpu::CpuMillicores(1500)),
+                ..Default::default()
+            },
+            64,
+        )
+        .unwrap();
+        assert_eq!(limits.cpu_max, Some((150_000, CPU_PERIOD_USEC)));
+
+        //the smallest quota the kernel accepts
+        let limits = to_limits(
+            &ResourceConstraints {
+                cpu: Some(Cpu::CpuMillicores(10)),
+                ..Default::default()
+            },
+            4,
+        )
+        .unwrap();
+        assert_eq!(limits.cpu_max, Some((MIN_CPU_PERIOD_USEC, CPU_PERIOD_USEC)));
+
+        let limits = to_limits(
+            &ResourceConstraints {
+                cpu: Some(Cpu::CpuQuota(CpuQuota {
+                    quota_usec: 25_000,
+                    period_usec: 50_000,
+                })),
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
+        assert_eq!(limits.cpu_max, Some((25_000, 50_000)));
+
+        //above what the host can offer means no constraint
+        let limits = to_limits(
+            &ResourceConstraints {
+                cpu: Some(Cpu::CpuMillicores(5000)),
+                ..Default::default()
+            },
+            4,
+        )
+        .unwrap();
+        assert_eq!(limits.cpu_max, None);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_legacy_constraints() {
+        let limits = to_limits(
+            &ResourceConstraints {
+                max_cpu: 50,
+                max_mem_bytes: 1024,
+                ..Default::default()
+            },
+            4,
+        )
+        .unwrap();
+        assert_eq!(limits.cpu_max, Some((200_000, CPU_PERIOD_USEC)));
+        assert_eq!(limits.memory_max, Some(1024));
+
+        //above what the host can offer means no constraint
+        let limits = to_limits(
+            &ResourceConstraints {
+                max_cpu: 150,
+                ..Default::default()
+            },
+            4,
+        )
+        .unwrap();
+        assert_eq!(limits.cpu_max, None);
+
+        //mixing deprecated fields and their replacements is ambiguous
+        for constraints in [
+            ResourceConstraints {
+                max_cpu: 50,
+                cpu: Some(Cpu::CpuMillicores(500)),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                max_mem_bytes: 1024,
+                memory_max_bytes: Some(2048),
+                ..Default::default()
+            },
+        ] {
+            let status = to_limits(&constraints, 4).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_invalid_constraints() {
+        for constraints in [
+            ResourceConstraints {
+                max_cpu: -1,
+                ..Default::default()
+            },
+            ResourceConstraints {
+                max_mem_bytes: -1,
+                ..Default::default()
+            },
+            ResourceConstraints {
+                memory_max_bytes: Some(0),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                cpu: Some(Cpu::CpuMillicores(0)),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                cpu: Some(Cpu::CpuQuota(CpuQuota {
+                    quota_usec: 1000,
+                    period_usec: 10,
+                })),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                cpu: Some(Cpu::CpuQuota(CpuQuota {
+                    quota_usec: 10,
+                    period_usec: 100_000,
+                })),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                cpu: Some(Cpu::CpuMillicores(9)),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                max_pids: Some(0),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                cpu_weight: Some(0),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                cpu_weight: Some(10_001),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                cpuset_cpus: "0-".into(),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                cpuset_mems: "3-1".into(),
+                ..Default::default()
+            },
+            ResourceConstraints {
+                io_limits: vec![IoDeviceLimit::default()],
+                ..Default::default()
+            },
+        ] {
+            let status = to_limits(&constraints, 4).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    }
+}
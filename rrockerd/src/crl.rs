@@ -0,0 +1,311 @@
+use anyhow::{anyhow, Context, Result};
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tonic::Status;
+use x509_parser::{
+    oid_registry::{
+        OID_PKCS1_SHA256WITHRSA, OID_PKCS1_SHA384WITHRSA, OID_PKCS1_SHA512WITHRSA,
+        OID_SIG_ECDSA_WITH_SHA256, OID_SIG_ECDSA_WITH_SHA384, OID_SIG_ED25519,
+    },
+    pem::Pem,
+    prelude::{CertificateRevocationList, X509Certificate},
+};
+
+/// The DER encoded certs of a PEM bundle
+fn pem_certs(content: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut certs = Vec::new();
+    for pem in Pem::iter_from_buffer(content) {
+        let pem = pem.map_err(|e| anyhow!("Invalid PEM: {:?}", e))?;
+        if pem.label == "CERTIFICATE" {
+            certs.push(pem.contents);
+        }
+    }
+    Ok(certs)
+}
+
+/// Check the CRL was issued and signed by one of the client CAs, anyone could write a
+/// CRL that revokes every client otherwise
+fn verify(crl: &CertificateRevocationList, issuers: &[X509Certificate]) -> Result<()> {
+    let issuer = issuers
+        .iter()
+        .find(|ca| ca.subject().as_raw() == crl.issuer().as_raw())
+        .ok_or_else(|| anyhow!("The CRL's issuer '{}' isn't a client CA", crl.issuer()))?;
+
+    let algorithm = &crl.signature_algorithm.algorithm;
+    let algorithm: &dyn VerificationAlgorithm = if *algorithm == OID_SIG_ECDSA_WITH_SHA256 {
+        &signature::ECDSA_P256_SHA256_ASN1
+    } else if *algorithm == OID_SIG_ECDSA_WITH_SHA384 {
+        &signature::ECDSA_P384_SHA384_ASN1
+    } else if *algorithm == OID_SIG_ED25519 {
+        &signature::ED25519
+    } else if *algorithm == OID_PKCS1_SHA256WITHRSA {
+        &signature::RSA_PKCS1_2048_8192_SHA256
+    } else if *algorithm == OID_PKCS1_SHA384WITHRSA {
+        &signature::RSA_PKCS1_2048_8192_SHA384
+    } else if *algorithm == OID_PKCS1_SHA512WITHRSA {
+        &signature::RSA_PKCS1_2048_8192_SHA512
+    } else {
+        return Err(anyhow!(
+            "The CRL's signature algorithm {} is unsupported",
+            algorithm
+        ));
+    };
+
+    UnparsedPublicKey::new(algorithm, issuer.public_key().subject_public_key.data)
+        .verify(crl.tbs_cert_list.as_ref(), crl.signature_value.data)
+        .map_err(|_| {
+            anyhow!(
+                "The CRL's signature doesn't match its issuer '{}'",
+                crl.issuer()
+            )
+        })
+}
+
+/// Serial numbers revoked by each issuer, keyed by the issuer's DER encoded name
+/// as serials are only unique per issuer.
+#[derive(Debug, Default)]
+pub struct CrlSet {
+    revoked: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+}
+
+impl CrlSet {
+    /// Load CRLs from PEM files (which may contain several CRLs) or DER files.
+    /// Each CRL must be issued and signed by a cert of the client CA bundle.
+    pub fn load(paths: &[PathBuf], client_ca: &Path) -> Result<Self> {
+        let mut set = Self::default();
+        if paths.is_empty() {
+            return Ok(set);
+        }
+
+        let content =
+            std::fs::read(client_ca).context(format!("Failed to read '{:?}'", client_ca))?;
+        let ders = pem_certs(&content).context(format!("Invalid client CA '{:?}'", client_ca))?;
+        let issuers = ders
+            .iter()
+            .map(|der| x509_parser::parse_x509_certificate(der).map(|(_, cert)| cert))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("Invalid client CA '{:?}': {:?}", client_ca, e))?;
+
+        for path in paths {
+            set.add_file(path, &issuers)
+                .context(format!("Failed to load CRL '{:?}'", path))?;
+        }
+        Ok(set)
+    }
+
+    fn add_file(&mut self, path: &Path, issuers: &[X509Certificate]) -> Result<()> {
+        let content = std::fs::read(path)?;
+        //an empty file is a CRL to be filled in later, not an error
+        if content.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+        if !content.starts_with(b"-----BEGIN") {
+            return self.add_der(&content, issuers);
+        }
+
+        for pem in Pem::iter_from_buffer(&content) {
+            let pem = pem.map_err(|e| anyhow!("Invalid PEM: {:?}", e))?;
+            //x509-parser only keeps the first word of the label so "X509 CRL" becomes "X509"
+            if pem.label == "X509" {
+                self.add_der(&pem.contents, issuers)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_der(&mut self, der: &[u8], issuers: &[X509Certificate]) -> Result<()> {
+        let (_, crl) =
+            x509_parser::parse_x509_crl(der).map_err(|e| anyhow!("Invalid CRL: {:?}", e))?;
+        verify(&crl, issuers)?;
+        self.revoked
+            .entry(crl.issuer().as_raw().to_vec())
+            .or_default()
+            .extend(
+                crl.iter_revoked_certificates()
+                    .map(|r| r.raw_serial().to_vec()),
+            );
+        Ok(())
+    }
+
+    pub fn is_revoked(&self, cert: &X509Certificate) -> bool {
+        self.revoked
+            .get(cert.issuer().as_raw())
+            .is_some_and(|serials| serials.contains(cert.tbs_certificate.raw_serial()))
+    }
+}
+
+/// The configured CRLs, reloaded periodically and on SIGHUP.
+/// A failed reload keeps the previously loaded CRLs.
+#[derive(Debug, Default)]
+pub struct Revocations {
+    paths: Vec<PathBuf>,
+    /// Re-read on every reload as it's hot reloaded too
+    client_ca: PathBuf,
+    current: RwLock<Arc<CrlSet>>,
+}
+
+impl Revocations {
+    /// Load the CRLs, unlike reloads the initial load has to succeed
+    pub fn new(paths: Vec<PathBuf>, client_ca: PathBuf) -> Result<Self> {
+        let set = CrlSet::load(&paths, &client_ca)?;
+        Ok(Self {
+            paths,
+            client_ca,
+            current: RwLock::new(Arc::new(set)),
+        })
+    }
+
+    pub fn reload(&self) -> Result<()> {
+        let set = CrlSet::load(&self.paths, &self.client_ca)?;
+        *self.current.write().unwrap() = Arc::new(set);
+        Ok(())
+    }
+
+    /// Reject the request if any cert of the peer's chain has been revoked
+    pub fn check(&self, certs: &[X509Certificate]) -> Result<(), Status> {
+        let current = self.current.read().unwrap().clone();
+        match certs.iter().find(|c| current.is_revoked(c)) {
+            Some(cert) => {
+                tracing::warn!(
+                    event = "revoked_cert",
+                    subject = %cert.subject(),
+                    issuer = %cert.issuer(),
+                    serial = %cert.tbs_certificate.raw_serial_as_string(),
+                    "Rejected request with a revoked certificate"
+                );
+                Err(Status::unauthenticated(
+                    "The provided cert has been revoked",
+                ))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Reload the CRLs every `interval` and whenever the daemon receives SIGHUP
+    pub async fn reload_loop(self: Arc<Self>, interval: Duration) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+        let mut ticker = tokio::time::interval(interval);
+        //the first tick completes right away and the CRLs were just loaded
+        ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                _ = hangup.recv() => tracing::info!("Received SIGHUP, reloading CRLs"),
+            }
+            match self.reload() {
+                Ok(()) => tracing::debug!("Reloaded CRLs"),
+                Err(e) => tracing::error!("Failed to reload CRLs, keeping the old ones: {:?}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const REVOKED_CERT: &[u8] = include_bytes!("../../certs/test/revoked_crt.pem");
+    const NOT_REVOKED_CERT: &[u8] = include_bytes!("../../certs/test/not_revoked_crt.pem");
+    const GOOD_CERT: &[u8] = include_bytes!("../../certs/test/good_crt.pem");
+
+    fn test_cert(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../certs/test")
+            .join(name)
+    }
+
+    fn crl_path() -> PathBuf {
+        test_cert("crl.pem")
+    }
+
+    fn ca_path() -> PathBuf {
+        test_cert("crl_ca_crt.pem")
+    }
+
+    #[test]
+    fn test_revoked() {
+        let revocations = Revocations::new(vec![crl_path()], ca_path()).unwrap();
+
+        let pems = vec![REVOKED_CERT, NOT_REVOKED_CERT, GOOD_CERT]
+            .into_iter()
+            .map(|c| Pem::iter_from_buffer(c).next().unwrap().unwrap())
+            .collect::<Vec<_>>();
+        let certs = pems
+            .iter()
+            .map(|p| p.parse_x509().unwrap())
+            .collect::<Vec<_>>();
+
+        let status = revocations.check(&certs[..1]).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        //a revoked cert anywhere in the chain rejects the request
+        assert!(revocations.check(&certs).is_err());
+
+        //other certs of the same CA and certs of other issuers pass
+        revocations.check(&certs[1..]).unwrap();
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("rrocker-crl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("crl.pem");
+        std::fs::write(&path, "").unwrap();
+
+        let pem = Pem::iter_from_buffer(REVOKED_CERT).next().unwrap().unwrap();
+        let certs = vec![pem.parse_x509().unwrap()];
+
+        let revocations = Revocations::new(vec![path.clone()], ca_path()).unwrap();
+        revocations.check(&certs).unwrap();
+
+        std::fs::copy(crl_path(), &path).unwrap();
+        revocations.reload().unwrap();
+        assert!(revocations.check(&certs).is_err());
+
+        //a broken file keeps the last good CRLs
+        std::fs::write(
+            &path,
+            "-----BEGIN X509 CRL-----\nbroken\n-----END X509 CRL-----\n",
+        )
+        .unwrap();
+        assert!(revocations.reload().is_err());
+        assert!(revocations.check(&certs).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unverified() {
+        let dir = std::env::temp_dir().join(format!("rrocker-crl-sig-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        //a CRL of another CA than the client CA
+        let err = CrlSet::load(&[crl_path()], &test_cert("good_crt.pem")).unwrap_err();
+        assert!(format!("{:#}", err).contains("isn't a client CA"));
+
+        //a CRL of the client CA whose signature doesn't match
+        let content = std::fs::read(crl_path()).unwrap();
+        let mut der = Pem::iter_from_buffer(&content)
+            .next()
+            .unwrap()
+            .unwrap()
+            .contents;
+        let last = der.len() - 1;
+        der[last] ^= 1;
+        let tampered = dir.join("crl.der");
+        std::fs::write(&tampered, &der).unwrap();
+        let err = CrlSet::load(&[tampered], &ca_path()).unwrap_err();
+        assert!(format!("{:#}", err).contains("signature"));
+
+        //without CRLs the client CA isn't needed
+        CrlSet::load(&[], &dir.join("missing.pem")).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
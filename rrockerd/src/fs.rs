@@ -1,24 +1,72 @@
 use anyhow::{Context, Result};
 use nix::{
     mount::{self, MntFlags, MsFlags},
+    sys::statvfs::{statvfs, FsFlags},
     unistd,
 };
-use std::path::{Path, PathBuf};
+use std::{
+    fs::File,
+    os::unix::{fs::symlink, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+/// Device nodes of /dev, bound from the host as a user namespace can't create any
+const DEVICES: &[&str] = &["full", "null", "random", "tty", "urandom", "zero"];
+/// Links of /dev into /proc/self
+const DEV_LINKS: &[(&str, &str)] = &[
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
 
-pub(crate) fn remount_private() -> Result<()> {
+/// Make every mount a slave, so mounts made by the task never propagate to the host while
+/// bind mounts of the host's shared mounts can still receive the host's mounts below them
+pub(crate) fn remount_slave() -> Result<()> {
     nix::mount::mount(
         Option::<&str>::None,
         "/",
         Option::<&str>::None,
-        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        MsFlags::MS_REC | MsFlags::MS_SLAVE,
         Option::<&str>::None,
     )
-    .context("Failed to remount privately")
+    .context("Failed to remount as slave")
+}
+
+/// Cover `root` with a tmpfs holding a writable overlay of it, so changes to the task's root
+/// never reach the directory it's started from. Returns the root the task pivots into.
+pub(crate) fn mount_layer(root: &Path, size_bytes: u64) -> Result<PathBuf> {
+    //the overlay reaches the covered root through the fd, which also keeps odd characters
+    //of the path out of the mount options
+    let lower = File::open(root).context(format!("Failed to open '{:?}'", root))?;
+    mount_tmpfs(root, MsFlags::MS_NOSUID, &format!("size={}", size_bytes))?;
+    for dir in &["upper", "work", "merged"] {
+        std::fs::create_dir(root.join(dir)).context(format!("Failed to create '{}' dir", dir))?;
+    }
+
+    let merged = root.join("merged");
+    let options = format!(
+        "lowerdir=/proc/self/fd/{},upperdir=upper,workdir=work",
+        lower.as_raw_fd()
+    );
+    //the upper and work dirs are resolved relative to the tmpfs
+    unistd::chdir(root).context(format!("Failed to change dir to '{:?}'", root))?;
+    mount::mount(
+        Some("overlay"),
+        &merged,
+        Some("overlay"),
+        MsFlags::MS_NOSUID,
+        Some(options.as_str()),
+    )
+    .context(format!("Failed to mount the overlay of '{:?}'", root))?;
+    Ok(merged)
 }
 
-pub(crate) fn mount_proc() -> Result<()> {
+/// Mount a proc under `root`. Must be called before pivoting as the kernel only allows
+/// a user namespace to mount proc or sysfs while the daemon's are visible.
+pub(crate) fn mount_proc(root: &Path) -> Result<()> {
     const NAME: Option<&'static str> = Some("proc");
-    let path = PathBuf::from("/proc");
+    let path = root.join("proc");
 
     if !path.exists() {
         std::fs::create_dir(&path).context("Failed to create /proc dir")?
@@ -57,23 +105,47 @@ pub(crate) fn pivot_root(root: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Bind mount `src` on `dst` along with the mounts below it, a missing `dst` is created
+/// as a file or a dir depending on `src`
+pub(crate) fn bind_mount(src: &Path, dst: &Path) -> Result<()> {
+    if !dst.exists() && src.is_file() {
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create '{:?}' dir", parent))?;
+        }
+        File::create(dst).context(format!("Failed to create '{:?}'", dst))?;
+    } else if !dst.exists() {
+        std::fs::create_dir_all(dst).context(format!("Failed to create '{:?}' dir", dst))?;
+    }
+
+    mount::mount(
+        Some(src),
+        dst,
+        Option::<&str>::None,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        Option::<&str>::None,
+    )
+    .context(format!("Failed to bind mount '{:?}' on '{:?}'", src, dst))
+}
+
 #[allow(dead_code)]
 pub(crate) fn unmount_all() -> Result<()> {
     mount::umount2("/", MntFlags::MNT_DETACH).context("Failed to unmount /")
 }
 
-pub(crate) fn mount_sysfs() -> Result<()> {
-    let p = Path::new("/sys");
+/// Mount a read-only sysfs under `root`, like `mount_proc` before pivoting
+pub(crate) fn mount_sysfs(root: &Path) -> Result<()> {
+    let p = &root.join("sys");
 
     if !p.exists() {
-        std::fs::create_dir_all(&p).context("Failed to create '/sys' path")?;
+        std::fs::create_dir_all(p).context("Failed to create '/sys' path")?;
     }
 
     mount::mount(
         Option::<&str>::None,
         p,
         Some("sysfs"),
-        MsFlags::empty(),
+        MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
         Option::<&str>::None,
     )
     .context("Failed to mount sysfs")?;
@@ -81,6 +153,137 @@ pub(crate) fn mount_sysfs() -> Result<()> {
     Ok(())
 }
 
+pub(crate) fn mount_tmpfs(path: &Path, flags: MsFlags, options: &str) -> Result<()> {
+    if !path.exists() {
+        std::fs::create_dir_all(path).context(format!("Failed to create '{:?}' dir", path))?;
+    }
+
+    mount::mount(Some("tmpfs"), path, Some("tmpfs"), flags, Some(options))
+        .context(format!("Failed to mount tmpfs on '{:?}'", path))
+}
+
+/// Build a minimal /dev under `root`: a tmpfs holding the safe device nodes of the host,
+/// a private devpts instance and a tmpfs for shared memory.
+/// Must be called before pivoting as the device nodes are bound from the host's /dev.
+pub(crate) fn mount_dev(root: &Path) -> Result<()> {
+    let dev = root.join("dev");
+    mount_tmpfs(
+        &dev,
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        "mode=755,size=64k",
+    )?;
+
+    for name in DEVICES {
+        let node = dev.join(name);
+        File::create(&node).context(format!("Failed to create '{:?}'", node))?;
+        mount::mount(
+            Some(&Path::new("/dev").join(name)),
+            &node,
+            Option::<&str>::None,
+            MsFlags::MS_BIND,
+            Option::<&str>::None,
+        )
+        .context(format!("Failed to bind mount /dev/{}", name))?;
+    }
+
+    let pts = dev.join("pts");
+    std::fs::create_dir(&pts).context("Failed to create /dev/pts dir")?;
+    mount::mount(
+        Some("devpts"),
+        &pts,
+        Some("devpts"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("newinstance,ptmxmode=0666,mode=0620"),
+    )
+    .context("Failed to mount devpts")?;
+    symlink("pts/ptmx", dev.join("ptmx")).context("Failed to link /dev/ptmx")?;
+
+    mount_tmpfs(
+        &dev.join("shm"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        "mode=1777,size=64m",
+    )?;
+
+    for (name, target) in DEV_LINKS {
+        symlink(target, dev.join(name)).context(format!("Failed to link /dev/{}", name))?;
+    }
+    Ok(())
+}
+
+/// Remount the mount at `path` read-only
+pub(crate) fn remount_readonly(path: &Path) -> Result<()> {
+    remount_bind(path, MsFlags::MS_RDONLY)
+}
+
+/// Add `flags` to the bind mount at `path`. The mount keeps its other flags as the kernel
+/// refuses to clear the ones locked by the user namespace it was inherited from.
+pub(crate) fn remount_bind(path: &Path, flags: MsFlags) -> Result<()> {
+    const KEPT: &[(FsFlags, MsFlags)] = &[
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ];
+    let current = statvfs(path)
+        .context(format!("Failed to stat '{:?}'", path))?
+        .flags();
+    let flags = KEPT.iter().filter(|(st, _)| current.contains(*st)).fold(
+        flags | MsFlags::MS_REMOUNT | MsFlags::MS_BIND,
+        |flags, (_, ms)| flags | *ms,
+    );
+
+    mount::mount(
+        Option::<&str>::None,
+        path,
+        Option::<&str>::None,
+        flags,
+        Option::<&str>::None,
+    )
+    .context(format!("Failed to remount '{:?}'", path))
+}
+
+/// Set the propagation type of the mount at `path` and the mounts below it
+pub(crate) fn set_propagation(path: &Path, propagation: MsFlags) -> Result<()> {
+    mount::mount(
+        Option::<&str>::None,
+        path,
+        Option::<&str>::None,
+        propagation | MsFlags::MS_REC,
+        Option::<&str>::None,
+    )
+    .context(format!("Failed to set the propagation of '{:?}'", path))
+}
+
+/// Make `path` read-only by binding it on itself, paths that don't exist are skipped
+pub(crate) fn readonly_path(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    bind_mount(path, path)?;
+    remount_readonly(path)
+}
+
+/// Hide `path` behind an empty read-only tmpfs or /dev/null if it's a file,
+/// paths that don't exist are skipped
+pub(crate) fn mask_path(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        mount_tmpfs(path, MsFlags::MS_RDONLY, "size=0")
+    } else if path.exists() {
+        mount::mount(
+            Some("/dev/null"),
+            path,
+            Option::<&str>::None,
+            MsFlags::MS_BIND,
+            Option::<&str>::None,
+        )
+        .context(format!("Failed to mask '{:?}'", path))
+    } else {
+        Ok(())
+    }
+}
+
 #[allow(dead_code)]
 pub(crate) fn mount_cgroups() -> Result<()> {
     mount::mount(
@@ -0,0 +1,388 @@
+use crate::oci;
+use anyhow::{Context, Result};
+use futures::{Stream, StreamExt};
+use rrocker_lib::api::ImportImageRequest;
+use serde::Deserialize;
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncWriteExt;
+use tonic::Status;
+use uuid::Uuid;
+
+/// Root of tasks that don't name an image when no default image is configured
+pub const BASE_ROOT: &str = "/var/rrocker-root/";
+/// Imported images by digest, the dot hides it from the images
+const STORE: &str = ".store";
+
+/// Where the daemon keeps the root filesystems tasks are started from
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageConfig {
+    /// Holds each image's root filesystem as `<dir>/<name>/rootfs`
+    pub dir: PathBuf,
+    /// Image of tasks that don't name one, unset starts them from `BASE_ROOT`
+    pub default_image: Option<String>,
+    /// Largest image archive that can be imported
+    pub max_import_bytes: u64,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            dir: "/var/lib/rrocker/images".into(),
+            default_image: None,
+            max_import_bytes: 16 << 30,
+        }
+    }
+}
+
+/// A root filesystem tasks may be started from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    /// Empty for `BASE_ROOT`
+    pub name: String,
+    pub rootfs: PathBuf,
+    /// Defaults of the tasks started from it, read from the `config.json` next to the rootfs
+    pub config: oci::RunConfig,
+}
+
+/// The images of the images dir. It's read on every lookup so images can be added and
+/// removed without restarting the daemon.
+#[derive(Debug, Clone, Default)]
+pub struct Images {
+    config: ImageConfig,
+}
+
+/// Names are used as a path component so they can't contain separators or start with a dot
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 128
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | ':'))
+}
+
+impl Images {
+    pub fn new(config: &ImageConfig) -> Result<Self> {
+        if let Some(name) = &config.default_image {
+            if !valid_name(name) {
+                anyhow::bail!("Invalid default image name '{}'", name);
+            }
+        }
+        Ok(Self {
+            config: config.clone(),
+        })
+    }
+
+    pub fn default_image(&self) -> &str {
+        self.config.default_image.as_deref().unwrap_or_default()
+    }
+
+    /// The image a task requested, empty selects the default image or `BASE_ROOT`
+    /// when there's none
+    pub fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        match name {
+            "" => self.default_image(),
+            name => name,
+        }
+    }
+
+    /// Look up a resolved image
+    pub fn get(&self, name: &str) -> Result<Image, Status> {
+        if name.is_empty() {
+            return Ok(Image {
+                name: String::new(),
+                rootfs: BASE_ROOT.into(),
+                config: Default::default(),
+            });
+        }
+        if !valid_name(name) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid image name '{}'",
+                name
+            )));
+        }
+        //resolved so tasks keep their rootfs when the image is replaced by an import
+        let rootfs = match self.config.dir.join(name).join("rootfs").canonicalize() {
+            Ok(rootfs) if rootfs.is_dir() => rootfs,
+            _ => return Err(Status::not_found(format!("Image '{}' not found", name))),
+        };
+        let config = oci::run_config(rootfs.parent().unwrap_or(&rootfs)).map_err(|e| {
+            tracing::error!("Failed to read the config of image '{}': {:?}", name, e);
+            Status::internal(format!("Image '{}' has an invalid config", name))
+        })?;
+        Ok(Image {
+            name: name.to_owned(),
+            rootfs,
+            config,
+        })
+    }
+
+    /// Receive an image archive and register it under the name of the first message.
+    /// Returns the image and its digest.
+    pub async fn import<S>(&self, mut stream: S) -> Result<(Image, String), Status>
+    where
+        S: Stream<Item = Result<ImportImageRequest, Status>> + Unpin,
+    {
+        let first = stream
+            .next()
+            .await
+            .ok_or_else(|| Status::invalid_argument("The image archive is empty"))??;
+        let name = first.name;
+        if !valid_name(&name) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid image name '{}'",
+                name
+            )));
+        }
+        if fs::symlink_metadata(self.config.dir.join(&name))
+            .is_ok_and(|meta| !meta.file_type().is_symlink())
+        {
+            return Err(Status::already_exists(format!(
+                "Image '{}' wasn't imported and can't be replaced",
+                name
+            )));
+        }
+
+        let store = self.config.dir.join(STORE);
+        let tmp = store.join("tmp");
+        tokio::fs::create_dir_all(&tmp)
+            .await
+            .map_err(internal("Failed to create the image store"))?;
+        let archive = tmp.join(format!("{}.tar", Uuid::new_v4()));
+        let chunk = first.chunk;
+        let imported: Result<PathBuf, Status> = async {
+            self.receive(chunk, stream, &archive).await?;
+            let (archive, store) = (archive.clone(), store.clone());
+            tokio::task::spawn_blocking(move || oci::import(&archive, &store))
+                .await
+                .map_err(|e| Status::internal(format!("The import failed: {}", e)))?
+                .map_err(|e| {
+                    Status::invalid_argument(format!("Failed to import the image: {:#}", e))
+                })
+        }
+        .await;
+        if let Err(e) = tokio::fs::remove_file(&archive).await {
+            if e.kind() != ErrorKind::NotFound {
+                tracing::warn!("Failed to remove '{:?}': {:?}", archive, e);
+            }
+        }
+
+        let image = imported?;
+        self.register(&name, &image).map_err(|e| {
+            tracing::error!("Failed to register image '{}': {:?}", name, e);
+            Status::internal("Failed to register the image")
+        })?;
+        let digest = format!(
+            "sha256:{}",
+            image.file_name().unwrap_or_default().to_string_lossy()
+        );
+        Ok((self.get(&name)?, digest))
+    }
+
+    /// Write the archive to `path`, starting with the first message's `chunk`
+    async fn receive<S>(&self, chunk: Vec<u8>, mut stream: S, path: &Path) -> Result<(), Status>
+    where
+        S: Stream<Item = Result<ImportImageRequest, Status>> + Unpin,
+    {
+        let mut file = tokio::fs::File::create(path)
+            .await
+            .map_err(internal("Failed to create the image archive"))?;
+        let mut chunk = chunk;
+        let mut size = 0;
+        loop {
+            size += chunk.len() as u64;
+            if size > self.config.max_import_bytes {
+                return Err(Status::invalid_argument(format!(
+                    "The image archive exceeds the limit of {} bytes",
+                    self.config.max_import_bytes
+                )));
+            }
+            file.write_all(&chunk)
+                .await
+                .map_err(internal("Failed to write the image archive"))?;
+            chunk = match stream.next().await {
+                Some(message) => message?.chunk,
+                None => break,
+            };
+        }
+        file.flush()
+            .await
+            .map_err(internal("Failed to write the image archive"))
+    }
+
+    /// Point `name` at an imported image, replacing the image it pointed at
+    fn register(&self, name: &str, image: &Path) -> Result<()> {
+        let digest = image.file_name().context("Invalid image dir")?;
+        let target = Path::new(STORE).join("images").join(digest);
+        let tmp = self
+            .config
+            .dir
+            .join(format!(".{}.{}", name, Uuid::new_v4()));
+        std::os::unix::fs::symlink(&target, &tmp).context(format!("Failed to link '{:?}'", tmp))?;
+        //renaming replaces the link atomically so the image never goes missing
+        fs::rename(&tmp, self.config.dir.join(name)).or_else(|e| {
+            let _ = fs::remove_file(&tmp);
+            Err(e).context(format!("Failed to register image '{}'", name))
+        })
+    }
+
+    /// All images sorted by name, a missing images dir has none
+    pub fn list(&self) -> Result<Vec<Image>> {
+        let dir = &self.config.dir;
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context(format!("Failed to read '{:?}'", dir)),
+        };
+
+        let mut images = Vec::new();
+        for entry in entries {
+            let entry = entry.context(format!("Failed to read '{:?}'", dir))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Ok(image) = self.get(&name) {
+                images.push(image);
+            }
+        }
+        images.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(images)
+    }
+}
+
+fn internal(message: &'static str) -> impl FnOnce(io::Error) -> Status {
+    move |e| {
+        tracing::error!("{}: {:?}", message, e);
+        Status::internal(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_images() {
+        let dir = std::env::temp_dir().join(format!("rrocker-images-{}", std::process::id()));
+        for image in ["debian:11", "alpine"] {
+            std::fs::create_dir_all(dir.join(image).join("rootfs")).unwrap();
+        }
+        //neither are images
+        std::fs::create_dir_all(dir.join("partial")).unwrap();
+        std::fs::create_dir_all(dir.join(".hidden/rootfs")).unwrap();
+
+        let images = Images::new(&ImageConfig {
+            dir: dir.clone(),
+            default_image: Some("alpine".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        let names = images
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|i| i.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["alpine", "debian:11"]);
+
+        assert_eq!(images.resolve(""), "alpine");
+        assert_eq!(images.resolve("debian:11"), "debian:11");
+        let debian = images.get("debian:11").unwrap();
+        assert_eq!(debian.rootfs, dir.join("debian:11/rootfs"));
+        assert_eq!(
+            images.get("partial").unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+        for name in ["../alpine", ".hidden", "alpine/rootfs"] {
+            assert_eq!(
+                images.get(name).unwrap_err().code(),
+                tonic::Code::InvalidArgument
+            );
+        }
+
+        let base = Images::default();
+        assert_eq!(base.resolve(""), "");
+        assert_eq!(base.get("").unwrap().rootfs, PathBuf::from(BASE_ROOT));
+        assert!(Images::new(&ImageConfig {
+            default_image: Some("../etc".into()),
+            ..Default::default()
+        })
+        .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(images.list().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        use crate::oci::test::{docker_save, Node};
+        let dir = std::env::temp_dir().join(format!("rrocker-import-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("manual/rootfs")).unwrap();
+        let images = Images::new(&ImageConfig {
+            dir: dir.clone(),
+            max_import_bytes: 1 << 20,
+            ..Default::default()
+        })
+        .unwrap();
+        let import = |name: &str, archive: &[u8]| {
+            let messages = archive
+                .chunks(1000)
+                .enumerate()
+                .map(|(i, chunk)| {
+                    Ok(ImportImageRequest {
+                        name: if i == 0 { name.into() } else { String::new() },
+                        chunk: chunk.to_vec(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            images.import(futures::stream::iter(messages))
+        };
+
+        let (v1, digest) = docker_save(&[Node::File("version", b"1")]);
+        let (image, imported) = import("app", &v1).await.unwrap();
+        assert_eq!(imported, digest);
+        assert_eq!(std::fs::read(image.rootfs.join("version")).unwrap(), b"1");
+        assert_eq!(image.config.cmd, Some(vec!["sh".to_owned()]));
+
+        //replacing an image leaves the rootfs of running tasks in place
+        let (v2, _) = docker_save(&[Node::File("version", b"2")]);
+        let (replaced, _) = import("app", &v2).await.unwrap();
+        assert_eq!(
+            std::fs::read(replaced.rootfs.join("version")).unwrap(),
+            b"2"
+        );
+        assert!(image.rootfs.is_dir());
+        let names = images.list().unwrap().into_iter().map(|i| i.name);
+        assert_eq!(names.collect::<Vec<_>>(), vec!["app", "manual"]);
+
+        let code = |result: Result<(Image, String), Status>| result.unwrap_err().code();
+        assert_eq!(
+            code(import("manual", &v1).await),
+            tonic::Code::AlreadyExists
+        );
+        assert_eq!(
+            code(import("../app", &v1).await),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            code(import("junk", b"junk").await),
+            tonic::Code::InvalidArgument
+        );
+        let huge = vec![0; (1 << 20) + 1];
+        assert_eq!(
+            code(import("huge", &huge).await),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            std::fs::read_dir(dir.join(STORE).join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,41 +1,344 @@
-use std::path::Path;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use crate::{
+    capabilities,
+    cgroup::Cgroup,
     clone_context::{CloneContext, ResultReader},
-    fs, user,
+    fs,
+    images::BASE_ROOT,
+    mounts::MountSpec,
+    namespaces::{Join, Namespace, Namespaces},
+    pipe::Pipe,
+    seccomp::Filter,
+    user::{self, IdMapping},
 };
 use anyhow::{Context, Result};
+use caps::CapsHashSet;
 use nix::unistd::{Gid, Pid, Uid};
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Paths of /proc and /sys hidden from tasks, same as runc's default maskedPaths
+const MASKED_PATHS: &[&str] = &[
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/sys/devices/virtual/powercap",
+    "/sys/firmware",
+];
+/// Paths of /proc tasks can only read, same as runc's default readonlyPaths
+const READONLY_PATHS: &[&str] = &[
+    "/proc/bus",
+    "/proc/fs",
+    "/proc/irq",
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+];
+
+/// Default size of the writable layer covering a task's root
+const ROOT_LAYER_SIZE_BYTES: u64 = 256 << 20;
+
+/// A writable tmpfs of tasks with a read-only root
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScratchPath {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+}
+
+/// Privileges tasks keep inside their user namespace. The defaults are least privilege.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IsolationConfig {
+    /// Capabilities tasks keep, e.g. `CAP_NET_BIND_SERVICE`, all others are dropped
+    pub capabilities: Vec<String>,
+    /// Set PR_SET_NO_NEW_PRIVS so setuid binaries and file capabilities can't regain privileges
+    pub no_new_privs: bool,
+    /// The uid tasks run as inside their user namespace
+    pub uid: u32,
+    /// The gid tasks run as inside their user namespace
+    pub gid: u32,
+    /// Give tasks their own time namespace too, which needs Linux 5.6
+    pub time_namespace: bool,
+    /// Paths hidden behind an empty tmpfs or /dev/null, replaces the defaults
+    pub masked_paths: Vec<PathBuf>,
+    /// Paths mounted read-only, replaces the defaults. /sys is always read-only.
+    pub readonly_paths: Vec<PathBuf>,
+    /// Writable tmpfs mounts of tasks with a read-only root, replaces the defaults
+    pub scratch_paths: Vec<ScratchPath>,
+    /// Size of the tmpfs holding a task's changes to its root, which are discarded once it exits
+    pub root_layer_size_bytes: u64,
+}
+
+impl Default for IsolationConfig {
+    fn default() -> Self {
+        Self {
+            capabilities: Vec::new(),
+            no_new_privs: true,
+            uid: 0,
+            gid: 0,
+            time_namespace: false,
+            masked_paths: MASKED_PATHS.iter().map(PathBuf::from).collect(),
+            readonly_paths: READONLY_PATHS.iter().map(PathBuf::from).collect(),
+            scratch_paths: vec![
+                ScratchPath {
+                    path: "/tmp".into(),
+                    size_bytes: 64 << 20,
+                },
+                ScratchPath {
+                    path: "/run".into(),
+                    size_bytes: 16 << 20,
+                },
+            ],
+            root_layer_size_bytes: ROOT_LAYER_SIZE_BYTES,
+        }
+    }
+}
+
+/// How a task is confined beyond its namespaces, decided when it's started
+#[derive(Debug, Clone)]
+pub struct Isolation {
+    /// The root filesystem of the task's image, shared with other tasks so it's never written
+    pub root: PathBuf,
+    /// Size of the writable layer covering `root`, which is what the task pivots into
+    pub root_layer_size_bytes: u64,
+    /// Installed right before the task's code runs, `None` is unconfined
+    pub seccomp: Option<Arc<Filter>>,
+    /// The capabilities left in every set, including the bounding set
+    pub capabilities: CapsHashSet,
+    pub no_new_privs: bool,
+    pub uid: Uid,
+    pub gid: Gid,
+    /// Subordinate IDs written by the daemon once the task is cloned, `None` maps the task's
+    /// uid and gid to the daemon's
+    pub id_mapping: Option<IdMapping>,
+    pub namespaces: Namespaces,
+    pub masked_paths: Vec<PathBuf>,
+    pub readonly_paths: Vec<PathBuf>,
+    /// Mounts requested by the client, applied after /dev
+    pub mounts: Vec<MountSpec>,
+    pub read_only_root: bool,
+    /// Mounted below the client's mounts when the root is read-only
+    pub scratch: Vec<MountSpec>,
+}
+
+impl Isolation {
+    pub fn from_config(config: &IsolationConfig) -> Result<Self> {
+        Ok(Self {
+            root: BASE_ROOT.into(),
+            root_layer_size_bytes: config.root_layer_size_bytes,
+            seccomp: None,
+            capabilities: capabilities::parse(&config.capabilities)?,
+            no_new_privs: config.no_new_privs,
+            uid: Uid::from_raw(config.uid),
+            gid: Gid::from_raw(config.gid),
+            id_mapping: None,
+            namespaces: {
+                let mut namespaces = Namespaces::default();
+                if config.time_namespace {
+                    namespaces.new.insert(Namespace::Time);
+                }
+                namespaces
+            },
+            masked_paths: config.masked_paths.clone(),
+            readonly_paths: config.readonly_paths.clone(),
+            mounts: Vec::new(),
+            read_only_root: false,
+            scratch: config
+                .scratch_paths
+                .iter()
+                .map(|s| {
+                    MountSpec::tmpfs(&s.path, s.size_bytes)
+                        .context(format!("Invalid scratch path '{:?}'", s.path))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Pivot into a writable layer over `root` and mount the task's /proc, /sys and /dev in it.
+    /// proc and sysfs can only be mounted for namespaces owned by the task's user
+    /// namespace, the daemon's are bound instead.
+    fn prepare_root(&self, root: &Path) -> Result<()> {
+        let own_pid = self.namespaces.new.contains(&Namespace::Pid);
+        let own_net = self.namespaces.is_isolated(Namespace::Net);
+        fs::remount_slave()?;
+        //mount points and anything else written below end up in the layer
+        let root = &fs::mount_layer(root, self.root_layer_size_bytes)
+            .context("Failed to mount the root's writable layer")?;
+        if own_pid {
+            fs::mount_proc(root).context("Failed to mount proc")?;
+        } else {
+            fs::bind_mount(Path::new("/proc"), &root.join("proc"))?;
+        }
+        if own_net {
+            fs::mount_sysfs(root).context("Failed to mount sysfs")?;
+        } else {
+            fs::bind_mount(Path::new("/sys"), &root.join("sys"))?;
+            fs::remount_readonly(&root.join("sys"))?;
+        }
+        fs::mount_dev(root).context("Failed to mount /dev")?;
+        let scratch = match self.read_only_root {
+            true => self.scratch.as_slice(),
+            false => &[],
+        };
+        for mount in scratch.iter().chain(&self.mounts) {
+            mount
+                .apply(root)
+                .context(format!("Failed to mount '{:?}'", mount.target))?;
+        }
+        fs::pivot_root(root).context("Failed to pivot root")?;
+        //fs::mount_cgroups().context("Failed to mount cgroup")?;
+        for path in &self.masked_paths {
+            fs::mask_path(path)?;
+        }
+        for path in &self.readonly_paths {
+            fs::readonly_path(path)?;
+        }
+        if self.read_only_root {
+            //only the root's own mount, the ones on top of it keep their flags
+            fs::remount_readonly(Path::new("/")).context("Failed to remount / read-only")?;
+        }
+        Ok(())
+    }
+
+    /// Drop the privileges and install the seccomp filter, in an order that works whether
+    /// or not no_new_privs is set as installing a filter needs either it or CAP_SYS_ADMIN
+    fn confine(&self) -> Result<()> {
+        if !self.no_new_privs {
+            self.apply_seccomp()?;
+        }
+        capabilities::restrict(&self.capabilities)?;
+        if self.no_new_privs {
+            capabilities::set_no_new_privs()?;
+            self.apply_seccomp()?;
+        }
+        Ok(())
+    }
+
+    fn apply_seccomp(&self) -> Result<()> {
+        match &self.seccomp {
+            Some(filter) => filter.apply(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for Isolation {
+    fn default() -> Self {
+        Self {
+            root: BASE_ROOT.into(),
+            root_layer_size_bytes: ROOT_LAYER_SIZE_BYTES,
+            seccomp: None,
+            capabilities: CapsHashSet::new(),
+            no_new_privs: true,
+            uid: ROOT_UID,
+            gid: ROOT_GID,
+            id_mapping: None,
+            namespaces: Namespaces::default(),
+            masked_paths: MASKED_PATHS.iter().map(PathBuf::from).collect(),
+            readonly_paths: READONLY_PATHS.iter().map(PathBuf::from).collect(),
+            mounts: Vec::new(),
+            read_only_root: false,
+            scratch: Vec::new(),
+        }
+    }
+}
+
 pub struct IsolatedProcess<'a, T: Serialize + DeserializeOwned + Send> {
     ctx: CloneContext<'a, T>,
+    id_mapping: Option<IdMapping>,
+    /// The cgroup the child is moved into before it does anything
+    cgroup: Option<Cgroup>,
+    /// Tells the child its maps are written and it's in its cgroup
+    ready: File,
+    namespaces: Namespaces,
 }
 
 const ROOT_UID: Uid = Uid::from_raw(0);
 const ROOT_GID: Gid = Gid::from_raw(0);
 
 impl<'a, T: Serialize + DeserializeOwned + Send> IsolatedProcess<'a, T> {
-    pub fn new<F: 'a + FnMut() -> Result<T>>(mut func: F) -> Result<Self> {
+    pub fn new<F: 'a + FnMut() -> Result<T>>(isolation: Isolation, mut func: F) -> Result<Self> {
         let gid = Gid::current();
         let uid = Uid::current();
+        let (wait_ready, ready) = Pipe::new()?.split();
+        let namespaces = isolation.namespaces.clone();
         Ok(Self {
+            id_mapping: isolation.id_mapping.clone(),
+            cgroup: None,
+            ready,
             ctx: CloneContext::new(move || -> Result<T> {
-                fs::remount_private().context("Failed to remount privately")?;
-                fs::pivot_root(Path::new("/var/rrocker-root/")).context("Failed to pivot root")?;
-                fs::mount_proc().context("Failed to mount proc")?;
-                fs::mount_sysfs().context("Failed to mount sysfs")?;
-                //fs::mount_cgroups().context("Failed to mount cgroup")?;
-                user::write_gid_map(ROOT_GID, gid, 1).context("Failed to write gid map")?;
-                user::write_uid_map(ROOT_UID, uid, 1).context("Failed to write uid map")?;
+                //first as files created in the new mounts must be owned by a mapped id
+                if isolation.namespaces.peer.is_some() || isolation.id_mapping.is_some() {
+                    //the peer's user namespace or the daemon mapped the ids already
+                    user::switch_ids(isolation.uid, isolation.gid)?;
+                } else {
+                    user::write_gid_map(isolation.gid, gid, 1)
+                        .context("Failed to write gid map")?;
+                    user::write_uid_map(isolation.uid, uid, 1)
+                        .context("Failed to write uid map")?;
+                }
+                isolation.prepare_root(&isolation.root)?;
+                //last as the setup above needs the capabilities and syscalls it takes away
+                isolation.confine().context("Failed to confine")?;
 
                 func()
             })
-            .context("Failed to create ctx of IsolatedProcess")?,
+            .context("Failed to create ctx of IsolatedProcess")?
+            .namespaces(namespaces.new.clone(), None)
+            .wait_ready(wait_ready),
+            namespaces,
         })
     }
 
+    /// Enter the namespaces shared with the task's peer, which runs as `pid`
+    pub fn join(mut self, pid: Pid) -> Result<Self> {
+        let peer = self
+            .namespaces
+            .peer
+            .as_ref()
+            .context("The task doesn't share any namespaces")?;
+        let join = Join::open(pid, &peer.namespaces)?;
+        self.ctx = self.ctx.namespaces(self.namespaces.new.clone(), Some(join));
+        Ok(self)
+    }
+
+    /// Run the child in `cgroup`, which must exist
+    pub fn cgroup(mut self, cgroup: Cgroup) -> Self {
+        self.cgroup = Some(cgroup);
+        self
+    }
+
     pub fn execute(self) -> Result<(Pid, ResultReader<T>)> {
-        self.ctx.execute()
+        let Self {
+            ctx,
+            id_mapping,
+            cgroup,
+            mut ready,
+            ..
+        } = self;
+        let (pid, reader) = ctx.execute()?;
+        let prepared = id_mapping
+            .map_or(Ok(()), |mapping| mapping.apply(pid))
+            .and_then(|_| cgroup.map_or(Ok(()), |cgroup| cgroup.add(pid)))
+            .and_then(|_| Ok(ready.write_all(&[0])?));
+        if let Err(e) = prepared {
+            //the child holds a copy of the pipe so it wouldn't notice us giving up
+            let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL);
+            let _ = nix::sys::wait::waitpid(pid, None);
+            return Err(e.context("Failed to prepare the child"));
+        }
+        Ok((pid, reader))
     }
 }
 
@@ -43,17 +346,24 @@ impl<'a, T: Serialize + DeserializeOwned + Send> IsolatedProcess<'a, T> {
 /// All these tests must be run with root (SYS_CAP_ADMIN)
 mod test {
     use super::*;
+    use crate::mounts::MountSource;
+    use anyhow::ensure;
+    use nix::{
+        mount::MsFlags,
+        sys::statvfs::{statvfs, FsFlags},
+    };
+    use std::io::Read;
 
     #[test]
     #[ignore]
     fn is_pid_isolated() {
         use sysinfo::{System, SystemExt};
 
-        let cc = IsolatedProcess::new(|| -> Result<Vec<i32>> {
+        let cc = IsolatedProcess::new(Isolation::default(), || -> Result<Vec<i32>> {
             let mut sys = System::new();
             sys.refresh_processes();
 
-            Ok(sys.processes().iter().map(|(k, _p)| *k).collect())
+            Ok(sys.processes().keys().copied().collect())
         })
         .unwrap();
 
@@ -61,7 +371,7 @@ mod test {
 
         match rr.get_result() {
             Ok(pids) => assert_eq!(pids, vec![1i32]), //ensure we can only see ourselves the init pid
-            Err(e) => assert!(false, "{:?}", e),
+            Err(e) => panic!("{:?}", e),
         }
     }
 
@@ -70,7 +380,7 @@ mod test {
     fn is_net_isolated() {
         use sysinfo::{System, SystemExt};
 
-        let cc = IsolatedProcess::new(|| -> Result<Vec<String>> {
+        let cc = IsolatedProcess::new(Isolation::default(), || -> Result<Vec<String>> {
             let mut sys = System::new();
             sys.refresh_networks_list();
 
@@ -86,7 +396,7 @@ mod test {
 
         match rr.get_result() {
             Ok(network_names) => assert!(network_names.is_empty()), //ensure no network access
-            Err(e) => assert!(false, "{:?}", e),
+            Err(e) => panic!("{:?}", e),
         }
     }
 
@@ -95,13 +405,13 @@ mod test {
     fn is_disk_isolated() {
         use sysinfo::{DiskExt, System, SystemExt};
 
-        let cc = IsolatedProcess::new(|| -> Result<Vec<String>> {
+        let cc = IsolatedProcess::new(Isolation::default(), || -> Result<Vec<String>> {
             let mut sys = System::new();
             sys.refresh_disks();
 
             Ok(sys
                 .disks()
-                .into_iter()
+                .iter()
                 .flat_map(|d| d.name().to_owned().into_string())
                 .collect())
         })
@@ -111,7 +421,263 @@ mod test {
 
         match rr.get_result() {
             Ok(disk_names) => assert!(disk_names.is_empty()), //ensure no network access
-            Err(e) => assert!(false, "{:?}", e),
+            Err(e) => panic!("{:?}", e),
         }
     }
+
+    #[test]
+    #[ignore]
+    fn is_root_fs_minimal() {
+        let root = std::env::temp_dir().join(format!("rrocker-root-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let (uid, gid) = (Uid::current(), Gid::current());
+
+        let mut host_net = Isolation::default();
+        host_net.namespaces.new.remove(&Namespace::Net);
+        for isolation in [Isolation::default(), host_net] {
+            let cc = CloneContext::new(|| -> Result<Vec<String>> {
+                user::write_gid_map(ROOT_GID, gid, 1)?;
+                user::write_uid_map(ROOT_UID, uid, 1)?;
+                isolation.prepare_root(&root)?;
+
+                let mut dev = std::fs::read_dir("/dev")?
+                    .map(|e| Ok(e?.file_name().to_string_lossy().into_owned()))
+                    .collect::<Result<Vec<_>>>()?;
+                dev.sort();
+                std::fs::write("/dev/null", b"discarded")?;
+                ensure!(std::fs::read("/dev/null")?.is_empty());
+                let mut zeros = [1u8; 16];
+                File::open("/dev/zero")?.read_exact(&mut zeros)?;
+                ensure!(zeros == [0u8; 16]);
+                std::fs::write("/dev/shm/segment", b"shared")?;
+                //allocates a pty of the private devpts instance
+                let _ptmx = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open("/dev/ptmx")?;
+                ensure!(Path::new("/dev/pts/0").exists());
+
+                ensure!(statvfs("/sys")?.flags().contains(FsFlags::ST_RDONLY));
+                ensure!(std::fs::read("/proc/timer_list")?.is_empty());
+                ensure!(std::fs::read_dir("/sys/firmware")?.next().is_none());
+                let hostname = std::fs::write("/proc/sys/kernel/hostname", b"escaped");
+                ensure!(hostname.unwrap_err().raw_os_error() == Some(nix::libc::EROFS));
+                Ok(dev)
+            })
+            .unwrap()
+            .namespaces(isolation.namespaces.new.clone(), None);
+
+            let (pid, mut rr) = cc.execute().unwrap();
+            let dev = rr.get_result().unwrap();
+            nix::sys::wait::waitpid(pid, None).unwrap();
+            assert_eq!(
+                dev,
+                vec![
+                    "fd", "full", "null", "ptmx", "pts", "random", "shm", "stderr", "stdin",
+                    "stdout", "tty", "urandom", "zero"
+                ]
+            );
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn are_mounts_applied() {
+        let dir = std::env::temp_dir().join(format!("rrocker-task-mounts-{}", std::process::id()));
+        let (root, input) = (dir.join("root"), dir.join("input"));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&input).unwrap();
+        std::fs::write(input.join("data"), b"input").unwrap();
+        let (uid, gid) = (Uid::current(), Gid::current());
+
+        let isolation = Isolation {
+            mounts: vec![
+                MountSpec {
+                    target: "/input".into(),
+                    source: MountSource::Bind(input.clone()),
+                    read_only: true,
+                    propagation: MsFlags::MS_PRIVATE,
+                },
+                MountSpec {
+                    target: "/scratch".into(),
+                    source: MountSource::Tmpfs {
+                        size_bytes: 1 << 20,
+                    },
+                    read_only: false,
+                    propagation: MsFlags::MS_PRIVATE,
+                },
+            ],
+            ..Default::default()
+        };
+        let cc = CloneContext::new(|| -> Result<()> {
+            user::write_gid_map(ROOT_GID, gid, 1)?;
+            user::write_uid_map(ROOT_UID, uid, 1)?;
+            isolation.prepare_root(&root)?;
+
+            ensure!(std::fs::read("/input/data")? == b"input");
+            let write = std::fs::write("/input/data", b"output");
+            ensure!(write.unwrap_err().raw_os_error() == Some(nix::libc::EROFS));
+            std::fs::write("/scratch/data", b"output")?;
+            let scratch = statvfs("/scratch")?;
+            ensure!(scratch.blocks() * scratch.fragment_size() == 1 << 20);
+            ensure!(scratch
+                .flags()
+                .contains(FsFlags::ST_NOSUID | FsFlags::ST_NODEV));
+            Ok(())
+        })
+        .unwrap()
+        .namespaces(isolation.namespaces.new.clone(), None);
+
+        let (pid, mut rr) = cc.execute().unwrap();
+        rr.get_result().unwrap();
+        nix::sys::wait::waitpid(pid, None).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn is_image_untouched() {
+        let root = std::env::temp_dir().join(format!("rrocker-image-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/hostname"), b"image").unwrap();
+        let (uid, gid) = (Uid::current(), Gid::current());
+
+        let isolation = Isolation {
+            mounts: vec![MountSpec::tmpfs("/scratch/nested", 1 << 20).unwrap()],
+            ..Default::default()
+        };
+        let cc = CloneContext::new(|| -> Result<()> {
+            user::write_gid_map(ROOT_GID, gid, 1)?;
+            user::write_uid_map(ROOT_UID, uid, 1)?;
+            isolation.prepare_root(&root)?;
+
+            ensure!(std::fs::read("/etc/hostname")? == b"image");
+            std::fs::write("/etc/hostname", b"task")?;
+            std::fs::write("/created", b"task")?;
+            ensure!(std::fs::read("/etc/hostname")? == b"task");
+            Ok(())
+        })
+        .unwrap()
+        .namespaces(isolation.namespaces.new.clone(), None);
+
+        let (pid, mut rr) = cc.execute().unwrap();
+        rr.get_result().unwrap();
+        nix::sys::wait::waitpid(pid, None).unwrap();
+
+        //neither the task's writes nor its mount points reach the image
+        let entries = std::fs::read_dir(&root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec!["etc"]);
+        assert_eq!(std::fs::read(root.join("etc/hostname")).unwrap(), b"image");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn is_propagation_set() {
+        let dir = std::env::temp_dir().join(format!("rrocker-propagation-{}", std::process::id()));
+        let (root, host) = (dir.join("root"), dir.join("host"));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&host).unwrap();
+        let (uid, gid) = (Uid::current(), Gid::current());
+
+        let bind = |target: &str, propagation| MountSpec {
+            target: target.into(),
+            source: MountSource::Bind(host.clone()),
+            read_only: false,
+            propagation,
+        };
+        let isolation = Isolation {
+            mounts: vec![
+                bind("/private", MsFlags::MS_PRIVATE),
+                bind("/slave", MsFlags::MS_SLAVE),
+                bind("/shared", MsFlags::MS_SHARED),
+            ],
+            ..Default::default()
+        };
+        let cc = CloneContext::new(|| -> Result<Vec<String>> {
+            user::write_gid_map(ROOT_GID, gid, 1)?;
+            user::write_uid_map(ROOT_UID, uid, 1)?;
+            //stand in for a host mount which is shared, as systemd sets them up
+            fs::mount_tmpfs(&host, MsFlags::empty(), "size=64k")?;
+            fs::set_propagation(&host, MsFlags::MS_SHARED)?;
+            //the host's namespace has to outlive the switch to the task's for the mount to
+            //keep its peer
+            let _host_ns = File::open("/proc/self/ns/mnt")?;
+            nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWNS)?;
+            isolation.prepare_root(&root)?;
+
+            //the optional fields of mountinfo list the peer group and master of a mount
+            let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+            Ok(["/private", "/slave", "/shared"]
+                .iter()
+                .map(|target| {
+                    mountinfo
+                        .lines()
+                        .map(|line| line.split(' ').collect::<Vec<_>>())
+                        .find(|fields| fields[4] == *target)
+                        .map(|fields| {
+                            fields[6..]
+                                .iter()
+                                .take_while(|f| **f != "-")
+                                .map(|f| f.split(':').next().unwrap())
+                                .collect::<Vec<_>>()
+                                .join(" ")
+                        })
+                        .unwrap_or_default()
+                })
+                .collect())
+        })
+        .unwrap()
+        .namespaces(isolation.namespaces.new.clone(), None);
+
+        let (pid, mut rr) = cc.execute().unwrap();
+        let propagation = rr.get_result().unwrap();
+        nix::sys::wait::waitpid(pid, None).unwrap();
+        assert_eq!(propagation, vec!["", "master", "shared master"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn is_root_read_only() {
+        let root = std::env::temp_dir().join(format!("rrocker-ro-root-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let (uid, gid) = (Uid::current(), Gid::current());
+
+        let isolation = Isolation {
+            read_only_root: true,
+            ..Isolation::from_config(&IsolationConfig::default()).unwrap()
+        };
+        let cc = CloneContext::new(|| -> Result<()> {
+            user::write_gid_map(ROOT_GID, gid, 1)?;
+            user::write_uid_map(ROOT_UID, uid, 1)?;
+            isolation.prepare_root(&root)?;
+
+            let write = std::fs::write("/tampered", b"tampered");
+            ensure!(write.unwrap_err().raw_os_error() == Some(nix::libc::EROFS));
+            for path in ["/tmp/scratch", "/run/scratch", "/dev/shm/scratch"] {
+                std::fs::write(path, b"scratch")?;
+            }
+            let tmp = statvfs("/tmp")?;
+            ensure!(tmp.blocks() * tmp.fragment_size() == 64 << 20);
+            Ok(())
+        })
+        .unwrap()
+        .namespaces(isolation.namespaces.new.clone(), None);
+
+        let (pid, mut rr) = cc.execute().unwrap();
+        rr.get_result().unwrap();
+        nix::sys::wait::waitpid(pid, None).unwrap();
+        assert!(!root.join("tampered").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
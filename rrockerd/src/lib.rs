@@ -1,8 +1,33 @@
+//tonic::Status is large but it is the error type of every RPC so there is no way around it
+#![allow(clippy::result_large_err)]
+
+pub mod audit;
 pub mod auth;
+pub mod capabilities;
+pub mod capacity;
+pub mod cgroup;
 pub mod clone_context;
+pub mod config;
+pub mod constraints;
+pub mod crl;
 pub mod fs;
+pub mod images;
 pub mod isolation;
+pub mod local;
 pub mod log;
+pub mod mounts;
+pub mod namespaces;
+pub mod oci;
 pub mod pipe;
+pub mod policy;
+pub mod process;
+pub mod quota;
+pub mod rate_limit;
 pub mod scheduler;
+pub mod seccomp;
+pub mod sharing;
+pub mod subid;
+pub mod syscalls;
+pub mod tls;
 pub mod user;
+pub mod worker;
@@ -0,0 +1,428 @@
+use crate::{
+    audit::AuditContext,
+    auth::{ClientAuth, Principal, LOCAL_PREFIX},
+    policy::{Binding, Policy},
+};
+use anyhow::{anyhow, Context as _, Result};
+use futures::Stream;
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    fmt, io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{UnixListener, UnixStream},
+};
+use tonic::{transport::server::Connected, Request, Status};
+
+/// A local user allowed to connect over the Unix socket
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawLocalUser")]
+pub struct LocalUser {
+    pub uid: u32,
+    /// The principal the user is identified as, defaults to `unix:<uid>`.
+    /// It must start with `unix:` so it can't be mistaken for a cert's CN.
+    pub principal: Option<String>,
+    /// Roles of the policy granted to the user
+    pub roles: Vec<String>,
+}
+
+/// A local user as written in the config, it's validated when converted into a `LocalUser`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLocalUser {
+    uid: u32,
+    principal: Option<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+impl TryFrom<RawLocalUser> for LocalUser {
+    type Error = String;
+
+    fn try_from(raw: RawLocalUser) -> Result<Self, Self::Error> {
+        match &raw.principal {
+            Some(principal) if !principal.starts_with(LOCAL_PREFIX) => {
+                return Err(format!(
+                    "The principal of local user {} must start with '{}', not '{}'",
+                    raw.uid, LOCAL_PREFIX, principal
+                ))
+            }
+            Some(principal) if principal.len() == LOCAL_PREFIX.len() => {
+                return Err(format!("The principal of local user {} is empty", raw.uid))
+            }
+            _ => {}
+        }
+        Ok(Self {
+            uid: raw.uid,
+            principal: raw.principal,
+            roles: raw.roles,
+        })
+    }
+}
+
+/// A local group whose members may connect over the Unix socket
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalGroup {
+    pub gid: u32,
+    /// The group members are counted in, same as a cert's organization (O)
+    pub name: String,
+    /// Roles of the policy granted to the group's members
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// The optional local transport. Clients connecting over the Unix socket are identified
+/// by the uid and gid of their process instead of a certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalConfig {
+    /// Path of the Unix socket, unset disables the local transport
+    pub socket: Option<PathBuf>,
+    /// Permissions of the socket, only users that may connect to it can get any further
+    pub socket_mode: u32,
+    pub users: Vec<LocalUser>,
+    /// Groups are matched against the primary gid of the client's process only
+    pub groups: Vec<LocalGroup>,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        Self {
+            socket: None,
+            socket_mode: 0o660,
+            users: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+fn default_principal(uid: u32) -> String {
+    format!("unix:{}", uid)
+}
+
+impl LocalConfig {
+    fn principal(user: &LocalUser) -> String {
+        user.principal
+            .clone()
+            .unwrap_or_else(|| default_principal(user.uid))
+    }
+
+    /// Policy bindings granting the configured roles to the local users and groups
+    pub fn bindings(&self) -> Vec<Binding> {
+        let users = self.users.iter().flat_map(|user| {
+            user.roles.iter().map(move |role| Binding {
+                role: role.clone(),
+                principals: vec![Self::principal(user)],
+                ..Default::default()
+            })
+        });
+        let groups = self.groups.iter().flat_map(|group| {
+            group.roles.iter().map(move |role| Binding {
+                role: role.clone(),
+                organizations: vec![group.name.clone()],
+                ..Default::default()
+            })
+        });
+        users.chain(groups).collect()
+    }
+
+    /// Identify a local client by its credentials, clients that are neither a
+    /// configured user nor a member of a configured group are rejected
+    pub fn client_auth(&self, cred: &PeerCred) -> Result<ClientAuth, Status> {
+        let user = self.users.iter().find(|u| u.uid == cred.uid);
+        let group = self.groups.iter().find(|g| g.gid == cred.gid);
+        if user.is_none() && group.is_none() {
+            tracing::warn!("Received request from unmapped local user {}", cred);
+            return Err(Status::unauthenticated(
+                "The local user isn't mapped to a principal",
+            ));
+        }
+
+        let id = user.map_or_else(|| default_principal(cred.uid), Self::principal);
+        let groups = group
+            .map(|g| g.name.clone())
+            .into_iter()
+            .collect::<Vec<_>>();
+        Ok(ClientAuth {
+            group: groups.first().cloned().unwrap_or_default(),
+            groups,
+            ..ClientAuth::new(Principal::Local(id))
+        })
+    }
+}
+
+/// Credentials of the process on the other end of the socket as reported by SO_PEERCRED
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+impl fmt::Display for PeerCred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "uid={} gid={}", self.uid, self.gid)?;
+        if let Some(pid) = self.pid {
+            write!(f, " pid={}", pid)?;
+        }
+        Ok(())
+    }
+}
+
+/// A connection to the Unix socket, the peer's credentials are read once when it's
+/// accepted and handed to every request as its connect info
+#[derive(Debug)]
+pub struct LocalStream {
+    stream: UnixStream,
+    cred: PeerCred,
+}
+
+impl LocalStream {
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        let cred = stream.peer_cred()?;
+        Ok(Self {
+            stream,
+            cred: PeerCred {
+                uid: cred.uid(),
+                gid: cred.gid(),
+                pid: cred.pid(),
+            },
+        })
+    }
+}
+
+impl Connected for LocalStream {
+    type ConnectInfo = PeerCred;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.cred
+    }
+}
+
+impl AsyncRead for LocalStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LocalStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Listen on the socket, replacing the socket a previous daemon left behind
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(anyhow!("'{:?}' exists and isn't a socket", path));
+        }
+        std::fs::remove_file(path).context(format!("Failed to remove stale '{:?}'", path))?;
+    }
+
+    let listener = UnixListener::bind(path).context(format!("Failed to listen on '{:?}'", path))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .context(format!("Failed to set the permissions of '{:?}'", path))?;
+    Ok(listener)
+}
+
+/// Accept connections on the socket, connections whose credentials can't be read are dropped
+pub fn incoming(listener: UnixListener) -> impl Stream<Item = io::Result<LocalStream>> {
+    async_stream::stream! {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => LocalStream::new(stream),
+                Err(e) => Err(e),
+            };
+            match stream {
+                Ok(stream) => yield Ok(stream),
+                Err(e) => tracing::warn!("Failed to accept local connection: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Interceptor of the Unix socket, the counterpart of `authorization_interceptor`.
+/// The client must be mapped to a principal and bound to at least one role.
+pub fn local_interceptor(
+    local: Arc<LocalConfig>,
+    policy: Arc<Policy>,
+) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |mut req: Request<()>| {
+        let cred = *req
+            .extensions()
+            .get::<PeerCred>()
+            .ok_or_else(|| Status::unauthenticated("Missing peer credentials"))?;
+        AuditContext::annotate(&req, |e| e.subject = Some(cred.to_string()));
+
+        let auth = local.client_auth(&cred)?;
+        if policy.grants(&auth).roles.is_empty() {
+            tracing::warn!(
+                "Received request from local user {} not bound to any role",
+                cred
+            );
+            return Err(Status::unauthenticated(
+                "The local user isn't bound to any role",
+            ));
+        }
+
+        AuditContext::annotate(&req, |e| e.principal = Some(auth.id.clone()));
+        req.extensions_mut().insert(auth);
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::policy::{Permission, PolicyConfig};
+
+    fn config() -> LocalConfig {
+        toml::from_str(
+            r#"
+            [[users]]
+            uid = 1001
+            principal = "unix:ci-runner"
+            roles = ["client"]
+
+            [[users]]
+            uid = 1002
+
+            [[groups]]
+            gid = 2000
+            name = "ops"
+            roles = ["admin"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn cred(uid: u32, gid: u32) -> PeerCred {
+        PeerCred {
+            uid,
+            gid,
+            pid: None,
+        }
+    }
+
+    #[test]
+    fn test_client_auth() {
+        let local = config();
+        assert_eq!(local.socket_mode, 0o660);
+
+        let auth = local.client_auth(&cred(1001, 1001)).unwrap();
+        assert_eq!(auth.id, "unix:ci-runner");
+        assert!(auth.groups.is_empty());
+
+        let auth = local.client_auth(&cred(1002, 2000)).unwrap();
+        assert_eq!(auth.id, "unix:1002");
+        assert_eq!(auth.group, "ops");
+
+        //members of a configured group don't need their own entry
+        let auth = local.client_auth(&cred(1003, 2000)).unwrap();
+        assert_eq!(auth.id, "unix:1003");
+
+        let status = local.client_auth(&cred(1003, 1003)).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn test_reserved_prefix() {
+        //a principal outside of `unix:` could be claimed by a cert's CN
+        for principal in ["ci-runner", "unix:"] {
+            let config = format!("[[users]]\nuid = 1001\nprincipal = \"{}\"", principal);
+            assert!(toml::from_str::<LocalConfig>(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_bindings() {
+        let local = config();
+        let policy = Policy::new(PolicyConfig::default())
+            .unwrap()
+            .with_local_bindings(local.bindings())
+            .unwrap();
+
+        let grants = |uid, gid| policy.grants(&local.client_auth(&cred(uid, gid)).unwrap());
+        assert_eq!(grants(1001, 1001).roles, vec!["client"]);
+        assert!(!grants(1001, 1001).has(Permission::AdminOps));
+        assert!(grants(1003, 2000).has(Permission::AdminOps));
+        //mapped but without any role
+        assert!(grants(1002, 1002).roles.is_empty());
+
+        //certs can't claim the roles of local users or groups
+        for (cn, org) in [
+            ("unix:ci-runner", "other"),
+            ("unix:1003", "other"),
+            ("x", "ops"),
+        ] {
+            let cert = ClientAuth {
+                common_name: Some(cn.into()),
+                group: org.into(),
+                groups: vec![org.into()],
+                ..ClientAuth::new(Principal::CommonName(cn.into()))
+            };
+            assert!(policy.grants(&cert).roles.is_empty());
+        }
+
+        let missing = LocalConfig {
+            users: vec![LocalUser {
+                uid: 1001,
+                principal: None,
+                roles: vec!["missing".into()],
+            }],
+            ..Default::default()
+        };
+        assert!(Policy::new(PolicyConfig::default())
+            .unwrap()
+            .with_local_bindings(missing.bindings())
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_peer_cred() {
+        let dir = std::env::temp_dir().join(format!("rrocker-local-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rrockerd.sock");
+
+        //a stale socket is replaced but other files are left alone
+        drop(bind(&path, 0o600).unwrap());
+        let listener = bind(&path, 0o600).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(bind(&dir, 0o600).is_err());
+
+        let _client = UnixStream::connect(&path).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = LocalStream::new(stream).unwrap();
+        assert_eq!(stream.connect_info().uid, nix::unistd::getuid().as_raw());
+        assert_eq!(stream.connect_info().gid, nix::unistd::getgid().as_raw());
+        assert_eq!(stream.connect_info().pid, Some(std::process::id() as i32));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,11 +5,30 @@ use std::{
     task::{Context, Poll, Waker},
 };
 
+/// Size of a log item in bytes, used to account how much log a task has produced
+pub trait LogSize {
+    fn log_size(&self) -> usize;
+}
+
+impl LogSize for String {
+    fn log_size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// A line tagged with e.g. the pipe it came from only counts the line
+impl<T> LogSize for (String, T) {
+    fn log_size(&self) -> usize {
+        self.0.len()
+    }
+}
+
 #[derive(Debug)]
 struct SharedInternal<T> {
     items: Vec<Arc<T>>,
     wakers: Vec<Waker>,
     closed: bool,
+    size_bytes: usize,
 }
 
 impl<T> SharedInternal<T> {
@@ -18,6 +37,7 @@ impl<T> SharedInternal<T> {
             items: Default::default(),
             wakers: Default::default(),
             closed: false,
+            size_bytes: 0,
         }
     }
 }
@@ -78,15 +98,23 @@ impl<T> Shared<T> {
         }
     }
 
-    pub fn write(self: &Arc<Shared<T>>, data: T) {
+    pub fn write(self: &Arc<Shared<T>>, data: T)
+    where
+        T: LogSize,
+    {
         let mut inner = self.inner.write().unwrap();
+        inner.size_bytes += data.log_size();
         inner.items.push(Arc::new(data));
         inner.wakers.iter().for_each(Waker::wake_by_ref);
         inner.wakers.clear();
     }
 
     pub fn close(self: &Arc<Shared<T>>) {
-        self.inner.write().unwrap().closed = true;
+        let mut inner = self.inner.write().unwrap();
+        inner.closed = true;
+        //readers waiting for the next item have to see the end
+        inner.wakers.iter().for_each(Waker::wake_by_ref);
+        inner.wakers.clear();
     }
 }
 
@@ -113,9 +141,14 @@ impl<T> LogReaderFactory<T> {
             idx: 0,
         }
     }
+
+    /// Total size of everything written to the log so far
+    pub fn size_bytes(&self) -> usize {
+        self.shared.inner.read().unwrap().size_bytes
+    }
 }
 
-impl<T> LogWriter<T> {
+impl<T: LogSize> LogWriter<T> {
     pub fn write(&self, data: T) {
         self.shared.write(data)
     }
@@ -187,5 +220,25 @@ mod test {
             .map(|s| s.as_ref().clone())
             .collect::<Vec<_>>();
         assert_eq!(res, data);
+
+        assert_eq!(
+            factory.size_bytes(),
+            data.iter().map(String::len).sum::<usize>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_close_wakes_readers() {
+        let (factory, writer) = log_channel::<String>();
+        let reader = tokio::spawn(factory.create_reader().into_stream().collect::<Vec<_>>());
+        //let the reader wait for the first item
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        drop(writer);
+
+        let res = tokio::time::timeout(std::time::Duration::from_secs(5), reader)
+            .await
+            .expect("the reader wasn't woken")
+            .unwrap();
+        assert!(res.is_empty());
     }
 }
@@ -1,3 +1,120 @@
-fn main() {
-    println!("Hello, world!");
+use anyhow::{Context, Result};
+use rrocker_lib::api::scheduler_server::SchedulerServer as SchedulerService;
+use rrockerd_lib::{
+    audit::{AuditLayer, AuditLog},
+    auth::authorization_interceptor,
+    config::Config,
+    crl::Revocations,
+    local::{self, local_interceptor},
+    policy::Policy,
+    rate_limit::{RateLimitLayer, RateLimiter},
+    scheduler::{SchedulerServer, SharedScheduler},
+    tls::ReloadableTls,
+};
+use std::{path::Path, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+use tonic::{service::interceptor::InterceptedService, transport::Server};
+use tower::Layer;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    //the only argument is an optional path to the config file
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::load(Path::new(&path))?,
+        None => Config::default(),
+    };
+
+    //TLS is terminated by us instead of tonic so the config can be swapped at runtime
+    let tls = Arc::new(ReloadableTls::new(config.tls.clone()).context("Invalid TLS config")?);
+    let watch_interval = match config.tls.watch_interval_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    tokio::spawn({
+        let tls = tls.clone();
+        async move {
+            if let Err(e) = tls.reload_loop(watch_interval).await {
+                tracing::error!("The TLS config won't be reloaded: {:?}", e);
+            }
+        }
+    });
+
+    //local users and groups are granted their roles through the policy, but only over the socket
+    let policy = Policy::new(config.policy.clone())
+        .and_then(|policy| policy.with_local_bindings(config.local.bindings()))
+        .context("Invalid policy")?;
+    let policy = Arc::new(policy);
+    let audit = Arc::new(AuditLog::new(config.audit.clone()).context("Invalid audit config")?);
+    let scheduler = SchedulerServer::new(&config, policy.clone())?.with_audit(audit.clone());
+    let scheduler = SharedScheduler(Arc::new(scheduler));
+    //registered before any task is started so no exit is missed
+    let exits = signal(SignalKind::child()).context("Failed to handle SIGCHLD")?;
+    tokio::spawn({
+        let scheduler = scheduler.clone();
+        async move {
+            if let Err(e) = scheduler.0.reap_loop(exits).await {
+                tracing::error!("Exited tasks won't be reaped: {:?}", e);
+            }
+        }
+    });
+    let identity = Arc::new(config.identity.clone());
+
+    let revocations = Arc::new(Revocations::new(
+        config.tls.crls.clone(),
+        config.tls.client_ca.clone(),
+    )?);
+    if !config.tls.crls.is_empty() {
+        let interval = Duration::from_secs(config.tls.crl_reload_interval_secs.max(1));
+        let revocations = revocations.clone();
+        tokio::spawn(async move {
+            if let Err(e) = revocations.reload_loop(interval).await {
+                tracing::error!("CRLs won't be reloaded: {:?}", e);
+            }
+        });
+    }
+
+    //rate limits are enforced after the interceptor so the client's principal is known
+    let limiter = Arc::new(RateLimiter::new(config.rate_limits.clone()));
+    let scheduler = RateLimitLayer::new(limiter).layer(SchedulerService::new(scheduler));
+
+    let local = match &config.local.socket {
+        Some(path) => {
+            let listener = local::bind(path, config.local.socket_mode)?;
+            tracing::info!("Listening on {:?}", path);
+            let server = Server::builder()
+                .layer(AuditLayer::new(audit.clone()))
+                .add_service(InterceptedService::new(
+                    scheduler.clone(),
+                    local_interceptor(Arc::new(config.local.clone()), policy.clone()),
+                ))
+                .serve_with_incoming(local::incoming(listener));
+            Some(tokio::spawn(server))
+        }
+        None => None,
+    };
+
+    let listener = TcpListener::bind(config.listen)
+        .await
+        .context(format!("Failed to listen on {}", config.listen))?;
+    tracing::info!("Listening on {}", config.listen);
+    let tcp = Server::builder()
+        .layer(AuditLayer::new(audit))
+        .add_service(InterceptedService::new(
+            scheduler,
+            authorization_interceptor(identity, policy, revocations),
+        ))
+        .serve_with_incoming(tls.incoming(listener));
+
+    match local {
+        Some(local) => tokio::select! {
+            res = tcp => res.context("Server failed"),
+            res = local => res?.context("Local server failed"),
+        },
+        None => tcp.await.context("Server failed"),
+    }
 }
@@ -0,0 +1,307 @@
+use crate::fs;
+use anyhow::{anyhow, Result};
+use nix::mount::MsFlags;
+use rrocker_lib::api::{
+    mount::{Propagation, Source},
+    Mount,
+};
+use serde::Deserialize;
+use std::path::{Component, Path, PathBuf};
+use tonic::Status;
+
+/// Tasks can't mount over these, it would undo the masking of /proc and /sys
+const RESERVED_TARGETS: &[&str] = &["/dev", "/proc", "/sys"];
+
+/// What clients may mount into their tasks
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MountConfig {
+    /// Host paths that may be bind mounted along with everything below them,
+    /// empty disables bind mounts
+    pub allowed_bind_prefixes: Vec<PathBuf>,
+    /// Largest tmpfs a task may mount, unset means no limit
+    pub max_tmpfs_size_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountSource {
+    /// A host path, resolved when the task is started
+    Bind(PathBuf),
+    Tmpfs {
+        size_bytes: u64,
+    },
+}
+
+/// A validated mount of a task's root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountSpec {
+    /// Absolute path inside the task
+    pub target: PathBuf,
+    pub source: MountSource,
+    pub read_only: bool,
+    pub propagation: MsFlags,
+}
+
+/// Lexically normalize an absolute path, `..` isn't allowed
+fn normalize<P: AsRef<Path>>(path: P, what: &str) -> Result<PathBuf, Status> {
+    let path = path.as_ref();
+    if !path.is_absolute() {
+        return Err(Status::invalid_argument(format!(
+            "The {} '{:?}' must be absolute",
+            what, path
+        )));
+    }
+    path.components()
+        .map(|c| match c {
+            Component::RootDir | Component::Normal(_) => Ok(c),
+            _ => Err(Status::invalid_argument(format!(
+                "The {} '{:?}' must not contain '..'",
+                what, path
+            ))),
+        })
+        .collect()
+}
+
+fn target<P: AsRef<Path>>(target: P) -> Result<PathBuf, Status> {
+    let target = normalize(target, "mount target")?;
+    if target == Path::new("/") || RESERVED_TARGETS.iter().any(|r| target.starts_with(r)) {
+        return Err(Status::invalid_argument(format!(
+            "Mounting on '{:?}' isn't allowed",
+            target
+        )));
+    }
+    Ok(target)
+}
+
+impl MountConfig {
+    fn allowed(&self, path: &Path) -> bool {
+        self.allowed_bind_prefixes.iter().any(|prefix| {
+            path.starts_with(prefix)
+                || prefix
+                    .canonicalize()
+                    .is_ok_and(|prefix| path.starts_with(prefix))
+        })
+    }
+
+    /// Resolve a bind mount's host path. It's checked before it's resolved so clients can't
+    /// probe what exists outside the allowed prefixes, and after so symlinks can't escape them.
+    fn bind_source(&self, source: &str) -> Result<PathBuf, Status> {
+        let denied = |source: &Path| {
+            Status::permission_denied(format!("Mounting '{:?}' isn't allowed", source))
+        };
+        let source = normalize(source, "mount source")?;
+        if !self.allowed(&source) {
+            return Err(denied(&source));
+        }
+        let resolved = source.canonicalize().map_err(|_| {
+            Status::invalid_argument(format!("The mount source '{:?}' doesn't exist", source))
+        })?;
+        if !self.allowed(&resolved) {
+            return Err(denied(&source));
+        }
+        Ok(resolved)
+    }
+
+    /// Validate the requested mounts
+    pub fn mounts(&self, mounts: &[Mount]) -> Result<Vec<MountSpec>, Status> {
+        mounts
+            .iter()
+            .map(|mount| {
+                let source = match &mount.source {
+                    Some(Source::Bind(path)) => MountSource::Bind(self.bind_source(path)?),
+                    Some(Source::TmpfsSizeBytes(0)) => {
+                        return Err(Status::invalid_argument("A tmpfs mount requires a size"))
+                    }
+                    Some(Source::TmpfsSizeBytes(size)) => match self.max_tmpfs_size_bytes {
+                        Some(max) if *size > max => {
+                            return Err(Status::invalid_argument(format!(
+                                "A tmpfs mount can't be larger than {} bytes",
+                                max
+                            )))
+                        }
+                        _ => MountSource::Tmpfs { size_bytes: *size },
+                    },
+                    None => return Err(Status::invalid_argument("A mount requires a source")),
+                };
+                let propagation = match Propagation::from_i32(mount.propagation) {
+                    Some(Propagation::Private) => MsFlags::MS_PRIVATE,
+                    Some(Propagation::Slave) => MsFlags::MS_SLAVE,
+                    Some(Propagation::Shared) => MsFlags::MS_SHARED,
+                    None => return Err(Status::invalid_argument("Invalid mount propagation")),
+                };
+
+                //a new tmpfs has no master to receive mounts from
+                if propagation == MsFlags::MS_SLAVE && matches!(source, MountSource::Tmpfs { .. }) {
+                    return Err(Status::invalid_argument(
+                        "Only bind mounts can be slaves of the host",
+                    ));
+                }
+
+                Ok(MountSpec {
+                    target: target(&mount.target)?,
+                    source,
+                    read_only: mount.read_only,
+                    propagation,
+                })
+            })
+            .collect()
+    }
+}
+
+impl MountSpec {
+    /// A private writable tmpfs
+    pub fn tmpfs<P: AsRef<Path>>(target: P, size_bytes: u64) -> Result<Self, Status> {
+        if size_bytes == 0 {
+            return Err(Status::invalid_argument("A tmpfs mount requires a size"));
+        }
+        Ok(Self {
+            target: self::target(target)?,
+            source: MountSource::Tmpfs { size_bytes },
+            read_only: false,
+            propagation: MsFlags::MS_PRIVATE,
+        })
+    }
+
+    /// Mount under `root`, before pivoting into it. A bind mount starts out as a slave of its
+    /// source's host mount, as `prepare_root` made all mounts slaves, until its propagation
+    /// is set.
+    pub(crate) fn apply(&self, root: &Path) -> Result<()> {
+        //an image's symlinks would otherwise redirect the mount to the daemon's files
+        let mut target = root.to_path_buf();
+        for component in self.target.components().skip(1) {
+            target.push(component);
+            if std::fs::symlink_metadata(&target).is_ok_and(|m| m.file_type().is_symlink()) {
+                return Err(anyhow!("The mount target '{:?}' is a symlink", target));
+            }
+        }
+
+        //setuid binaries and device nodes mustn't gain anything in the task
+        let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+        if self.read_only {
+            flags |= MsFlags::MS_RDONLY;
+        }
+        match &self.source {
+            MountSource::Bind(source) => {
+                fs::bind_mount(source, &target)?;
+                fs::remount_bind(&target, flags)?;
+            }
+            MountSource::Tmpfs { size_bytes } => {
+                fs::mount_tmpfs(&target, flags, &format!("size={}", size_bytes))?;
+            }
+        }
+        fs::set_propagation(&target, self.propagation)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bind(target: &str, source: &str) -> Mount {
+        Mount {
+            target: target.into(),
+            source: Some(Source::Bind(source.into())),
+            ..Default::default()
+        }
+    }
+
+    fn tmpfs(target: &str, size: u64) -> Mount {
+        Mount {
+            target: target.into(),
+            source: Some(Source::TmpfsSizeBytes(size)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mounts() {
+        let dir = std::env::temp_dir().join(format!("rrocker-mounts-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("data/input")).unwrap();
+        std::os::unix::fs::symlink("/etc", dir.join("data/escape")).unwrap();
+        let config = MountConfig {
+            allowed_bind_prefixes: vec![dir.join("data")],
+            max_tmpfs_size_bytes: Some(1 << 20),
+        };
+        let data = dir.join("data/input").to_string_lossy().into_owned();
+
+        let mounts = config
+            .mounts(&[
+                Mount {
+                    read_only: true,
+                    propagation: Propagation::Slave as i32,
+                    ..bind("/input/", &data)
+                },
+                tmpfs("/input/scratch", 1 << 20),
+            ])
+            .unwrap();
+        assert_eq!(mounts[0].target, PathBuf::from("/input"));
+        assert_eq!(
+            mounts[0].source,
+            MountSource::Bind(dir.join("data/input").canonicalize().unwrap())
+        );
+        assert!(mounts[0].read_only);
+        assert_eq!(mounts[0].propagation, MsFlags::MS_SLAVE);
+        assert_eq!(
+            mounts[1].source,
+            MountSource::Tmpfs {
+                size_bytes: 1 << 20
+            }
+        );
+
+        let code = |mount: Mount| config.mounts(&[mount]).unwrap_err().code();
+        //symlinks and .. can't escape the allowed prefixes
+        let escape = dir
+            .join("data/escape/shadow")
+            .to_string_lossy()
+            .into_owned();
+        assert_eq!(code(bind("/etc", &escape)), tonic::Code::PermissionDenied);
+        assert_eq!(
+            code(bind("/etc", &format!("{}/../..", data))),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            code(bind("/etc", "/etc/shadow")),
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(
+            code(bind("/in", &format!("{}/missing", data))),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(code(bind("input", &data)), tonic::Code::InvalidArgument);
+        for reserved in &["/", "/proc/sys", "/dev", "/sys/"] {
+            assert_eq!(code(bind(reserved, &data)), tonic::Code::InvalidArgument);
+        }
+        assert_eq!(code(tmpfs("/tmp", 0)), tonic::Code::InvalidArgument);
+        assert_eq!(
+            code(tmpfs("/tmp", (1 << 20) + 1)),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            code(Mount {
+                propagation: 42,
+                ..tmpfs("/tmp", 1)
+            }),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            code(Mount {
+                propagation: Propagation::Slave as i32,
+                ..tmpfs("/tmp", 1)
+            }),
+            tonic::Code::InvalidArgument
+        );
+
+        let scratch = MountSpec::tmpfs("/tmp", 1 << 20).unwrap();
+        assert_eq!(scratch.propagation, MsFlags::MS_PRIVATE);
+        assert!(MountSpec::tmpfs("/proc", 1 << 20).is_err());
+        assert!(MountSpec::tmpfs("/tmp", 0).is_err());
+
+        //bind mounts are disabled unless prefixes are allowed
+        let status = MountConfig::default()
+            .mounts(&[bind("/in", &data)])
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
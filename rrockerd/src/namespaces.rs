@@ -0,0 +1,217 @@
+use anyhow::{anyhow, Context, Result};
+use nix::{
+    errno::Errno,
+    libc,
+    sched::{self, CloneFlags},
+    unistd::Pid,
+};
+use serde::Deserialize;
+use std::{collections::BTreeSet, fmt, fs::File, os::unix::io::AsRawFd, str::FromStr};
+use uuid::Uuid;
+
+/// Missing from libc, it can't be passed to clone() as it clashes with the exit signal
+const CLONE_NEWTIME: libc::c_int = 0x80;
+
+/// A kind of namespace, named like its file in /proc/<pid>/ns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Namespace {
+    Cgroup,
+    Ipc,
+    Mnt,
+    Net,
+    Pid,
+    Time,
+    User,
+    Uts,
+}
+
+impl Namespace {
+    pub const ALL: &'static [Namespace] = &[
+        Namespace::Cgroup,
+        Namespace::Ipc,
+        Namespace::Mnt,
+        Namespace::Net,
+        Namespace::Pid,
+        Namespace::Time,
+        Namespace::User,
+        Namespace::Uts,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Namespace::Cgroup => "cgroup",
+            Namespace::Ipc => "ipc",
+            Namespace::Mnt => "mnt",
+            Namespace::Net => "net",
+            Namespace::Pid => "pid",
+            Namespace::Time => "time",
+            Namespace::User => "user",
+            Namespace::Uts => "uts",
+        }
+    }
+
+    fn flag(&self) -> libc::c_int {
+        match self {
+            Namespace::Cgroup => libc::CLONE_NEWCGROUP,
+            Namespace::Ipc => libc::CLONE_NEWIPC,
+            Namespace::Mnt => libc::CLONE_NEWNS,
+            Namespace::Net => libc::CLONE_NEWNET,
+            Namespace::Pid => libc::CLONE_NEWPID,
+            Namespace::Time => CLONE_NEWTIME,
+            Namespace::User => libc::CLONE_NEWUSER,
+            Namespace::Uts => libc::CLONE_NEWUTS,
+        }
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Namespace {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Namespace::ALL
+            .iter()
+            .find(|ns| ns.name() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown namespace '{}'", s))
+    }
+}
+
+/// Flags of clone() or unshare() creating the namespaces, except the time namespace
+pub fn clone_flags(namespaces: &BTreeSet<Namespace>) -> CloneFlags {
+    namespaces
+        .iter()
+        .filter(|ns| **ns != Namespace::Time)
+        .fold(CloneFlags::empty(), |flags, ns| {
+            flags | CloneFlags::from_bits_truncate(ns.flag())
+        })
+}
+
+/// A task whose namespaces another task shares, e.g. a sidecar sharing its localhost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub task: Uuid,
+    /// Entered along with the peer's user namespace which owns them
+    pub namespaces: BTreeSet<Namespace>,
+}
+
+/// The namespaces of a task
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespaces {
+    /// Created for the task, any other namespace is the daemon's or the peer's
+    pub new: BTreeSet<Namespace>,
+    pub peer: Option<Peer>,
+}
+
+impl Default for Namespaces {
+    fn default() -> Self {
+        Self {
+            new: [
+                Namespace::Cgroup,
+                Namespace::Ipc,
+                Namespace::Mnt,
+                Namespace::Net,
+                Namespace::Pid,
+                Namespace::User,
+                Namespace::Uts,
+            ]
+            .iter()
+            .copied()
+            .collect(),
+            peer: None,
+        }
+    }
+}
+
+impl Namespaces {
+    /// Whether the task has its own namespace or the peer's, i.e. not the daemon's
+    pub fn is_isolated(&self, ns: Namespace) -> bool {
+        self.new.contains(&ns)
+            || self
+                .peer
+                .as_ref()
+                .is_some_and(|p| p.namespaces.contains(&ns))
+    }
+}
+
+/// The namespaces of a running process, opened before cloning so the child enters
+/// them even if the process exits and its pid is reused in the meantime
+#[derive(Debug)]
+pub struct Join {
+    files: Vec<(Namespace, File)>,
+}
+
+impl Join {
+    /// Open the user namespace of the process and the given namespaces it owns
+    pub fn open(pid: Pid, namespaces: &BTreeSet<Namespace>) -> Result<Self> {
+        let files = std::iter::once(Namespace::User)
+            .chain(
+                namespaces
+                    .iter()
+                    .copied()
+                    .filter(|ns| *ns != Namespace::User),
+            )
+            .map(|ns| {
+                let path = format!("/proc/{}/ns/{}", pid, ns);
+                let file = File::open(&path).context(format!("Failed to open '{}'", path))?;
+                Ok((ns, file))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { files })
+    }
+
+    /// Enter the namespaces, the user namespace first so the others may be entered
+    pub(crate) fn enter(&self) -> Result<()> {
+        for (ns, file) in &self.files {
+            sched::setns(file.as_raw_fd(), CloneFlags::from_bits_truncate(ns.flag()))
+                .context(format!("Failed to enter the {} namespace", ns))?;
+        }
+        Ok(())
+    }
+}
+
+/// Move the calling process into a new time namespace. unshare() only moves its
+/// children so the process follows them afterwards.
+pub(crate) fn enter_new_time() -> Result<()> {
+    Errno::result(unsafe { libc::unshare(CLONE_NEWTIME) })
+        .context("Failed to create a time namespace")?;
+    let file = File::open("/proc/self/ns/time_for_children")
+        .context("Failed to open /proc/self/ns/time_for_children")?;
+    Errno::result(unsafe { libc::setns(file.as_raw_fd(), CLONE_NEWTIME) })
+        .context("Failed to enter the time namespace")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_namespaces() {
+        assert_eq!("net".parse::<Namespace>().unwrap(), Namespace::Net);
+        assert!("network".parse::<Namespace>().is_err());
+
+        let ns = Namespaces::default();
+        assert!(ns.is_isolated(Namespace::Ipc));
+        assert!(!ns.is_isolated(Namespace::Time));
+        let flags = clone_flags(&ns.new);
+        assert!(flags.contains(CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWUSER));
+
+        let ns = Namespaces {
+            new: [Namespace::Mnt, Namespace::Time].iter().copied().collect(),
+            peer: Some(Peer {
+                task: Uuid::new_v4(),
+                namespaces: [Namespace::Net].iter().copied().collect(),
+            }),
+        };
+        assert!(ns.is_isolated(Namespace::Net));
+        assert!(!ns.is_isolated(Namespace::Pid));
+        assert_eq!(clone_flags(&ns.new), CloneFlags::CLONE_NEWNS);
+    }
+}
@@ -0,0 +1,820 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use flate2::read::GzDecoder;
+use ring::digest::{Context as Sha256, SHA256};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, Entry, EntryType};
+use uuid::Uuid;
+
+const INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+/// How deep image indexes may nest, `docker save` links shared layers up to this deep too
+const MAX_DEPTH: usize = 8;
+/// Symlinks followed while resolving a path in a rootfs, same as the kernel's limit
+const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    size: u64,
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+/// An image of `docker save`'s manifest.json
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    layers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    rootfs: RootFs,
+}
+
+/// The defaults tasks started from the image run with, Docker writes missing ones as null
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct RunConfig {
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    /// `KEY=VALUE` entries
+    pub env: Option<Vec<String>>,
+    pub working_dir: Option<String>,
+    /// A user name or uid, optionally followed by `:` and a group name or gid
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct StoredConfig {
+    #[serde(default)]
+    config: Option<RunConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RootFs {
+    /// Digests of the uncompressed layers
+    diff_ids: Vec<String>,
+}
+
+/// Computes the `sha256:<hex>` digest of what's read or written through it
+struct Hashing<T> {
+    inner: T,
+    sha: Sha256,
+    size: u64,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            sha: Sha256::new(&SHA256),
+            size: 0,
+        }
+    }
+
+    fn finish(self) -> (String, u64) {
+        let hex = self
+            .sha
+            .finish()
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        (format!("sha256:{}", hex), self.size)
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.sha.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.sha.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The hex of a `sha256:<hex>` digest, which is validated as it's used as a file name
+fn sha256_hex(digest: &str) -> Result<&str> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex)
+            if hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) =>
+        {
+            Ok(hex)
+        }
+        _ => bail!("Unsupported digest '{}'", digest),
+    }
+}
+
+/// Lexically resolve `..` like a chroot would, it never leaves the root
+pub(crate) fn clean(path: &Path) -> PathBuf {
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => clean.push(name),
+            Component::ParentDir => {
+                clean.pop();
+            }
+            _ => {}
+        }
+    }
+    clean
+}
+
+/// A file of the uploaded archive, staged and hashed
+struct Blob {
+    path: PathBuf,
+    digest: String,
+    size: u64,
+}
+
+/// The uploaded archive's files by path
+struct Staged {
+    files: HashMap<PathBuf, Blob>,
+    links: HashMap<PathBuf, PathBuf>,
+}
+
+impl Staged {
+    /// Copy the regular files of `archive` into `dir` and remember its symlinks
+    fn new(archive: &Path, dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).context(format!("Failed to create '{:?}'", dir))?;
+        let file = File::open(archive).context(format!("Failed to open '{:?}'", archive))?;
+        let mut staged = Self {
+            files: HashMap::new(),
+            links: HashMap::new(),
+        };
+
+        let mut archive = Archive::new(BufReader::new(file));
+        for (i, entry) in archive.entries()?.enumerate() {
+            let mut entry = entry.context("Invalid archive")?;
+            let path = clean(&entry.path()?);
+            match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous => {
+                    let copy = dir.join(i.to_string());
+                    let mut hashing = Hashing::new(
+                        File::create(&copy).context(format!("Failed to create '{:?}'", copy))?,
+                    );
+                    io::copy(&mut entry, &mut hashing)
+                        .context(format!("Failed to stage '{:?}'", path))?;
+                    let (digest, size) = hashing.finish();
+                    staged.files.insert(
+                        path,
+                        Blob {
+                            path: copy,
+                            digest,
+                            size,
+                        },
+                    );
+                }
+                EntryType::Symlink => {
+                    if let Some(target) = entry.link_name()? {
+                        let target = path.parent().unwrap_or(&path).join(target);
+                        staged.links.insert(path, clean(&target));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(staged)
+    }
+
+    /// The file at `path`, following the archive's symlinks
+    fn file<P: AsRef<Path>>(&self, path: P) -> Result<&Blob> {
+        let mut path = clean(path.as_ref());
+        for _ in 0..MAX_DEPTH {
+            if let Some(blob) = self.files.get(&path) {
+                return Ok(blob);
+            }
+            path = self
+                .links
+                .get(&path)
+                .ok_or_else(|| anyhow!("'{:?}' is missing from the archive", path))?
+                .clone();
+        }
+        bail!("Too many symlinks to '{:?}' in the archive", path)
+    }
+
+    /// The blob of an OCI image layout, verified against its descriptor
+    fn blob(&self, descriptor: &Descriptor) -> Result<&Blob> {
+        let hex = sha256_hex(&descriptor.digest)?;
+        let blob = self.file(Path::new("blobs/sha256").join(hex))?;
+        ensure!(
+            blob.digest == descriptor.digest && blob.size == descriptor.size,
+            "Blob '{}' doesn't match its digest or size",
+            descriptor.digest
+        );
+        Ok(blob)
+    }
+
+    /// The config and layers of the image
+    fn image(&self) -> Result<(&Blob, Vec<&Blob>)> {
+        if self.files.contains_key(Path::new("index.json")) {
+            let mut index: Index = json(self.file("index.json")?)?;
+            for _ in 0..MAX_DEPTH {
+                let descriptor = select(&index.manifests)?;
+                let blob = self.blob(descriptor)?;
+                if INDEX_MEDIA_TYPES.contains(&descriptor.media_type.as_str()) {
+                    index = json(blob)?;
+                    continue;
+                }
+                let manifest: Manifest = json(blob)?;
+                let layers = manifest
+                    .layers
+                    .iter()
+                    .map(|layer| self.blob(layer))
+                    .collect::<Result<_>>()?;
+                return Ok((self.blob(&manifest.config)?, layers));
+            }
+            bail!("The image indexes are nested too deep")
+        } else if self.files.contains_key(Path::new("manifest.json")) {
+            let manifests: Vec<DockerManifest> = json(self.file("manifest.json")?)?;
+            let manifest = match manifests.as_slice() {
+                [manifest] => manifest,
+                [] => bail!("The archive contains no image"),
+                _ => bail!("The archive contains several images, save them one at a time"),
+            };
+            let layers = manifest
+                .layers
+                .iter()
+                .map(|layer| self.file(layer))
+                .collect::<Result<_>>()?;
+            Ok((self.file(&manifest.config)?, layers))
+        } else {
+            bail!("The archive is neither an OCI image layout nor from docker save")
+        }
+    }
+}
+
+fn json<T: DeserializeOwned>(blob: &Blob) -> Result<T> {
+    let file = File::open(&blob.path).context(format!("Failed to open '{:?}'", blob.path))?;
+    serde_json::from_reader(BufReader::new(file))
+        .context(format!("Invalid JSON in blob '{}'", blob.digest))
+}
+
+/// The manifest of the daemon's platform, or the only one of single platform images
+fn select(manifests: &[Descriptor]) -> Result<&Descriptor> {
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    manifests
+        .iter()
+        .find(|d| {
+            d.platform
+                .as_ref()
+                .is_some_and(|p| p.os == "linux" && p.architecture == arch)
+        })
+        .or_else(|| manifests.iter().find(|d| d.platform.is_none()))
+        .ok_or_else(|| anyhow!("The image has no manifest for linux/{}", arch))
+}
+
+/// Remove whatever is at `path`
+fn remove(path: &Path) -> Result<()> {
+    let removed = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    removed.context(format!("Failed to remove '{:?}'", path))
+}
+
+/// Resolve the dir at `path` in `rootfs` like a chroot would, following the rootfs' own
+/// symlinks without ever leaving it. Missing dirs are created and added to `created`.
+fn resolve_dir(rootfs: &Path, path: &Path, created: &mut HashSet<PathBuf>) -> Result<PathBuf> {
+    resolve(rootfs, path, Some(created))
+}
+
+/// Look up `path` in `rootfs` like a chroot would, it may be a dir or a file
+pub(crate) fn lookup(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+    resolve(rootfs, path, None)
+}
+
+/// Resolve `path` in `rootfs` following the rootfs' own symlinks without ever leaving it.
+/// With `created` missing dirs are created, without it the last component may be a file.
+fn resolve(
+    rootfs: &Path,
+    path: &Path,
+    mut created: Option<&mut HashSet<PathBuf>>,
+) -> Result<PathBuf> {
+    let components = |path: &Path| -> VecDeque<OsString> {
+        path.components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_owned()),
+                Component::ParentDir => Some("..".into()),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut pending = components(path);
+    let mut resolved = PathBuf::new();
+    let mut symlinks = 0;
+
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        let dir = rootfs.join(&resolved).join(&name);
+        match fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                symlinks += 1;
+                ensure!(
+                    symlinks <= MAX_SYMLINKS,
+                    "Too many symlinks in '{:?}'",
+                    path
+                );
+                let target = fs::read_link(&dir)?;
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                let mut target = components(&target);
+                target.extend(pending);
+                pending = target;
+            }
+            Ok(meta) if meta.is_dir() => resolved.push(&name),
+            Ok(_) if pending.is_empty() && created.is_none() => resolved.push(&name),
+            Ok(_) => bail!("'{:?}' isn't a directory", dir),
+            Err(e) if e.kind() == ErrorKind::NotFound => match created.as_deref_mut() {
+                Some(created) => {
+                    fs::create_dir(&dir).context(format!("Failed to create '{:?}'", dir))?;
+                    created.insert(dir);
+                    resolved.push(&name);
+                }
+                None => return Err(e).context(format!("'{:?}' doesn't exist", path)),
+            },
+            Err(e) => return Err(e).context(format!("Failed to stat '{:?}'", dir)),
+        }
+    }
+    Ok(rootfs.join(resolved))
+}
+
+/// Apply an entry of a layer on `rootfs`, `created` are the paths the layer added so far
+fn apply_entry<R: Read>(
+    mut entry: Entry<R>,
+    rootfs: &Path,
+    created: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let path = entry.path()?.into_owned();
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Ok(()),
+    };
+    let dir = resolve_dir(rootfs, path.parent().unwrap_or(&path), created)?;
+
+    if name == OPAQUE_WHITEOUT {
+        //hides what the lower layers put in the dir but not what this layer adds
+        for child in fs::read_dir(&dir)? {
+            let child = child?.path();
+            if !created.contains(&child) {
+                remove(&child)?;
+            }
+        }
+        return Ok(());
+    }
+    if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+        //these would remove the dir itself or its parent
+        ensure!(
+            !matches!(hidden, "" | "." | ".."),
+            "Invalid whiteout '{:?}'",
+            path
+        );
+        return remove(&dir.join(hidden));
+    }
+
+    let dst = dir.join(&name);
+    let existing = fs::symlink_metadata(&dst).ok();
+    match entry.header().entry_type() {
+        EntryType::Directory => {
+            if existing.is_some_and(|meta| !meta.is_dir()) {
+                remove(&dst)?;
+            }
+            entry.unpack(&dst)?;
+        }
+        EntryType::Regular | EntryType::Continuous | EntryType::Symlink => {
+            //replaced files are removed first so they're never written through
+            if existing.is_some() {
+                remove(&dst)?;
+            }
+            entry.unpack(&dst)?;
+        }
+        EntryType::Link => {
+            let target = entry
+                .link_name()?
+                .context(format!("Hard link '{:?}' has no target", path))?;
+            let src = match target.file_name() {
+                Some(file) => {
+                    resolve_dir(rootfs, target.parent().unwrap_or(&target), created)?.join(file)
+                }
+                None => bail!("Invalid hard link target '{:?}'", target),
+            };
+            if existing.is_some() {
+                remove(&dst)?;
+            }
+            fs::hard_link(&src, &dst).context(format!("Failed to link '{:?}'", path))?;
+        }
+        //device nodes could give tasks access to the daemon host's devices
+        _ => return Ok(()),
+    }
+    created.insert(dst);
+    Ok(())
+}
+
+/// Apply a layer on `rootfs`, returns the digest of its uncompressed tar
+fn apply_layer(blob: &Blob, rootfs: &Path) -> Result<String> {
+    let mut file = BufReader::new(File::open(&blob.path)?);
+    let magic = file.fill_buf()?;
+    let reader: Box<dyn Read> = if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(GzDecoder::new(file))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        bail!("zstd compressed layers aren't supported")
+    } else {
+        Box::new(file)
+    };
+
+    let mut hashing = Hashing::new(reader);
+    {
+        let mut archive = Archive::new(&mut hashing);
+        archive.set_preserve_permissions(true);
+        //the store is readable by anyone on the host, where setuid binaries of the daemon's
+        //user mustn't be lying around
+        archive.set_mask(0o6000);
+        archive.set_preserve_mtime(true);
+        archive.set_unpack_xattrs(false);
+        let mut created = HashSet::new();
+        for entry in archive.entries()? {
+            apply_entry(entry?, rootfs, &mut created)?;
+        }
+    }
+    //the padding after the end of the archive is part of the digest
+    io::copy(&mut hashing, &mut io::sink())?;
+    Ok(hashing.finish().0)
+}
+
+fn import_staged(archive: &Path, store: &Path, staging: &Path) -> Result<PathBuf> {
+    let staged = Staged::new(archive, &staging.join("files"))?;
+    let (config_blob, layers) = staged.image()?;
+    let config: Config = json(config_blob)?;
+    ensure!(
+        config.rootfs.diff_ids.len() == layers.len(),
+        "The image's config lists {} layers but its manifest {}",
+        config.rootfs.diff_ids.len(),
+        layers.len()
+    );
+
+    let images = store.join("images");
+    let image = images.join(sha256_hex(&config_blob.digest)?);
+    if image.is_dir() {
+        return Ok(image);
+    }
+
+    let unpacked = staging.join("image");
+    let rootfs = unpacked.join("rootfs");
+    fs::create_dir_all(&rootfs).context(format!("Failed to create '{:?}'", rootfs))?;
+    for (layer, diff_id) in layers.iter().zip(&config.rootfs.diff_ids) {
+        let digest = apply_layer(layer, &rootfs)
+            .context(format!("Failed to unpack layer '{}'", layer.digest))?;
+        ensure!(
+            &digest == diff_id,
+            "Layer '{}' doesn't match its diff id '{}'",
+            layer.digest,
+            diff_id
+        );
+    }
+    fs::copy(&config_blob.path, unpacked.join("config.json"))
+        .context("Failed to store the image's config")?;
+
+    fs::create_dir_all(&images).context(format!("Failed to create '{:?}'", images))?;
+    match fs::rename(&unpacked, &image) {
+        Ok(()) => Ok(image),
+        //imported concurrently
+        Err(_) if image.is_dir() => Ok(image),
+        Err(e) => Err(e).context(format!("Failed to move the image to '{:?}'", image)),
+    }
+}
+
+/// Read the run config stored in the dir of an imported image, dirs without one have none
+pub fn run_config(image: &Path) -> Result<RunConfig> {
+    let path = image.join("config.json");
+    let config = match fs::read(&path) {
+        Ok(config) => config,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(RunConfig::default()),
+        Err(e) => return Err(e).context(format!("Failed to read '{:?}'", path)),
+    };
+    let stored: StoredConfig =
+        serde_json::from_slice(&config).context(format!("Invalid image config '{:?}'", path))?;
+    Ok(stored.config.unwrap_or_default())
+}
+
+/// Import the image of an OCI image layout or `docker save` tar into `store`. Digests are
+/// verified and the layers unpacked into the image's rootfs, images are stored by the
+/// digest of their config so identical images are unpacked once.
+/// Returns the image's dir holding `rootfs` and `config.json`.
+pub fn import(archive: &Path, store: &Path) -> Result<PathBuf> {
+    let staging = store.join("tmp").join(Uuid::new_v4().to_string());
+    let image = import_staged(archive, store, &staging);
+    if let Err(e) = remove(&staging) {
+        tracing::warn!("Failed to clean up an image import: {:?}", e);
+    }
+    image
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use tar::{Builder, Header};
+
+    pub(crate) enum Node<'a> {
+        File(&'a str, &'a [u8]),
+        /// A file with the given mode
+        Mode(&'a str, u32),
+        Dir(&'a str),
+        Symlink(&'a str, &'a str),
+        Link(&'a str, &'a str),
+    }
+
+    fn layer(nodes: &[Node]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for node in nodes {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o755);
+            header.set_size(0);
+            let (path, data): (_, &[u8]) = match node {
+                Node::File(path, data) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    (path, data)
+                }
+                Node::Mode(path, mode) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_mode(*mode);
+                    (path, &[])
+                }
+                Node::Dir(path) => {
+                    header.set_entry_type(EntryType::Directory);
+                    (path, &[])
+                }
+                Node::Symlink(path, target) | Node::Link(path, target) => {
+                    header.set_entry_type(match node {
+                        Node::Symlink(..) => EntryType::Symlink,
+                        _ => EntryType::Link,
+                    });
+                    header.set_link_name(target).unwrap();
+                    (path, &[])
+                }
+            };
+            //set_path refuses `..`, which a malicious layer could contain
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn digest(data: &[u8]) -> String {
+        let mut hashing = Hashing::new(io::sink());
+        hashing.write_all(data).unwrap();
+        hashing.finish().0
+    }
+
+    fn descriptor(media_type: &str, data: &[u8]) -> serde_json::Value {
+        serde_json::json!({ "mediaType": media_type, "digest": digest(data), "size": data.len() })
+    }
+
+    /// An OCI image layout of the layers as a tar, the first layer is uncompressed
+    fn oci_archive(dir: &Path, layers: &[Vec<u8>]) -> PathBuf {
+        let mut blobs = Vec::new();
+        let config = serde_json::to_vec(&serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": { "type": "layers", "diff_ids": layers.iter().map(|l| digest(l)).collect::<Vec<_>>() },
+        }))
+        .unwrap();
+        let mut descriptors = Vec::new();
+        for (i, layer) in layers.iter().enumerate() {
+            let blob = match i {
+                0 => layer.clone(),
+                _ => gzip(layer),
+            };
+            descriptors.push(descriptor(
+                "application/vnd.oci.image.layer.v1.tar+gzip",
+                &blob,
+            ));
+            blobs.push(blob);
+        }
+        let manifest = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "config": descriptor("application/vnd.oci.image.config.v1+json", &config),
+            "layers": descriptors,
+        }))
+        .unwrap();
+        let index = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [descriptor("application/vnd.oci.image.manifest.v1+json", &manifest)],
+        }))
+        .unwrap();
+        blobs.push(config);
+        blobs.push(manifest);
+
+        let mut files = vec![
+            (
+                "oci-layout".to_owned(),
+                br#"{"imageLayoutVersion":"1.0.0"}"#.to_vec(),
+            ),
+            ("index.json".to_owned(), index),
+        ];
+        for blob in blobs {
+            let path = format!("blobs/sha256/{}", sha256_hex(&digest(&blob)).unwrap());
+            files.push((path, blob));
+        }
+        let nodes = files
+            .iter()
+            .map(|(path, data)| Node::File(path, data))
+            .collect::<Vec<_>>();
+        let path = dir.join(format!("{}.tar", Uuid::new_v4()));
+        fs::write(&path, layer(&nodes)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_import_oci() {
+        let dir = std::env::temp_dir().join(format!("rrocker-oci-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let store = dir.join("store");
+        let base = layer(&[
+            Node::Dir("etc/"),
+            Node::File("etc/hostname", b"base"),
+            Node::File("etc/removed", b"removed"),
+            Node::Dir("usr/lib/"),
+            Node::Symlink("lib", "/usr/lib"),
+            Node::File("opt/dir/old", b"old"),
+        ]);
+        let top = layer(&[
+            Node::File("etc/.wh.removed", b""),
+            Node::File("opt/dir/new", b"new"),
+            Node::File("opt/dir/.wh..wh..opq", b""),
+            //written through the image's symlink, inside the rootfs
+            Node::File("lib/libc.so", b"libc"),
+            Node::Link("etc/hostname.bak", "etc/hostname"),
+        ]);
+
+        let archive = oci_archive(&dir, &[base.clone(), top]);
+        let image = import(&archive, &store).unwrap();
+        let rootfs = image.join("rootfs");
+        assert_eq!(fs::read(rootfs.join("etc/hostname")).unwrap(), b"base");
+        assert_eq!(fs::read(rootfs.join("etc/hostname.bak")).unwrap(), b"base");
+        assert!(!rootfs.join("etc/removed").exists());
+        assert!(!rootfs.join("opt/dir/old").exists());
+        assert_eq!(fs::read(rootfs.join("opt/dir/new")).unwrap(), b"new");
+        assert_eq!(fs::read(rootfs.join("usr/lib/libc.so")).unwrap(), b"libc");
+        assert!(image.join("config.json").is_file());
+        //staged files are cleaned up and the same image is stored once
+        assert_eq!(fs::read_dir(store.join("tmp")).unwrap().count(), 0);
+        assert_eq!(import(&archive, &store).unwrap(), image);
+
+        //layers can't escape the rootfs through `..` or absolute symlinks
+        let escape = layer(&[
+            Node::File("../../escaped", b"escaped"),
+            Node::Symlink("host", "/"),
+            Node::File("host/escaped-link", b"escaped"),
+        ]);
+        let image = import(&oci_archive(&dir, &[escape]), &store).unwrap();
+        assert!(image.join("rootfs/escaped").is_file());
+        assert!(image.join("rootfs/escaped-link").is_file());
+        assert!(!dir.join("escaped").exists());
+
+        //whiteouts can't remove the dir they're in or its parent
+        for whiteout in ["etc/.wh.", "etc/.wh..", "etc/.wh..."] {
+            let wipe = layer(&[
+                Node::File("etc/hostname", b"kept"),
+                Node::File(whiteout, b""),
+            ]);
+            let err = import(&oci_archive(&dir, &[wipe]), &store).unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("Invalid whiteout"),
+                "{}",
+                whiteout
+            );
+        }
+        assert!(archive.is_file());
+
+        //setuid and setgid bits are dropped
+        let setuid = layer(&[Node::Mode("bin/su", 0o6755)]);
+        let image = import(&oci_archive(&dir, &[setuid]), &store).unwrap();
+        let mode = fs::metadata(image.join("rootfs/bin/su"))
+            .unwrap()
+            .permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o7777,
+            0o755
+        );
+
+        //a corrupted layer doesn't match its digest
+        let archive = oci_archive(&dir, &[base]);
+        let mut data = fs::read(&archive).unwrap();
+        let at = data.windows(4).position(|w| w == b"base").unwrap();
+        data[at] = b'B';
+        fs::write(&archive, data).unwrap();
+        let err = import(&archive, &store).unwrap_err();
+        assert!(format!("{:#}", err).contains("doesn't match"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A `docker save` archive of an image with a single layer, returns it and its digest
+    pub(crate) fn docker_save(nodes: &[Node]) -> (Vec<u8>, String) {
+        let base = layer(nodes);
+        let config = serde_json::to_vec(&serde_json::json!({
+            "rootfs": { "type": "layers", "diff_ids": [digest(&base)] },
+            "config": { "Entrypoint": null, "Cmd": ["sh"], "Env": ["PATH=/bin"] },
+        }))
+        .unwrap();
+        let manifest = serde_json::to_vec(&serde_json::json!([{
+            "Config": "config.json",
+            "RepoTags": ["alpine:latest"],
+            "Layers": ["shared/layer.tar"],
+        }]))
+        .unwrap();
+        //docker save links layers shared with other images
+        let archive = layer(&[
+            Node::File("manifest.json", &manifest),
+            Node::File("config.json", &config),
+            Node::File("abc/layer.tar", &base),
+            Node::Symlink("shared/layer.tar", "../abc/layer.tar"),
+        ]);
+        (archive, digest(&config))
+    }
+
+    #[test]
+    fn test_import_docker_save() {
+        let dir = std::env::temp_dir().join(format!("rrocker-docker-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (archive, digest) = docker_save(&[Node::File("bin/sh", b"sh")]);
+        fs::write(dir.join("image.tar"), archive).unwrap();
+
+        let image = import(&dir.join("image.tar"), &dir.join("store")).unwrap();
+        assert_eq!(fs::read(image.join("rootfs/bin/sh")).unwrap(), b"sh");
+        assert!(image.ends_with(sha256_hex(&digest).unwrap()));
+        let config = run_config(&image).unwrap();
+        assert_eq!(config.entrypoint, None);
+        assert_eq!(config.cmd, Some(vec!["sh".to_owned()]));
+        assert_eq!(run_config(&dir).unwrap(), RunConfig::default());
+
+        fs::write(dir.join("empty.tar"), layer(&[])).unwrap();
+        assert!(import(&dir.join("empty.tar"), &dir.join("store")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
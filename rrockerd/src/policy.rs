@@ -0,0 +1,436 @@
+use crate::{
+    auth::{ClientAuth, Principal},
+    seccomp::UNCONFINED,
+};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+
+/// Actions a role can be permitted to perform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    /// Start new tasks
+    Start,
+    /// Stop your own tasks
+    Stop,
+    /// Query, stream the output of and read the stats of your own tasks
+    ReadOutput,
+    /// See every task, combined with `read-output` this allows reading all tasks
+    ListAll,
+    /// Start tasks in the network and IPC namespaces of your own running tasks
+    JoinNamespaces,
+    /// Act on every task and use the admin only RPCs
+    AdminOps,
+}
+
+/// A named set of permissions plus optional restrictions on what may be started
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Role {
+    pub permissions: BTreeSet<Permission>,
+    /// Commands tasks may be started with, a trailing `*` matches any suffix.
+    /// Unset means any command.
+    pub allowed_commands: Option<Vec<String>>,
+    /// Images tasks may be started from, unset means any image
+    pub allowed_images: Option<Vec<String>>,
+    /// Seccomp profiles tasks may be started with, unset means any profile but
+    /// `unconfined` which has to be listed explicitly
+    pub allowed_seccomp_profiles: Option<Vec<String>>,
+    /// Namespaces tasks may share with the daemon's host instead of getting their own, e.g.
+    /// `net`. Tasks always get their own namespaces unless listed here.
+    pub allowed_host_namespaces: Vec<String>,
+}
+
+/// Grants a role to every client whose certificate matches any of the listed
+/// principals, common names, organizations (groups) or organizational units
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Binding {
+    pub role: String,
+    /// Principals such as SPIFFE IDs, a trailing `*` matches any suffix
+    pub principals: Vec<String>,
+    pub common_names: Vec<String>,
+    pub organizations: Vec<String>,
+    pub organizational_units: Vec<String>,
+}
+
+/// The authorization policy as configured by the admin.
+/// Without any configuration the built in `client` and `admin` roles are
+/// bound to the organizations of the same name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub roles: HashMap<String, Role>,
+    pub bindings: Vec<Binding>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        use Permission::*;
+
+        let mut roles = HashMap::new();
+        roles.insert(
+            "client".to_owned(),
+            Role {
+                permissions: vec![Start, Stop, ReadOutput].into_iter().collect(),
+                ..Default::default()
+            },
+        );
+        roles.insert(
+            "admin".to_owned(),
+            Role {
+                permissions: vec![Start, Stop, ReadOutput, ListAll, JoinNamespaces, AdminOps]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+        );
+
+        let bindings = ["client", "admin"]
+            .iter()
+            .map(|name| Binding {
+                role: (*name).to_owned(),
+                organizations: vec![(*name).to_owned()],
+                ..Default::default()
+            })
+            .collect();
+
+        Self { roles, bindings }
+    }
+}
+
+/// The combined permissions of all roles bound to a client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Grants {
+    pub roles: Vec<String>,
+    permissions: BTreeSet<Permission>,
+    allowed_commands: Option<Vec<String>>,
+    allowed_images: Option<Vec<String>>,
+    /// Profiles listed by the roles, `unconfined` is only allowed if it's listed
+    allowed_seccomp_profiles: Vec<String>,
+    /// Whether a role leaves its profiles unrestricted, allowing any but `unconfined`
+    any_confined_seccomp_profile: bool,
+    allowed_host_namespaces: Vec<String>,
+}
+
+/// Union of two optional allow lists where `None` means anything is allowed
+fn union(a: Option<&Vec<String>>, b: Option<&Vec<String>>) -> Option<Vec<String>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.iter().chain(b).cloned().collect()),
+        _ => None,
+    }
+}
+
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => value.starts_with(prefix),
+        None => pattern == value,
+    }
+}
+
+impl Grants {
+    pub fn has(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// Whether the permission may be used on tasks owned by other clients
+    pub fn applies_to_all_tasks(&self, permission: Permission) -> bool {
+        self.has(Permission::AdminOps)
+            || (permission == Permission::ReadOutput && self.has(Permission::ListAll))
+    }
+
+    pub fn may_see_all_tasks(&self) -> bool {
+        self.has(Permission::ListAll) || self.has(Permission::AdminOps)
+    }
+
+    pub fn command_allowed(&self, cmd: &str) -> bool {
+        self.allowed_commands
+            .as_ref()
+            .is_none_or(|cmds| cmds.iter().any(|p| matches_pattern(p, cmd)))
+    }
+
+    pub fn image_allowed(&self, image: &str) -> bool {
+        self.allowed_images
+            .as_ref()
+            .is_none_or(|imgs| imgs.iter().any(|p| matches_pattern(p, image)))
+    }
+
+    pub fn seccomp_profile_allowed(&self, profile: &str) -> bool {
+        (self.any_confined_seccomp_profile && profile != UNCONFINED)
+            || self
+                .allowed_seccomp_profiles
+                .iter()
+                .any(|p| matches_pattern(p, profile))
+    }
+
+    pub fn host_namespace_allowed(&self, namespace: &str) -> bool {
+        self.allowed_host_namespaces
+            .iter()
+            .any(|ns| ns == namespace)
+    }
+}
+
+/// Resolves which roles a client has and thereby what it's allowed to do
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    config: PolicyConfig,
+    /// Bindings of the local users and groups, only clients of the Unix socket match them
+    local_bindings: Vec<Binding>,
+}
+
+impl Policy {
+    pub fn new(config: PolicyConfig) -> Result<Self> {
+        Self::check_roles(&config, &config.bindings)?;
+        Ok(Self {
+            config,
+            local_bindings: Vec::new(),
+        })
+    }
+
+    /// Add the bindings of the local users and groups. They're kept apart from the
+    /// configured bindings so certs can't claim them with a matching CN or O.
+    pub fn with_local_bindings(mut self, bindings: Vec<Binding>) -> Result<Self> {
+        Self::check_roles(&self.config, &bindings)?;
+        self.local_bindings = bindings;
+        Ok(self)
+    }
+
+    fn check_roles(config: &PolicyConfig, bindings: &[Binding]) -> Result<()> {
+        match bindings
+            .iter()
+            .find(|b| !config.roles.contains_key(&b.role))
+        {
+            Some(b) => Err(anyhow!("Binding refers to unknown role '{}'", b.role)),
+            None => Ok(()),
+        }
+    }
+
+    fn binding_matches(binding: &Binding, auth: &ClientAuth) -> bool {
+        binding
+            .principals
+            .iter()
+            .any(|p| matches_pattern(p, &auth.id))
+            || auth
+                .common_name
+                .as_ref()
+                .is_some_and(|cn| binding.common_names.contains(cn))
+            || auth
+                .groups
+                .iter()
+                .any(|g| binding.organizations.contains(g))
+            || auth
+                .org_units
+                .iter()
+                .any(|ou| binding.organizational_units.contains(ou))
+    }
+
+    /// Combine the roles of every binding matching the client.
+    /// Only roles that may start tasks contribute to the command and image allow lists,
+    /// otherwise binding an unrestricted read only role would lift the restrictions of another.
+    pub fn grants(&self, auth: &ClientAuth) -> Grants {
+        let mut grants = Grants {
+            allowed_commands: Some(Vec::new()),
+            allowed_images: Some(Vec::new()),
+            ..Default::default()
+        };
+        let local = match auth.principal {
+            Principal::Local(_) => self.local_bindings.as_slice(),
+            _ => &[],
+        };
+        let bound_roles = self
+            .config
+            .bindings
+            .iter()
+            .chain(local)
+            .filter(|b| Self::binding_matches(b, auth))
+            .flat_map(|b| self.config.roles.get_key_value(&b.role));
+
+        for (name, role) in bound_roles {
+            if grants.roles.contains(name) {
+                continue;
+            }
+            grants.roles.push(name.clone());
+            grants.permissions.extend(&role.permissions);
+            if role.permissions.contains(&Permission::Start) {
+                grants.allowed_commands = union(
+                    grants.allowed_commands.as_ref(),
+                    role.allowed_commands.as_ref(),
+                );
+                grants.allowed_images =
+                    union(grants.allowed_images.as_ref(), role.allowed_images.as_ref());
+                //unlike the other lists unset isn't a superset of every list, it lacks `unconfined`
+                match &role.allowed_seccomp_profiles {
+                    Some(profiles) => grants
+                        .allowed_seccomp_profiles
+                        .extend(profiles.iter().cloned()),
+                    None => grants.any_confined_seccomp_profile = true,
+                }
+                grants
+                    .allowed_host_namespaces
+                    .extend(role.allowed_host_namespaces.iter().cloned());
+            }
+        }
+
+        grants
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::SpiffeId;
+
+    fn auth(id: &str, group: &str, org_units: &[&str]) -> ClientAuth {
+        ClientAuth {
+            common_name: Some(id.into()),
+            group: group.into(),
+            groups: vec![group.into()],
+            org_units: org_units.iter().map(|s| (*s).to_owned()).collect(),
+            ..ClientAuth::new(Principal::CommonName(id.into()))
+        }
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = Policy::default();
+
+        let client = policy.grants(&auth("c1", "client", &[]));
+        assert_eq!(client.roles, vec!["client"]);
+        assert!(client.has(Permission::Start));
+        assert!(!client.may_see_all_tasks());
+        assert!(!client.applies_to_all_tasks(Permission::Stop));
+
+        let admin = policy.grants(&auth("a1", "admin", &[]));
+        assert!(admin.may_see_all_tasks());
+        assert!(admin.applies_to_all_tasks(Permission::Stop));
+
+        assert!(policy.grants(&auth("x", "invalid", &[])).roles.is_empty());
+    }
+
+    #[test]
+    fn test_config() {
+        let config: PolicyConfig = toml::from_str(
+            r#"
+            [roles.oncall]
+            permissions = ["read-output", "list-all", "stop"]
+
+            [roles.ml]
+            permissions = ["start", "stop", "read-output"]
+            allowed_commands = ["/usr/bin/python3", "/opt/ml/*"]
+            allowed_seccomp_profiles = ["strict"]
+            allowed_host_namespaces = ["net"]
+
+            [[bindings]]
+            role = "oncall"
+            organizational_units = ["sre"]
+
+            [[bindings]]
+            role = "ml"
+            organizations = ["ml"]
+            common_names = ["bob"]
+            principals = ["spiffe://corp/team/ml/*"]
+            "#,
+        )
+        .unwrap();
+        let policy = Policy::new(config).unwrap();
+
+        //on-call can read everything but only stop their own tasks
+        let oncall = policy.grants(&auth("alice", "ops", &["sre"]));
+        assert!(oncall.applies_to_all_tasks(Permission::ReadOutput));
+        assert!(!oncall.applies_to_all_tasks(Permission::Stop));
+        assert!(!oncall.has(Permission::Start));
+
+        let ml = policy.grants(&auth("carol", "ml", &[]));
+        assert!(ml.command_allowed("/usr/bin/python3"));
+        assert!(ml.command_allowed("/opt/ml/train"));
+        assert!(!ml.command_allowed("/bin/bash"));
+        //only the profiles that are listed, unconfined has to be listed explicitly
+        assert!(ml.seccomp_profile_allowed("strict"));
+        assert!(!ml.seccomp_profile_allowed("default"));
+        assert!(!oncall.seccomp_profile_allowed("strict"));
+        let admin = Policy::default().grants(&auth("a1", "admin", &[]));
+        assert!(admin.seccomp_profile_allowed("default"));
+        assert!(!admin.seccomp_profile_allowed("unconfined"));
+        //tasks get their own namespaces unless listed
+        assert!(ml.host_namespace_allowed("net"));
+        assert!(!ml.host_namespace_allowed("pid"));
+        assert!(!admin.host_namespace_allowed("net"));
+
+        //roles combine, only roles that may start tasks restrict what can be started
+        let bob = policy.grants(&auth("bob", "ops", &["sre"]));
+        assert_eq!(bob.roles, vec!["oncall", "ml"]);
+        assert!(bob.has(Permission::Start));
+        assert!(bob.applies_to_all_tasks(Permission::ReadOutput));
+        assert!(!bob.command_allowed("/bin/bash"));
+        assert!(bob.image_allowed("anything"));
+        assert!(!oncall.command_allowed("/opt/ml/train"));
+
+        let spiffe = ClientAuth::new(Principal::Spiffe(
+            SpiffeId::parse("spiffe://corp/team/ml/user/dave").unwrap(),
+        ));
+        assert_eq!(policy.grants(&spiffe).roles, vec!["ml"]);
+
+        //the built in roles are replaced by the configured ones
+        assert!(policy.grants(&auth("c1", "client", &[])).roles.is_empty());
+
+        //permissions nothing checks are rejected rather than silently granting nothing
+        let exec = toml::from_str::<PolicyConfig>(
+            r#"
+            [roles.ops]
+            permissions = ["start", "exec"]
+            "#,
+        );
+        assert!(exec.is_err());
+    }
+
+    #[test]
+    fn test_seccomp_profiles() {
+        let config: PolicyConfig = toml::from_str(
+            r#"
+            [roles.debug]
+            permissions = ["start"]
+            allowed_seccomp_profiles = ["unconfined"]
+
+            [roles.client]
+            permissions = ["start", "stop"]
+
+            [[bindings]]
+            role = "debug"
+            common_names = ["alice"]
+
+            [[bindings]]
+            role = "client"
+            organizations = ["client"]
+            "#,
+        )
+        .unwrap();
+        let policy = Policy::new(config).unwrap();
+
+        //an unrestricted role must not take away the unconfined profile another role grants
+        let alice = policy.grants(&auth("alice", "client", &[]));
+        assert_eq!(alice.roles, vec!["debug", "client"]);
+        assert!(alice.seccomp_profile_allowed("unconfined"));
+        assert!(alice.seccomp_profile_allowed("default"));
+        assert!(alice.seccomp_profile_allowed("custom"));
+
+        let bob = policy.grants(&auth("bob", "client", &[]));
+        assert!(!bob.seccomp_profile_allowed("unconfined"));
+        assert!(bob.seccomp_profile_allowed("default"));
+        let debug = policy.grants(&auth("alice", "other", &[]));
+        assert!(debug.seccomp_profile_allowed("unconfined"));
+        assert!(!debug.seccomp_profile_allowed("default"));
+    }
+
+    #[test]
+    fn test_unknown_role() {
+        let config = PolicyConfig {
+            roles: HashMap::new(),
+            bindings: vec![Binding {
+                role: "missing".into(),
+                ..Default::default()
+            }],
+        };
+        assert!(Policy::new(config).is_err());
+    }
+}
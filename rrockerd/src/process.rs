@@ -0,0 +1,347 @@
+use crate::images::Image;
+use crate::oci;
+use nix::unistd::{Gid, Uid};
+use rrocker_lib::api::StartTaskRequest;
+use std::{
+    fs::{self, OpenOptions},
+    io::Read,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+use tonic::Status;
+
+/// PATH of tasks whose image and request don't set one, the same as runc's
+const DEFAULT_PATH: &str = "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// How much of an image's /etc/passwd and /etc/group is read
+const MAX_ID_FILE_BYTES: u64 = 1 << 20;
+
+/// What a task runs, the request merged with the defaults of its image's config
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Process {
+    /// The program, absolute in the task's root unless the request named it otherwise
+    pub cmd: String,
+    pub args: Vec<String>,
+    /// `KEY=VALUE` entries
+    pub env: Vec<String>,
+    pub working_dir: PathBuf,
+}
+
+impl Process {
+    /// Merge the request with the image's config. A requested cmd replaces both the image's
+    /// entrypoint and cmd, requested args only replace its cmd.
+    pub fn resolve(request: &StartTaskRequest, image: &Image) -> Result<Self, Status> {
+        let config = &image.config;
+        let env = env(config.env.as_deref().unwrap_or_default(), &request.env)?;
+        let working_dir = match (request.working_dir.as_str(), &config.working_dir) {
+            ("", Some(dir)) => absolute(Path::new(dir)),
+            ("", None) => PathBuf::from("/"),
+            (dir, _) if Path::new(dir).is_absolute() => absolute(Path::new(dir)),
+            (dir, _) => {
+                return Err(Status::invalid_argument(format!(
+                    "The working dir '{}' isn't absolute",
+                    dir
+                )))
+            }
+        };
+
+        if !request.cmd.is_empty() {
+            return Ok(Self {
+                cmd: request.cmd.clone(),
+                args: request.args.clone(),
+                env,
+                working_dir,
+            });
+        }
+        let mut args = config.entrypoint.clone().unwrap_or_default();
+        match request.args.as_slice() {
+            [] => args.extend(config.cmd.clone().unwrap_or_default()),
+            requested => args.extend_from_slice(requested),
+        }
+        if args.is_empty() {
+            return Err(Status::invalid_argument(
+                "No command was given and the image has none",
+            ));
+        }
+        let program = args.remove(0);
+        let cmd = find_program(&image.rootfs, &program, &env, &working_dir)?;
+        Ok(Self {
+            cmd,
+            args,
+            env,
+            working_dir,
+        })
+    }
+
+    /// Find `cmd` in `rootfs` like execvp would, run in the task with its root as `rootfs`
+    pub fn program(&self, rootfs: &Path) -> Result<String, Status> {
+        find_program(rootfs, &self.cmd, &self.env, &self.working_dir)
+    }
+}
+
+/// `path` made absolute with its `..` resolved like the task's root would
+fn absolute(path: &Path) -> PathBuf {
+    Path::new("/").join(oci::clean(path))
+}
+
+fn key(entry: &str) -> &str {
+    entry.split('=').next().unwrap_or(entry)
+}
+
+/// The image's environment with the requested entries replacing the ones of the same name
+fn env(image: &[String], requested: &[String]) -> Result<Vec<String>, Status> {
+    if let Some(entry) = requested
+        .iter()
+        .find(|entry| key(entry).is_empty() || !entry.contains('=') || entry.contains('\0'))
+    {
+        return Err(Status::invalid_argument(format!(
+            "Invalid environment entry '{}', expected KEY=VALUE",
+            entry
+        )));
+    }
+
+    let mut env: Vec<String> = Vec::new();
+    for entry in image.iter().chain(requested) {
+        match env.iter_mut().find(|e| key(e) == key(entry)) {
+            Some(e) => *e = entry.clone(),
+            None => env.push(entry.clone()),
+        }
+    }
+    if !env.iter().any(|e| key(e) == "PATH") {
+        env.push(DEFAULT_PATH.into());
+    }
+    Ok(env)
+}
+
+/// Find the program in `rootfs` like execvp would in the task, so the policy sees the path
+/// that's run. Programs containing a `/` are relative to the working dir.
+fn find_program(
+    rootfs: &Path,
+    program: &str,
+    env: &[String],
+    working_dir: &Path,
+) -> Result<String, Status> {
+    let candidates = if program.contains('/') {
+        vec![absolute(&working_dir.join(program))]
+    } else {
+        let path = env
+            .iter()
+            .find_map(|e| e.strip_prefix("PATH="))
+            .unwrap_or_default();
+        path.split(':')
+            .filter(|dir| dir.starts_with('/'))
+            .map(|dir| absolute(&Path::new(dir).join(program)))
+            .collect()
+    };
+
+    candidates
+        .into_iter()
+        .find(|candidate| {
+            oci::lookup(rootfs, candidate)
+                .and_then(|path| Ok(fs::metadata(path)?))
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+        .map(|cmd| cmd.to_string_lossy().into_owned())
+        .ok_or_else(|| Status::not_found(format!("Command '{}' not found in the image", program)))
+}
+
+/// Read an ID database such as /etc/passwd of the image, a missing one is empty
+fn read_id_file(rootfs: &Path, path: &str) -> Result<String, Status> {
+    let path = match oci::lookup(rootfs, Path::new(path)) {
+        Ok(path) => path,
+        Err(_) => return Ok(String::new()),
+    };
+    let mut content = String::new();
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::libc::O_NOFOLLOW)
+        .open(&path)
+        .and_then(|file| file.take(MAX_ID_FILE_BYTES).read_to_string(&mut content))
+        .map_err(|e| {
+            tracing::warn!("Failed to read '{:?}': {:?}", path, e);
+            Status::invalid_argument(format!("The image's '{:?}' is unreadable", path))
+        })?;
+    Ok(content)
+}
+
+/// The fields of the entry whose `field` is `value`
+fn find_entry<'a>(file: &'a str, field: usize, value: &str) -> Option<Vec<&'a str>> {
+    file.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 3 && fields[field] == value)
+}
+
+fn parse_id(id: &str, spec: &str) -> Result<u32, Status> {
+    id.parse()
+        .map_err(|_| Status::invalid_argument(format!("Invalid ID '{}' of user '{}'", id, spec)))
+}
+
+/// Resolve a `user[:group]` spec to the IDs the task runs as inside its user namespace.
+/// Without a group the user's primary group is used, or gid 0 when /etc/passwd doesn't list it.
+pub fn user(rootfs: &Path, spec: &str) -> Result<(Uid, Gid), Status> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    if user.is_empty() || group == Some("") {
+        return Err(Status::invalid_argument(format!(
+            "Invalid user '{}', expected user[:group]",
+            spec
+        )));
+    }
+
+    let passwd = read_id_file(rootfs, "/etc/passwd")?;
+    let (uid, primary) = match user.parse::<u32>() {
+        Ok(uid) => (uid, find_entry(&passwd, 2, user).map(|entry| entry[3])),
+        Err(_) => {
+            let entry = find_entry(&passwd, 0, user).ok_or_else(|| {
+                Status::not_found(format!("User '{}' not found in the image", user))
+            })?;
+            (parse_id(entry[2], spec)?, Some(entry[3]))
+        }
+    };
+    let gid = match (group, primary) {
+        (Some(group), _) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                let groups = read_id_file(rootfs, "/etc/group")?;
+                let entry = find_entry(&groups, 0, group).ok_or_else(|| {
+                    Status::not_found(format!("Group '{}' not found in the image", group))
+                })?;
+                parse_id(entry[2], spec)?
+            }
+        },
+        (None, Some(gid)) => parse_id(gid, spec)?,
+        (None, None) => 0,
+    };
+    Ok((Uid::from_raw(uid), Gid::from_raw(gid)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::oci::RunConfig;
+
+    fn rootfs(name: &str) -> PathBuf {
+        let rootfs =
+            std::env::temp_dir().join(format!("rrocker-process-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&rootfs);
+        fs::create_dir_all(rootfs.join("usr/bin")).unwrap();
+        fs::create_dir_all(rootfs.join("etc")).unwrap();
+        fs::write(rootfs.join("usr/bin/python3"), "").unwrap();
+        fs::set_permissions(
+            rootfs.join("usr/bin/python3"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        fs::write(rootfs.join("usr/bin/data"), "").unwrap();
+        std::os::unix::fs::symlink("usr/bin", rootfs.join("bin")).unwrap();
+        rootfs
+    }
+
+    #[test]
+    fn test_resolve() {
+        let image = Image {
+            name: "python".into(),
+            rootfs: rootfs("resolve"),
+            config: RunConfig {
+                entrypoint: Some(vec!["python3".into()]),
+                cmd: Some(vec!["-V".into()]),
+                env: Some(vec!["PATH=/bin".into(), "LANG=C".into()]),
+                working_dir: Some("/app".into()),
+                user: None,
+            },
+        };
+        let request = |cmd: &str, args: &[&str]| StartTaskRequest {
+            cmd: cmd.into(),
+            args: args.iter().map(|&a| a.into()).collect(),
+            ..Default::default()
+        };
+
+        let process = Process::resolve(&request("", &[]), &image).unwrap();
+        assert_eq!(process.cmd, "/bin/python3");
+        assert_eq!(process.args, vec!["-V"]);
+        assert_eq!(process.env, vec!["PATH=/bin", "LANG=C"]);
+        assert_eq!(process.working_dir, PathBuf::from("/app"));
+
+        //args replace the image's cmd but not its entrypoint
+        let process = Process::resolve(&request("", &["main.py"]), &image).unwrap();
+        assert_eq!(process.cmd, "/bin/python3");
+        assert_eq!(process.args, vec!["main.py"]);
+        let process = Process::resolve(&request("/bin/sh", &["-c", "ls"]), &image).unwrap();
+        assert_eq!(process.cmd, "/bin/sh");
+        assert_eq!(process.args, vec!["-c", "ls"]);
+
+        let process = Process::resolve(
+            &StartTaskRequest {
+                env: vec!["LANG=C.UTF-8".into(), "DEBUG=".into()],
+                working_dir: "/srv/../tmp".into(),
+                ..request("", &[])
+            },
+            &image,
+        )
+        .unwrap();
+        assert_eq!(process.env, vec!["PATH=/bin", "LANG=C.UTF-8", "DEBUG="]);
+        assert_eq!(process.working_dir, PathBuf::from("/tmp"));
+
+        let code = |request: StartTaskRequest, image: &Image| {
+            Process::resolve(&request, image).unwrap_err().code()
+        };
+        for env in ["LANG", "=C"] {
+            let request = StartTaskRequest {
+                env: vec![env.into()],
+                ..request("/bin/sh", &[])
+            };
+            assert_eq!(code(request, &image), tonic::Code::InvalidArgument);
+        }
+        let relative = StartTaskRequest {
+            working_dir: "app".into(),
+            ..request("/bin/sh", &[])
+        };
+        assert_eq!(code(relative, &image), tonic::Code::InvalidArgument);
+        let bare = Image {
+            config: Default::default(),
+            ..image.clone()
+        };
+        assert_eq!(code(request("", &[]), &bare), tonic::Code::InvalidArgument);
+        //not executable
+        assert_eq!(code(request("", &["data"]), &bare), tonic::Code::NotFound);
+        //images without a PATH get the default one
+        let process = Process::resolve(&request("", &["python3"]), &bare).unwrap();
+        assert_eq!(process.cmd, "/usr/bin/python3");
+        assert_eq!(process.env, vec![DEFAULT_PATH]);
+        assert_eq!(process.working_dir, PathBuf::from("/"));
+
+        fs::remove_dir_all(&image.rootfs).unwrap();
+    }
+
+    #[test]
+    fn test_user() {
+        let rootfs = rootfs("user");
+        fs::write(
+            rootfs.join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\napp:x:1000:1001::/home/app:/bin/sh\n",
+        )
+        .unwrap();
+        fs::write(rootfs.join("etc/group"), "root:x:0:\nstaff:x:50:app\n").unwrap();
+        let ids = |spec: &str| user(&rootfs, spec).map(|(uid, gid)| (uid.as_raw(), gid.as_raw()));
+
+        assert_eq!(ids("app").unwrap(), (1000, 1001));
+        assert_eq!(ids("1000").unwrap(), (1000, 1001));
+        assert_eq!(ids("app:staff").unwrap(), (1000, 50));
+        assert_eq!(ids("2000").unwrap(), (2000, 0));
+        assert_eq!(ids("2000:3000").unwrap(), (2000, 3000));
+        for spec in ["nobody", "app:wheel"] {
+            assert_eq!(ids(spec).unwrap_err().code(), tonic::Code::NotFound);
+        }
+        for spec in [":staff", "app:"] {
+            assert_eq!(ids(spec).unwrap_err().code(), tonic::Code::InvalidArgument);
+        }
+
+        //the image's /etc/passwd can't point outside of it
+        fs::remove_file(rootfs.join("etc/passwd")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", rootfs.join("etc/passwd")).unwrap();
+        assert_eq!(ids("root").unwrap_err().code(), tonic::Code::NotFound);
+
+        fs::remove_dir_all(&rootfs).unwrap();
+    }
+}
@@ -0,0 +1,487 @@
+use crate::{
+    auth::{ClientAuth, Group, Principal},
+    capacity::Resources,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Mutex};
+use tonic::Status;
+use uuid::Uuid;
+
+/// Limits on what a client or a group may consume at once, unset means no limit
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quota {
+    /// Running plus pending tasks
+    pub max_tasks: Option<u64>,
+    pub cpu_millicores: Option<u64>,
+    pub memory_bytes: Option<u64>,
+    /// Output retained by the daemon for unfinished tasks
+    pub log_bytes: Option<u64>,
+}
+
+/// Quotas as configured by the admin.
+/// `default` applies to every client without an entry in `clients`,
+/// while a group's quota applies to the sum of all of its members.
+/// A client in several groups is held to the quotas of all of them.
+/// Local users and groups are listed with their `unix:` prefix.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub default: Quota,
+    pub clients: HashMap<Principal, Quota>,
+    pub groups: HashMap<Group, Quota>,
+}
+
+/// What a client or group currently consumes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub tasks: u64,
+    pub cpu_millicores: u64,
+    pub memory_bytes: u64,
+    pub log_bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, res: &Resources) {
+        self.tasks += 1;
+        self.cpu_millicores = self.cpu_millicores.saturating_add(res.cpu_millicores);
+        self.memory_bytes = self.memory_bytes.saturating_add(res.memory_bytes);
+    }
+
+    fn sub(&mut self, res: &Resources) {
+        self.tasks = self.tasks.saturating_sub(1);
+        self.cpu_millicores = self.cpu_millicores.saturating_sub(res.cpu_millicores);
+        self.memory_bytes = self.memory_bytes.saturating_sub(res.memory_bytes);
+    }
+}
+
+/// A quota and the usage it's checked against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaEntry {
+    pub name: String,
+    pub quota: Quota,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Default)]
+struct QuotaState {
+    clients: HashMap<Principal, Usage>,
+    groups: HashMap<Group, Usage>,
+    /// Who each unfinished task was charged to
+    tasks: HashMap<Uuid, (Principal, Vec<Group>, Resources)>,
+}
+
+/// Tracks the usage of every client and group and enforces their quotas
+#[derive(Debug, Default)]
+pub struct Quotas {
+    config: QuotaConfig,
+    state: Mutex<QuotaState>,
+}
+
+/// Log bytes already retained by a client's unfinished tasks and by those of its groups
+#[derive(Debug, Clone, Default)]
+pub struct LogUsage {
+    pub client: u64,
+    pub groups: HashMap<Group, u64>,
+}
+
+fn check(
+    kind: &str,
+    name: &dyn std::fmt::Display,
+    quota: &Quota,
+    usage: &Usage,
+    req: &Resources,
+) -> Result<(), Status> {
+    let exhausted = |what: String| {
+        Err(Status::resource_exhausted(format!(
+            "{} '{}' has exhausted its quota of {}",
+            kind, name, what
+        )))
+    };
+
+    if let Some(max) = quota.max_tasks {
+        if usage.tasks >= max {
+            return exhausted(format!("{} concurrent tasks", max));
+        }
+    }
+    if let Some(max) = quota.cpu_millicores {
+        if usage.cpu_millicores.saturating_add(req.cpu_millicores) > max {
+            return exhausted(format!(
+                "{} CPU millicores ({} in use, task requests {})",
+                max, usage.cpu_millicores, req.cpu_millicores
+            ));
+        }
+    }
+    if let Some(max) = quota.memory_bytes {
+        if usage.memory_bytes.saturating_add(req.memory_bytes) > max {
+            return exhausted(format!(
+                "{} memory bytes ({} in use, task requests {})",
+                max, usage.memory_bytes, req.memory_bytes
+            ));
+        }
+    }
+    if let Some(max) = quota.log_bytes {
+        if usage.log_bytes >= max {
+            return exhausted(format!("{} log bytes ({} retained)", max, usage.log_bytes));
+        }
+    }
+    Ok(())
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig) -> Self {
+        Self {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    fn client_quota(&self, principal: &Principal) -> &Quota {
+        self.config
+            .clients
+            .get(principal)
+            .unwrap_or(&self.config.default)
+    }
+
+    /// Check the quotas of the client and its group and charge the task to them on success
+    pub fn charge(
+        &self,
+        uuid: Uuid,
+        auth: &ClientAuth,
+        req: Resources,
+        logs: LogUsage,
+    ) -> Result<(), Status> {
+        let mut state = self.state.lock().unwrap();
+
+        let client = &auth.principal;
+        let client_usage = Usage {
+            log_bytes: logs.client,
+            ..state.clients.get(client).copied().unwrap_or_default()
+        };
+        check(
+            "Client",
+            client,
+            self.client_quota(client),
+            &client_usage,
+            &req,
+        )?;
+
+        let groups = auth.qualified_groups();
+        for group in &groups {
+            if let Some(quota) = self.config.groups.get(group) {
+                let group_usage = Usage {
+                    log_bytes: logs.groups.get(group).copied().unwrap_or_default(),
+                    ..state.groups.get(group).copied().unwrap_or_default()
+                };
+                check("Group", group, quota, &group_usage, &req)?;
+            }
+        }
+
+        state.clients.entry(client.clone()).or_default().add(&req);
+        for group in &groups {
+            state.groups.entry(group.clone()).or_default().add(&req);
+        }
+        state.tasks.insert(uuid, (client.clone(), groups, req));
+        Ok(())
+    }
+
+    /// Undo the charge of a task once it has stopped, calling it more than once is harmless
+    pub fn release(&self, uuid: &Uuid) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((client, groups, req)) = state.tasks.remove(uuid) {
+            if let Some(usage) = state.clients.get_mut(&client) {
+                usage.sub(&req);
+            }
+            for group in groups {
+                if let Some(usage) = state.groups.get_mut(&group) {
+                    usage.sub(&req);
+                }
+            }
+        }
+    }
+
+    /// The quota and usage of every client and group that has a quota or has run tasks.
+    /// `client_logs` and `group_logs` return the log bytes retained by the unfinished tasks of
+    /// a client or group.
+    pub fn report<C, G>(&self, client_logs: C, group_logs: G) -> (Vec<QuotaEntry>, Vec<QuotaEntry>)
+    where
+        C: Fn(&Principal) -> u64,
+        G: Fn(&Group) -> u64,
+    {
+        let state = self.state.lock().unwrap();
+
+        let mut client_names = state.clients.keys().collect::<Vec<_>>();
+        client_names.extend(self.config.clients.keys());
+        client_names.sort();
+        client_names.dedup();
+
+        let mut group_names = state.groups.keys().collect::<Vec<_>>();
+        group_names.extend(self.config.groups.keys());
+        group_names.sort();
+        group_names.dedup();
+
+        let clients = client_names
+            .into_iter()
+            .map(|name| QuotaEntry {
+                name: name.to_string(),
+                quota: self.client_quota(name).clone(),
+                usage: Usage {
+                    log_bytes: client_logs(name),
+                    ..state.clients.get(name).copied().unwrap_or_default()
+                },
+            })
+            .collect();
+
+        let groups = group_names
+            .into_iter()
+            .map(|name| QuotaEntry {
+                name: name.to_string(),
+                quota: self.config.groups.get(name).cloned().unwrap_or_default(),
+                usage: Usage {
+                    log_bytes: group_logs(name),
+                    ..state.groups.get(name).copied().unwrap_or_default()
+                },
+            })
+            .collect();
+
+        (clients, groups)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tonic::Code;
+
+    fn auth(id: &str, group: &str) -> ClientAuth {
+        ClientAuth {
+            group: group.into(),
+            groups: vec![group.into()],
+            ..ClientAuth::new(Principal::CommonName(id.into()))
+        }
+    }
+
+    const RES: Resources = Resources {
+        cpu_millicores: 1000,
+        memory_bytes: 1024,
+    };
+
+    #[test]
+    fn test_client_quota() {
+        let mut config = QuotaConfig {
+            default: Quota {
+                max_tasks: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config.clients.insert(
+            Principal::CommonName("big".into()),
+            Quota {
+                cpu_millicores: Some(1500),
+                ..Default::default()
+            },
+        );
+        let quotas = Quotas::new(config);
+        let c1 = auth("c1", "client");
+        let big = auth("big", "client");
+
+        let t1 = Uuid::new_v4();
+        quotas.charge(t1, &c1, RES, LogUsage::default()).unwrap();
+        quotas
+            .charge(Uuid::new_v4(), &c1, RES, LogUsage::default())
+            .unwrap();
+        let status = quotas
+            .charge(Uuid::new_v4(), &c1, RES, LogUsage::default())
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(status.message().contains("'c1'"));
+
+        quotas.release(&t1);
+        quotas.release(&t1);
+        quotas
+            .charge(Uuid::new_v4(), &c1, RES, LogUsage::default())
+            .unwrap();
+
+        //the specific quota replaces the default one
+        quotas
+            .charge(Uuid::new_v4(), &big, RES, LogUsage::default())
+            .unwrap();
+        let status = quotas
+            .charge(Uuid::new_v4(), &big, RES, LogUsage::default())
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(status.message().contains("CPU"));
+    }
+
+    #[test]
+    fn test_group_quota() {
+        let mut config = QuotaConfig::default();
+        config.groups.insert(
+            Group::Cert("team".into()),
+            Quota {
+                memory_bytes: Some(2048),
+                log_bytes: Some(100),
+                ..Default::default()
+            },
+        );
+        let quotas = Quotas::new(config);
+
+        quotas
+            .charge(
+                Uuid::new_v4(),
+                &auth("c1", "team"),
+                RES,
+                LogUsage::default(),
+            )
+            .unwrap();
+        quotas
+            .charge(
+                Uuid::new_v4(),
+                &auth("c2", "team"),
+                RES,
+                LogUsage::default(),
+            )
+            .unwrap();
+        let status = quotas
+            .charge(
+                Uuid::new_v4(),
+                &auth("c3", "team"),
+                RES,
+                LogUsage::default(),
+            )
+            .unwrap_err();
+        assert!(status.message().starts_with("Group 'team'"));
+
+        //other groups aren't affected
+        quotas
+            .charge(
+                Uuid::new_v4(),
+                &auth("c3", "other"),
+                RES,
+                LogUsage::default(),
+            )
+            .unwrap();
+
+        let logs = LogUsage {
+            client: 0,
+            groups: vec![(Group::Cert("team".into()), 100)]
+                .into_iter()
+                .collect(),
+        };
+        let status = quotas
+            .charge(
+                Uuid::new_v4(),
+                &auth("c3", "team"),
+                Resources::default(),
+                logs,
+            )
+            .unwrap_err();
+        assert!(status.message().contains("log bytes"));
+    }
+
+    #[test]
+    fn test_multiple_groups() {
+        let mut config = QuotaConfig::default();
+        config.groups.insert(
+            Group::Cert("ml".into()),
+            Quota {
+                max_tasks: Some(1),
+                ..Default::default()
+            },
+        );
+        let quotas = Quotas::new(config);
+        let c1 = ClientAuth {
+            groups: vec!["client".into(), "ml".into()],
+            ..auth("c1", "client")
+        };
+
+        //the quota of a group other than the primary one applies as well
+        let t1 = Uuid::new_v4();
+        quotas.charge(t1, &c1, RES, LogUsage::default()).unwrap();
+        let status = quotas
+            .charge(Uuid::new_v4(), &auth("c2", "ml"), RES, LogUsage::default())
+            .unwrap_err();
+        assert!(status.message().starts_with("Group 'ml'"));
+
+        quotas.release(&t1);
+        quotas
+            .charge(Uuid::new_v4(), &auth("c2", "ml"), RES, LogUsage::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_local_users() {
+        let config: QuotaConfig = toml::from_str(
+            r#"
+            [clients."unix:1003"]
+            max_tasks = 1
+
+            [groups.ops]
+            max_tasks = 1
+
+            [groups."unix:ops"]
+            max_tasks = 1
+            "#,
+        )
+        .unwrap();
+        let quotas = Quotas::new(config);
+        let local = ClientAuth {
+            group: "ops".into(),
+            groups: vec!["ops".into()],
+            ..ClientAuth::new(Principal::Local("unix:1003".into()))
+        };
+
+        //the local user and group are charged separately from the cert group of the same name
+        quotas
+            .charge(Uuid::new_v4(), &local, RES, LogUsage::default())
+            .unwrap();
+        quotas
+            .charge(Uuid::new_v4(), &auth("c1", "ops"), RES, LogUsage::default())
+            .unwrap();
+        let status = quotas
+            .charge(Uuid::new_v4(), &local, RES, LogUsage::default())
+            .unwrap_err();
+        assert!(status.message().starts_with("Client 'unix:1003'"));
+
+        let (_, groups) = quotas.report(|_| 0, |_| 0);
+        assert_eq!(
+            groups.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(),
+            vec!["ops", "unix:ops"]
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let mut config = QuotaConfig::default();
+        config
+            .clients
+            .insert(Principal::CommonName("idle".into()), Quota::default());
+        let quotas = Quotas::new(config);
+        quotas
+            .charge(
+                Uuid::new_v4(),
+                &auth("c1", "team"),
+                RES,
+                LogUsage::default(),
+            )
+            .unwrap();
+
+        let (clients, groups) = quotas.report(|_| 5, |_| 10);
+        assert_eq!(
+            clients.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(),
+            vec!["c1", "idle"]
+        );
+        assert_eq!(
+            clients[0].usage,
+            Usage {
+                tasks: 1,
+                cpu_millicores: 1000,
+                memory_bytes: 1024,
+                log_bytes: 5,
+            }
+        );
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].usage.log_bytes, 10);
+    }
+}
@@ -0,0 +1,426 @@
+use crate::auth::ClientAuth;
+use futures::future::BoxFuture;
+use hyper::{Request, Response};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tonic::{
+    body::BoxBody, codegen::Body, metadata::MetadataValue, transport::NamedService, Status,
+};
+use tower::{Layer, Service};
+
+/// RPCs whose calls stay open until the client hangs up or the task ends
+const STREAMING_RPCS: &[&str] = &["TaskOutputStream", "TaskStatsStream"];
+/// Buckets are only pruned once there are this many, to keep the common path cheap
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// A token bucket refilled at `per_second` tokens up to `burst`, each call takes one token
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "RawBucket")]
+pub struct Bucket {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// A bucket as written in the config, it's validated when converted into a `Bucket`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBucket {
+    per_second: f64,
+    burst: u32,
+}
+
+impl TryFrom<RawBucket> for Bucket {
+    type Error = String;
+
+    fn try_from(raw: RawBucket) -> Result<Self, Self::Error> {
+        if !raw.per_second.is_finite() || raw.per_second < 0.0 {
+            return Err(format!(
+                "per_second must be a non-negative number, not {}",
+                raw.per_second
+            ));
+        }
+        if raw.burst < 1 {
+            return Err("burst must be at least 1".to_owned());
+        }
+        Ok(Self {
+            per_second: raw.per_second,
+            burst: raw.burst,
+        })
+    }
+}
+
+/// Rate limits apply to each client and RPC separately, i.e. a client exhausting its
+/// StartTask bucket can still query its tasks. Unset means no limit.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Bucket of every RPC without an entry in `rpcs`
+    pub default: Option<Bucket>,
+    /// Buckets by RPC name, e.g. `StartTask`
+    pub rpcs: HashMap<String, Bucket>,
+    /// Streams (TaskOutputStream, TaskStatsStream) a single client may have open at once
+    pub max_streams: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(bucket: &Bucket, now: Instant) -> Self {
+        Self {
+            tokens: bucket.burst.into(),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, bucket: &Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * bucket.per_second).min(bucket.burst.into());
+        self.updated = now;
+    }
+
+    /// Take a token or return how long until the next one is available
+    fn take(&mut self, bucket: &Bucket, now: Instant) -> Result<(), Duration> {
+        self.refill(bucket, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            //a rate of 0 or one so low the wait overflows never refills
+            Err(
+                Duration::try_from_secs_f64((1.0 - self.tokens) / bucket.per_second)
+                    .unwrap_or(Duration::MAX),
+            )
+        }
+    }
+}
+
+/// The token buckets and open streams of every client
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// Keyed by principal and RPC
+    buckets: Mutex<HashMap<(String, String), TokenBucket>>,
+    streams: Mutex<HashMap<String, usize>>,
+}
+
+/// Releases a client's stream slot once dropped
+#[derive(Debug)]
+pub struct StreamGuard {
+    limiter: Arc<RateLimiter>,
+    client: String,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut streams = self.limiter.streams.lock().unwrap();
+        if let Some(open) = streams.get_mut(&self.client) {
+            *open -= 1;
+            if *open == 0 {
+                streams.remove(&self.client);
+            }
+        }
+    }
+}
+
+fn resource_exhausted(message: String, retry_after: Option<Duration>) -> Status {
+    let mut status = Status::resource_exhausted(message);
+    if let Some(retry_after) = retry_after {
+        //round up so retrying after the hint is guaranteed to succeed
+        let millis = retry_after.as_millis() + 1;
+        status.metadata_mut().insert(
+            "retry-after-ms",
+            MetadataValue::from_str(&millis.to_string()).unwrap(),
+        );
+    }
+    status
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    fn bucket(&self, rpc: &str) -> Option<&Bucket> {
+        self.config.rpcs.get(rpc).or(self.config.default.as_ref())
+    }
+
+    /// Take a token from the client's bucket of the RPC
+    pub fn check_rate(&self, auth: &ClientAuth, rpc: &str, now: Instant) -> Result<(), Status> {
+        let bucket = match self.bucket(rpc) {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            //buckets that refilled completely are the same as new ones
+            buckets.retain(|(_, rpc), state| match self.bucket(rpc) {
+                Some(bucket) => {
+                    state.refill(bucket, now);
+                    state.tokens < bucket.burst.into()
+                }
+                None => false,
+            });
+        }
+
+        buckets
+            .entry((auth.id.clone(), rpc.to_owned()))
+            .or_insert_with(|| TokenBucket::full(bucket, now))
+            .take(bucket, now)
+            .map_err(|wait| {
+                tracing::warn!("{} exceeded the rate limit of {}", auth.id, rpc);
+                resource_exhausted(
+                    format!(
+                        "Rate limit of {} exceeded, retry in {}ms",
+                        rpc,
+                        wait.as_millis() + 1
+                    ),
+                    Some(wait),
+                )
+            })
+    }
+
+    /// Reserve one of the client's stream slots until the returned guard is dropped
+    pub fn open_stream(self: &Arc<Self>, auth: &ClientAuth) -> Result<StreamGuard, Status> {
+        let mut streams = self.streams.lock().unwrap();
+        let open = streams.entry(auth.id.clone()).or_default();
+        if self.config.max_streams.is_some_and(|max| *open >= max) {
+            tracing::warn!("{} exceeded the limit of concurrent streams", auth.id);
+            return Err(resource_exhausted(
+                format!(
+                    "Too many concurrent streams ({} are open), retry once one of them ends",
+                    open
+                ),
+                None,
+            ));
+        }
+        *open += 1;
+
+        Ok(StreamGuard {
+            limiter: self.clone(),
+            client: auth.id.clone(),
+        })
+    }
+}
+
+/// Enforces the rate limits in front of the scheduler. It sits between the authorization
+/// interceptor and the service so the client's principal is known.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S: NamedService> NamedService for RateLimit<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for RateLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>>,
+    S::Error: Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        //requests without a principal are rejected by the scheduler
+        let auth = match req.extensions().get::<ClientAuth>() {
+            Some(auth) => auth,
+            None => return Box::pin(self.inner.call(req)),
+        };
+        let rpc = req.uri().path().rsplit('/').next().unwrap_or("");
+
+        let guard = self
+            .limiter
+            .check_rate(auth, rpc, Instant::now())
+            .and_then(|_| {
+                if STREAMING_RPCS.contains(&rpc) {
+                    self.limiter.open_stream(auth).map(Some)
+                } else {
+                    Ok(None)
+                }
+            });
+        let guard = match guard {
+            Ok(guard) => guard,
+            Err(status) => return Box::pin(futures::future::ok(status.to_http())),
+        };
+
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            Ok(match guard {
+                //the guard lives as long as the body, i.e. until the stream ends
+                Some(guard) => res.map(|body| {
+                    body.map_data(move |data| {
+                        let _ = &guard;
+                        data
+                    })
+                    .boxed()
+                }),
+                None => res,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::Principal;
+
+    fn auth(id: &str) -> ClientAuth {
+        ClientAuth::new(Principal::CommonName(id.into()))
+    }
+
+    #[test]
+    fn test_rate() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            default: Some(Bucket {
+                per_second: 2.0,
+                burst: 2,
+            }),
+            rpcs: vec![(
+                "QueryTask".to_owned(),
+                Bucket {
+                    per_second: 100.0,
+                    burst: 100,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        });
+        let (c1, c2) = (auth("c1"), auth("c2"));
+        let now = Instant::now();
+
+        limiter.check_rate(&c1, "StartTask", now).unwrap();
+        limiter.check_rate(&c1, "StartTask", now).unwrap();
+        let status = limiter.check_rate(&c1, "StartTask", now).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("retry-after-ms").unwrap(), "501");
+
+        //other clients and RPCs have their own buckets
+        limiter.check_rate(&c2, "StartTask", now).unwrap();
+        limiter.check_rate(&c1, "StopTask", now).unwrap();
+        for _ in 0..100 {
+            limiter.check_rate(&c1, "QueryTask", now).unwrap();
+        }
+
+        //refilled at the configured rate
+        let later = now + Duration::from_millis(500);
+        limiter.check_rate(&c1, "StartTask", later).unwrap();
+        assert!(limiter.check_rate(&c1, "StartTask", later).is_err());
+    }
+
+    #[test]
+    fn test_bucket_config() {
+        let config: RateLimitConfig = toml::from_str(
+            r#"
+            default = { per_second = 0.5, burst = 1 }
+            rpcs.StartTask = { per_second = 0, burst = 5 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.rpcs["StartTask"],
+            Bucket {
+                per_second: 0.0,
+                burst: 5
+            }
+        );
+
+        for invalid in [
+            "default = { per_second = nan, burst = 1 }",
+            "default = { per_second = inf, burst = 1 }",
+            "default = { per_second = -1, burst = 1 }",
+            "default = { per_second = 1, burst = 0 }",
+        ] {
+            assert!(
+                toml::from_str::<RateLimitConfig>(invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+
+        //waits too long to represent mean the bucket never refills
+        let tiny = Bucket {
+            per_second: 1e-300,
+            burst: 1,
+        };
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full(&tiny, now);
+        bucket.take(&tiny, now).unwrap();
+        assert_eq!(bucket.take(&tiny, now), Err(Duration::MAX));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        for _ in 0..1000 {
+            limiter.check_rate(&auth("c1"), "StartTask", now).unwrap();
+        }
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_streams() {
+        let limiter = Arc::new(RateLimiter::new(RateLimitConfig {
+            max_streams: Some(2),
+            ..Default::default()
+        }));
+        let (c1, c2) = (auth("c1"), auth("c2"));
+
+        let first = limiter.open_stream(&c1).unwrap();
+        let _second = limiter.open_stream(&c1).unwrap();
+        let status = limiter.open_stream(&c1).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        let _other = limiter.open_stream(&c2).unwrap();
+
+        //ending a stream frees its slot
+        drop(first);
+        limiter.open_stream(&c1).unwrap();
+    }
+}
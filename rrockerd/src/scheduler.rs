@@ -1,132 +1,676 @@
-use crate::auth::ClientAuth;
-use crate::log::{log_channel, LogReader, LogReaderFactory};
+use crate::audit::{AuditContext, AuditEvent, AuditLog};
+use crate::auth::{ClientAuth, Group, Principal};
+use crate::capacity::{Admission, Capacity, Resources};
+use crate::cgroup::{Cgroup, CgroupStats, Controller, Limits};
+use crate::config::Config;
+use crate::constraints;
+use crate::images::{Image, Images};
+use crate::isolation::Isolation;
+use crate::log::{log_channel, LogReader, LogReaderFactory, LogWriter};
+use crate::mounts::MountConfig;
+use crate::namespaces::{Namespace, Namespaces, Peer};
+use crate::policy::{Grants, Permission, Policy};
+use crate::process::{self, Process};
+use crate::quota::{LogUsage, QuotaEntry, Quotas};
+use crate::seccomp::SeccompProfiles;
+use crate::sharing::{Ownership, Sharing};
+use crate::subid::SubIds;
+use crate::worker::{self, Worker};
+use anyhow::Context as _;
 use dashmap::{
     mapref::one::{Ref, RefMut},
     DashMap,
 };
 use futures::{Stream, StreamExt};
+use nix::unistd::Pid;
 use rrocker_lib::api::{
-    scheduler_server::Scheduler, OutputStream, QueryTaskReply, StartTaskReply, StartTaskRequest,
-    TaskHandle, TaskOutputReply, TaskState, TaskStatus,
+    scheduler_server::Scheduler, CpuStats, ImportImageReply, ImportImageRequest, IoDeviceStats,
+    ListImagesReply, ListQuotasReply, ListTasksReply, MemoryStats, NamespaceOptions, OutputStream,
+    PidsStats, QueryTaskReply, QuotaLimits, QuotaUsage, ResourceConstraints, SetTaskSharingRequest,
+    StartTaskReply, StartTaskRequest, TaskHandle, TaskListEntry, TaskOutputReply, TaskState,
+    TaskStatsReply, TaskStatsStreamRequest, TaskStatus,
 };
-use std::{collections::HashSet, pin::Pin};
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Deref,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::signal::unix::Signal;
 use tonic::{Response, Status};
 use uuid::Uuid;
 #[derive(Debug)]
 struct Task {
     log_factory: LogReaderFactory<(String, OutputStream)>,
-} //todo
+    /// Handed to the worker when it spawns the task
+    log_writer: Option<LogWriter<(String, OutputStream)>>,
+    cgroup: Cgroup,
+    /// Applied to the cgroup once the worker spawns the task
+    limits: Limits,
+    /// Applied by the worker when it spawns the task
+    isolation: Isolation,
+    process: Process,
+    /// Set while the task's process hasn't been reaped
+    pid: Option<Pid>,
+    status: TaskStatus,
+    /// Exit code of a completed task
+    code: i32,
+    ownership: Ownership,
+}
 
 impl Task {
-    pub fn new() -> Self {
-        let (log_factory, _log_writer) = log_channel();
-        Self { log_factory }
+    pub fn new(
+        cgroup: Cgroup,
+        ownership: Ownership,
+        limits: Limits,
+        isolation: Isolation,
+        process: Process,
+        status: TaskStatus,
+    ) -> Self {
+        let (log_factory, log_writer) = log_channel();
+        Self {
+            log_factory,
+            log_writer: Some(log_writer),
+            cgroup,
+            limits,
+            isolation,
+            process,
+            pid: None,
+            status,
+            code: 0,
+            ownership,
+        }
     }
     pub fn log_subscribe(&self) -> LogReader<(String, OutputStream)> {
         self.log_factory.create_reader()
     }
+
+    /// Log bytes counted against quotas, a finished task's output no longer counts
+    fn quota_log_bytes(&self) -> u64 {
+        match self.status {
+            TaskStatus::TaskRunning | TaskStatus::TaskPending => {
+                self.log_factory.size_bytes() as u64
+            }
+            TaskStatus::TaskCompleted | TaskStatus::TaskKilled => 0,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
-struct SchedulerServer {
+pub struct SchedulerServer {
     task_map: DashMap<Uuid, Task>,
-    client_tasks: DashMap<String, HashSet<Uuid>>,
+    /// The cgroup controllers available to task cgroups on this host
+    controllers: BTreeSet<Controller>,
+    /// Admission control of tasks against the host's budget.
+    /// To avoid dead locks always lock `capacity` before taking any lock into `task_map`
+    capacity: Capacity,
+    /// What tasks without CPU or memory constraints are charged
+    default_task: Resources,
+    quotas: Quotas,
+    /// Decides what each client may do, shared with the authorization interceptor
+    policy: Arc<Policy>,
+    seccomp: SeccompProfiles,
+    /// The privileges every task is confined to, the seccomp profile is chosen per task
+    isolation: Isolation,
+    /// Present when tasks are mapped to their own subordinate IDs
+    subids: Option<SubIds>,
+    mounts: MountConfig,
+    images: Images,
+    /// Records queued tasks once they're admitted, which happens outside of any call
+    audit: Option<Arc<AuditLog>>,
+    /// Starts the processes of admitted tasks, without one tasks are only scheduled
+    worker: Option<Worker>,
 }
 
-const ADMIN_GROUP: &str = "admin";
+/// Number of cores on the daemon host, CPU constraints are relative to this
+fn host_cpus() -> u64 {
+    std::thread::available_parallelism().map_or(1, |n| n.get() as u64)
+}
 
 impl SchedulerServer {
-    fn verify_task_access(&self, auth: &ClientAuth, uuid: &Uuid) -> bool {
-        if auth.group == ADMIN_GROUP {
-            return true;
+    /// Create a scheduler and set up the parent cgroup of all tasks.
+    /// Controllers that can't be enabled are logged and constraints needing them are refused.
+    pub fn new(config: &Config, policy: Arc<Policy>) -> anyhow::Result<Self> {
+        let controllers = Cgroup::setup_parent().unwrap_or_else(|e| {
+            tracing::error!(
+                "Failed to setup cgroups, resource constraints are unavailable: {:?}",
+                e
+            );
+            BTreeSet::new()
+        });
+
+        let unavailable = Controller::ALL
+            .iter()
+            .filter(|c| !controllers.contains(c))
+            .map(Controller::name)
+            .collect::<Vec<_>>();
+        if !unavailable.is_empty() {
+            tracing::warn!("Unavailable cgroup controllers: {}", unavailable.join(", "));
+        }
+
+        let subids = SubIds::load(&config.subids).context("Invalid subids config")?;
+        if let Some(subids) = &subids {
+            if config.isolation.uid.max(config.isolation.gid) >= subids.size() {
+                anyhow::bail!(
+                    "The isolation uid and gid must be below the subids range_size of {}",
+                    subids.size()
+                );
+            }
         }
-        if let Some(set) = self.client_tasks.get(&auth.id) {
-            set.contains(uuid)
+
+        Ok(Self {
+            controllers,
+            capacity: Capacity::new(config.capacity.budget()),
+            default_task: config.capacity.default_task(),
+            quotas: Quotas::new(config.quotas.clone()),
+            policy,
+            seccomp: SeccompProfiles::load(&config.seccomp).context("Invalid seccomp config")?,
+            isolation: Isolation::from_config(&config.isolation)
+                .context("Invalid isolation config")?,
+            subids,
+            mounts: config.mounts.clone(),
+            images: Images::new(&config.images).context("Invalid images config")?,
+            worker: Some(Worker::new(Cgroup::parent())),
+            ..Default::default()
+        })
+    }
+
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Translate the requested constraints and ensure the host can enforce them
+    fn limits(&self, constraints: Option<&ResourceConstraints>) -> Result<Limits, Status> {
+        let limits = match constraints {
+            Some(c) => constraints::to_limits(c, host_cpus())?,
+            None => Limits::default(),
+        };
+
+        let missing = limits
+            .required_controllers()
+            .into_iter()
+            .filter(|c| !self.controllers.contains(c))
+            .map(|c| c.name())
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            Ok(limits)
         } else {
-            false
+            Err(Status::failed_precondition(format!(
+                "The requested constraints need cgroup controllers that are unavailable on the host: {}",
+                missing.join(", ")
+            )))
         }
     }
 
-    /// Returns an iterator over a specific user's tasks.
-    #[allow(dead_code)]
+    /// Returns an iterator over the tasks visible to a specific user.
     fn iter_tasks<'a>(
         &'a self,
         auth: &ClientAuth,
     ) -> impl Iterator<Item = Ref<'a, Uuid, Task>> + 'a {
-        //We don't want to hold locks into task_map or client_tasks
-        //for longer than necessary so collect/clone when needed.
+        //We don't want to hold locks into task_map
+        //for longer than necessary so collect when needed.
         //This means the iterator won't see new tasks spawned
         //while iterating but that's ok
-        let tasks = if auth.group == ADMIN_GROUP {
-            self.task_map
-                .iter()
-                .map(|ent| *ent.key())
-                .collect::<Vec<_>>()
-        } else {
-            self.client_tasks
-                .get(&auth.id)
-                .map(|e| e.clone())
-                .unwrap_or_default()
-                .into_iter()
-                .collect::<Vec<_>>()
-        };
+        let grants = self.policy.grants(auth);
+        let tasks = self
+            .task_map
+            .iter()
+            .filter(|ent| may_see(&grants, auth, &ent.ownership))
+            .map(|ent| *ent.key())
+            .collect::<Vec<_>>();
 
         tasks
             .into_iter()
             .flat_map(move |uuid| self.task_map.get(&uuid))
     }
 
-    /// Lookup a task based on it's handle while respecting the provided authorization
-    fn lookup_task(&self, auth: &ClientAuth, uuid: &Uuid) -> Result<Ref<'_, Uuid, Task>, Status> {
-        let task = self
-            .task_map
-            .get(uuid)
-            .ok_or_else(|| Status::invalid_argument("Invalid task handle"))?;
+    /// The single place RPCs resolve task handles through. A task that doesn't exist and
+    /// one the client may not access both fail with the same NOT_FOUND so clients can't
+    /// probe for other clients' tasks. To not give it away through timing either, the
+    /// access check runs against a placeholder for missing tasks so both take the same path.
+    fn resolve_task<R: Deref<Target = Task>>(
+        &self,
+        uuid: &str,
+        get: impl FnOnce(&Uuid) -> Option<R>,
+        allowed: impl FnOnce(&Ownership) -> bool,
+    ) -> Result<R, Status> {
+        let uuid = string_to_uuid(uuid)?;
+        let task = get(&uuid);
+        let placeholder = Ownership::default();
+        let allowed = allowed(task.as_deref().map_or(&placeholder, |t| &t.ownership));
 
-        if self.verify_task_access(auth, uuid) {
-            Ok(task)
-        } else {
-            Err(Status::invalid_argument("Invalid task handle"))
+        match task {
+            Some(task) if allowed => Ok(task),
+            _ => Err(task_not_found()),
         }
     }
+
+    /// Lookup a task the client may use `permission` on by its handle
+    fn lookup_task(
+        &self,
+        auth: &ClientAuth,
+        uuid: &str,
+        permission: Permission,
+    ) -> Result<Ref<'_, Uuid, Task>, Status> {
+        let grants = self.policy.grants(auth);
+        self.resolve_task(
+            uuid,
+            |uuid| self.task_map.get(uuid),
+            |ownership| may_use(&grants, auth, ownership, permission),
+        )
+    }
+
     /// Same as `lookup_task` but mut
     fn lookup_task_mut(
         &self,
         auth: &ClientAuth,
-        uuid: &Uuid,
+        uuid: &str,
+        permission: Permission,
     ) -> Result<RefMut<'_, Uuid, Task>, Status> {
-        let task = self
-            .task_map
-            .get_mut(uuid)
-            .ok_or_else(|| Status::invalid_argument("Invalid task handle"))?;
-
-        if self.verify_task_access(auth, uuid) {
-            Ok(task)
-        } else {
-            Err(Status::invalid_argument("Invalid task handle"))
-        }
+        let grants = self.policy.grants(auth);
+        self.resolve_task(
+            uuid,
+            |uuid| self.task_map.get_mut(uuid),
+            |ownership| may_use(&grants, auth, ownership, permission),
+        )
     }
 
     fn new_task(
         &self,
         auth: &ClientAuth,
-        _cmd: &str,
-        _args: &[String],
+        request: &StartTaskRequest,
     ) -> Result<Ref<'_, Uuid, Task>, Status> {
-        let ent = self
+        let grants = self.policy.grants(auth);
+        if !grants.has(Permission::Start) {
+            return Err(Status::permission_denied("Starting tasks isn't permitted"));
+        }
+        let image = self.image(&grants, request)?;
+        let process = Process::resolve(request, &image)?;
+        authorize_command(&grants, &process)?;
+        let mut isolation = self.isolation(auth, &grants, request, image)?;
+        let sharing = Sharing::from_proto(request.sharing.as_ref())?;
+        let limits = self.limits(request.constraints.as_ref())?;
+        let resources = Resources::from_limits(&limits, self.default_task);
+        let uuid = Uuid::new_v4();
+
+        self.quotas
+            .charge(uuid, auth, resources, self.log_usage(auth))?;
+        //tasks joining a peer use the IDs of its user namespace
+        if let (Some(subids), None) = (&self.subids, &isolation.namespaces.peer) {
+            isolation.id_mapping = match subids.allocate(uuid) {
+                Ok(mapping) => Some(mapping),
+                Err(status) => {
+                    self.quotas.release(&uuid);
+                    return Err(status);
+                }
+            };
+        }
+
+        //hold the capacity lock until the task is in the map so
+        //a concurrent `finish_task` can't admit it before it exists
+        let mut capacity = self.capacity.lock();
+        let status = match capacity.admit(uuid, resources, request.wait_for_capacity) {
+            Ok(Admission::Admitted) => TaskStatus::TaskRunning,
+            Ok(Admission::Queued) => TaskStatus::TaskPending,
+            Err(status) => {
+                self.quotas.release(&uuid);
+                self.release_ids(&uuid);
+                return Err(status);
+            }
+        };
+        let cgroup = match &self.worker {
+            Some(worker) => worker.cgroup(&uuid),
+            None => Cgroup::for_task(&uuid.to_string()),
+        };
+        self.task_map.entry(uuid).or_insert_with(|| {
+            let ownership = Ownership::new(auth, sharing);
+            Task::new(cgroup, ownership, limits, isolation, process, status)
+        });
+        drop(capacity);
+
+        if status == TaskStatus::TaskRunning {
+            if let Err(e) = self.start(&uuid) {
+                self.task_map.remove(&uuid);
+                self.finish_task(&uuid);
+                return Err(Status::internal(format!(
+                    "Failed to start the task: {:#}",
+                    e
+                )));
+            }
+        }
+
+        self.task_map.get(&uuid).ok_or_else(task_not_found)
+    }
+
+    /// Spawn the process of an admitted task.
+    /// Must not be called while holding a lock into `task_map`.
+    fn start(&self, uuid: &Uuid) -> anyhow::Result<()> {
+        let (cgroup, limits, isolation, process, log) = {
+            let mut task = self.task_map.get_mut(uuid).context("The task is gone")?;
+            let log = task
+                .log_writer
+                .take()
+                .context("The task was started before")?;
+            (
+                task.cgroup.clone(),
+                task.limits.clone(),
+                task.isolation.clone(),
+                task.process.clone(),
+                log,
+            )
+        };
+        let worker = match &self.worker {
+            Some(worker) => worker,
+            //nothing will ever write to the log
+            None => return Ok(()),
+        };
+        let peer = match &isolation.namespaces.peer {
+            Some(peer) => match self.task_map.get(&peer.task).and_then(|peer| peer.pid) {
+                Some(pid) => Some(pid),
+                None => {
+                    let e = "The task's peer isn't running anymore";
+                    log.write((format!("{}\n", e), OutputStream::Stderr));
+                    anyhow::bail!(e);
+                }
+            },
+            None => None,
+        };
+
+        let pid = worker.spawn(&cgroup, &limits, isolation, &process, peer, log)?;
+        let mut task = self.task_map.get_mut(uuid).context("The task is gone")?;
+        task.pid = Some(pid);
+        //stopped while it was being spawned, the reaper cleans up after it
+        if task.status == TaskStatus::TaskKilled {
+            worker::kill(pid, &task.cgroup);
+        }
+        Ok(())
+    }
+
+    fn release_ids(&self, uuid: &Uuid) {
+        if let Some(subids) = &self.subids {
+            subids.release(uuid);
+        }
+    }
+
+    /// Look up the requested image and check the client's roles allow it
+    fn image(&self, grants: &Grants, request: &StartTaskRequest) -> Result<Image, Status> {
+        let image = self.images.resolve(&request.image);
+        if !grants.image_allowed(image) {
+            return Err(Status::permission_denied(match image {
+                "" => "Starting tasks without an image isn't permitted".to_owned(),
+                image => format!("Image '{}' isn't permitted", image),
+            }));
+        }
+        self.images.get(image)
+    }
+
+    /// Resolve how the requested task is confined and check the client's roles allow it
+    fn isolation(
+        &self,
+        auth: &ClientAuth,
+        grants: &Grants,
+        request: &StartTaskRequest,
+        image: Image,
+    ) -> Result<Isolation, Status> {
+        let (profile, seccomp) = self.seccomp.get(&request.seccomp_profile).ok_or_else(|| {
+            Status::invalid_argument(format!(
+                "Unknown seccomp profile '{}'",
+                request.seccomp_profile
+            ))
+        })?;
+        if !grants.seccomp_profile_allowed(profile) {
+            return Err(Status::permission_denied(format!(
+                "Seccomp profile '{}' isn't permitted",
+                profile
+            )));
+        }
+
+        let user = match (request.user.as_str(), image.config.user.as_deref()) {
+            ("", None) | ("", Some("")) => None,
+            ("", Some(user)) | (user, _) => Some(user),
+        };
+        let (uid, gid) = match user {
+            Some(user) => process::user(&image.rootfs, user)?,
+            None => (self.isolation.uid, self.isolation.gid),
+        };
+        if let (Some(subids), Some(user)) = (&self.subids, user) {
+            if uid.as_raw().max(gid.as_raw()) >= subids.size() {
+                return Err(Status::invalid_argument(format!(
+                    "User '{}' is outside of the task's {} IDs",
+                    user,
+                    subids.size()
+                )));
+            }
+        }
+
+        let namespaces = match &request.namespaces {
+            Some(options) => self.namespaces(auth, grants, options)?,
+            None => self.isolation.namespaces.clone(),
+        };
+
+        Ok(Isolation {
+            root: image.rootfs,
+            uid,
+            gid,
+            seccomp,
+            namespaces,
+            mounts: self.mounts.mounts(&request.mounts)?,
+            read_only_root: request.read_only_root,
+            ..self.isolation.clone()
+        })
+    }
+
+    /// Remove the namespaces shared with the host or joined from a peer task from the default set
+    fn namespaces(
+        &self,
+        auth: &ClientAuth,
+        grants: &Grants,
+        options: &NamespaceOptions,
+    ) -> Result<Namespaces, Status> {
+        let mut namespaces = self.isolation.namespaces.clone();
+        let unshareable = |ns: Namespace| {
+            Status::invalid_argument(format!("The {} namespace can't be shared", ns))
+        };
+
+        for name in &options.host {
+            let ns = name
+                .parse::<Namespace>()
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            if ns == Namespace::User || ns == Namespace::Mnt {
+                return Err(unshareable(ns));
+            }
+            if !grants.host_namespace_allowed(ns.name()) {
+                return Err(Status::permission_denied(format!(
+                    "Sharing the host's {} namespace isn't permitted",
+                    ns
+                )));
+            }
+            namespaces.new.remove(&ns);
+        }
+
+        let joins = [
+            (Namespace::Net, &options.join_net_task),
+            (Namespace::Ipc, &options.join_ipc_task),
+        ];
+        for (ns, handle) in joins.iter().filter(|(_, handle)| !handle.is_empty()) {
+            let task = self.lookup_task(auth, handle, Permission::JoinNamespaces)?;
+            //a pending task has no namespaces yet and a finished one none anymore
+            if task.status != TaskStatus::TaskRunning {
+                return Err(Status::failed_precondition(format!(
+                    "Task {} isn't running",
+                    handle
+                )));
+            }
+            if !namespaces.new.remove(ns) {
+                return Err(Status::invalid_argument(format!(
+                    "The {} namespace can't be both joined and shared with the host",
+                    ns
+                )));
+            }
+            let peer = namespaces.peer.get_or_insert_with(|| Peer {
+                task: *task.key(),
+                namespaces: Default::default(),
+            });
+            if peer.task != *task.key() {
+                return Err(Status::invalid_argument(
+                    "Joined namespaces must belong to the same task",
+                ));
+            }
+            peer.namespaces.insert(*ns);
+        }
+        if namespaces.peer.is_some() {
+            namespaces.new.remove(&Namespace::User);
+        }
+
+        Ok(namespaces)
+    }
+
+    /// Log bytes retained by the client's unfinished tasks and by those of its groups
+    fn log_usage(&self, auth: &ClientAuth) -> LogUsage {
+        let groups = auth.qualified_groups();
+        self.task_map
+            .iter()
+            .fold(LogUsage::default(), |mut usage, task| {
+                let bytes = task.quota_log_bytes();
+                if task.ownership.is_owner(auth) {
+                    usage.client += bytes;
+                }
+                for group in &groups {
+                    if task.ownership.groups.contains(group) {
+                        *usage.groups.entry(group.clone()).or_default() += bytes;
+                    }
+                }
+                usage
+            })
+    }
+
+    /// Release the capacity and quota of a stopped task and start any queued tasks that now fit.
+    /// Must not be called while holding a lock into `task_map`.
+    fn finish_task(&self, uuid: &Uuid) {
+        self.quotas.release(uuid);
+        self.release_ids(uuid);
+        let mut events = Vec::new();
+        let mut started = Vec::new();
+        let mut capacity = self.capacity.lock();
+        for admitted in capacity.release(uuid) {
+            if let Some(mut task) = self.task_map.get_mut(&admitted) {
+                //the task may have been stopped while we waited for the lock
+                if task.status == TaskStatus::TaskPending {
+                    task.status = TaskStatus::TaskRunning;
+                    started.push(admitted);
+                    let mut event = AuditEvent {
+                        rpc: "TaskAdmitted".into(),
+                        principal: Some(task.ownership.owner.to_string()),
+                        task: Some(admitted.to_string()),
+                        cmd: Some(task.process.cmd.clone()),
+                        args: task.process.args.clone(),
+                        ..Default::default()
+                    };
+                    event.finish(tonic::Code::Ok, None);
+                    events.push(event);
+                }
+            }
+        }
+        drop(capacity);
+
+        if let Some(audit) = &self.audit {
+            for event in &events {
+                audit.record(event);
+            }
+        }
+
+        for admitted in started {
+            if let Err(e) = self.start(&admitted) {
+                tracing::warn!("Failed to start task {}: {:?}", admitted, e);
+                let failed = match self.task_map.get_mut(&admitted) {
+                    Some(mut task) if task.status == TaskStatus::TaskRunning => {
+                        task.status = TaskStatus::TaskCompleted;
+                        task.code = START_FAILED_CODE;
+                        true
+                    }
+                    _ => false,
+                };
+                if failed {
+                    self.finish_task(&admitted);
+                }
+            }
+        }
+    }
+
+    /// Reap the processes of tasks that exited and finish the tasks.
+    /// SIGCHLD only tells some child exited, so every task with a process is checked.
+    pub async fn reap_loop(&self, mut exits: Signal) -> anyhow::Result<()> {
+        loop {
+            self.reap();
+            exits
+                .recv()
+                .await
+                .context("SIGCHLD can't be received anymore")?;
+        }
+    }
+
+    fn reap(&self) {
+        let exited = self
             .task_map
-            .entry(Uuid::new_v4())
-            .or_insert_with(Task::new);
+            .iter_mut()
+            .filter_map(|mut task| {
+                let code = worker::try_wait(task.pid?)?;
+                task.pid = None;
+                //a stopped task was finished when it was stopped
+                let finish = task.status == TaskStatus::TaskRunning;
+                if finish {
+                    task.status = TaskStatus::TaskCompleted;
+                    task.code = code;
+                }
+                Some((*task.key(), task.cgroup.clone(), finish))
+            })
+            .collect::<Vec<_>>();
+
+        for (uuid, cgroup, finish) in exited {
+            if let Err(e) = cgroup.remove() {
+                tracing::warn!("Failed to remove the cgroup of task {}: {:?}", uuid, e);
+            }
+            if finish {
+                self.finish_task(&uuid);
+            }
+        }
+    }
+}
 
-        self.client_tasks
-            .entry(auth.id.clone())
-            .or_default()
-            .insert(*ent.key());
+/// Check the client's roles grant `permission` and that it applies to the task, either
+/// because the client owns it, it's shared with the client or the permission covers all tasks
+fn may_use(
+    grants: &Grants,
+    auth: &ClientAuth,
+    ownership: &Ownership,
+    permission: Permission,
+) -> bool {
+    grants.has(permission)
+        && (grants.applies_to_all_tasks(permission) || ownership.has_access(auth))
+}
+
+/// Whether the client may know a task exists, i.e. whether it's listed to the client
+fn may_see(grants: &Grants, auth: &ClientAuth, ownership: &Ownership) -> bool {
+    grants.may_see_all_tasks() || ownership.has_access(auth)
+}
 
-        //todo hookup worker
+/// The error for tasks that don't exist as well as for tasks the client may not access
+fn task_not_found() -> Status {
+    Status::not_found("Task not found")
+}
 
-        Ok(ent.downgrade())
+/// Check the client's roles allow it to run the task's program
+fn authorize_command(grants: &Grants, process: &Process) -> Result<(), Status> {
+    if !grants.command_allowed(&process.cmd) {
+        return Err(Status::permission_denied(format!(
+            "Starting '{}' isn't permitted",
+            process.cmd
+        )));
     }
+    Ok(())
 }
 
 fn request_to_auth<T>(req: &tonic::Request<T>) -> Result<&ClientAuth, Status> {
@@ -135,12 +679,92 @@ fn request_to_auth<T>(req: &tonic::Request<T>) -> Result<&ClientAuth, Status> {
         .ok_or_else(|| Status::internal("Missing ClientAuth extension"))
 }
 
+fn quota_entry_to_proto(entry: QuotaEntry) -> rrocker_lib::api::QuotaEntry {
+    rrocker_lib::api::QuotaEntry {
+        name: entry.name,
+        limits: Some(QuotaLimits {
+            max_tasks: entry.quota.max_tasks,
+            cpu_millicores: entry.quota.cpu_millicores,
+            memory_bytes: entry.quota.memory_bytes,
+            log_bytes: entry.quota.log_bytes,
+        }),
+        usage: Some(QuotaUsage {
+            tasks: entry.usage.tasks,
+            cpu_millicores: entry.usage.cpu_millicores,
+            memory_bytes: entry.usage.memory_bytes,
+            log_bytes: entry.usage.log_bytes,
+        }),
+    }
+}
+
 fn string_to_uuid(uuid_string: &str) -> Result<Uuid, Status> {
     uuid_string
         .parse::<Uuid>()
         .map_err(|_| Status::invalid_argument("TaskHandle.uuid is not a valid UUIDv4"))
 }
 
+/// Exit code of queued tasks that couldn't be started, a shell's for commands it can't run
+const START_FAILED_CODE: i32 = 127;
+/// Default interval between samples of `TaskStatsStream`
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// Lower bound on the interval between samples of `TaskStatsStream` so clients can't busy loop the daemon
+const MIN_STATS_INTERVAL: Duration = Duration::from_millis(100);
+
+fn read_task_stats(uuid: &Uuid, cgroup: &Cgroup) -> Result<TaskStatsReply, Status> {
+    let timestamp = std::time::SystemTime::now();
+    let stats = cgroup.stats().map_err(|e| {
+        tracing::warn!("Failed to read stats of task {}: {:?}", uuid, e);
+        Status::unavailable("Failed to read task resource usage")
+    })?;
+
+    Ok(stats_to_reply(uuid, timestamp, stats))
+}
+
+fn stats_to_reply(
+    uuid: &Uuid,
+    timestamp: std::time::SystemTime,
+    stats: CgroupStats,
+) -> TaskStatsReply {
+    TaskStatsReply {
+        handle: Some(TaskHandle {
+            uuid: uuid.to_string(),
+        }),
+        timestamp: Some(timestamp.into()),
+        cpu: Some(CpuStats {
+            usage_usec: stats.cpu.usage_usec,
+            user_usec: stats.cpu.user_usec,
+            system_usec: stats.cpu.system_usec,
+            nr_periods: stats.cpu.nr_periods,
+            nr_throttled: stats.cpu.nr_throttled,
+            throttled_usec: stats.cpu.throttled_usec,
+        }),
+        memory: Some(MemoryStats {
+            current_bytes: stats.memory_current,
+            peak_bytes: stats.memory_peak.unwrap_or_default(),
+            low_events: stats.memory_events.low,
+            high_events: stats.memory_events.high,
+            max_events: stats.memory_events.max,
+            oom_events: stats.memory_events.oom,
+            oom_kill_events: stats.memory_events.oom_kill,
+        }),
+        pids: stats.pids_current.map(|current| PidsStats { current }),
+        io: stats
+            .io
+            .into_iter()
+            .map(|io| IoDeviceStats {
+                major: io.major,
+                minor: io.minor,
+                read_bytes: io.rbytes,
+                write_bytes: io.wbytes,
+                read_ios: io.rios,
+                write_ios: io.wios,
+                discard_bytes: io.dbytes,
+                discard_ios: io.dios,
+            })
+            .collect(),
+    }
+}
+
 #[tonic::async_trait]
 impl Scheduler for SchedulerServer {
     #[tracing::instrument]
@@ -148,9 +772,20 @@ impl Scheduler for SchedulerServer {
         &self,
         request: tonic::Request<StartTaskRequest>,
     ) -> Result<Response<StartTaskReply>, Status> {
+        AuditContext::annotate(&request, |e| {
+            let data = request.get_ref();
+            e.cmd = Some(data.cmd.clone());
+            e.args = data.args.clone();
+            e.constraints = data.constraints.clone();
+        });
         let auth = request_to_auth(&request)?;
-        let data = request.get_ref();
-        let task = self.new_task(auth, &data.cmd, &data.args[..])?;
+        let task = self.new_task(auth, request.get_ref())?;
+        //the command may come from the image
+        AuditContext::annotate(&request, |e| {
+            e.task = Some(task.key().to_string());
+            e.cmd = Some(task.process.cmd.clone());
+            e.args = task.process.args.clone();
+        });
 
         Ok(Response::new(StartTaskReply {
             handle: Some(TaskHandle {
@@ -161,12 +796,30 @@ impl Scheduler for SchedulerServer {
 
     #[tracing::instrument]
     async fn stop_task(&self, request: tonic::Request<TaskHandle>) -> Result<Response<()>, Status> {
+        AuditContext::annotate(&request, |e| e.task = Some(request.get_ref().uuid.clone()));
         let auth = request_to_auth(&request)?;
-        let uuid = string_to_uuid(&request.get_ref().uuid)?;
 
-        let _task = self.lookup_task_mut(auth, &uuid)?;
+        let uuid = {
+            let mut task = self.lookup_task_mut(auth, &request.get_ref().uuid, Permission::Stop)?;
+            match task.status {
+                TaskStatus::TaskCompleted | TaskStatus::TaskKilled => {
+                    return Err(Status::failed_precondition("Task is already dead"))
+                }
+                TaskStatus::TaskRunning | TaskStatus::TaskPending => {
+                    task.status = TaskStatus::TaskKilled;
+                    //a queued task never got to write to it
+                    task.log_writer = None;
+                    //reaped and its cgroup removed once it's dead
+                    if let Some(pid) = task.pid {
+                        worker::kill(pid, &task.cgroup);
+                    }
+                }
+            }
+            *task.key()
+        };
+        self.finish_task(&uuid);
 
-        todo!()
+        Ok(Response::new(()))
     }
 
     #[tracing::instrument]
@@ -174,16 +827,46 @@ impl Scheduler for SchedulerServer {
         &self,
         request: tonic::Request<TaskHandle>,
     ) -> Result<Response<QueryTaskReply>, Status> {
+        AuditContext::annotate(&request, |e| e.task = Some(request.get_ref().uuid.clone()));
         let auth = request_to_auth(&request)?;
-        let uuid = string_to_uuid(&request.get_ref().uuid)?;
-
-        let _task = self.lookup_task(auth, &uuid)?;
+        let task = self.lookup_task(auth, &request.get_ref().uuid, Permission::ReadOutput)?;
 
         Ok(Response::new(QueryTaskReply {
             state: Some(TaskState {
-                status: TaskStatus::TaskRunning.into(),
-                code: 0,
+                status: task.status.into(),
+                code: task.code,
             }),
+            owner: task.ownership.owner.to_string(),
+            sharing: Some(task.ownership.sharing.to_proto()),
+        }))
+    }
+
+    #[tracing::instrument]
+    async fn list_tasks(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<Response<ListTasksReply>, Status> {
+        let auth = request_to_auth(&request)?;
+        if !self.policy.grants(auth).has(Permission::ReadOutput) {
+            return Err(Status::permission_denied(
+                "Listing tasks requires the read-output permission",
+            ));
+        }
+
+        Ok(Response::new(ListTasksReply {
+            tasks: self
+                .iter_tasks(auth)
+                .map(|task| TaskListEntry {
+                    handle: Some(TaskHandle {
+                        uuid: task.key().to_string(),
+                    }),
+                    state: Some(TaskState {
+                        status: task.status.into(),
+                        code: task.code,
+                    }),
+                    owner: task.ownership.owner.to_string(),
+                })
+                .collect(),
         }))
     }
 
@@ -195,9 +878,9 @@ impl Scheduler for SchedulerServer {
         &self,
         request: tonic::Request<TaskHandle>,
     ) -> Result<Response<Self::TaskOutputStreamStream>, Status> {
+        AuditContext::annotate(&request, |e| e.task = Some(request.get_ref().uuid.clone()));
         let auth = request_to_auth(&request)?;
-        let uuid = string_to_uuid(&request.get_ref().uuid)?;
-        let task = self.lookup_task(auth, &uuid)?;
+        let task = self.lookup_task(auth, &request.get_ref().uuid, Permission::ReadOutput)?;
 
         let log_stream = task.log_subscribe().into_stream().map(|arc| {
             let (line, output) = arc.as_ref();
@@ -209,75 +892,598 @@ impl Scheduler for SchedulerServer {
 
         Ok(Response::new(Box::pin(log_stream)))
     }
+
+    #[tracing::instrument]
+    async fn task_stats(
+        &self,
+        request: tonic::Request<TaskHandle>,
+    ) -> Result<Response<TaskStatsReply>, Status> {
+        AuditContext::annotate(&request, |e| e.task = Some(request.get_ref().uuid.clone()));
+        let auth = request_to_auth(&request)?;
+        let task = self.lookup_task(auth, &request.get_ref().uuid, Permission::ReadOutput)?;
+
+        Ok(Response::new(read_task_stats(task.key(), &task.cgroup)?))
+    }
+
+    type TaskStatsStreamStream =
+        Pin<Box<dyn Stream<Item = Result<TaskStatsReply, Status>> + Send + Sync + 'static>>;
+
+    #[tracing::instrument]
+    async fn task_stats_stream(
+        &self,
+        request: tonic::Request<TaskStatsStreamRequest>,
+    ) -> Result<Response<Self::TaskStatsStreamStream>, Status> {
+        let auth = request_to_auth(&request)?;
+        let data = request.get_ref();
+        let handle = data
+            .handle
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("Missing task handle"))?;
+        AuditContext::annotate(&request, |e| e.task = Some(handle.uuid.clone()));
+        //only clone the cgroup handle so we don't hold a lock into task_map while streaming
+        let (uuid, cgroup) = {
+            let task = self.lookup_task(auth, &handle.uuid, Permission::ReadOutput)?;
+            (*task.key(), task.cgroup.clone())
+        };
+
+        let interval = match data.interval_ms {
+            0 => DEFAULT_STATS_INTERVAL,
+            ms => Duration::from_millis(ms.into()).max(MIN_STATS_INTERVAL),
+        };
+
+        let stats_stream = async_stream::stream! {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                //the cgroup is removed once the task exits which ends the stream
+                if !cgroup.path().exists() {
+                    break;
+                }
+                let res = read_task_stats(&uuid, &cgroup);
+                let failed = res.is_err();
+                yield res;
+                if failed {
+                    break;
+                }
+            }
+        };
+
+        Ok(Response::new(Box::pin(stats_stream)))
+    }
+
+    #[tracing::instrument]
+    async fn list_quotas(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<Response<ListQuotasReply>, Status> {
+        let auth = request_to_auth(&request)?;
+        if !self.policy.grants(auth).has(Permission::AdminOps) {
+            return Err(Status::permission_denied(
+                "Listing quotas requires an admin",
+            ));
+        }
+
+        //sum up the log bytes up front so we don't hold locks into task_map and quotas at once
+        let mut client_logs = HashMap::<Principal, u64>::new();
+        let mut group_logs = HashMap::<Group, u64>::new();
+        for task in self.task_map.iter() {
+            let bytes = task.quota_log_bytes();
+            *client_logs.entry(task.ownership.owner.clone()).or_default() += bytes;
+            for group in &task.ownership.groups {
+                *group_logs.entry(group.clone()).or_default() += bytes;
+            }
+        }
+
+        let (clients, groups) = self.quotas.report(
+            |client| client_logs.get(client).copied().unwrap_or_default(),
+            |group| group_logs.get(group).copied().unwrap_or_default(),
+        );
+
+        Ok(Response::new(ListQuotasReply {
+            clients: clients.into_iter().map(quota_entry_to_proto).collect(),
+            groups: groups.into_iter().map(quota_entry_to_proto).collect(),
+        }))
+    }
+
+    #[tracing::instrument]
+    async fn list_images(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<Response<ListImagesReply>, Status> {
+        let auth = request_to_auth(&request)?;
+        let grants = self.policy.grants(auth);
+        if !grants.has(Permission::Start) {
+            return Err(Status::permission_denied(
+                "Listing images requires the start permission",
+            ));
+        }
+
+        let images = self.images.list().map_err(|e| {
+            tracing::error!("Failed to list images: {:?}", e);
+            Status::unavailable("Failed to list images")
+        })?;
+        Ok(Response::new(ListImagesReply {
+            images: images
+                .into_iter()
+                .filter(|image| grants.image_allowed(&image.name))
+                .map(|image| rrocker_lib::api::Image { name: image.name })
+                .collect(),
+            default_image: self.images.default_image().to_owned(),
+        }))
+    }
+
+    #[tracing::instrument]
+    async fn import_image(
+        &self,
+        request: tonic::Request<tonic::Streaming<ImportImageRequest>>,
+    ) -> Result<Response<ImportImageReply>, Status> {
+        let auth = request_to_auth(&request)?;
+        if !self.policy.grants(auth).has(Permission::AdminOps) {
+            return Err(Status::permission_denied(
+                "Importing images requires an admin",
+            ));
+        }
+
+        let (image, digest) = self.images.import(request.into_inner()).await?;
+        Ok(Response::new(ImportImageReply {
+            name: image.name,
+            digest,
+        }))
+    }
+
+    #[tracing::instrument]
+    async fn set_task_sharing(
+        &self,
+        request: tonic::Request<SetTaskSharingRequest>,
+    ) -> Result<Response<()>, Status> {
+        let auth = request_to_auth(&request)?;
+        let data = request.get_ref();
+        let handle = data
+            .handle
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("Missing task handle"))?;
+        AuditContext::annotate(&request, |e| e.task = Some(handle.uuid.clone()));
+        let sharing = Sharing::from_proto(data.sharing.as_ref())?;
+
+        //clients that can see the task learn nothing new from being denied
+        let grants = self.policy.grants(auth);
+        let mut task = self.resolve_task(
+            &handle.uuid,
+            |uuid| self.task_map.get_mut(uuid),
+            |ownership| may_see(&grants, auth, ownership),
+        )?;
+
+        if task.ownership.is_owner(auth) || grants.has(Permission::AdminOps) {
+            task.ownership.sharing = sharing;
+            Ok(Response::new(()))
+        } else {
+            Err(Status::permission_denied(
+                "Only the task's owner can change who it's shared with",
+            ))
+        }
+    }
+}
+
+/// The scheduler as it's served, shared with the loop reaping its tasks
+#[derive(Debug, Clone)]
+pub struct SharedScheduler(pub Arc<SchedulerServer>);
+
+#[tonic::async_trait]
+impl Scheduler for SharedScheduler {
+    async fn start_task(
+        &self,
+        request: tonic::Request<StartTaskRequest>,
+    ) -> Result<Response<StartTaskReply>, Status> {
+        self.0.start_task(request).await
+    }
+
+    async fn stop_task(&self, request: tonic::Request<TaskHandle>) -> Result<Response<()>, Status> {
+        self.0.stop_task(request).await
+    }
+
+    async fn query_task(
+        &self,
+        request: tonic::Request<TaskHandle>,
+    ) -> Result<Response<QueryTaskReply>, Status> {
+        self.0.query_task(request).await
+    }
+
+    async fn list_tasks(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<Response<ListTasksReply>, Status> {
+        self.0.list_tasks(request).await
+    }
+
+    type TaskOutputStreamStream = <SchedulerServer as Scheduler>::TaskOutputStreamStream;
+
+    async fn task_output_stream(
+        &self,
+        request: tonic::Request<TaskHandle>,
+    ) -> Result<Response<Self::TaskOutputStreamStream>, Status> {
+        self.0.task_output_stream(request).await
+    }
+
+    async fn task_stats(
+        &self,
+        request: tonic::Request<TaskHandle>,
+    ) -> Result<Response<TaskStatsReply>, Status> {
+        self.0.task_stats(request).await
+    }
+
+    type TaskStatsStreamStream = <SchedulerServer as Scheduler>::TaskStatsStreamStream;
+
+    async fn task_stats_stream(
+        &self,
+        request: tonic::Request<TaskStatsStreamRequest>,
+    ) -> Result<Response<Self::TaskStatsStreamStream>, Status> {
+        self.0.task_stats_stream(request).await
+    }
+
+    async fn list_quotas(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<Response<ListQuotasReply>, Status> {
+        self.0.list_quotas(request).await
+    }
+
+    async fn list_images(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<Response<ListImagesReply>, Status> {
+        self.0.list_images(request).await
+    }
+
+    async fn import_image(
+        &self,
+        request: tonic::Request<tonic::Streaming<ImportImageRequest>>,
+    ) -> Result<Response<ImportImageReply>, Status> {
+        self.0.import_image(request).await
+    }
+
+    async fn set_task_sharing(
+        &self,
+        request: tonic::Request<SetTaskSharingRequest>,
+    ) -> Result<Response<()>, Status> {
+        self.0.set_task_sharing(request).await
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use rrocker_lib::api::{task_sharing, TaskSharing};
+
+    impl SchedulerServer {
+        fn has_access(&self, auth: &ClientAuth, uuid: &Uuid, permission: Permission) -> bool {
+            self.lookup_task(auth, &uuid.to_string(), permission)
+                .is_ok()
+        }
+    }
+
+    /// Wrap a message in a request as the authorization interceptor would
+    fn with_auth<T>(auth: &ClientAuth, message: T) -> tonic::Request<T> {
+        let mut req = tonic::Request::new(message);
+        req.extensions_mut().insert(auth.clone());
+        req
+    }
+
+    fn request(cmd: &str) -> StartTaskRequest {
+        StartTaskRequest {
+            cmd: cmd.into(),
+            ..Default::default()
+        }
+    }
 
     #[test]
     fn test_verify_access() {
         let server = SchedulerServer::default();
         let a1 = ClientAuth {
-            id: "a1".into(),
-            group: ADMIN_GROUP.into(),
+            group: "admin".into(),
+            groups: vec!["admin".into()],
+            ..ClientAuth::new(Principal::CommonName("a1".into()))
         };
         let c1 = ClientAuth {
-            id: "c1".into(),
             group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
         };
         let c2 = ClientAuth {
-            id: "c2".into(),
             group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c2".into()))
         };
 
         //this has to be done in seperate scopes as items might end up in the same bucket and dead lock
-        let k1 = { *server.new_task(&c1, &"asd", &vec![]).unwrap().key() };
-        let k2 = { *server.new_task(&c1, &"foo", &vec![]).unwrap().key() };
-        let k3 = { *server.new_task(&c2, &"bar", &vec![]).unwrap().key() };
-        let k4 = { *server.new_task(&c2, &"dsa", &vec![]).unwrap().key() };
+        let k1 = { *server.new_task(&c1, &request("asd")).unwrap().key() };
+        let k2 = { *server.new_task(&c1, &request("foo")).unwrap().key() };
+        let k3 = { *server.new_task(&c2, &request("bar")).unwrap().key() };
+        let k4 = { *server.new_task(&c2, &request("dsa")).unwrap().key() };
 
         //admin has access to everything
-        assert_eq!(server.verify_task_access(&a1, &k1), true);
-        assert_eq!(server.verify_task_access(&a1, &k2), true);
-        assert_eq!(server.verify_task_access(&a1, &k3), true);
-        assert_eq!(server.verify_task_access(&a1, &k4), true);
+        assert!(server.has_access(&a1, &k1, Permission::Stop));
+        assert!(server.has_access(&a1, &k2, Permission::Stop));
+        assert!(server.has_access(&a1, &k3, Permission::Stop));
+        assert!(server.has_access(&a1, &k4, Permission::Stop));
 
         //c1 has access to his own stuff
-        assert_eq!(server.verify_task_access(&c1, &k1), true);
-        assert_eq!(server.verify_task_access(&c1, &k2), true);
+        assert!(server.has_access(&c1, &k1, Permission::Stop));
+        assert!(server.has_access(&c1, &k2, Permission::Stop));
         //but not c2's tasks
-        assert_eq!(server.verify_task_access(&c1, &k3), false);
-        assert_eq!(server.verify_task_access(&c1, &k4), false);
+        assert!(!server.has_access(&c1, &k3, Permission::Stop));
+        assert!(!server.has_access(&c1, &k4, Permission::Stop));
 
         //and vice versa for c2
-        assert_eq!(server.verify_task_access(&c2, &k1), false);
-        assert_eq!(server.verify_task_access(&c2, &k2), false);
-        assert_eq!(server.verify_task_access(&c2, &k3), true);
-        assert_eq!(server.verify_task_access(&c2, &k4), true);
+        assert!(!server.has_access(&c2, &k1, Permission::Stop));
+        assert!(!server.has_access(&c2, &k2, Permission::Stop));
+        assert!(server.has_access(&c2, &k3, Permission::Stop));
+        assert!(server.has_access(&c2, &k4, Permission::Stop));
+    }
+
+    #[test]
+    fn test_policy_access() {
+        let config: crate::policy::PolicyConfig = toml::from_str(
+            r#"
+            [roles.client]
+            permissions = ["start", "stop", "read-output"]
+            allowed_commands = ["/bin/*"]
+
+            [roles.oncall]
+            permissions = ["start", "stop", "read-output", "list-all"]
+
+            [[bindings]]
+            role = "client"
+            organizations = ["client"]
+
+            [[bindings]]
+            role = "oncall"
+            organizational_units = ["oncall"]
+            "#,
+        )
+        .unwrap();
+        let server = SchedulerServer {
+            policy: Arc::new(Policy::new(config).unwrap()),
+            ..Default::default()
+        };
+        let c1 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+        let oncall = ClientAuth {
+            group: "ops".into(),
+            groups: vec!["ops".into()],
+            org_units: vec!["oncall".into()],
+            ..ClientAuth::new(Principal::CommonName("o1".into()))
+        };
+
+        let status = server
+            .new_task(&c1, &request("/usr/bin/rm"))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let k1 = { *server.new_task(&c1, &request("/bin/ls")).unwrap().key() };
+
+        //on-call can read every task but only stop their own
+        assert!(server.has_access(&oncall, &k1, Permission::ReadOutput));
+        assert!(!server.has_access(&oncall, &k1, Permission::Stop));
+        assert_eq!(server.iter_tasks(&oncall).count(), 1);
+        let k2 = {
+            *server
+                .new_task(&oncall, &request("/usr/bin/rm"))
+                .unwrap()
+                .key()
+        };
+        assert!(server.has_access(&oncall, &k2, Permission::Stop));
+
+        //permissions the roles don't grant are denied even on owned tasks
+        assert!(!server.has_access(&c1, &k1, Permission::JoinNamespaces));
+    }
+
+    #[tokio::test]
+    async fn test_sharing() {
+        let c1 = ClientAuth {
+            group: "team-a".into(),
+            groups: vec!["team-a".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+        let c2 = ClientAuth {
+            group: "team-a".into(),
+            groups: vec!["team-a".into()],
+            ..ClientAuth::new(Principal::CommonName("c2".into()))
+        };
+        let c3 = ClientAuth {
+            group: "team-b".into(),
+            groups: vec!["team-b".into()],
+            ..ClientAuth::new(Principal::CommonName("c3".into()))
+        };
+        //the default policy binds roles by organization, so bind these teams as clients
+        let server = SchedulerServer {
+            policy: Arc::new(
+                Policy::new(crate::policy::PolicyConfig {
+                    bindings: vec![crate::policy::Binding {
+                        role: "client".into(),
+                        organizations: vec!["team-a".into(), "team-b".into()],
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                })
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let sharing = |scope: task_sharing::Scope, principals: Vec<String>| TaskSharing {
+            scope: scope as i32,
+            principals,
+        };
+
+        let k1 = *server
+            .new_task(
+                &c1,
+                &StartTaskRequest {
+                    sharing: Some(sharing(task_sharing::Scope::Group, vec![])),
+                    ..request("asd")
+                },
+            )
+            .unwrap()
+            .key();
+        assert!(server.has_access(&c2, &k1, Permission::Stop));
+        assert!(!server.has_access(&c3, &k1, Permission::ReadOutput));
+        assert_eq!(server.iter_tasks(&c2).count(), 1);
+        assert_eq!(server.iter_tasks(&c3).count(), 0);
+
+        let set = |auth: &ClientAuth, sharing| {
+            with_auth(
+                auth,
+                SetTaskSharingRequest {
+                    handle: Some(TaskHandle {
+                        uuid: k1.to_string(),
+                    }),
+                    sharing: Some(sharing),
+                },
+            )
+        };
+
+        //only the owner may change the sharing, others it's shared with are denied
+        let status = server
+            .set_task_sharing(set(&c2, sharing(task_sharing::Scope::Private, vec![])))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let status = server
+            .set_task_sharing(set(&c3, sharing(task_sharing::Scope::Private, vec![])))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        //hand the task over to c3
+        server
+            .set_task_sharing(set(
+                &c1,
+                sharing(task_sharing::Scope::Principals, vec!["c3".into()]),
+            ))
+            .await
+            .unwrap();
+        assert!(!server.has_access(&c2, &k1, Permission::ReadOutput));
+        assert!(server.has_access(&c3, &k1, Permission::Stop));
+
+        let reply = server
+            .query_task(with_auth(
+                &c3,
+                TaskHandle {
+                    uuid: k1.to_string(),
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(reply.owner, "c1");
+        assert_eq!(
+            reply.sharing,
+            Some(sharing(task_sharing::Scope::Principals, vec!["c3".into()]))
+        );
+    }
+
+    /// The code and message of a failed call
+    fn error<T>(result: Result<T, Status>) -> (tonic::Code, String) {
+        match result {
+            Ok(_) => panic!("Expected the call to fail"),
+            Err(status) => (status.code(), status.message().to_owned()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_uniform_not_found() {
+        let server = SchedulerServer::default();
+        let c1 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+        let c2 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c2".into()))
+        };
+        let k1 = *server.new_task(&c1, &request("asd")).unwrap().key();
+
+        //every RPC taking a handle must fail the same way for c1's task and a missing one
+        let mut probes = Vec::new();
+        for uuid in [k1, Uuid::new_v4()] {
+            let handle = || TaskHandle {
+                uuid: uuid.to_string(),
+            };
+            probes.push(vec![
+                error(server.stop_task(with_auth(&c2, handle())).await),
+                error(server.query_task(with_auth(&c2, handle())).await),
+                error(server.task_output_stream(with_auth(&c2, handle())).await),
+                error(server.task_stats(with_auth(&c2, handle())).await),
+                error(
+                    server
+                        .task_stats_stream(with_auth(
+                            &c2,
+                            TaskStatsStreamRequest {
+                                handle: Some(handle()),
+                                interval_ms: 0,
+                            },
+                        ))
+                        .await,
+                ),
+                error(
+                    server
+                        .set_task_sharing(with_auth(
+                            &c2,
+                            SetTaskSharingRequest {
+                                handle: Some(handle()),
+                                sharing: None,
+                            },
+                        ))
+                        .await,
+                ),
+            ]);
+        }
+
+        assert_eq!(probes[0], probes[1]);
+        assert!(probes[0]
+            .iter()
+            .all(|(code, _)| *code == tonic::Code::NotFound));
+
+        //the owner still gets through
+        server
+            .query_task(with_auth(
+                &c1,
+                TaskHandle {
+                    uuid: k1.to_string(),
+                },
+            ))
+            .await
+            .unwrap();
     }
 
     #[test]
     fn test_task_iter_access() {
         let server = SchedulerServer::default();
         let c1 = ClientAuth {
-            id: "c1".into(),
             group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
         };
         let c2 = ClientAuth {
-            id: "c2".into(),
             group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c2".into()))
         };
         let a1 = ClientAuth {
-            id: "a1".into(),
-            group: ADMIN_GROUP.into(),
+            group: "admin".into(),
+            groups: vec!["admin".into()],
+            ..ClientAuth::new(Principal::CommonName("a1".into()))
         };
 
-        server.new_task(&c1, &"asd", &vec![]).unwrap();
+        server.new_task(&c1, &request("asd")).unwrap();
         assert_eq!(server.iter_tasks(&c1).count(), 1);
         assert_eq!(server.iter_tasks(&c2).count(), 0);
         assert_eq!(server.iter_tasks(&a1).count(), 1);
-        server.new_task(&c2, &"foo", &vec![]).unwrap();
+        server.new_task(&c2, &request("foo")).unwrap();
         assert_eq!(server.iter_tasks(&c1).count(), 1);
         assert_eq!(server.iter_tasks(&c2).count(), 1);
         assert_eq!(server.iter_tasks(&a1).count(), 2);
@@ -287,12 +1493,13 @@ mod test {
     fn test_task_iter() {
         let server = SchedulerServer::default();
         let c1 = ClientAuth {
-            id: "c1".into(),
             group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
         };
 
-        let key1 = *server.new_task(&c1, &"asd", &vec![]).unwrap().key();
-        server.new_task(&c1, &"foo", &vec![]).unwrap();
+        let key1 = *server.new_task(&c1, &request("asd")).unwrap().key();
+        server.new_task(&c1, &request("foo")).unwrap();
 
         let it = server.iter_tasks(&c1);
         server.task_map.remove(&key1);
@@ -302,4 +1509,593 @@ mod test {
         //it tries to lookup a removed task
         assert_eq!(it.count(), 1);
     }
+
+    #[tokio::test]
+    async fn test_list_tasks() {
+        let server = SchedulerServer::default();
+        let c1 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+        let unbound = ClientAuth::new(Principal::CommonName("c2".into()));
+
+        let key = *server.new_task(&c1, &request("asd")).unwrap().key();
+        let tasks = server
+            .list_tasks(with_auth(&c1, ()))
+            .await
+            .unwrap()
+            .into_inner()
+            .tasks;
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].handle.as_ref().unwrap().uuid, key.to_string());
+        assert_eq!(tasks[0].owner, "c1");
+
+        let status = server
+            .list_tasks(with_auth(&unbound, ()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn test_unavailable_controllers() {
+        let server = SchedulerServer {
+            controllers: vec![Controller::Cpu, Controller::Memory]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let c1 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+
+        let constraints = ResourceConstraints {
+            cpu: Some(rrocker_lib::api::resource_constraints::Cpu::CpuMillicores(
+                100,
+            )),
+            memory_max_bytes: Some(1024),
+            ..Default::default()
+        };
+        let req = StartTaskRequest {
+            constraints: Some(constraints),
+            ..request("asd")
+        };
+        assert!(server.new_task(&c1, &req).is_ok());
+
+        let constraints = ResourceConstraints {
+            max_pids: Some(10),
+            cpuset_cpus: "0".into(),
+            ..Default::default()
+        };
+        let req = StartTaskRequest {
+            constraints: Some(constraints),
+            ..request("asd")
+        };
+        let status = server.new_task(&c1, &req).map(|_| ()).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert!(status.message().ends_with("cpuset, pids"));
+    }
+
+    #[test]
+    fn test_seccomp_profile() {
+        let server = SchedulerServer::default();
+        let c1 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+        let req = |profile: &str| StartTaskRequest {
+            seccomp_profile: profile.into(),
+            ..request("asd")
+        };
+
+        let k1 = *server.new_task(&c1, &req("")).unwrap().key();
+        assert!(server
+            .task_map
+            .get(&k1)
+            .unwrap()
+            .isolation
+            .seccomp
+            .is_some());
+        let k2 = *server.new_task(&c1, &req("strict")).unwrap().key();
+        assert_ne!(
+            server.task_map.get(&k1).unwrap().isolation.seccomp,
+            server.task_map.get(&k2).unwrap().isolation.seccomp
+        );
+
+        let status = server
+            .new_task(&c1, &req("missing"))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        //unconfined has to be granted explicitly
+        let status = server
+            .new_task(&c1, &req("unconfined"))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_images() {
+        let dir = std::env::temp_dir().join(format!("rrocker-sched-images-{}", std::process::id()));
+        for image in ["alpine", "debian"] {
+            std::fs::create_dir_all(dir.join(image).join("rootfs")).unwrap();
+        }
+        let config: crate::policy::PolicyConfig = toml::from_str(
+            r#"
+            [roles.client]
+            permissions = ["start"]
+            allowed_images = ["alpine*"]
+
+            [[bindings]]
+            role = "client"
+            organizations = ["client"]
+            "#,
+        )
+        .unwrap();
+        let server = SchedulerServer {
+            policy: Arc::new(Policy::new(config).unwrap()),
+            images: Images::new(&crate::images::ImageConfig {
+                dir: dir.clone(),
+                default_image: Some("alpine".into()),
+                ..Default::default()
+            })
+            .unwrap(),
+            ..Default::default()
+        };
+        let c1 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+        let req = |image: &str| StartTaskRequest {
+            image: image.into(),
+            ..request("/bin/ls")
+        };
+
+        let k1 = *server.new_task(&c1, &req("")).unwrap().key();
+        assert_eq!(
+            server.task_map.get(&k1).unwrap().isolation.root,
+            dir.join("alpine/rootfs")
+        );
+        let code = |image: &str| {
+            server
+                .new_task(&c1, &req(image))
+                .map(|_| ())
+                .unwrap_err()
+                .code()
+        };
+        assert_eq!(code("debian"), tonic::Code::PermissionDenied);
+        assert_eq!(code("alpine-edge"), tonic::Code::NotFound);
+
+        //the image's config fills in what the request leaves out
+        let sh = dir.join("alpine/rootfs/bin/sh");
+        std::fs::create_dir_all(sh.parent().unwrap()).unwrap();
+        std::fs::write(&sh, "").unwrap();
+        std::fs::set_permissions(&sh, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        std::fs::write(
+            dir.join("alpine/config.json"),
+            r#"{"config": {"Cmd": ["sh"], "Env": ["PATH=/bin"], "User": "1000:1000"}}"#,
+        )
+        .unwrap();
+        let k2 = *server
+            .new_task(&c1, &StartTaskRequest::default())
+            .unwrap()
+            .key();
+        let task = server.task_map.get(&k2).unwrap();
+        assert_eq!(task.process.cmd, "/bin/sh");
+        assert_eq!(task.isolation.uid, nix::unistd::Uid::from_raw(1000));
+        drop(task);
+        let root = StartTaskRequest {
+            user: "0".into(),
+            ..req("alpine")
+        };
+        let k3 = *server.new_task(&c1, &root).unwrap().key();
+        assert_eq!(
+            server.task_map.get(&k3).unwrap().isolation.uid,
+            nix::unistd::Uid::from_raw(0)
+        );
+
+        let reply = server
+            .list_images(with_auth(&c1, ()))
+            .await
+            .unwrap()
+            .into_inner();
+        let names = reply.images.into_iter().map(|i| i.name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["alpine"]);
+        assert_eq!(reply.default_image, "alpine");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_admission() {
+        let dir = std::env::temp_dir().join(format!("rrocker-admission-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let audit = AuditLog::new(crate::audit::AuditConfig {
+            path: Some(dir.join("audit.log")),
+            ..Default::default()
+        })
+        .unwrap();
+        let server = SchedulerServer {
+            audit: Some(Arc::new(audit)),
+            controllers: vec![Controller::Cpu].into_iter().collect(),
+            capacity: Capacity::new(Resources {
+                cpu_millicores: 500,
+                memory_bytes: u64::MAX,
+            }),
+            ..Default::default()
+        };
+        let c1 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+        let req = |wait_for_capacity| StartTaskRequest {
+            constraints: Some(ResourceConstraints {
+                cpu: Some(rrocker_lib::api::resource_constraints::Cpu::CpuMillicores(
+                    500,
+                )),
+                ..Default::default()
+            }),
+            wait_for_capacity,
+            ..request("asd")
+        };
+        let status_of = |uuid| server.task_map.get(uuid).unwrap().status;
+
+        let k1 = *server.new_task(&c1, &req(false)).unwrap().key();
+        assert_eq!(status_of(&k1), TaskStatus::TaskRunning);
+
+        let status = server.new_task(&c1, &req(false)).map(|_| ()).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        let k2 = *server.new_task(&c1, &req(true)).unwrap().key();
+        let k3 = *server.new_task(&c1, &req(true)).unwrap().key();
+        assert_eq!(status_of(&k2), TaskStatus::TaskPending);
+        assert_eq!(status_of(&k3), TaskStatus::TaskPending);
+
+        //a pending task that's stopped must never be started
+        server.task_map.get_mut(&k2).unwrap().status = TaskStatus::TaskKilled;
+        server.finish_task(&k2);
+        assert_eq!(status_of(&k3), TaskStatus::TaskPending);
+
+        server.task_map.get_mut(&k1).unwrap().status = TaskStatus::TaskKilled;
+        server.finish_task(&k1);
+        assert_eq!(status_of(&k2), TaskStatus::TaskKilled);
+        assert_eq!(status_of(&k3), TaskStatus::TaskRunning);
+
+        //admission happens outside of any call so it's audited on its own
+        let log = std::fs::read_to_string(dir.join("audit.log")).unwrap();
+        let events = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["rpc"], "TaskAdmitted");
+        assert_eq!(events[0]["task"], k3.to_string());
+        assert_eq!(events[0]["principal"], "c1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_quota() {
+        let mut server = SchedulerServer {
+            controllers: vec![Controller::Cpu].into_iter().collect(),
+            ..Default::default()
+        };
+        let mut config = crate::quota::QuotaConfig::default();
+        config.default.log_bytes = Some(100);
+        config.groups.insert(
+            Group::Cert("ml".into()),
+            crate::quota::Quota {
+                log_bytes: Some(100),
+                ..Default::default()
+            },
+        );
+        server.quotas = Quotas::new(config);
+        let c1 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into(), "ml".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+        //a client of the same groups, of which only the secondary one has a quota
+        let c2 = ClientAuth {
+            id: "c2".into(),
+            principal: Principal::CommonName("c2".into()),
+            ..c1.clone()
+        };
+
+        let k1 = *server.new_task(&c1, &request("asd")).unwrap().key();
+        {
+            let (log_factory, log_writer) = log_channel();
+            log_writer.write(("x".repeat(100), OutputStream::Stdout));
+            server.task_map.get_mut(&k1).unwrap().log_factory = log_factory;
+        }
+        for auth in [&c1, &c2] {
+            let status = server
+                .new_task(auth, &request("asd"))
+                .map(|_| ())
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        }
+
+        //the output is kept for the client to read but no longer counts once the task stopped
+        server.task_map.get_mut(&k1).unwrap().status = TaskStatus::TaskKilled;
+        server.finish_task(&k1);
+        assert_eq!(
+            server.task_map.get(&k1).unwrap().log_factory.size_bytes(),
+            100
+        );
+        for auth in [&c1, &c2] {
+            server.new_task(auth, &request("asd")).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let config: crate::policy::PolicyConfig = toml::from_str(
+            r#"
+            [roles.sidecar]
+            permissions = ["start", "join-namespaces"]
+            allowed_host_namespaces = ["uts"]
+
+            [[bindings]]
+            role = "sidecar"
+            organizations = ["client"]
+            "#,
+        )
+        .unwrap();
+        let server = SchedulerServer {
+            policy: Arc::new(Policy::new(config).unwrap()),
+            ..Default::default()
+        };
+        let auth = |id: &str| ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName(id.into()))
+        };
+        let (c1, c2) = (auth("c1"), auth("c2"));
+        let req = |host: &[&str], join_net: &str, join_ipc: &str| StartTaskRequest {
+            namespaces: Some(NamespaceOptions {
+                host: host.iter().map(|ns| (*ns).to_owned()).collect(),
+                join_net_task: join_net.into(),
+                join_ipc_task: join_ipc.into(),
+            }),
+            ..request("asd")
+        };
+        let namespaces = |uuid: &Uuid| {
+            server
+                .task_map
+                .get(uuid)
+                .unwrap()
+                .isolation
+                .namespaces
+                .clone()
+        };
+        let code =
+            |req: &StartTaskRequest| server.new_task(&c1, req).map(|_| ()).unwrap_err().code();
+
+        //completely isolated by default, including IPC
+        let app = *server.new_task(&c1, &request("asd")).unwrap().key();
+        assert!(namespaces(&app).new.contains(&Namespace::Ipc));
+        assert!(namespaces(&app).peer.is_none());
+
+        let k = *server.new_task(&c1, &req(&["uts"], "", "")).unwrap().key();
+        assert!(!namespaces(&k).new.contains(&Namespace::Uts));
+        assert_eq!(code(&req(&["net"], "", "")), tonic::Code::PermissionDenied);
+        assert_eq!(code(&req(&["user"], "", "")), tonic::Code::InvalidArgument);
+        assert_eq!(
+            code(&req(&["network"], "", "")),
+            tonic::Code::InvalidArgument
+        );
+
+        //a sidecar enters the app's user namespace along with the joined ones
+        let app_handle = app.to_string();
+        let sidecar = *server
+            .new_task(&c1, &req(&[], &app_handle, &app_handle))
+            .unwrap()
+            .key();
+        let peer = namespaces(&sidecar).peer.unwrap();
+        assert_eq!(peer.task, app);
+        assert_eq!(peer.namespaces.len(), 2);
+        assert!(!namespaces(&sidecar).new.contains(&Namespace::User));
+        assert!(namespaces(&sidecar).new.contains(&Namespace::Pid));
+
+        assert_eq!(
+            code(&req(&[], &app_handle, &k.to_string())),
+            tonic::Code::InvalidArgument
+        );
+        //others' tasks don't exist as far as the client can tell
+        let status = server
+            .new_task(&c2, &req(&[], &app_handle, ""))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        for status in [TaskStatus::TaskPending, TaskStatus::TaskKilled] {
+            server.task_map.get_mut(&app).unwrap().status = status;
+            assert_eq!(
+                code(&req(&[], &app_handle, "")),
+                tonic::Code::FailedPrecondition
+            );
+        }
+    }
+
+    /// Needs root like the isolation tests, the cgroup is a plain dir as the
+    /// host's cgroup v2 controllers can't be relied on
+    #[tokio::test]
+    #[ignore]
+    async fn test_run_task() {
+        use rrocker_lib::api::{mount::Source, Mount};
+
+        let dir = std::env::temp_dir().join(format!("rrocker-run-{}", std::process::id()));
+        let (images, cgroups) = (dir.join("images"), dir.join("cgroups"));
+        let rootfs = images.join("host/rootfs");
+        std::fs::create_dir_all(&rootfs).unwrap();
+        std::fs::create_dir_all(&cgroups).unwrap();
+        //an image of the host's programs, which may be symlinks into /usr
+        let host_dirs = ["/usr", "/bin", "/lib", "/lib64", "/sbin"];
+        let mut mounts = Vec::new();
+        for host_dir in host_dirs {
+            match std::fs::read_link(host_dir) {
+                Ok(target) => {
+                    std::os::unix::fs::symlink(target, rootfs.join(&host_dir[1..])).unwrap()
+                }
+                Err(_) if std::path::Path::new(host_dir).is_dir() => mounts.push(Mount {
+                    target: host_dir.into(),
+                    source: Some(Source::Bind(host_dir.into())),
+                    read_only: true,
+                    ..Default::default()
+                }),
+                Err(_) => {}
+            }
+        }
+        let server = SchedulerServer {
+            images: Images::new(&crate::images::ImageConfig {
+                dir: images.clone(),
+                default_image: Some("host".into()),
+                ..Default::default()
+            })
+            .unwrap(),
+            mounts: MountConfig {
+                allowed_bind_prefixes: host_dirs.iter().map(Into::into).collect(),
+                ..Default::default()
+            },
+            worker: Some(Worker::new(Cgroup::from_path(&cgroups))),
+            ..Default::default()
+        };
+        let c1 = ClientAuth {
+            group: "client".into(),
+            groups: vec!["client".into()],
+            ..ClientAuth::new(Principal::CommonName("c1".into()))
+        };
+
+        let handle = server
+            .start_task(with_auth(
+                &c1,
+                StartTaskRequest {
+                    args: vec!["-c".into(), "echo out; echo err >&2; exit 3".into()],
+                    mounts: mounts.clone(),
+                    ..request("sh")
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner()
+            .handle
+            .unwrap();
+        let uuid = string_to_uuid(&handle.uuid).unwrap();
+        let pid = server.task_map.get(&uuid).unwrap().pid.unwrap();
+        let cgroup = cgroups.join(&handle.uuid);
+        assert_eq!(
+            std::fs::read_to_string(cgroup.join("cgroup.procs")).unwrap(),
+            pid.to_string()
+        );
+
+        //stand in for the accounting the kernel does
+        for (file, content) in [
+            ("cpu.stat", "usage_usec 10\nuser_usec 5\nsystem_usec 5\n"),
+            ("memory.current", "4096\n"),
+            ("memory.events", "low 0\nhigh 0\nmax 0\noom 0\noom_kill 0\n"),
+        ] {
+            std::fs::write(cgroup.join(file), content).unwrap();
+        }
+        let stats = server
+            .task_stats(with_auth(&c1, handle.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(stats.cpu.unwrap().usage_usec, 10);
+        assert_eq!(stats.memory.unwrap().current_bytes, 4096);
+
+        let mut output = server
+            .task_output_stream(with_auth(&c1, handle.clone()))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|reply| {
+                let reply = reply.unwrap();
+                (reply.line, reply.stream)
+            })
+            .collect::<Vec<_>>()
+            .await;
+        output.sort();
+        assert_eq!(
+            output,
+            vec![
+                ("err\n".to_owned(), OutputStream::Stderr as i32),
+                ("out\n".to_owned(), OutputStream::Stdout as i32)
+            ]
+        );
+
+        //the reaper runs on SIGCHLD in the daemon
+        async fn state(
+            server: &SchedulerServer,
+            auth: &ClientAuth,
+            handle: &TaskHandle,
+        ) -> TaskState {
+            let uuid = string_to_uuid(&handle.uuid).unwrap();
+            for _ in 0..500 {
+                server.reap();
+                if server.task_map.get(&uuid).unwrap().pid.is_none() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            server
+                .query_task(with_auth(auth, handle.clone()))
+                .await
+                .unwrap()
+                .into_inner()
+                .state
+                .unwrap()
+        }
+        let completed = state(&server, &c1, &handle).await;
+        assert_eq!(completed.status, TaskStatus::TaskCompleted as i32);
+        assert_eq!(completed.code, 3);
+
+        let sleeper = StartTaskRequest {
+            args: vec!["-c".into(), "sleep 60".into()],
+            mounts: mounts.clone(),
+            ..request("sh")
+        };
+        let handle = server
+            .start_task(with_auth(&c1, sleeper))
+            .await
+            .unwrap()
+            .into_inner()
+            .handle
+            .unwrap();
+        server
+            .stop_task(with_auth(&c1, handle.clone()))
+            .await
+            .unwrap();
+        let killed = state(&server, &c1, &handle).await;
+        assert_eq!(killed.status, TaskStatus::TaskKilled as i32);
+        let uuid = string_to_uuid(&handle.uuid).unwrap();
+        assert!(server.task_map.get(&uuid).unwrap().pid.is_none());
+
+        //the reason ends up in the error as the task is discarded
+        let missing = StartTaskRequest {
+            mounts,
+            ..request("/missing")
+        };
+        let status = server
+            .start_task(with_auth(&c1, missing))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+        assert!(
+            status.message().contains("'/missing' not found"),
+            "{}",
+            status.message()
+        );
+        assert_eq!(server.task_map.len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,807 @@
+use crate::syscalls;
+use anyhow::{anyhow, Context, Result};
+use nix::{errno::Errno, libc};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+};
+
+/// Profile without any filter
+pub const UNCONFINED: &str = "unconfined";
+const DEFAULT: &str = "default";
+const STRICT: &str = "strict";
+
+/// What happens when a rule matches, named like in Docker's profiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Action {
+    #[serde(rename = "SCMP_ACT_ALLOW")]
+    Allow,
+    /// Fail with the rule's `errnoRet`, EPERM when unset
+    #[serde(rename = "SCMP_ACT_ERRNO")]
+    Errno,
+    #[serde(rename = "SCMP_ACT_KILL_THREAD", alias = "SCMP_ACT_KILL")]
+    KillThread,
+    #[serde(rename = "SCMP_ACT_KILL_PROCESS")]
+    KillProcess,
+    #[serde(rename = "SCMP_ACT_TRAP")]
+    Trap,
+    #[serde(rename = "SCMP_ACT_LOG")]
+    Log,
+}
+
+impl Action {
+    fn ret(self, errno: Option<u32>) -> u32 {
+        match self {
+            Action::Allow => 0x7fff_0000,
+            Action::Errno => 0x0005_0000 | (errno.unwrap_or(libc::EPERM as u32) & 0xffff),
+            Action::KillThread => 0,
+            Action::KillProcess => 0x8000_0000,
+            Action::Trap => 0x0003_0000,
+            Action::Log => 0x7ffc_0000,
+        }
+    }
+}
+
+/// Comparison of a syscall argument, all comparisons are unsigned 64 bit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Op {
+    #[serde(rename = "SCMP_CMP_NE")]
+    Ne,
+    #[serde(rename = "SCMP_CMP_LT")]
+    Lt,
+    #[serde(rename = "SCMP_CMP_LE")]
+    Le,
+    #[serde(rename = "SCMP_CMP_EQ")]
+    Eq,
+    #[serde(rename = "SCMP_CMP_GE")]
+    Ge,
+    #[serde(rename = "SCMP_CMP_GT")]
+    Gt,
+    /// `arg & value == value_two`
+    #[serde(rename = "SCMP_CMP_MASKED_EQ")]
+    MaskedEq,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArgCondition {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default)]
+    pub value_two: u64,
+    pub op: Op,
+}
+
+/// Docker's conditional rules, only architectures are evaluated. Tasks are treated as
+/// having no capabilities and rules needing a minimum kernel version are skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RuleCondition {
+    pub arches: Vec<String>,
+    pub caps: Vec<String>,
+    pub min_kernel: Option<String>,
+}
+
+/// The action taken for the listed syscalls if all argument conditions match
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallRule {
+    pub names: Vec<String>,
+    pub action: Action,
+    #[serde(default)]
+    pub args: Option<Vec<ArgCondition>>,
+    pub errno_ret: Option<u32>,
+    #[serde(default)]
+    pub includes: RuleCondition,
+    #[serde(default)]
+    pub excludes: RuleCondition,
+}
+
+/// A seccomp profile in the format of Docker's `seccomp.json`. The rules of a syscall
+/// are evaluated in order and the first matching one wins, syscalls without a matching
+/// rule get the default action. Names unknown to the host's architecture are ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub default_action: Action,
+    pub default_errno_ret: Option<u32>,
+    #[serde(default)]
+    pub syscalls: Vec<SyscallRule>,
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(target_arch = "x86_64")]
+const ARCH_NAMES: &[&str] = &["amd64", "x86_64", "SCMP_ARCH_X86_64"];
+#[cfg(target_arch = "aarch64")]
+const ARCH_NAMES: &[&str] = &["arm64", "aarch64", "SCMP_ARCH_AARCH64"];
+/// Syscalls of the x32 ABI share the x86_64 arch but have this bit set
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Namespaces a task may not create by cloning
+const CLONE_NS_FLAGS: u64 = (libc::CLONE_NEWNS
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWCGROUP) as u64;
+
+/// Denied by the default profile: kernel keyrings, BPF, perf, mounting and namespaces,
+/// modules, tracing other processes, clocks and other host wide state
+const DEFAULT_DENIED: &[&str] = &[
+    "acct",
+    "add_key",
+    "bpf",
+    "clock_adjtime",
+    "clock_settime",
+    "create_module",
+    "delete_module",
+    "finit_module",
+    "fsconfig",
+    "fsmount",
+    "fsopen",
+    "fspick",
+    "get_kernel_syms",
+    "init_module",
+    "ioperm",
+    "iopl",
+    "kexec_file_load",
+    "kexec_load",
+    "keyctl",
+    "lookup_dcookie",
+    "mount",
+    "mount_setattr",
+    "move_mount",
+    "name_to_handle_at",
+    "nfsservctl",
+    "open_by_handle_at",
+    "open_tree",
+    "perf_event_open",
+    "pivot_root",
+    "process_vm_readv",
+    "process_vm_writev",
+    "ptrace",
+    "query_module",
+    "quotactl",
+    "reboot",
+    "request_key",
+    "setns",
+    "settimeofday",
+    "swapoff",
+    "swapon",
+    "syslog",
+    "umount2",
+    "unshare",
+    "uselib",
+    "userfaultfd",
+    "ustat",
+    "vhangup",
+    "_sysctl",
+];
+
+/// Additionally denied by the strict profile
+const STRICT_DENIED: &[&str] = &[
+    "chroot",
+    "fanotify_init",
+    "io_uring_enter",
+    "io_uring_register",
+    "io_uring_setup",
+    "kcmp",
+    "mbind",
+    "migrate_pages",
+    "mknod",
+    "mknodat",
+    "move_pages",
+    "personality",
+    "pidfd_getfd",
+    "set_mempolicy",
+];
+
+fn rule(
+    names: &[&str],
+    action: Action,
+    args: Vec<ArgCondition>,
+    errno: Option<u32>,
+) -> SyscallRule {
+    SyscallRule {
+        names: names.iter().map(|n| (*n).to_owned()).collect(),
+        action,
+        args: Some(args),
+        errno_ret: errno,
+        includes: Default::default(),
+        excludes: Default::default(),
+    }
+}
+
+fn arg(index: u32, op: Op, value: u64, value_two: u64) -> ArgCondition {
+    ArgCondition {
+        index,
+        value,
+        value_two,
+        op,
+    }
+}
+
+impl Profile {
+    /// Allows everything but the syscalls that let a task escape or affect the host
+    pub fn default_profile() -> Self {
+        Self {
+            default_action: Action::Allow,
+            default_errno_ret: None,
+            syscalls: vec![
+                rule(DEFAULT_DENIED, Action::Errno, Vec::new(), None),
+                //clone may create threads and processes but no namespaces
+                rule(
+                    &["clone"],
+                    Action::Allow,
+                    vec![arg(0, Op::MaskedEq, CLONE_NS_FLAGS, 0)],
+                    None,
+                ),
+                rule(&["clone"], Action::Errno, Vec::new(), None),
+                //clone3's flags are behind a pointer, ENOSYS makes libc fall back to clone
+                rule(
+                    &["clone3"],
+                    Action::Errno,
+                    Vec::new(),
+                    Some(libc::ENOSYS as u32),
+                ),
+            ],
+        }
+    }
+
+    /// The default profile plus io_uring, NUMA, device nodes, TIOCSTI and sockets of
+    /// families other than Unix, IPv4 and IPv6
+    pub fn strict_profile() -> Self {
+        let mut profile = Self::default_profile();
+        profile.syscalls.extend(vec![
+            rule(STRICT_DENIED, Action::Errno, Vec::new(), None),
+            rule(
+                &["ioctl"],
+                Action::Errno,
+                vec![arg(1, Op::Eq, libc::TIOCSTI, 0)],
+                None,
+            ),
+        ]);
+        for family in [libc::AF_UNIX, libc::AF_INET, libc::AF_INET6] {
+            profile.syscalls.push(rule(
+                &["socket"],
+                Action::Allow,
+                vec![arg(0, Op::Eq, family as u64, 0)],
+                None,
+            ));
+        }
+        profile.syscalls.push(rule(
+            &["socket"],
+            Action::Errno,
+            Vec::new(),
+            Some(libc::EAFNOSUPPORT as u32),
+        ));
+        profile
+    }
+
+    /// Compile the profile to a BPF program for the host's architecture
+    pub fn compile(&self) -> Result<Filter> {
+        let mut by_nr = BTreeMap::<u32, Vec<&SyscallRule>>::new();
+        for rule in self.syscalls.iter().filter(|r| applies(r)) {
+            for name in &rule.names {
+                match syscalls::number(name) {
+                    Some(nr) => by_nr.entry(nr).or_default().push(rule),
+                    None => tracing::debug!("Ignoring syscall '{}' unknown to this arch", name),
+                }
+            }
+        }
+        let default = self.default_action.ret(self.default_errno_ret);
+
+        //seccomp_data is { nr: u32, arch: u32, ip: u64, args: [u64; 6] }
+        let mut prog = vec![
+            stmt(BPF_LD | BPF_W | BPF_ABS, 4),
+            jump(BPF_JEQ, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET, Action::KillProcess.ret(None)),
+            stmt(BPF_LD | BPF_W | BPF_ABS, 0),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        prog.extend(vec![
+            jump(BPF_JGE, X32_SYSCALL_BIT, 0, 1),
+            stmt(BPF_RET, Action::KillProcess.ret(None)),
+        ]);
+
+        for (nr, rules) in by_nr {
+            let mut block = Vec::new();
+            for rule in rules {
+                let args = rule.args.as_deref().unwrap_or_default();
+                block.extend(compile_rule(args, rule.action.ret(rule.errno_ret))?);
+                //rules after an unconditional one are unreachable
+                if args.is_empty() {
+                    break;
+                }
+            }
+            block.push(stmt(BPF_RET, default));
+            let len = u8::try_from(block.len())
+                .map_err(|_| anyhow!("Too many rules for syscall {}", nr))?;
+            prog.push(jump(BPF_JEQ, nr, 0, len));
+            prog.extend(block);
+        }
+        prog.push(stmt(BPF_RET, default));
+
+        if prog.len() > BPF_MAXINSNS {
+            return Err(anyhow!(
+                "The profile compiles to {} instructions, at most {} are supported",
+                prog.len(),
+                BPF_MAXINSNS
+            ));
+        }
+        Ok(Filter(prog))
+    }
+}
+
+/// Whether a rule's conditions hold on this host for a task without capabilities
+fn applies(rule: &SyscallRule) -> bool {
+    let arch = |arches: &[String]| arches.iter().any(|a| ARCH_NAMES.contains(&a.as_str()));
+    (rule.includes.arches.is_empty() || arch(&rule.includes.arches))
+        && rule.includes.caps.is_empty()
+        && rule.includes.min_kernel.is_none()
+        && !arch(&rule.excludes.arches)
+}
+
+const BPF_LD: u16 = 0x00;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_W: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_AND: u16 = 0x50;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_MAXINSNS: usize = 4096;
+
+/// `struct sock_filter`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// `struct sock_fprog`
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(op: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter {
+        code: BPF_JMP | op,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Target of a conditional jump within a rule
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Next,
+    Skip(u8),
+    /// The start of the next rule
+    Fail,
+}
+
+enum Ins {
+    Load(u32),
+    And(u32),
+    Jump(u16, u32, Target, Target),
+}
+
+/// Compare an argument's high and low words, the comparisons fall through on success
+fn compile_condition(cond: &ArgCondition) -> Result<Vec<Ins>> {
+    use Ins::*;
+    use Target::*;
+
+    if cond.index > 5 {
+        return Err(anyhow!(
+            "Syscalls have 6 arguments, {} is out of range",
+            cond.index
+        ));
+    }
+    //little endian, the low word comes first
+    let lo_off = 16 + 8 * cond.index;
+    let hi_off = lo_off + 4;
+    let (hi, lo) = ((cond.value >> 32) as u32, cond.value as u32);
+
+    Ok(match cond.op {
+        Op::Eq => vec![
+            Load(hi_off),
+            Jump(BPF_JEQ, hi, Next, Fail),
+            Load(lo_off),
+            Jump(BPF_JEQ, lo, Next, Fail),
+        ],
+        Op::Ne => vec![
+            Load(hi_off),
+            Jump(BPF_JEQ, hi, Next, Skip(2)),
+            Load(lo_off),
+            Jump(BPF_JEQ, lo, Fail, Next),
+        ],
+        Op::MaskedEq => {
+            let (hi_two, lo_two) = ((cond.value_two >> 32) as u32, cond.value_two as u32);
+            vec![
+                Load(hi_off),
+                And(hi),
+                Jump(BPF_JEQ, hi_two, Next, Fail),
+                Load(lo_off),
+                And(lo),
+                Jump(BPF_JEQ, lo_two, Next, Fail),
+            ]
+        }
+        Op::Gt | Op::Ge => vec![
+            Load(hi_off),
+            Jump(BPF_JGT, hi, Skip(3), Next),
+            Jump(BPF_JEQ, hi, Next, Fail),
+            Load(lo_off),
+            Jump(
+                if cond.op == Op::Gt { BPF_JGT } else { BPF_JGE },
+                lo,
+                Next,
+                Fail,
+            ),
+        ],
+        Op::Lt | Op::Le => vec![
+            Load(hi_off),
+            Jump(BPF_JGE, hi, Next, Skip(3)),
+            Jump(BPF_JEQ, hi, Next, Fail),
+            Load(lo_off),
+            Jump(
+                if cond.op == Op::Lt { BPF_JGE } else { BPF_JGT },
+                lo,
+                Fail,
+                Next,
+            ),
+        ],
+    })
+}
+
+/// Return `ret` if all conditions hold, otherwise fall through to the next rule
+fn compile_rule(args: &[ArgCondition], ret: u32) -> Result<Vec<SockFilter>> {
+    let mut ins = Vec::new();
+    for cond in args {
+        ins.extend(compile_condition(cond)?);
+    }
+
+    //the return is the last instruction so failing jumps just past it
+    let len = ins.len() + 1;
+    let offset = |target: Target, i: usize| match target {
+        Target::Next => Ok(0),
+        Target::Skip(n) => Ok(n),
+        Target::Fail => u8::try_from(len - i - 1).map_err(|_| anyhow!("Too many conditions")),
+    };
+    let mut prog = ins
+        .into_iter()
+        .enumerate()
+        .map(|(i, ins)| {
+            Ok(match ins {
+                Ins::Load(off) => stmt(BPF_LD | BPF_W | BPF_ABS, off),
+                Ins::And(k) => stmt(BPF_ALU | BPF_AND, k),
+                Ins::Jump(op, k, jt, jf) => jump(op, k, offset(jt, i)?, offset(jf, i)?),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    prog.push(stmt(BPF_RET, ret));
+    Ok(prog)
+}
+
+/// A compiled profile
+#[derive(Clone, PartialEq, Eq)]
+pub struct Filter(Vec<SockFilter>);
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Filter({} instructions)", self.0.len())
+    }
+}
+
+impl Filter {
+    /// Install the filter on the calling thread, it's inherited by every child and kept
+    /// across exec. Needs NO_NEW_PRIVS or CAP_SYS_ADMIN in the thread's user namespace.
+    pub fn apply(&self) -> Result<()> {
+        let prog = SockFprog {
+            len: self.0.len() as libc::c_ushort,
+            filter: self.0.as_ptr(),
+        };
+        //SAFETY: the program outlives the call, the kernel copies it
+        let res = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER as libc::c_ulong,
+                &prog as *const SockFprog,
+            )
+        };
+        Errno::result(res).context("Failed to install the seccomp filter")?;
+        Ok(())
+    }
+}
+
+/// Seccomp profiles tasks may select. Besides the custom ones there are the built in
+/// `default` and `strict` profiles and `unconfined` which installs no filter.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SeccompConfig {
+    /// Profile of tasks that don't select one
+    pub default_profile: String,
+    /// Custom profiles in Docker's JSON format by name
+    pub profiles: HashMap<String, PathBuf>,
+}
+
+impl Default for SeccompConfig {
+    fn default() -> Self {
+        Self {
+            default_profile: DEFAULT.to_owned(),
+            profiles: HashMap::new(),
+        }
+    }
+}
+
+/// The compiled profiles by name, `None` for unconfined
+#[derive(Debug, Clone)]
+pub struct SeccompProfiles {
+    default_profile: String,
+    filters: HashMap<String, Option<Arc<Filter>>>,
+}
+
+impl SeccompProfiles {
+    /// Compile the built in profiles and load the custom ones
+    pub fn load(config: &SeccompConfig) -> Result<Self> {
+        let mut filters = HashMap::new();
+        filters.insert(UNCONFINED.to_owned(), None);
+        for (name, profile) in [
+            (DEFAULT, Profile::default_profile()),
+            (STRICT, Profile::strict_profile()),
+        ] {
+            filters.insert(name.to_owned(), Some(Arc::new(profile.compile()?)));
+        }
+
+        for (name, path) in &config.profiles {
+            if filters.contains_key(name) {
+                return Err(anyhow!("Seccomp profile '{}' is built in", name));
+            }
+            let content = std::fs::read_to_string(path)
+                .context(format!("Failed to read seccomp profile '{:?}'", path))?;
+            let profile = serde_json::from_str::<Profile>(&content)
+                .context(format!("Failed to parse seccomp profile '{:?}'", path))?;
+            let filter = profile
+                .compile()
+                .context(format!("Invalid seccomp profile '{:?}'", path))?;
+            filters.insert(name.clone(), Some(Arc::new(filter)));
+        }
+
+        if !filters.contains_key(&config.default_profile) {
+            return Err(anyhow!(
+                "Unknown default seccomp profile '{}'",
+                config.default_profile
+            ));
+        }
+        Ok(Self {
+            default_profile: config.default_profile.clone(),
+            filters,
+        })
+    }
+
+    /// Resolve the profile a task requested, empty selects the default profile.
+    /// Returns the profile's name and filter.
+    pub fn get<'a>(&'a self, name: &'a str) -> Option<(&'a str, Option<Arc<Filter>>)> {
+        let name = if name.is_empty() {
+            &self.default_profile
+        } else {
+            name
+        };
+        self.filters.get(name).map(|filter| (name, filter.clone()))
+    }
+}
+
+impl Default for SeccompProfiles {
+    fn default() -> Self {
+        //the built in profiles always compile
+        Self::load(&SeccompConfig::default()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clone_context::CloneContext;
+
+    /// Run the program against a syscall like the kernel would
+    fn run(filter: &Filter, nr: u32, args: [u64; 6]) -> u32 {
+        let mut data = vec![nr, AUDIT_ARCH, 0, 0];
+        for arg in args {
+            data.extend([arg as u32, (arg >> 32) as u32]);
+        }
+
+        let (mut pc, mut a) = (0, 0u32);
+        loop {
+            let ins = filter.0[pc];
+            pc += 1;
+            match ins.code {
+                c if c == BPF_LD | BPF_W | BPF_ABS => a = data[ins.k as usize / 4],
+                c if c == BPF_ALU | BPF_AND => a &= ins.k,
+                BPF_RET => return ins.k,
+                c => {
+                    let taken = match c & !BPF_JMP {
+                        BPF_JEQ => a == ins.k,
+                        BPF_JGT => a > ins.k,
+                        BPF_JGE => a >= ins.k,
+                        op => panic!("Unexpected op {:#x}", op),
+                    };
+                    pc += if taken { ins.jt } else { ins.jf } as usize;
+                }
+            }
+        }
+    }
+
+    fn nr(name: &str) -> u32 {
+        syscalls::number(name).unwrap()
+    }
+
+    const ALLOW: u32 = 0x7fff_0000;
+    const EPERM: u32 = 0x0005_0000 | libc::EPERM as u32;
+
+    #[test]
+    fn test_builtin_profiles() {
+        let default = Profile::default_profile().compile().unwrap();
+        assert_eq!(run(&default, nr("keyctl"), [0; 6]), EPERM);
+        assert_eq!(run(&default, nr("read"), [0; 6]), ALLOW);
+        //threads are fine, namespaces aren't
+        let thread = (libc::CLONE_VM | libc::CLONE_THREAD) as u64;
+        assert_eq!(run(&default, nr("clone"), [thread, 0, 0, 0, 0, 0]), ALLOW);
+        let userns = thread | libc::CLONE_NEWUSER as u64;
+        assert_eq!(run(&default, nr("clone"), [userns, 0, 0, 0, 0, 0]), EPERM);
+        assert_eq!(
+            run(&default, nr("clone3"), [0; 6]),
+            0x0005_0000 | libc::ENOSYS as u32
+        );
+        assert_eq!(
+            run(
+                &default,
+                nr("socket"),
+                [libc::AF_PACKET as u64, 0, 0, 0, 0, 0]
+            ),
+            ALLOW
+        );
+
+        let strict = Profile::strict_profile().compile().unwrap();
+        assert_eq!(run(&strict, nr("keyctl"), [0; 6]), EPERM);
+        assert_eq!(run(&strict, nr("io_uring_setup"), [0; 6]), EPERM);
+        assert_eq!(
+            run(&strict, nr("socket"), [libc::AF_INET as u64, 0, 0, 0, 0, 0]),
+            ALLOW
+        );
+        assert_ne!(
+            run(
+                &strict,
+                nr("socket"),
+                [libc::AF_PACKET as u64, 0, 0, 0, 0, 0]
+            ),
+            ALLOW
+        );
+        let tiocsti = [0, libc::TIOCSTI, 0, 0, 0, 0];
+        assert_eq!(run(&strict, nr("ioctl"), tiocsti), EPERM);
+        assert_eq!(
+            run(&strict, nr("ioctl"), [0, libc::TIOCGWINSZ, 0, 0, 0, 0]),
+            ALLOW
+        );
+
+        //other architectures are killed
+        let mut data = Profile::default_profile().compile().unwrap();
+        data.0[1].k = 0;
+        assert_eq!(run(&data, nr("read"), [0; 6]), 0x8000_0000);
+    }
+
+    #[test]
+    fn test_docker_profile() {
+        let profile: Profile = serde_json::from_str(
+            r#"{
+                "defaultAction": "SCMP_ACT_ERRNO",
+                "defaultErrnoRet": 38,
+                "architectures": ["SCMP_ARCH_X86_64", "SCMP_ARCH_AARCH64"],
+                "syscalls": [
+                    {"names": ["read", "write", "not_a_syscall"], "action": "SCMP_ACT_ALLOW", "args": null},
+                    {"names": ["personality"], "action": "SCMP_ACT_ALLOW",
+                     "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_LE"}]},
+                    {"names": ["lseek"], "action": "SCMP_ACT_ALLOW",
+                     "args": [{"index": 1, "value": 4294967296, "op": "SCMP_CMP_GT"},
+                              {"index": 2, "value": 1, "op": "SCMP_CMP_NE"}]},
+                    {"names": ["dup"], "action": "SCMP_ACT_ALLOW",
+                     "args": [{"index": 0, "value": 255, "valueTwo": 3, "op": "SCMP_CMP_MASKED_EQ"}]},
+                    {"names": ["getpid"], "action": "SCMP_ACT_ALLOW", "includes": {"caps": ["CAP_SYS_ADMIN"]}},
+                    {"names": ["getppid"], "action": "SCMP_ACT_ALLOW", "excludes": {"caps": ["CAP_SYS_ADMIN"]}},
+                    {"names": ["close"], "action": "SCMP_ACT_ERRNO", "errnoRet": 9, "comment": "x"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let filter = profile.compile().unwrap();
+        let enosys = 0x0005_0000 | 38;
+
+        assert_eq!(run(&filter, nr("read"), [0; 6]), ALLOW);
+        assert_eq!(run(&filter, nr("openat"), [0; 6]), enosys);
+        assert_eq!(run(&filter, nr("personality"), [8, 0, 0, 0, 0, 0]), ALLOW);
+        assert_eq!(run(&filter, nr("personality"), [9, 0, 0, 0, 0, 0]), enosys);
+        assert_eq!(
+            run(&filter, nr("personality"), [1 << 32, 0, 0, 0, 0, 0]),
+            enosys
+        );
+        assert_eq!(
+            run(&filter, nr("lseek"), [0, (1 << 32) + 1, 0, 0, 0, 0]),
+            ALLOW
+        );
+        assert_eq!(run(&filter, nr("lseek"), [0, 1 << 32, 0, 0, 0, 0]), enosys);
+        assert_eq!(run(&filter, nr("lseek"), [0, 1 << 33, 1, 0, 0, 0]), enosys);
+        assert_eq!(run(&filter, nr("dup"), [0x1003, 0, 0, 0, 0, 0]), ALLOW);
+        assert_eq!(run(&filter, nr("dup"), [0x1004, 0, 0, 0, 0, 0]), enosys);
+        assert_eq!(run(&filter, nr("getpid"), [0; 6]), enosys);
+        assert_eq!(run(&filter, nr("getppid"), [0; 6]), ALLOW);
+        assert_eq!(run(&filter, nr("close"), [0; 6]), 0x0005_0000 | 9);
+
+        let invalid = r#"{"defaultAction": "SCMP_ACT_NOTIFY"}"#;
+        assert!(serde_json::from_str::<Profile>(invalid).is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let filter = Profile::default_profile().compile().unwrap();
+        //the child of the clone is root in its own user namespace, which allows the filter
+        let cc = CloneContext::new(|| -> Result<(Option<i32>, bool)> {
+            filter.apply()?;
+            let keyctl = unsafe { libc::syscall(libc::SYS_keyctl, 0, 0, 0, 0, 0) };
+            let errno = (keyctl == -1).then(|| Errno::last() as i32);
+            Ok((errno, nix::unistd::getpid().as_raw() > 0))
+        })
+        .unwrap();
+        let (pid, mut rr) = cc.execute().unwrap();
+        assert_eq!(rr.get_result().unwrap(), (Some(libc::EPERM), true));
+        nix::sys::wait::waitpid(pid, None).unwrap();
+    }
+
+    #[test]
+    fn test_profiles() {
+        let dir = std::env::temp_dir().join(format!("rrocker-seccomp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("build.json");
+        std::fs::write(&path, r#"{"defaultAction": "SCMP_ACT_ALLOW"}"#).unwrap();
+
+        let mut config = SeccompConfig::default();
+        config.profiles.insert("build".into(), path.clone());
+        let profiles = SeccompProfiles::load(&config).unwrap();
+        assert_eq!(profiles.get("").unwrap().0, "default");
+        assert!(profiles.get("build").unwrap().1.is_some());
+        assert!(profiles.get(UNCONFINED).unwrap().1.is_none());
+        assert!(profiles.get("missing").is_none());
+
+        config.default_profile = "missing".into();
+        assert!(SeccompProfiles::load(&config).is_err());
+        config.default_profile = "build".into();
+        config.profiles.insert(STRICT.into(), path);
+        assert!(SeccompProfiles::load(&config).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
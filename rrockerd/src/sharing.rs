@@ -0,0 +1,213 @@
+use crate::auth::{ClientAuth, Group, Principal};
+use rrocker_lib::api::{task_sharing::Scope, TaskSharing};
+use std::{collections::BTreeSet, convert::TryFrom};
+use tonic::Status;
+
+/// Who besides its owner may access a task
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Sharing {
+    #[default]
+    Private,
+    /// Clients sharing a group or an organizational unit with the owner
+    Group,
+    /// Clients with one of the listed principal IDs
+    Principals(BTreeSet<Principal>),
+}
+
+impl Sharing {
+    /// Validate the requested sharing, unset means private
+    pub fn from_proto(sharing: Option<&TaskSharing>) -> Result<Self, Status> {
+        let sharing = match sharing {
+            Some(s) => s,
+            None => return Ok(Sharing::Private),
+        };
+
+        match Scope::from_i32(sharing.scope) {
+            Some(Scope::Principals) if sharing.principals.iter().any(String::is_empty) => Err(
+                Status::invalid_argument("Principals to share a task with must not be empty"),
+            ),
+            Some(Scope::Principals) if sharing.principals.is_empty() => Err(
+                Status::invalid_argument("Sharing with principals requires at least one principal"),
+            ),
+            Some(Scope::Principals) => sharing
+                .principals
+                .iter()
+                .map(|id| Principal::try_from(id.clone()).map_err(Status::invalid_argument))
+                .collect::<Result<_, _>>()
+                .map(Sharing::Principals),
+            Some(_) if !sharing.principals.is_empty() => Err(Status::invalid_argument(
+                "Principals can only be listed when sharing with principals",
+            )),
+            Some(Scope::Private) => Ok(Sharing::Private),
+            Some(Scope::Group) => Ok(Sharing::Group),
+            None => Err(Status::invalid_argument("Invalid sharing scope")),
+        }
+    }
+
+    pub fn to_proto(&self) -> TaskSharing {
+        let (scope, principals) = match self {
+            Sharing::Private => (Scope::Private, Vec::new()),
+            Sharing::Group => (Scope::Group, Vec::new()),
+            Sharing::Principals(p) => {
+                (Scope::Principals, p.iter().map(|p| p.to_string()).collect())
+            }
+        };
+        TaskSharing {
+            scope: scope as i32,
+            principals,
+        }
+    }
+}
+
+/// The client that started a task plus who it's shared with
+#[derive(Debug, Clone, Default)]
+pub struct Ownership {
+    pub owner: Principal,
+    /// The owner's primary group
+    pub group: String,
+    pub groups: Vec<Group>,
+    pub org_units: Vec<String>,
+    pub sharing: Sharing,
+}
+
+impl Ownership {
+    pub fn new(auth: &ClientAuth, sharing: Sharing) -> Self {
+        Self {
+            owner: auth.principal.clone(),
+            group: auth.group.clone(),
+            groups: auth.qualified_groups(),
+            org_units: auth.org_units.clone(),
+            sharing,
+        }
+    }
+
+    pub fn is_owner(&self, auth: &ClientAuth) -> bool {
+        self.owner == auth.principal
+    }
+
+    /// Whether the client owns the task or it's shared with it
+    pub fn has_access(&self, auth: &ClientAuth) -> bool {
+        self.is_owner(auth)
+            || match &self.sharing {
+                Sharing::Private => false,
+                Sharing::Group => {
+                    auth.qualified_groups()
+                        .iter()
+                        .any(|g| self.groups.contains(g))
+                        || (!auth.principal.is_local()
+                            && !self.owner.is_local()
+                            && auth.org_units.iter().any(|ou| self.org_units.contains(ou)))
+                }
+                Sharing::Principals(principals) => principals.contains(&auth.principal),
+            }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn auth(id: &str, group: &str, org_units: &[&str]) -> ClientAuth {
+        ClientAuth {
+            group: group.into(),
+            groups: vec![group.into()],
+            org_units: org_units.iter().map(|s| (*s).to_owned()).collect(),
+            ..ClientAuth::new(Principal::CommonName(id.into()))
+        }
+    }
+
+    #[test]
+    fn test_has_access() {
+        let owner = auth("c1", "team-a", &["ml"]);
+        let teammate = auth("c2", "team-a", &[]);
+        let ou_member = auth("c3", "team-b", &["ml"]);
+        let other = auth("c4", "team-b", &["web"]);
+
+        let mut ownership = Ownership::new(&owner, Sharing::Private);
+        assert!(ownership.has_access(&owner));
+        assert!(!ownership.has_access(&teammate));
+
+        ownership.sharing = Sharing::Group;
+        assert!(ownership.has_access(&teammate));
+        assert!(ownership.has_access(&ou_member));
+        assert!(!ownership.has_access(&other));
+
+        ownership.sharing = Sharing::Principals(
+            vec![Principal::CommonName("c4".into())]
+                .into_iter()
+                .collect(),
+        );
+        assert!(ownership.has_access(&owner));
+        assert!(!ownership.has_access(&teammate));
+        assert!(ownership.has_access(&other));
+    }
+
+    #[test]
+    fn test_local_users() {
+        let local = ClientAuth {
+            group: "ops".into(),
+            groups: vec!["ops".into()],
+            ..ClientAuth::new(Principal::Local("unix:1003".into()))
+        };
+        let cert = auth("ops-bot", "ops", &[]);
+
+        //local groups and cert groups of the same name are different groups
+        let mut ownership = Ownership::new(&local, Sharing::Group);
+        assert!(ownership.has_access(&local));
+        assert!(!ownership.has_access(&cert));
+        assert!(!Ownership::new(&cert, Sharing::Group).has_access(&local));
+
+        let sharing = TaskSharing {
+            scope: Scope::Principals as i32,
+            principals: vec!["ops-bot".into()],
+        };
+        ownership.sharing = Sharing::from_proto(Some(&sharing)).unwrap();
+        assert!(ownership.has_access(&cert));
+        assert_eq!(
+            Sharing::from_proto(Some(&TaskSharing {
+                principals: vec!["unix:1003".into()],
+                ..sharing
+            }))
+            .unwrap(),
+            Sharing::Principals(vec![local.principal].into_iter().collect())
+        );
+    }
+
+    #[test]
+    fn test_from_proto() {
+        assert_eq!(Sharing::from_proto(None).unwrap(), Sharing::Private);
+
+        let principals = TaskSharing {
+            scope: Scope::Principals as i32,
+            principals: vec!["c2".into(), "c3".into()],
+        };
+        let sharing = Sharing::from_proto(Some(&principals)).unwrap();
+        assert_eq!(sharing.to_proto(), principals);
+
+        for invalid in &[
+            TaskSharing {
+                scope: 42,
+                principals: vec![],
+            },
+            TaskSharing {
+                scope: Scope::Principals as i32,
+                principals: vec![],
+            },
+            TaskSharing {
+                scope: Scope::Principals as i32,
+                principals: vec!["".into()],
+            },
+            TaskSharing {
+                scope: Scope::Group as i32,
+                principals: vec!["c2".into()],
+            },
+            TaskSharing {
+                scope: Scope::Principals as i32,
+                principals: vec!["spiffe://corp".into()],
+            },
+        ] {
+            let status = Sharing::from_proto(Some(invalid)).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use crate::user::{IdMap, IdMapping};
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::{Uid, User};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tonic::Status;
+use uuid::Uuid;
+
+/// Subordinate IDs (see subuid(5)) tasks are mapped to instead of the daemon's own uid and gid
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubIdConfig {
+    /// Give every task its own range of subordinate uids and gids, otherwise the task's
+    /// uid and gid are mapped to the daemon's and every other ID is unmapped
+    pub enabled: bool,
+    /// IDs in each task's range, the task sees them as 0 up to `range_size`
+    pub range_size: u32,
+    /// Owner of the ranges in the files, defaults to the user the daemon runs as
+    pub user: Option<String>,
+    pub subuid_file: PathBuf,
+    pub subgid_file: PathBuf,
+}
+
+impl Default for SubIdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            range_size: 65536,
+            user: None,
+            subuid_file: "/etc/subuid".into(),
+            subgid_file: "/etc/subgid".into(),
+        }
+    }
+}
+
+/// A range of subordinate IDs as `(start, count)`
+type Range = (u32, u32);
+
+/// The ranges of `owners`, given by name or numeric ID, in a subuid/subgid file
+fn parse(contents: &str, owners: &[String]) -> Result<Vec<Range>> {
+    let mut ranges = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split(':').collect::<Vec<_>>();
+        let (owner, start, count) = match fields.as_slice() {
+            [owner, start, count] => (owner, start, count),
+            _ => return Err(anyhow!("Invalid line '{}'", line)),
+        };
+        if !owners.iter().any(|o| o == owner) {
+            continue;
+        }
+        let start = start
+            .parse::<u32>()
+            .context(format!("Invalid start in '{}'", line))?;
+        let count = count
+            .parse::<u32>()
+            .context(format!("Invalid count in '{}'", line))?;
+        if start.checked_add(count).is_none() {
+            return Err(anyhow!("Range '{}' overflows", line));
+        }
+        ranges.push((start, count));
+    }
+
+    //handing the same ID to two tasks would let them tamper with each other
+    let mut sorted = ranges.clone();
+    sorted.sort_unstable();
+    if let Some(w) = sorted.windows(2).find(|w| w[0].0 + w[0].1 > w[1].0) {
+        return Err(anyhow!(
+            "Ranges starting at {} and {} overlap",
+            w[0].0,
+            w[1].0
+        ));
+    }
+    Ok(ranges)
+}
+
+fn load(path: &Path, owners: &[String]) -> Result<Vec<Range>> {
+    let contents = std::fs::read_to_string(path).context(format!("Failed to read '{:?}'", path))?;
+    parse(&contents, owners).context(format!("Invalid '{:?}'", path))
+}
+
+fn total(ranges: &[Range]) -> u64 {
+    ranges.iter().map(|&(_, count)| u64::from(count)).sum()
+}
+
+/// Carve block `index` of `size` IDs out of the ranges, it may span several of them
+fn block(ranges: &[Range], index: u32, size: u32) -> Vec<IdMap> {
+    let mut skip = u64::from(index) * u64::from(size);
+    let mut maps = Vec::new();
+    let mut inside = 0;
+    for &(start, count) in ranges {
+        if skip >= u64::from(count) {
+            skip -= u64::from(count);
+            continue;
+        }
+        let offset = skip as u32;
+        skip = 0;
+        let len = (count - offset).min(size - inside);
+        maps.push(IdMap {
+            inside,
+            outside: start + offset,
+            len,
+        });
+        inside += len;
+        if inside == size {
+            break;
+        }
+    }
+    maps
+}
+
+/// Hands every task its own block of subordinate uids and gids
+#[derive(Debug, Default)]
+pub struct SubIds {
+    uids: Vec<Range>,
+    gids: Vec<Range>,
+    size: u32,
+    blocks: u32,
+    /// The block of every unfinished task
+    tasks: Mutex<HashMap<Uuid, u32>>,
+}
+
+impl SubIds {
+    /// Load the daemon's ranges, `None` if subordinate IDs aren't enabled
+    pub fn load(config: &SubIdConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let owners = match &config.user {
+            Some(user) => {
+                let uid = User::from_name(user)?.map(|u| u.uid.to_string());
+                std::iter::once(user.clone()).chain(uid).collect()
+            }
+            None => {
+                let uid = Uid::current();
+                let name = User::from_uid(uid)?.map(|u| u.name);
+                std::iter::once(uid.to_string())
+                    .chain(name)
+                    .collect::<Vec<_>>()
+            }
+        };
+        Self::new(
+            load(&config.subuid_file, &owners)?,
+            load(&config.subgid_file, &owners)?,
+            config.range_size,
+        )
+        .map(Some)
+    }
+
+    fn new(uids: Vec<Range>, gids: Vec<Range>, size: u32) -> Result<Self> {
+        if size == 0 {
+            return Err(anyhow!("The range size must be positive"));
+        }
+        let blocks = total(&uids).min(total(&gids)) / u64::from(size);
+        if blocks == 0 {
+            return Err(anyhow!(
+                "There aren't {} subordinate uids and gids for a single task",
+                size
+            ));
+        }
+        Ok(Self {
+            uids,
+            gids,
+            size,
+            blocks: u32::try_from(blocks).unwrap_or(u32::MAX),
+            ..Default::default()
+        })
+    }
+
+    /// IDs each task sees, `0..size`
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Assign the lowest free block to the task
+    pub fn allocate(&self, uuid: Uuid) -> Result<IdMapping, Status> {
+        let mut tasks = self.tasks.lock().unwrap();
+        let used = tasks.values().copied().collect::<HashSet<_>>();
+        let index = (0..self.blocks)
+            .find(|i| !used.contains(i))
+            .ok_or_else(|| {
+                Status::resource_exhausted(format!(
+                    "All {} ranges of subordinate IDs are in use",
+                    self.blocks
+                ))
+            })?;
+        tasks.insert(uuid, index);
+        Ok(IdMapping {
+            uids: block(&self.uids, index, self.size),
+            gids: block(&self.gids, index, self.size),
+        })
+    }
+
+    pub fn release(&self, uuid: &Uuid) {
+        self.tasks.lock().unwrap().remove(uuid);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SUBUID: &str = "
+        # comment
+        alice:100000:65536
+        rrocker:200000:100000
+        1001:400000:31072
+    ";
+
+    #[test]
+    fn test_parse() {
+        let owners = vec!["rrocker".to_owned(), "1001".to_owned()];
+        assert_eq!(
+            parse(SUBUID, &owners).unwrap(),
+            vec![(200000, 100000), (400000, 31072)]
+        );
+        assert!(parse(SUBUID, &["bob".to_owned()]).unwrap().is_empty());
+        assert!(parse("rrocker:1", &owners).is_err());
+        assert!(parse("rrocker:100:100\n1001:150:100", &owners).is_err());
+    }
+
+    #[test]
+    fn test_allocate() {
+        let ranges = vec![(200000, 100000), (400000, 31072)];
+        let ids = SubIds::new(ranges.clone(), ranges, 65536).unwrap();
+        let (t1, t2, t3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        let m1 = ids.allocate(t1).unwrap();
+        assert_eq!(
+            m1.uids,
+            vec![IdMap {
+                inside: 0,
+                outside: 200000,
+                len: 65536
+            }]
+        );
+        //the second block spans both ranges
+        let m2 = ids.allocate(t2).unwrap();
+        assert_eq!(
+            m2.gids,
+            vec![
+                IdMap {
+                    inside: 0,
+                    outside: 265536,
+                    len: 34464
+                },
+                IdMap {
+                    inside: 34464,
+                    outside: 400000,
+                    len: 31072
+                }
+            ]
+        );
+        let status = ids.allocate(t3).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        //a finished task's block is reused
+        ids.release(&t1);
+        assert_eq!(ids.allocate(t3).unwrap(), m1);
+
+        assert!(SubIds::new(vec![(1000, 100)], vec![(1000, 100)], 65536).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::config::TlsConfig;
+use anyhow::{anyhow, Context, Result};
+use futures::Stream;
+use std::{
+    io::Cursor,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile, AllowAnyAuthenticatedClient, PrivateKey, RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Clients taking longer than this to complete the handshake are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).context(format!("Failed to read '{:?}'", path))
+}
+
+/// Same as tonic, the key may either be PKCS8 or RSA encoded
+fn load_key(pem: &[u8]) -> Result<PrivateKey> {
+    let keys = pemfile::pkcs8_private_keys(&mut Cursor::new(pem))
+        .ok()
+        .filter(|k| !k.is_empty())
+        .or_else(|| pemfile::rsa_private_keys(&mut Cursor::new(pem)).ok());
+
+    keys.and_then(|k| k.into_iter().next())
+        .ok_or_else(|| anyhow!("No PKCS8 or RSA private key found"))
+}
+
+/// Build the server's TLS config requiring clients to present a cert signed by the client CA
+fn load_config(tls: &TlsConfig) -> Result<ServerConfig> {
+    let certs = pemfile::certs(&mut Cursor::new(read(&tls.cert)?))
+        .map_err(|_| anyhow!("Invalid certificate '{:?}'", tls.cert))?;
+    let key = load_key(&read(&tls.key)?).context(format!("Invalid key '{:?}'", tls.key))?;
+
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut Cursor::new(read(&tls.client_ca)?)) {
+        Ok((added, _)) if added > 0 => {}
+        _ => return Err(anyhow!("Invalid client CA '{:?}'", tls.client_ca)),
+    }
+
+    let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots));
+    config
+        .set_single_cert(certs, key)
+        .context("Invalid certificate or key")?;
+    config.set_protocols(&[b"h2".to_vec()]);
+    Ok(config)
+}
+
+/// Modification times of the cert, key and client CA
+fn modified(tls: &TlsConfig) -> Vec<Option<SystemTime>> {
+    vec![&tls.cert, &tls.key, &tls.client_ca]
+        .into_iter()
+        .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// The server's TLS identity and client CA which can be reloaded without a restart.
+/// A reload only affects new connections, established ones keep the config
+/// they were accepted with so running tasks and streams are unaffected.
+pub struct ReloadableTls {
+    paths: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+    /// Modification times of the files the current config was loaded from
+    loaded: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl ReloadableTls {
+    /// Load the TLS config, unlike reloads the initial load has to succeed
+    pub fn new(paths: TlsConfig) -> Result<Self> {
+        let loaded = modified(&paths);
+        let config = load_config(&paths)?;
+        Ok(Self {
+            paths,
+            current: RwLock::new(Arc::new(config)),
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    /// Reload the files, on failure the current config is kept
+    pub fn reload(&self) -> Result<()> {
+        let loaded = modified(&self.paths);
+        let config = load_config(&self.paths)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        *self.loaded.lock().unwrap() = loaded;
+        Ok(())
+    }
+
+    /// Whether any of the files changed since they were last loaded
+    pub fn changed(&self) -> bool {
+        *self.loaded.lock().unwrap() != modified(&self.paths)
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    /// Reload on SIGHUP and, unless `watch_interval` is `None`, whenever the files change
+    pub async fn reload_loop(self: Arc<Self>, watch_interval: Option<Duration>) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+        //without watching the ticker is never polled
+        let mut ticker = tokio::time::interval(watch_interval.unwrap_or(Duration::from_secs(3600)));
+        ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick(), if watch_interval.is_some() => {
+                    if !self.changed() {
+                        continue;
+                    }
+                    tracing::info!("TLS files changed, reloading");
+                }
+                _ = hangup.recv() => tracing::info!("Received SIGHUP, reloading TLS config"),
+            }
+            match self.reload() {
+                Ok(()) => tracing::info!("Reloaded TLS config"),
+                Err(e) => {
+                    tracing::error!("Failed to reload TLS config, keeping the old one: {:?}", e)
+                }
+            }
+        }
+    }
+
+    /// Accept connections and complete their handshakes with the TLS config current at
+    /// the time of accepting. Handshakes run concurrently so a slow client can't stall others.
+    pub fn incoming(
+        self: Arc<Self>,
+        listener: TcpListener,
+    ) -> impl Stream<Item = Result<TlsStream<TcpStream>, std::io::Error>> {
+        let (tx, rx) = mpsc::channel(32);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        tracing::warn!("Failed to accept connection: {:?}", e);
+                        continue;
+                    }
+                };
+                let acceptor = self.acceptor();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(tls)) => {
+                            let _ = tx.send(Ok(tls)).await;
+                        }
+                        Ok(Err(e)) => tracing::warn!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => tracing::warn!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+
+        ReceiverStream::new(rx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_cert(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../certs/test")
+            .join(name)
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("rrocker-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = TlsConfig {
+            cert: dir.join("crt.pem"),
+            key: dir.join("key.pem"),
+            client_ca: dir.join("ca.pem"),
+            ..Default::default()
+        };
+        let install = |name: &str| {
+            std::fs::copy(test_cert(&format!("{}_crt.pem", name)), &paths.cert).unwrap();
+            std::fs::copy(test_cert(&format!("{}_key.pem", name)), &paths.key).unwrap();
+        };
+        install("good");
+        std::fs::copy(test_cert("crl_ca_crt.pem"), &paths.client_ca).unwrap();
+
+        let tls = ReloadableTls::new(paths.clone()).unwrap();
+        assert!(!tls.changed());
+        let before = tls.current.read().unwrap().clone();
+
+        install("not_revoked");
+        //make sure the modification time differs on file systems with coarse timestamps
+        let mtime = SystemTime::now() + Duration::from_secs(1);
+        for p in &[&paths.cert, &paths.key] {
+            std::fs::File::options()
+                .write(true)
+                .open(p)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+        assert!(tls.changed());
+        tls.reload().unwrap();
+        assert!(!tls.changed());
+        assert!(!Arc::ptr_eq(&before, &tls.current.read().unwrap()));
+
+        //a broken key keeps the current config
+        let current = tls.current.read().unwrap().clone();
+        std::fs::write(&paths.key, "garbage").unwrap();
+        assert!(tls.reload().is_err());
+        assert!(Arc::ptr_eq(&current, &tls.current.read().unwrap()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let paths = TlsConfig {
+            cert: test_cert("good_crt.pem"),
+            key: test_cert("good_key.pem"),
+            client_ca: test_cert("crl_ca_crt.pem"),
+            ..Default::default()
+        };
+        ReloadableTls::new(paths.clone()).unwrap();
+
+        assert!(ReloadableTls::new(TlsConfig {
+            client_ca: test_cert("crl.pem"),
+            ..paths.clone()
+        })
+        .is_err());
+        assert!(ReloadableTls::new(TlsConfig {
+            key: test_cert("missing.pem"),
+            ..paths
+        })
+        .is_err());
+    }
+}
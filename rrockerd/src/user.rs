@@ -1,7 +1,83 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use caps::CapSet;
 #[cfg(target_family = "unix")]
-use nix::unistd::{Gid, Uid};
-use std::{fs::OpenOptions, io::Write};
+use nix::unistd::{self, Gid, Pid, Uid};
+use nix::{errno::Errno, libc};
+use std::{fs::OpenOptions, io::Write, process::Command};
+
+/// A range of IDs inside a user namespace and the IDs of the parent namespace it maps to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMap {
+    pub inside: u32,
+    pub outside: u32,
+    pub len: u32,
+}
+
+/// The uid and gid maps of a task's user namespace
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMapping {
+    pub uids: Vec<IdMap>,
+    pub gids: Vec<IdMap>,
+}
+
+#[cfg(target_family = "unix")]
+impl IdMapping {
+    /// Write the maps of the user namespace of `pid` from the parent namespace. Root writes
+    /// them directly, anyone else needs newuidmap/newgidmap to vouch for the ranges.
+    pub fn apply(&self, pid: Pid) -> Result<()> {
+        if Uid::effective().is_root() {
+            write_map(pid, "uid_map", &self.uids)?;
+            write_map(pid, "gid_map", &self.gids)
+        } else {
+            run_map_helper("newuidmap", pid, &self.uids)?;
+            run_map_helper("newgidmap", pid, &self.gids)
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn write_map(pid: Pid, file: &str, maps: &[IdMap]) -> Result<()> {
+    //the kernel only accepts the whole map in a single write
+    let map = maps
+        .iter()
+        .map(|m| format!("{} {} {}\n", m.inside, m.outside, m.len))
+        .collect::<String>();
+    std::fs::write(format!("/proc/{}/{}", pid, file), map)
+        .context(format!("Failed to write {} of {}", file, pid))
+}
+
+#[cfg(target_family = "unix")]
+fn run_map_helper(helper: &str, pid: Pid, maps: &[IdMap]) -> Result<()> {
+    let status = Command::new(helper)
+        .arg(pid.to_string())
+        .args(
+            maps.iter()
+                .flat_map(|m| vec![m.inside, m.outside, m.len])
+                .map(|id| id.to_string()),
+        )
+        .status()
+        .context(format!("Failed to run {}", helper))?;
+    if !status.success() {
+        return Err(anyhow!("{} failed with {}", helper, status));
+    }
+    Ok(())
+}
+
+/// Become `uid` and `gid` in a user namespace whose maps were written by the parent, which
+/// unlike writing our own map doesn't make us any of the mapped IDs.
+/// The daemon's supplementary groups are dropped as they'd otherwise leak into the task.
+#[cfg(target_family = "unix")]
+pub(crate) fn switch_ids(uid: Uid, gid: Gid) -> Result<()> {
+    unistd::setgroups(&[]).context("Failed to drop the supplementary groups")?;
+    //keep the capabilities across setresuid, they're restricted later on
+    Errno::result(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) })
+        .context("Failed to set keepcaps")?;
+    unistd::setresgid(gid, gid, gid).context(format!("Failed to switch to gid {}", gid))?;
+    unistd::setresuid(uid, uid, uid).context(format!("Failed to switch to uid {}", uid))?;
+    //leaving uid 0 clears the effective set even with keepcaps
+    let permitted = caps::read(None, CapSet::Permitted)?;
+    caps::set(None, CapSet::Effective, &permitted).context("Failed to raise the capabilities")
+}
 
 #[cfg(target_family = "unix")]
 pub(crate) fn write_uid_map(inside: Uid, outside: Uid, len: u32) -> Result<()> {
@@ -46,3 +122,58 @@ pub(crate) fn write_uid_map(inside: u32, outside: u32, len: u32) -> Result<()> {
 pub(crate) fn write_gid_map(inside: u32, outside: u32, len: u32) -> Result<()> {
     unimplemented!()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{clone_context::CloneContext, pipe::Pipe};
+    use std::io::Read;
+
+    #[test]
+    #[ignore]
+    fn test_id_mapping() {
+        //two ranges appear as one contiguous range inside the namespace
+        let mapping = IdMapping {
+            uids: vec![
+                IdMap {
+                    inside: 0,
+                    outside: 200_000,
+                    len: 1000,
+                },
+                IdMap {
+                    inside: 1000,
+                    outside: 300_000,
+                    len: 1000,
+                },
+            ],
+            gids: vec![IdMap {
+                inside: 0,
+                outside: 200_000,
+                len: 2000,
+            }],
+        };
+        let (reader, mut writer) = Pipe::new().unwrap().split();
+        let cc = CloneContext::new(|| -> Result<(u32, u32, String)> {
+            (&reader).read_exact(&mut [0u8])?;
+            switch_ids(Uid::from_raw(1500), Gid::from_raw(1500))?;
+            let map = std::fs::read_to_string("/proc/self/uid_map")?;
+            Ok((Uid::current().as_raw(), Gid::current().as_raw(), map))
+        })
+        .unwrap();
+        let (pid, mut rr) = cc.execute().unwrap();
+        mapping.apply(pid).unwrap();
+        writer.write_all(&[0]).unwrap();
+
+        let (uid, gid, map) = rr.get_result().unwrap();
+        nix::sys::wait::waitpid(pid, None).unwrap();
+        assert_eq!((uid, gid), (1500, 1500));
+        let map = map
+            .lines()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            map,
+            vec![vec!["0", "200000", "1000"], vec!["1000", "300000", "1000"]]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    cgroup::{Cgroup, Limits},
+    isolation::{IsolatedProcess, Isolation},
+    log::LogWriter,
+    pipe::Pipe,
+    process::Process,
+};
+use anyhow::{anyhow, Context, Result};
+use nix::{
+    errno::Errno,
+    fcntl::{self, FcntlArg, OFlag},
+    sys::{
+        signal::{self, Signal},
+        wait::{self, WaitPidFlag, WaitStatus},
+    },
+    unistd::{self, Pid},
+};
+use rrocker_lib::api::OutputStream;
+use std::{ffi::CString, fs::File, io::Read, os::unix::io::AsRawFd, path::Path};
+use tokio::io::unix::AsyncFd;
+use uuid::Uuid;
+
+/// Lines longer than this are truncated so they fit into a gRPC message
+const MAX_LINE_BYTES: usize = 1 << 20;
+
+type Log = LogWriter<(String, OutputStream)>;
+
+/// Starts the processes of tasks, each in its own cgroup below `cgroups`
+#[derive(Debug)]
+pub struct Worker {
+    cgroups: Cgroup,
+}
+
+impl Worker {
+    pub fn new(cgroups: Cgroup) -> Self {
+        Self { cgroups }
+    }
+
+    /// The cgroup of a task, which is created when the task is started
+    pub fn cgroup(&self, uuid: &Uuid) -> Cgroup {
+        self.cgroups.child(&uuid.to_string())
+    }
+
+    /// Create the task's cgroup with its limits and run its process in it confined by `isolation`,
+    /// joining the namespaces of `peer` if it has one. Its stdout and stderr are written to `log`
+    /// line by line, why it couldn't be started ends up in its stderr.
+    /// Must be called inside the tokio runtime.
+    pub fn spawn(
+        &self,
+        cgroup: &Cgroup,
+        limits: &Limits,
+        isolation: Isolation,
+        process: &Process,
+        peer: Option<Pid>,
+        log: Log,
+    ) -> Result<Pid> {
+        match start(cgroup, limits, isolation, process, peer) {
+            Ok((pid, stdout, stderr)) => {
+                tokio::spawn(forward_output(stdout, stderr, log));
+                Ok(pid)
+            }
+            Err(e) => {
+                log.write((
+                    format!("Failed to start the task: {:#}\n", e),
+                    OutputStream::Stderr,
+                ));
+                Err(e)
+            }
+        }
+    }
+}
+
+fn start(
+    cgroup: &Cgroup,
+    limits: &Limits,
+    isolation: Isolation,
+    process: &Process,
+    peer: Option<Pid>,
+) -> Result<(Pid, File, File)> {
+    cgroup.create()?;
+    let started = cgroup
+        .apply(limits)
+        .and_then(|_| exec(cgroup, isolation, process, peer));
+    if started.is_err() {
+        if let Err(e) = cgroup.remove() {
+            tracing::warn!("Failed to clean up after a task: {:?}", e);
+        }
+    }
+    started
+}
+
+/// Clone the task and exec its process, returns the read ends of its stdout and stderr
+fn exec(
+    cgroup: &Cgroup,
+    isolation: Isolation,
+    process: &Process,
+    peer: Option<Pid>,
+) -> Result<(Pid, File, File)> {
+    let (stdout, stdout_writer) = Pipe::new()?.split();
+    let (stderr, stderr_writer) = Pipe::new()?.split();
+    let cstring = |s: &str| CString::new(s).context(format!("'{}' contains a nul byte", s));
+    let argv = std::iter::once(&process.cmd)
+        .chain(&process.args)
+        .map(|arg| cstring(arg))
+        .collect::<Result<Vec<_>>>()?;
+    let env = process
+        .env
+        .iter()
+        .map(|entry| cstring(entry))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut child = IsolatedProcess::new(isolation, move || -> Result<()> {
+        //looked up in the task so its own symlinks apply
+        let program = process
+            .program(Path::new("/"))
+            .map_err(|status| anyhow!("{}", status.message()))?;
+        let stdin = File::open("/dev/null").context("Failed to open /dev/null")?;
+        for (file, fd) in [(&stdin, 0), (&stdout_writer, 1), (&stderr_writer, 2)] {
+            unistd::dup2(file.as_raw_fd(), fd).context("Failed to redirect stdio")?;
+        }
+        unistd::chdir(&process.working_dir)
+            .context(format!("Failed to change into '{:?}'", process.working_dir))?;
+        let e = unistd::execve(&cstring(&program)?, &argv, &env).unwrap_err();
+        Err(e).context(format!("Failed to execute '{}'", program))
+    })?
+    .cgroup(cgroup.clone());
+    if let Some(peer) = peer {
+        child = child.join(peer)?;
+    }
+
+    let (pid, mut result) = child.execute()?;
+    if let Err(e) = result.wait_exec() {
+        let _ = wait::waitpid(pid, None);
+        return Err(e);
+    }
+    Ok((pid, stdout, stderr))
+}
+
+/// Write the output of both pipes to the log, which is closed once both are
+async fn forward_output(stdout: File, stderr: File, log: Log) {
+    let (out, err) = tokio::join!(
+        forward_lines(stdout, OutputStream::Stdout, &log),
+        forward_lines(stderr, OutputStream::Stderr, &log),
+    );
+    if let Err(e) = out.and(err) {
+        tracing::warn!("Failed to read the output of a task: {:?}", e);
+    }
+}
+
+/// Write the pipe's lines to the log, without holding a thread while the task is quiet
+async fn forward_lines(pipe: File, stream: OutputStream, log: &Log) -> Result<()> {
+    fcntl::fcntl(pipe.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    let pipe = AsyncFd::new(pipe)?;
+    let mut buf = vec![0u8; 64 << 10];
+    let mut line = Vec::new();
+    let write = |line: &mut Vec<u8>| {
+        log.write((String::from_utf8_lossy(line).into_owned(), stream));
+        line.clear();
+    };
+
+    loop {
+        let mut ready = pipe.readable().await?;
+        let n = match ready.try_io(|pipe| pipe.get_ref().read(&mut buf)) {
+            Ok(n) => n?,
+            Err(_would_block) => continue,
+        };
+        if n == 0 {
+            break;
+        }
+        for chunk in buf[..n].split_inclusive(|b| *b == b'\n') {
+            let keep = chunk.len().min(MAX_LINE_BYTES.saturating_sub(line.len()));
+            line.extend_from_slice(&chunk[..keep]);
+            if chunk.ends_with(b"\n") {
+                write(&mut line);
+            }
+        }
+    }
+    if !line.is_empty() {
+        write(&mut line);
+    }
+    Ok(())
+}
+
+/// SIGKILL the task's process and anything it left in its cgroup
+pub fn kill(pid: Pid, cgroup: &Cgroup) {
+    if let Err(e) = signal::kill(pid, Signal::SIGKILL) {
+        tracing::warn!("Failed to kill process {}: {:?}", pid, e);
+    }
+    //without a pid namespace of their own orphans would outlive the task
+    if let Err(e) = cgroup.kill() {
+        tracing::debug!("Failed to kill cgroup {:?}: {:?}", cgroup.path(), e);
+    }
+}
+
+/// Reap the process if it exited, returning its exit code.
+/// Killed processes exit with 128 + the signal like in a shell.
+pub fn try_wait(pid: Pid) -> Option<i32> {
+    match wait::waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+        Ok(WaitStatus::Exited(_, code)) => Some(code),
+        Ok(WaitStatus::Signaled(_, signal, _)) => Some(128 + signal as i32),
+        Ok(_) | Err(Errno::EINTR) => None,
+        //someone else reaped it, there's no exit code to report
+        Err(e) => {
+            tracing::warn!("Failed to wait for process {}: {:?}", pid, e);
+            Some(1)
+        }
+    }
+}